@@ -0,0 +1,65 @@
+// Timings for VerifyCredentials' batch verification. The auth service is a binary, so the modules involved are
+// compiled in here straight from its sources, like in the fuzz harness.
+//
+//     cargo bench --bench batch_verify
+//
+// Each case verifies the same number of passwords with the production hash rounds. Concurrent batches share one
+// `BatchThreads`, so they should take about as long as a single batch on as many threads, not be sped up by it.
+#![allow(dead_code, unused_imports)] // The modules' tests aren't compiled in, but their imports are.
+
+#[path = "../src/auth-service/hashing.rs"]
+mod hashing;
+#[path = "../src/auth-service/metrics.rs"]
+mod metrics;
+#[path = "../src/auth-service/password.rs"]
+mod password;
+#[path = "../src/auth-service/pool.rs"]
+mod pool;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hashing::{PasswordScheme, Pbkdf2Scheme};
+use password::Password;
+use pool::BatchThreads;
+
+const VERIFICATIONS: usize = 32;
+
+fn main() {
+    let scheme = Pbkdf2Scheme::new(pbkdf2::Params::RECOMMENDED_ROUNDS as u32);
+    let password = Password::from("correct horse battery staple");
+    let hash = scheme.hash(&password).unwrap();
+    let verify = |_: &usize| assert!(scheme.verify(&password, &hash));
+
+    for threads in [1, 2, 4] {
+        let batch_threads = BatchThreads::new(threads);
+        let elapsed = timed(|| {
+            batch_threads.map(&[0; VERIFICATIONS], verify);
+        });
+        report(&format!("1 batch of {VERIFICATIONS} on {threads} threads"), elapsed);
+    }
+
+    for batches in [2, 4] {
+        let batch_threads = BatchThreads::new(2);
+        let items = vec![0; VERIFICATIONS / batches];
+        let elapsed = timed(|| {
+            thread::scope(|scope| {
+                for _ in 0..batches {
+                    scope.spawn(|| batch_threads.map(&items, verify));
+                }
+            });
+        });
+        report(&format!("{batches} batches of {} sharing 2 threads", items.len()), elapsed);
+    }
+}
+
+fn timed(f: impl FnOnce()) -> Duration {
+    let start = Instant::now();
+    f();
+    start.elapsed()
+}
+
+fn report(case: &str, elapsed: Duration) {
+    let per_second = VERIFICATIONS as f64 / elapsed.as_secs_f64();
+    println!("{case:<36} {:>8.1}ms {per_second:>8.1} verifications/s", elapsed.as_secs_f64() * 1000.0);
+}
@@ -0,0 +1,17 @@
+// Email normalization: any string must be accepted or rejected without panicking, and an accepted address must
+// already be in normal form, so storing it and comparing it later agree.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use microservice_project_fuzz::email::normalize_email;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(email) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    if let Ok(normalized) = normalize_email(email) {
+        assert!(normalized.len() <= 254 && normalized.contains('@'), "accepted {email:?} as {normalized:?}");
+        assert_eq!(normalize_email(&normalized).as_ref(), Ok(&normalized), "not idempotent for {email:?}");
+    }
+});
@@ -0,0 +1,36 @@
+// Stored password hashes: describing or verifying any string must not panic or stall, and a hash must only verify
+// for the password it was made from.
+#![no_main]
+
+use std::sync::OnceLock;
+
+use libfuzzer_sys::fuzz_target;
+use microservice_project_fuzz::hashing::{describe_hash, PasswordScheme, Pbkdf2Scheme};
+use microservice_project_fuzz::mutate;
+use microservice_project_fuzz::password::Password;
+
+fn hash() -> &'static str {
+    static HASH: OnceLock<String> = OnceLock::new();
+    HASH.get_or_init(|| Pbkdf2Scheme::new(1_000).hash(&Password::from("password")).unwrap())
+}
+
+fuzz_target!(|data: &[u8]| {
+    let scheme = Pbkdf2Scheme::new(1_000);
+    let password = Password::from("password");
+
+    if let Ok(stored) = std::str::from_utf8(data) {
+        let _ = describe_hash(stored);
+        assert!(stored == hash() || !scheme.verify(&password, stored), "forged hash verified: {stored:?}");
+    }
+
+    // Edits of a real hash. Changing the salt, output or rounds must break it; the fuzzer finds rounds values that
+    // would take forever to compute as timeouts.
+    if let Ok(stored) = String::from_utf8(mutate(hash(), data)) {
+        let verified = scheme.verify(&password, &stored);
+        if verified && stored != hash() {
+            // Only changes the parser treats as equivalent may still verify, e.g. an explicit default parameter.
+            let (original, edited) = (describe_hash(hash()).unwrap(), describe_hash(&stored).unwrap());
+            assert_eq!(original.rounds, edited.rounds, "hash verified with other rounds: {stored:?}");
+        }
+    }
+});
@@ -0,0 +1,22 @@
+// Session tokens: arbitrary input must never panic, and nothing but the exact token issued may verify.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use microservice_project_fuzz::{mutate, signer, USER_UUID};
+
+fuzz_target!(|data: &[u8]| {
+    let signer = signer();
+
+    // Raw input: can't carry a valid signature.
+    if let Ok(token) = std::str::from_utf8(data) {
+        assert!(signer.verify(token).is_err(), "forged token accepted: {token:?}");
+    }
+
+    // Edits of a real token: anything but the original must be rejected.
+    let issued = signer.issue(USER_UUID);
+    if let Ok(token) = String::from_utf8(mutate(&issued, data)) {
+        if token != issued {
+            assert!(signer.verify(&token).is_err(), "tampered token accepted: {token:?}");
+        }
+    }
+});
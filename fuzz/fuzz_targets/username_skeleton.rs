@@ -0,0 +1,19 @@
+// Username skeletons: any string must map without panicking, and invisible characters must never tell two
+// usernames apart.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use microservice_project_fuzz::skeleton::skeleton;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(username) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    let mapped = skeleton(username);
+    assert!(!mapped.contains('\u{200B}') && !mapped.contains('\u{FEFF}'), "invisible character kept: {mapped:?}");
+
+    // Zero-width spaces between every character still map to the same skeleton.
+    let padded: String = username.chars().flat_map(|c| ['\u{200B}', c]).collect();
+    assert_eq!(skeleton(&padded), mapped, "zero-width padding changed the skeleton of {username:?}");
+});
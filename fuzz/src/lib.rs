@@ -0,0 +1,55 @@
+// Harness for the fuzz targets. The auth service is a binary, so the modules that parse untrusted input are
+// compiled in here straight from its sources, along with the few service modules they use.
+//
+// To run a target locally (needs a nightly toolchain):
+//
+//     cargo install cargo-fuzz
+//     cd fuzz && cargo +nightly fuzz run token_verify
+//
+// Targets: token_verify, phc_hash, username_skeleton, email_normalize. Crashes are written to
+// fuzz/artifacts/<target>/; replay one with `cargo +nightly fuzz run <target> <file>`, then add it as a unit test
+// next to the code it broke.
+#![allow(dead_code)]
+
+#[path = "../../src/auth-service/clock.rs"]
+pub mod clock;
+#[path = "../../src/auth-service/email.rs"]
+pub mod email;
+#[path = "../../src/auth-service/hashing.rs"]
+pub mod hashing;
+#[path = "../../src/auth-service/password.rs"]
+pub mod password;
+#[path = "../../src/auth-service/skeleton.rs"]
+pub mod skeleton;
+#[path = "../../src/auth-service/tokens.rs"]
+pub mod tokens;
+#[path = "../../src/auth-service/uuids.rs"]
+pub mod uuids;
+
+use std::time::Duration;
+
+use tokens::{KeySet, TokenSigner};
+
+pub const USER_UUID: &str = "3fa85f64-5717-4562-b3fc-2c963f66afa6";
+
+// Signer as the service builds it from a static key.
+pub fn signer() -> TokenSigner {
+    TokenSigner::new(KeySet::single(b"fuzzing key"), Duration::from_secs(60 * 60))
+}
+
+// Applies `edits` to `base`, two bytes per edit: a position and a replacement byte. Lets the fuzzer explore inputs
+// close to a valid one, which random bytes almost never reach.
+pub fn mutate(base: &str, edits: &[u8]) -> Vec<u8> {
+    let mut bytes = base.as_bytes().to_vec();
+    for edit in edits.chunks_exact(2) {
+        let position = edit[0] as usize % (bytes.len() + 1);
+        match edit[1] {
+            0 if position < bytes.len() => {
+                bytes.remove(position);
+            }
+            byte if position < bytes.len() => bytes[position] = byte,
+            byte => bytes.push(byte),
+        }
+    }
+    bytes
+}
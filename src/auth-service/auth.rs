@@ -1,13 +1,27 @@
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::{sessions::Sessions, users::Users};
+use sha2::{Digest, Sha256};
 
-use tonic::{Request, Response, Status, Code};
+use crate::{binding::{ClientInfo, Fingerprint}, gates::{SignupContext, SignupGate}, idempotency::{Claim, IdempotencyKeys}, invitations::{InvitationError, Invitations, InvitationsImpl}, latency::{PhaseTimings, SignInLatency, SignInPhase}, logins::LoginNotifier, maintenance::MaintenanceMode, password::Password, pool::{BatchThreads, HashingPool, PoolError}, reload::ConfigReloader, sessions::{SessionError, Sessions}, shedding::LoadShedder, store::AccountKind, user_events::{self, UserEventLog}, users::{CredentialFailure, Users, UsersError}, validation::{validate_signup, NormalizedSignUp, SignUpInput, MAX_CREDENTIALS_PER_BATCH, MAX_IDEMPOTENCY_KEY_BYTES, MAX_PASSWORD_BYTES, MAX_USERNAME_BYTES}};
+
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
 
 use authentication::auth_server::Auth;
 use authentication::{
-    SignInRequest, SignInResponse, SignOutRequest, SignOutResponse, SignUpRequest, SignUpResponse,
-    StatusCode,
+    AccountSummary, ChangePasswordRequest, ChangePasswordResponse, CredentialResult, CompletePasswordResetRequest, CompletePasswordResetResponse, ConfirmEmailRequest, ConfirmEmailResponse,
+    CreateGuestRequest, CreateGuestResponse, CreateUserRequest, CreateUserResponse, GetUserSecurityReportRequest, GetUserSecurityReportResponse,
+    ListUsersByHashAlgorithmRequest, ListUsersByHashAlgorithmResponse,
+    MintInvitationRequest, MintInvitationResponse, PurgeGuestsRequest, PurgeGuestsResponse, ReauthenticateRequest, ReauthenticateResponse, ReloadConfigRequest,
+    ReloadConfigResponse, RequirePasswordChangeRequest, RequirePasswordChangeResponse, SecurityAccount, SecuritySession, SetMaintenanceModeRequest,
+    SetMaintenanceModeResponse, SignInRequest,
+    SignInResponse, SignOutRequest, SignOutResponse, SignUpRequest, SignUpResponse, StartEmailVerificationRequest,
+    StartEmailVerificationResponse, StartPasswordResetRequest, StartPasswordResetResponse, StatusCode,
+    UpgradeGuestRequest, UpgradeGuestResponse, UserEvent, UserEventKind, VerifyCredentialsRequest, VerifyCredentialsResponse,
+    VerifyRequest, VerifyResponse,
+    WatchUserEventsRequest,
 };
 
 pub mod authentication {
@@ -18,79 +32,391 @@ pub mod authentication {
 pub use authentication::auth_server::AuthServer;
 pub use tonic::transport::Server;
 
+// Account events included in a security report when the request doesn't say, and at most.
+const DEFAULT_REPORT_EVENTS: usize = 20;
+const MAX_REPORT_EVENTS: usize = 100;
+
 pub struct AuthService {
-    users_service: Box<Mutex<dyn Users + Send + Sync>>,
-    sessions_service: Box<Mutex<dyn Sessions + Send + Sync>>,
+    users_service: Arc<dyn Users + Send + Sync>,
+    sessions_service: Arc<Mutex<dyn Sessions + Send + Sync>>,
+    hashing_pool: HashingPool,
+    invitations_service: Arc<dyn Invitations + Send + Sync>,
+    invite_only: bool,
+    admin_token: Option<String>,
+    signup_gates: Vec<Box<dyn SignupGate + Send + Sync>>,
+    user_events: Arc<UserEventLog>,
+    config_reloader: Option<Arc<ConfigReloader>>,
+    sign_in_latency: Arc<SignInLatency>, // Shared with the metrics endpoint.
+    login_notifier: Option<Arc<LoginNotifier>>, // Shared with the purger, which forgets deleted accounts' devices.
+    maintenance: Arc<MaintenanceMode>,
+    sign_up_keys: Option<IdempotencyKeys<SignUpResponse>>,
+    load_shedder: Option<Arc<LoadShedder>>,
+    batch_threads: Arc<BatchThreads>, // Shared by every VerifyCredentials call.
+    reauth_max_age: Option<Duration>,
 }
 
 impl AuthService {
     pub fn new(
-        users_service: Box<Mutex<dyn Users + Send + Sync>>,
-        sessions_service: Box<Mutex<dyn Sessions + Send + Sync>>,
+        users_service: Arc<dyn Users + Send + Sync>,
+        sessions_service: Arc<Mutex<dyn Sessions + Send + Sync>>,
+        hashing_pool: HashingPool,
     ) -> Self {
         Self {
             users_service,
             sessions_service,
+            hashing_pool,
+            invitations_service: Arc::new(InvitationsImpl::default()),
+            invite_only: false,
+            admin_token: None,
+            signup_gates: Vec::new(),
+            user_events: Arc::new(UserEventLog::default()),
+            config_reloader: None,
+            sign_in_latency: Arc::new(SignInLatency::new(None)),
+            login_notifier: None,
+            maintenance: Arc::new(MaintenanceMode::new(Duration::from_secs(60 * 60))),
+            sign_up_keys: Some(IdempotencyKeys::new(Duration::from_secs(10 * 60), 10_000)),
+            load_shedder: None,
+            batch_threads: Arc::new(BatchThreads::new(2)),
+            reauth_max_age: Some(Duration::from_secs(5 * 60)),
+        }
+    }
+
+    // Require an invitation code on SignUp.
+    pub fn with_invite_only(mut self, invite_only: bool) -> Self {
+        self.invite_only = invite_only;
+        self
+    }
+
+    // Where invitation codes are minted and consumed. Shared with the purger, which drops expired ones.
+    pub fn with_invitations(mut self, invitations_service: Arc<dyn Invitations + Send + Sync>) -> Self {
+        self.invitations_service = invitations_service;
+        self
+    }
+
+    // Enable admin RPCs, authenticated with `authorization: Bearer <admin_token>`.
+    pub fn with_admin_token(mut self, admin_token: Option<String>) -> Self {
+        self.admin_token = admin_token;
+        self
+    }
+
+    // Checks run, in order, before every SignUp.
+    pub fn with_signup_gates(mut self, signup_gates: Vec<Box<dyn SignupGate + Send + Sync>>) -> Self {
+        self.signup_gates = signup_gates;
+        self
+    }
+
+    // Where WatchUserEvents reads from. Should be the log the users service records to.
+    pub fn with_user_events(mut self, user_events: Arc<UserEventLog>) -> Self {
+        self.user_events = user_events;
+        self
+    }
+
+    // What ReloadConfig runs. ReloadConfig is unimplemented without one.
+    pub fn with_config_reloader(mut self, config_reloader: Option<Arc<ConfigReloader>>) -> Self {
+        self.config_reloader = config_reloader;
+        self
+    }
+
+    // Record SignIn phases in `sign_in_latency`, which also logs a breakdown of requests over its budget.
+    pub fn with_sign_in_latency(mut self, sign_in_latency: Arc<SignInLatency>) -> Self {
+        self.sign_in_latency = sign_in_latency;
+        self
+    }
+
+    // Publish a LoginOccurred event for every successful SignIn.
+    pub fn with_login_notifier(mut self, login_notifier: Option<Arc<LoginNotifier>>) -> Self {
+        self.login_notifier = login_notifier;
+        self
+    }
+
+    // What SetMaintenanceMode toggles, and what account-changing RPCs check.
+    pub fn with_maintenance(mut self, maintenance: Arc<MaintenanceMode>) -> Self {
+        self.maintenance = maintenance;
+        self
+    }
+
+    // Where the idempotency-key metadata of successful SignUps is remembered. None ignores the metadata.
+    pub fn with_sign_up_keys(mut self, sign_up_keys: Option<IdempotencyKeys<SignUpResponse>>) -> Self {
+        self.sign_up_keys = sign_up_keys;
+        self
+    }
+
+    // Turn away a share of SignIns and SignUps while overloaded. None never does.
+    pub fn with_load_shedder(mut self, load_shedder: Option<Arc<LoadShedder>>) -> Self {
+        self.load_shedder = load_shedder;
+        self
+    }
+
+    // Most threads VerifyCredentials verifies on, outside the hashing pool. Across all calls, not per call.
+    pub fn with_batch_verify_parallelism(mut self, batch_verify_parallelism: usize) -> Self {
+        self.batch_threads = Arc::new(BatchThreads::new(batch_verify_parallelism));
+        self
+    }
+
+    // How recently the password must have been proven on the session for sensitive RPCs. None never asks again.
+    pub fn with_reauth_max_age(mut self, reauth_max_age: Option<Duration>) -> Self {
+        self.reauth_max_age = reauth_max_age;
+        self
+    }
+
+    // The session's user, for sensitive RPCs. Err with the status code to answer, REAUTHENTICATION_REQUIRED when
+    // only the password proof is too old.
+    fn check_fresh_session(&self, session_token: &str, client: &ClientInfo) -> Result<String, StatusCode> {
+        let result = self.sessions_service.lock().unwrap().check_fresh_session_from(session_token, client, self.reauth_max_age);
+        result.map_err(|e| match e {
+            SessionError::Stale => StatusCode::ReauthenticationRequired,
+            _ => StatusCode::Failure,
+        })
+    }
+
+    #[allow(clippy::result_large_err)] // Status is what the handlers return anyway.
+    fn check_admin<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let Some(admin_token) = &self.admin_token else {
+            return Err(Status::permission_denied("Admin RPCs are disabled"));
+        };
+
+        let presented = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or("");
+
+        // Compare digests so the comparison time doesn't depend on how much of the token matches.
+        if Sha256::digest(presented.as_bytes()) == Sha256::digest(admin_token.as_bytes()) {
+            Ok(())
+        } else {
+            Err(Status::unauthenticated("Invalid admin token"))
         }
     }
+
+    // Sheds the request with RESOURCE_EXHAUSTED, and a retry-after (in seconds), while overloaded. Only for RPCs that
+    // hash a password, never Verify.
+    #[allow(clippy::result_large_err)] // Status is what the handlers return anyway.
+    fn check_load(&self) -> Result<(), Status> {
+        let Some(load_shedder) = &self.load_shedder else {
+            return Ok(());
+        };
+        let queue_length = self.hashing_pool.metrics().queue_length.get().max(0) as usize;
+        let Err(retry_after) = load_shedder.admit(queue_length) else {
+            return Ok(());
+        };
+        let mut status = Status::resource_exhausted("Service overloaded, try again later");
+        status.metadata_mut().insert("retry-after", retry_after.as_secs().into());
+        Err(status)
+    }
+
+    // Refuses account changes during maintenance with UNAVAILABLE, and a retry-after (in seconds) when it ends.
+    #[allow(clippy::result_large_err)] // Status is what the handlers return anyway.
+    fn check_writable(&self) -> Result<(), Status> {
+        let Some(remaining) = self.maintenance.remaining() else {
+            return Ok(());
+        };
+        let retry_after = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+        let mut status = Status::unavailable("Account changes are paused for maintenance");
+        status.metadata_mut().insert("retry-after", retry_after.into());
+        Err(status)
+    }
+
+    // Runs a `users_service` operation (which hashes or verifies a password) on the hashing pool.
+    async fn run_hashing<F, T>(&self, job: F) -> Result<T, Status>
+    where
+        F: FnOnce(&(dyn Users + Send + Sync)) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let users_service = self.users_service.clone();
+        self.hashing_pool
+            .run(move || job(&*users_service))
+            .await
+            .map_err(|e| match e {
+                PoolError::QueueFull => {
+                    let metrics = self.hashing_pool.metrics();
+                    println!(
+                        "Hashing queue full ({} queued, {} rejected so far)",
+                        metrics.queue_length.get(),
+                        metrics.rejections.get()
+                    );
+                    Status::resource_exhausted("Too many concurrent requests, try again later")
+                }
+                PoolError::WorkerFailed => Status::internal("Password hashing failed"),
+            })
+    }
 }
 
 #[tonic::async_trait]
 impl Auth for AuthService {
+    type WatchUserEventsStream = ReceiverStream<Result<UserEvent, Status>>;
+
     async fn sign_in(
         &self,
         request: Request<SignInRequest>,
     ) -> Result<Response<SignInResponse>, Status> {
-        println!("Got a request: {:?}", request);
+        // Don't log the request, it carries the password.
+        println!("Got a sign in request");
 
+        self.check_load()?;
+        let started = Instant::now();
+        let client = ClientInfo::from_metadata(request.metadata());
+        let client_addr = request.remote_addr().map(|addr| addr.ip());
         let req = request.into_inner();
 
-        let result: Option<String> = self.users_service.lock().unwrap().get_user_uuid(req.username, req.password);
+        let (result, mut timings): (Option<String>, PhaseTimings) = self
+            .run_hashing(move |users| {
+                let mut timings = PhaseTimings::default();
+                timings.add(SignInPhase::PoolWait, started.elapsed());
+                let result = users.get_user_uuid_timed(req.username, req.password.into(), &mut timings);
+                (result, timings)
+            })
+            .await?;
 
-        if result.is_none() { 
-            let reply: SignInResponse = SignInResponse{
-                status_code : 0,
-                user_uuid : "".to_string(),
-                session_token : "".to_string(),
-            };
-            return Ok(Response::new(reply.into()));
-        }       
-        else {
-            let user_uuid: String = result.unwrap();
-            let session_token: String = self.sessions_service.lock().unwrap().create_session(&user_uuid);
-            let reply: SignInResponse = SignInResponse{
-                status_code : 1,
-                user_uuid : user_uuid,
-                session_token : session_token,
-            };
-            return Ok(Response::new(reply.into()));
+        let reply: SignInResponse = match result {
+            None => {
+                SignInResponse{
+                    status_code : 0,
+                    user_uuid : "".to_string(),
+                    session_token : "".to_string(),
+                }
+            }
+            Some(user_uuid) if timings
+                .time(SignInPhase::StoreLookup, || self.users_service.get_user(&user_uuid))
+                .is_some_and(|user| user.password_change_required) => {
+                SignInResponse{
+                    status_code : StatusCode::PasswordChangeRequired.into(),
+                    user_uuid : "".to_string(),
+                    session_token : "".to_string(),
+                }
+            }
+            Some(user_uuid) => {
+                let session_token: String = timings.time(SignInPhase::SessionCreation, || {
+                    self.sessions_service.lock().unwrap().create_session_for(&user_uuid, &client)
+                });
+                if let Some(login_notifier) = &self.login_notifier {
+                    login_notifier.record_login(&user_uuid, &client, client_addr);
+                }
+                SignInResponse{
+                    status_code : 1,
+                    user_uuid,
+                    session_token,
+                }
+            }
+        };
+        self.sign_in_latency.observe(&timings, started.elapsed());
+        if let Some(load_shedder) = &self.load_shedder {
+            load_shedder.observe(started.elapsed());
         }
+        Ok(Response::new(reply))
     }
-    
+
 
     async fn sign_up(
         &self,
         request: Request<SignUpRequest>,
     ) -> Result<Response<SignUpResponse>, Status> {
-        println!("Got a request: {:?}", request);
+        // Don't log the request, it carries the password.
+        println!("Got a sign up request");
+
+        self.check_writable()?;
+        self.check_load()?;
 
+        let client_addr = request.remote_addr().map(|addr| addr.ip().to_string());
+        let idempotency_key = match request.metadata().get("idempotency-key").map(|value| value.to_str()) {
+            None => None,
+            Some(Ok(key)) if !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_BYTES => Some(key.to_owned()),
+            Some(_) => return Err(Status::invalid_argument(format!("idempotency-key: must be 1 to {MAX_IDEMPOTENCY_KEY_BYTES} visible ASCII characters"))),
+        };
         let req = request.into_inner();
 
-        let result: Result<(), String> = self.users_service.lock().unwrap().create_user(req.username, req.password); // Create a new user through `users_service`. Panic if the lock is poisoned.
+        // Malformed input is turned away before it reaches the gates, the invitations or the hashing pool.
+        let input = SignUpInput {
+            username: &req.username,
+            password: &req.password,
+            email: &req.email,
+            invitation_code: &req.invitation_code,
+            challenge_response: &req.challenge_response,
+        };
+        let req = validated("Sign up", &input)?;
+
+        // A retry of a sign up that went through gets the same answer, without running the gates again.
+        let mut pending_key = None;
+        if let (Some(keys), Some(key)) = (&self.sign_up_keys, &idempotency_key) {
+            let fingerprint = keys.fingerprint(&[
+                &req.username,
+                req.password.expose(),
+                req.email.as_deref().unwrap_or(""),
+                &req.invitation_code,
+            ]);
+            match keys.claim(key, fingerprint).await {
+                Claim::New(pending) => pending_key = Some(pending),
+                Claim::Untracked => println!("Sign up idempotency keys are full, signing up without one"),
+                Claim::Replay(reply) => {
+                    println!("Sign up replayed for a repeated idempotency key");
+                    return Ok(Response::new(reply));
+                }
+                Claim::Conflict => {
+                    return Err(Status::already_exists("idempotency-key was already used for a different sign up"));
+                }
+            }
+        }
+
+        // Run the signup checks first, so a rejected signup doesn't use up an invitation.
+        for gate in &self.signup_gates {
+            let ctx = SignupContext {
+                username: req.username.clone(),
+                client_addr: client_addr.clone(),
+                challenge_response: req.challenge_response.clone(),
+            };
+            if let Err(rejection) = gate.check(ctx).await {
+                println!("Sign up rejected: {} ({})", rejection.message, rejection.code);
+                return Err(Status::failed_precondition(rejection.code));
+            }
+        }
+
+        // Take a use of the invitation before hashing anything, so concurrent signups can't share a single-use
+        // code. The use is given back if the signup doesn't go through.
+        let invitation_code: Option<String> = if self.invite_only {
+            if let Err(e) = self.invitations_service.consume(&req.invitation_code, &req.username) {
+                println!("Sign up rejected: invitation {:?}", e);
+                let reply: SignUpResponse = SignUpResponse{
+                    status_code : invitation_status(e).into(),
+                    user_uuid : "".to_string(),
+                };
+                return Ok(Response::new(reply));
+            }
+            Some(req.invitation_code)
+        } else {
+            None
+        };
+
+        // Create a new user through `users_service`.
+        let result: Result<Result<String, UsersError>, Status> = self
+            .run_hashing(move |users| users.create_user(req.username, req.password, req.email))
+            .await;
+
+        if !matches!(result, Ok(Ok(_))) {
+            if let Some(code) = &invitation_code {
+                self.invitations_service.release(code);
+            }
+        }
+        let result = result?;
 
-        // TODO: Return a `SignUpResponse` with the appropriate `status_code` based on `result`.
         match result {
-            Ok(_) => {
+            Ok(user_uuid) => {
                 let reply: SignUpResponse = SignUpResponse{
                     status_code : 1,
+                    user_uuid,
                 };
-                return Ok(Response::new(reply.into()));
+                if let Some(pending) = pending_key {
+                    pending.succeeded(reply.clone());
+                }
+                Ok(Response::new(reply))
             }
-            Err(_) => {
+            Err(e) => {
+                println!("Sign up rejected: {}", e);
                 let reply: SignUpResponse = SignUpResponse{
-                    status_code : 0,
+                    status_code : users_status(&e).into(),
+                    user_uuid : "".to_string(),
                 };
-                return Ok(Response::new(reply.into()));
+                Ok(Response::new(reply))
             }
         }
     }
@@ -99,145 +425,2219 @@ impl Auth for AuthService {
         &self,
         request: Request<SignOutRequest>,
     ) -> Result<Response<SignOutResponse>, Status> {
-        println!("Got a request: {:?}", request);
+        println!("Got a sign out request");
 
+        let client = ClientInfo::from_metadata(request.metadata());
         let req = request.into_inner();
 
-        let session_token = req.session_token;
-
-        // TODO: Delete session using `sessions_service`.
-        self.sessions_service.lock().unwrap().delete_session(&session_token);
+        // Sessions are kept by user, so find whose the token is first. Only the token's holder can sign it out.
+        let mut sessions = self.sessions_service.lock().unwrap();
+        let status_code: StatusCode = match sessions.check_session_from(&req.session_token, &client) {
+            Ok(user_uuid) => {
+                sessions.delete_session(&user_uuid);
+                StatusCode::Success
+            }
+            Err(_) => StatusCode::Failure,
+        };
+        drop(sessions);
 
         let reply: SignOutResponse = SignOutResponse{
+            status_code : status_code.into(),
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn verify(
+        &self,
+        request: Request<VerifyRequest>,
+    ) -> Result<Response<VerifyResponse>, Status> {
+        println!("Got a verify request");
+
+        let client = ClientInfo::from_metadata(request.metadata());
+        let req = request.into_inner();
+
+        let result: Result<String, SessionError> =
+            self.sessions_service.lock().unwrap().check_session_from(&req.session_token, &client);
+
+        let reply: VerifyResponse = match result {
+            Ok(user_uuid) => {
+                let user = self.users_service.get_user(&user_uuid);
+                VerifyResponse{
+                    status_code : 1,
+                    email_verified : user.as_ref().is_some_and(|user| user.email_verified),
+                    guest : user.as_ref().is_some_and(|user| user.guest),
+                    user_uuid,
+                }
+            }
+            Err(e) => {
+                // Tell the client which limit ended the session, so it can say why the user has to sign in again.
+                let status_code: StatusCode = match e {
+                    SessionError::Unknown => StatusCode::Failure,
+                    SessionError::IdleTimeout => StatusCode::SessionIdleTimeout,
+                    SessionError::Expired => StatusCode::SessionExpired,
+                    SessionError::BindingMismatch => StatusCode::SessionBindingMismatch,
+                    SessionError::Stale => StatusCode::ReauthenticationRequired,
+                };
+                if e != SessionError::Unknown {
+                    println!("Session rejected: {:?}", e);
+                }
+                VerifyResponse{
+                    status_code : status_code.into(),
+                    user_uuid : "".to_string(),
+                    email_verified : false,
+                    guest : false,
+                }
+            }
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn start_email_verification(
+        &self,
+        request: Request<StartEmailVerificationRequest>,
+    ) -> Result<Response<StartEmailVerificationResponse>, Status> {
+        println!("Got a start email verification request");
+
+        self.check_writable()?;
+
+        let client = ClientInfo::from_metadata(request.metadata());
+        let req = request.into_inner();
+
+        // A verified email is where password resets go, so a stolen session alone mustn't set it up.
+        let status_code: StatusCode = match self.check_fresh_session(&req.session_token, &client) {
+            // The token goes out through the event it publishes, never back to the caller.
+            Ok(user_uuid) => match self.users_service.start_email_verification(user_uuid) {
+                Ok(_) => StatusCode::Success,
+                Err(e) => users_status(&e),
+            },
+            Err(status_code) => status_code,
+        };
+
+        let reply: StartEmailVerificationResponse = StartEmailVerificationResponse{
+            status_code : status_code.into(),
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn confirm_email(
+        &self,
+        request: Request<ConfirmEmailRequest>,
+    ) -> Result<Response<ConfirmEmailResponse>, Status> {
+        // Don't log the request, it carries the verification token.
+        println!("Got a confirm email request");
+
+        self.check_writable()?;
+
+        let client = ClientInfo::from_metadata(request.metadata());
+        let req = request.into_inner();
+
+        let status_code: StatusCode = match self.check_fresh_session(&req.session_token, &client) {
+            Ok(user_uuid) => match self.users_service.confirm_email(user_uuid, &req.verification_token) {
+                Ok(_) => StatusCode::Success,
+                Err(e) => users_status(&e),
+            },
+            Err(status_code) => status_code,
+        };
+
+        let reply: ConfirmEmailResponse = ConfirmEmailResponse{
+            status_code : status_code.into(),
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn start_password_reset(
+        &self,
+        request: Request<StartPasswordResetRequest>,
+    ) -> Result<Response<StartPasswordResetResponse>, Status> {
+        println!("Got a start password reset request");
+
+        self.check_writable()?;
+
+        let req = request.into_inner();
+
+        // The token only goes out through the event. Answer the same either way, so the response doesn't tell
+        // whether the account exists.
+        let _ = self.users_service.start_password_reset(req.username_or_email);
+
+        let reply: StartPasswordResetResponse = StartPasswordResetResponse{
             status_code : 1,
-        }; // Create `SignOutResponse` with `status_code` set to `Success`
+        };
 
         Ok(Response::new(reply))
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::{users::UsersImpl, sessions::SessionsImpl};
+    async fn complete_password_reset(
+        &self,
+        request: Request<CompletePasswordResetRequest>,
+    ) -> Result<Response<CompletePasswordResetResponse>, Status> {
+        // Don't log the request, it carries the reset token and the new password.
+        println!("Got a complete password reset request");
 
-    use super::*;
+        self.check_writable()?;
 
-    #[tokio::test]
-    async fn sign_in_should_fail_if_user_not_found() {
-        let users_service = Box::new(Mutex::new(UsersImpl::default()));
-        let sessions_service = Box::new(Mutex::new(SessionsImpl::default()));
+        let req = request.into_inner();
 
-        let auth_service = AuthService::new(users_service, sessions_service);
+        let result: Result<String, UsersError> = self
+            .run_hashing(move |users| users.complete_password_reset(req.reset_token, req.new_password.into()))
+            .await?;
 
-        let request = tonic::Request::new(SignInRequest {
-            username: "123456".to_owned(),
-            password: "654321".to_owned(),
-        });
+        let status_code: StatusCode = match result {
+            Ok(user_uuid) => {
+                // Whoever knew the old password may be signed in; sign the account out everywhere.
+                self.sessions_service.lock().unwrap().delete_session(&user_uuid);
+                StatusCode::Success
+            }
+            Err(e) => {
+                println!("Password reset rejected: {}", e);
+                users_status(&e)
+            }
+        };
 
-        let result = auth_service.sign_in(request).await.unwrap().into_inner();
+        let reply: CompletePasswordResetResponse = CompletePasswordResetResponse{
+            status_code : status_code.into(),
+        };
 
-        assert_eq!(result.status_code, StatusCode::Failure.into());
-        assert_eq!(result.user_uuid.is_empty(), true);
-        assert_eq!(result.session_token.is_empty(), true);
+        Ok(Response::new(reply))
     }
 
-    #[tokio::test]
-    async fn sign_in_should_fail_if_incorrect_password() {
-        let mut users_service = UsersImpl::default();
+    async fn change_password(
+        &self,
+        request: Request<ChangePasswordRequest>,
+    ) -> Result<Response<ChangePasswordResponse>, Status> {
+        // Don't log the request, it carries both passwords.
+        println!("Got a change password request");
 
-        let _ = users_service.create_user("123456".to_owned(), "654321".to_owned());
+        self.check_writable()?;
 
-        let users_service = Box::new(Mutex::new(users_service));
-        let sessions_service = Box::new(Mutex::new(SessionsImpl::default()));
+        let req = request.into_inner();
 
-        let auth_service = AuthService::new(users_service, sessions_service);
+        let result: Result<String, UsersError> = self
+            .run_hashing(move |users| users.change_password(req.username, req.password.into(), req.new_password.into()))
+            .await?;
 
-        let request = tonic::Request::new(SignInRequest {
-            username: "123456".to_owned(),
-            password: "wrong password".to_owned(),
-        });
+        let status_code: StatusCode = match result {
+            Ok(user_uuid) => {
+                self.sessions_service.lock().unwrap().delete_session(&user_uuid);
+                StatusCode::Success
+            }
+            Err(e) => {
+                println!("Password change rejected: {}", e);
+                users_status(&e)
+            }
+        };
 
-        let result = auth_service.sign_in(request).await.unwrap().into_inner();
+        let reply: ChangePasswordResponse = ChangePasswordResponse{
+            status_code : status_code.into(),
+        };
 
-        assert_eq!(result.status_code, StatusCode::Failure.into());
-        assert_eq!(result.user_uuid.is_empty(), true);
-        assert_eq!(result.session_token.is_empty(), true);
+        Ok(Response::new(reply))
     }
 
-    #[tokio::test]
-    async fn sign_in_should_succeed() {
-        let mut users_service = UsersImpl::default();
+    async fn create_guest(
+        &self,
+        request: Request<CreateGuestRequest>,
+    ) -> Result<Response<CreateGuestResponse>, Status> {
+        println!("Got a create guest request");
 
-        let _ = users_service.create_user("123456".to_owned(), "654321".to_owned());
+        self.check_writable()?;
 
-        let users_service = Box::new(Mutex::new(users_service));
-        let sessions_service = Box::new(Mutex::new(SessionsImpl::default()));
+        // A guest would otherwise be a way in without an invitation.
+        if self.invite_only {
+            return Err(Status::permission_denied("Guest accounts are disabled while invite-only"));
+        }
 
-        let auth_service = AuthService::new(users_service, sessions_service);
+        let client = ClientInfo::from_metadata(request.metadata());
+        let user_uuid: String = self.users_service.create_guest();
+        let session_token: String = self.sessions_service.lock().unwrap().create_session_for(&user_uuid, &client);
 
-        let request = tonic::Request::new(SignInRequest {
-            username: "123456".to_owned(),
-            password: "654321".to_owned(),
-        });
+        let reply: CreateGuestResponse = CreateGuestResponse{
+            status_code : 1,
+            user_uuid,
+            session_token,
+        };
 
-        let result = auth_service.sign_in(request).await.unwrap().into_inner();
+        Ok(Response::new(reply))
+    }
 
-        assert_eq!(result.status_code, StatusCode::Success.into());
-        assert_eq!(result.user_uuid.is_empty(), false);
-        assert_eq!(result.session_token.is_empty(), false);
+    async fn upgrade_guest(
+        &self,
+        request: Request<UpgradeGuestRequest>,
+    ) -> Result<Response<UpgradeGuestResponse>, Status> {
+        // Don't log the request, it carries the password.
+        println!("Got an upgrade guest request");
+
+        self.check_writable()?;
+
+        let client = ClientInfo::from_metadata(request.metadata());
+        let req = request.into_inner();
+
+        // The same checks as SignUp, before the session is looked up or anything is hashed.
+        let input = SignUpInput {
+            username: &req.username,
+            password: &req.password,
+            email: "",
+            invitation_code: "",
+            challenge_response: "",
+        };
+        let upgrade = validated("Guest upgrade", &input)?;
+
+        let session_user: Option<String> =
+            self.sessions_service.lock().unwrap().check_session_from(&req.session_token, &client).ok();
+
+        let status_code: StatusCode = match session_user {
+            Some(user_uuid) => {
+                let result: Result<(), UsersError> = self
+                    .run_hashing(move |users| users.upgrade_guest(user_uuid, upgrade.username, upgrade.password))
+                    .await?;
+                match result {
+                    Ok(_) => StatusCode::Success,
+                    Err(e) => {
+                        println!("Guest upgrade rejected: {}", e);
+                        users_status(&e)
+                    }
+                }
+            }
+            None => StatusCode::Failure,
+        };
+
+        let reply: UpgradeGuestResponse = UpgradeGuestResponse{
+            status_code : status_code.into(),
+        };
+
+        Ok(Response::new(reply))
     }
 
-    #[tokio::test]
-    async fn sign_up_should_fail_if_username_exists() {
-        let mut users_service = UsersImpl::default();
+    async fn reauthenticate(
+        &self,
+        request: Request<ReauthenticateRequest>,
+    ) -> Result<Response<ReauthenticateResponse>, Status> {
+        // Don't log the request, it carries the password.
+        println!("Got a reauthenticate request");
 
-        let _ = users_service.create_user("123456".to_owned(), "654321".to_owned());
+        self.check_load()?;
+        let client = ClientInfo::from_metadata(request.metadata());
+        let req = request.into_inner();
+        let session_token = req.session_token;
 
-        let users_service = Box::new(Mutex::new(users_service));
-        let sessions_service = Box::new(Mutex::new(SessionsImpl::default()));
+        let session_user: Option<String> =
+            self.sessions_service.lock().unwrap().check_session_from(&session_token, &client).ok();
+        // Guests have no password to prove.
+        let user = session_user.and_then(|user_uuid| self.users_service.get_user(&user_uuid)).filter(|user| !user.guest);
 
-        let auth_service = AuthService::new(users_service, sessions_service);
+        let status_code: StatusCode = match user {
+            Some(user) => {
+                let username = user.username;
+                let proven: Option<String> = self
+                    .run_hashing(move |users| users.get_user_uuid(username, req.password.into()))
+                    .await?;
+                if proven != Some(user.user_uuid) {
+                    StatusCode::WrongPassword
+                } else if self.sessions_service.lock().unwrap().record_reauthentication(&session_token).is_ok() {
+                    StatusCode::Success
+                } else {
+                    // Signed out or replaced while the password was checked.
+                    StatusCode::Failure
+                }
+            }
+            None => StatusCode::Failure,
+        };
 
-        let request = tonic::Request::new(SignUpRequest {
-            username: "123456".to_owned(),
-            password: "654321".to_owned(),
-        });
+        let reply: ReauthenticateResponse = ReauthenticateResponse{
+            status_code : status_code.into(),
+        };
 
-        let result = auth_service.sign_up(request).await.unwrap();
+        Ok(Response::new(reply))
+    }
 
-        assert_eq!(result.into_inner().status_code, StatusCode::Failure.into());
+    async fn mint_invitation(
+        &self,
+        request: Request<MintInvitationRequest>,
+    ) -> Result<Response<MintInvitationResponse>, Status> {
+        // Don't log the metadata, it carries the admin token.
+        println!("Got a request: {:?}", request.get_ref());
+
+        self.check_admin(&request)?;
+
+        let req = request.into_inner();
+
+        let ttl: Option<Duration> = (req.ttl_secs > 0).then(|| Duration::from_secs(req.ttl_secs));
+        let username: Option<String> = (!req.username.trim().is_empty()).then_some(req.username);
+        let invitation_code: String = self.invitations_service.mint(req.max_uses.max(1), ttl, username);
+
+        let reply: MintInvitationResponse = MintInvitationResponse{
+            status_code : 1,
+            invitation_code,
+        };
+
+        Ok(Response::new(reply))
     }
 
-    #[tokio::test]
-    async fn sign_up_should_succeed() {
-        let users_service = Box::new(Mutex::new(UsersImpl::default()));
-        let sessions_service = Box::new(Mutex::new(SessionsImpl::default()));
+    async fn reload_config(
+        &self,
+        request: Request<ReloadConfigRequest>,
+    ) -> Result<Response<ReloadConfigResponse>, Status> {
+        // Don't log the metadata, it carries the admin token.
+        println!("Got a request: {:?}", request.get_ref());
 
-        let auth_service = AuthService::new(users_service, sessions_service);
+        self.check_admin(&request)?;
+        let Some(config_reloader) = &self.config_reloader else {
+            return Err(Status::unimplemented("Config reloading is not set up"));
+        };
 
-        let request = tonic::Request::new(SignUpRequest {
-            username: "123456".to_owned(),
-            password: "654321".to_owned(),
-        });
+        let report = config_reloader
+            .reload()
+            .map_err(|e| Status::failed_precondition(format!("Config not reloaded: {e}")))?;
 
-        let result = auth_service.sign_up(request).await.unwrap();
+        let reply: ReloadConfigResponse = ReloadConfigResponse{
+            status_code : 1,
+            ignored_settings : report.ignored,
+            keyset_reloaded : report.keyset_reloaded,
+            ban_list_reloaded : report.ban_list_reloaded,
+        };
 
-        assert_eq!(result.into_inner().status_code, StatusCode::Success.into());
+        Ok(Response::new(reply))
     }
 
-    #[tokio::test]
-    async fn sign_out_should_succeed() {
-        let users_service = Box::new(Mutex::new(UsersImpl::default()));
-        let sessions_service = Box::new(Mutex::new(SessionsImpl::default()));
+    async fn list_users_by_hash_algorithm(
+        &self,
+        request: Request<ListUsersByHashAlgorithmRequest>,
+    ) -> Result<Response<ListUsersByHashAlgorithmResponse>, Status> {
+        // Don't log the metadata, it carries the admin token.
+        println!("Got a request: {:?}", request.get_ref());
 
-        let auth_service = AuthService::new(users_service, sessions_service);
+        self.check_admin(&request)?;
+        let algorithm = request.into_inner().algorithm;
 
-        let request = tonic::Request::new(SignOutRequest {
-            session_token: "".to_owned()
-        });
+        // Reads every account, so keep it off the async workers.
+        let users_service = self.users_service.clone();
+        let users = tokio::task::spawn_blocking(move || users_service.list_users_by_hash_algorithm(&algorithm))
+            .await
+            .map_err(|e| Status::internal(format!("Listing users failed: {e}")))?;
 
-        let result = auth_service.sign_out(request).await.unwrap();
+        let reply: ListUsersByHashAlgorithmResponse = ListUsersByHashAlgorithmResponse{
+            status_code : 1,
+            users : users
+                .into_iter()
+                .map(|user| AccountSummary{
+                    user_uuid : user.user_uuid,
+                    username : user.username,
+                    password_change_required : user.password_change_required,
+                })
+                .collect(),
+        };
 
-        assert_eq!(result.into_inner().status_code, StatusCode::Success.into());
+        Ok(Response::new(reply))
+    }
+
+    async fn require_password_change(
+        &self,
+        request: Request<RequirePasswordChangeRequest>,
+    ) -> Result<Response<RequirePasswordChangeResponse>, Status> {
+        // Don't log the metadata, it carries the admin token.
+        println!("Got a request: {:?}", request.get_ref());
+
+        self.check_admin(&request)?;
+        self.check_writable()?;
+        let user_uuids = request.into_inner().user_uuids;
+
+        let flagged = self.users_service.require_password_change_for(&user_uuids);
+        println!("Password change required for {} of {} accounts", flagged, user_uuids.len());
+
+        let reply: RequirePasswordChangeResponse = RequirePasswordChangeResponse{
+            status_code : 1,
+            flagged : flagged as u32,
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn purge_guests(
+        &self,
+        request: Request<PurgeGuestsRequest>,
+    ) -> Result<Response<PurgeGuestsResponse>, Status> {
+        // Don't log the metadata, it carries the admin token.
+        println!("Got a request: {:?}", request.get_ref());
+
+        self.check_admin(&request)?;
+        let req = request.into_inner();
+        if !req.dry_run {
+            self.check_writable()?;
+        }
+
+        let users_service = self.users_service.clone();
+        let older_than = Duration::from_secs(req.older_than_secs);
+        let report = tokio::task::spawn_blocking(move || users_service.purge_guests(older_than, req.dry_run))
+            .await
+            .map_err(|_| Status::internal("Guest purge failed"))?;
+        if report.dry_run {
+            println!("Admin dry run: would purge {} guest accounts", report.guests);
+        } else {
+            println!("Admin purged {} guest accounts", report.guests);
+        }
+
+        let reply: PurgeGuestsResponse = PurgeGuestsResponse{
+            status_code : 1,
+            guests : report.guests as u32,
+            user_uuids : report.user_uuids,
+            dry_run : report.dry_run,
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn set_maintenance_mode(
+        &self,
+        request: Request<SetMaintenanceModeRequest>,
+    ) -> Result<Response<SetMaintenanceModeResponse>, Status> {
+        // Don't log the metadata, it carries the admin token.
+        println!("Got a request: {:?}", request.get_ref());
+
+        self.check_admin(&request)?;
+        let req = request.into_inner();
+
+        let remaining = if req.enabled {
+            self.maintenance.enter(Duration::from_secs(req.duration_secs), "admin")
+        } else {
+            self.maintenance.leave("admin");
+            Duration::ZERO
+        };
+
+        let reply: SetMaintenanceModeResponse = SetMaintenanceModeResponse{
+            status_code : 1,
+            remaining_secs : remaining.as_secs(),
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn get_user_security_report(
+        &self,
+        request: Request<GetUserSecurityReportRequest>,
+    ) -> Result<Response<GetUserSecurityReportResponse>, Status> {
+        // Don't log the metadata, it carries the admin token.
+        println!("Got a request: {:?}", request.get_ref());
+
+        self.check_admin(&request)?;
+        let req = request.into_inner();
+
+        let Some(credentials) = self.users_service.credential_report(&req.user_uuid) else {
+            let reply: GetUserSecurityReportResponse = GetUserSecurityReportResponse{
+                status_code : StatusCode::Failure.into(),
+                ..Default::default()
+            };
+            return Ok(Response::new(reply));
+        };
+        let session = self.sessions_service.lock().unwrap().session_report(&req.user_uuid);
+
+        // None of these exist in this service, so the report says so instead of showing them as empty.
+        let mut unavailable: Vec<String> = ["roles", "mfa", "api_keys", "failed_logins"].map(String::from).to_vec();
+        let known_devices = match &self.login_notifier {
+            Some(login_notifier) => login_notifier.known_devices(&req.user_uuid) as u32,
+            None => {
+                unavailable.push("known_devices".to_owned());
+                0
+            }
+        };
+        let max_events = match req.max_events {
+            0 => DEFAULT_REPORT_EVENTS,
+            max => (max as usize).min(MAX_REPORT_EVENTS),
+        };
+        println!("Admin viewed the security report of {}", req.user_uuid);
+
+        let user = credentials.user;
+        let kind = match (user.guest, credentials.directory, user.account_kind) {
+            (true, _, _) => "guest",
+            (_, true, _) => "directory",
+            (_, _, AccountKind::Federated) => "federated",
+            (_, _, AccountKind::Local) => "local",
+        };
+        let reply: GetUserSecurityReportResponse = GetUserSecurityReportResponse{
+            status_code : StatusCode::Success.into(),
+            account : Some(SecurityAccount{
+                user_uuid : user.user_uuid,
+                username : user.username,
+                kind : kind.to_owned(),
+                email : user.email.unwrap_or_default(),
+                email_verified : user.email_verified,
+                created_at_secs : epoch_secs(Some(credentials.created_at)),
+                hash_algorithm : credentials.hash_algorithm.unwrap_or_default(),
+                password_change_required : user.password_change_required,
+                password_reset_pending_until_secs : epoch_secs(credentials.password_reset_pending_until),
+                email_verification_pending_until_secs : epoch_secs(credentials.email_verification_pending_until),
+            }),
+            sessions : session.into_iter().map(|session| SecuritySession{
+                created_at_secs : epoch_secs(Some(session.created_at)),
+                last_seen_at_secs : epoch_secs(Some(session.last_seen_at)),
+                ends_at_secs : epoch_secs(session.ends_at),
+                live : session.live,
+                signed : session.signed,
+                client_fingerprint : session.fingerprint.map(|fingerprint| fingerprint_prefix(&fingerprint)).unwrap_or_default(),
+            }).collect(),
+            known_devices,
+            recent_events : self.user_events.recent_for(&req.user_uuid, max_events).into_iter().map(UserEvent::from).collect(),
+            unavailable,
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn verify_credentials(
+        &self,
+        request: Request<VerifyCredentialsRequest>,
+    ) -> Result<Response<VerifyCredentialsResponse>, Status> {
+        // Don't log the request, it carries the admin token and the passwords.
+        println!("Got a verify credentials request");
+
+        self.check_admin(&request)?;
+        let req = request.into_inner();
+
+        if req.credentials.len() > MAX_CREDENTIALS_PER_BATCH {
+            return Err(Status::invalid_argument(format!("credentials: at most {MAX_CREDENTIALS_PER_BATCH} per call")));
+        }
+        if let Some(i) = req
+            .credentials
+            .iter()
+            .position(|credential| credential.username.len() > MAX_USERNAME_BYTES || credential.password.len() > MAX_PASSWORD_BYTES)
+        {
+            return Err(Status::invalid_argument(format!("credentials[{i}]: username or password too long")));
+        }
+
+        let pairs: Vec<(String, Password)> = req
+            .credentials
+            .into_iter()
+            .map(|credential| (credential.username, credential.password.into()))
+            .collect();
+        let users_service = self.users_service.clone();
+        let batch_threads = self.batch_threads.clone();
+        let results = tokio::task::spawn_blocking(move || users_service.verify_credentials_batch(&pairs, &batch_threads))
+            .await
+            .map_err(|_| Status::internal("Credential verification failed"))?;
+
+        let verified = results.iter().filter(|result| result.is_ok()).count();
+        println!("Admin verified {} credentials: {} matched, {} didn't", results.len(), verified, results.len() - verified);
+
+        let reply: VerifyCredentialsResponse = VerifyCredentialsResponse{
+            status_code : StatusCode::Success.into(),
+            results : results.into_iter().map(|result| match result {
+                Ok(user_uuid) => CredentialResult{ status_code : StatusCode::Success.into(), user_uuid },
+                Err(CredentialFailure::UnknownUser) => CredentialResult{ status_code : StatusCode::UserNotFound.into(), user_uuid : "".to_string() },
+                Err(CredentialFailure::WrongPassword) => CredentialResult{ status_code : StatusCode::WrongPassword.into(), user_uuid : "".to_string() },
+            }).collect(),
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn create_user(
+        &self,
+        request: Request<CreateUserRequest>,
+    ) -> Result<Response<CreateUserResponse>, Status> {
+        // Don't log the request, it carries the admin token and the password.
+        println!("Got a create user request");
+
+        self.check_admin(&request)?;
+        self.check_writable()?;
+        let req = request.into_inner();
+
+        let input = SignUpInput {
+            username: &req.username,
+            password: &req.password,
+            email: &req.email,
+            invitation_code: "",
+            challenge_response: "",
+        };
+        let signup = validated("Create user", &input)?;
+
+        let allow_banned_username = req.allow_banned_username;
+        let result: Result<String, UsersError> = self
+            .run_hashing(move |users| {
+                if allow_banned_username {
+                    users.create_reserved_user(signup.username, signup.password, signup.email)
+                } else {
+                    users.create_user(signup.username, signup.password, signup.email)
+                }
+            })
+            .await?;
+
+        let status_code: StatusCode = match result {
+            Ok(_) => StatusCode::Success,
+            Err(e) => {
+                println!("Create user rejected: {}", e);
+                users_status(&e)
+            }
+        };
+        let reply: CreateUserResponse = CreateUserResponse{
+            status_code : status_code.into(),
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn watch_user_events(
+        &self,
+        request: Request<WatchUserEventsRequest>,
+    ) -> Result<Response<Self::WatchUserEventsStream>, Status> {
+        // Don't log the metadata, it carries the admin token.
+        println!("Got a request: {:?}", request.get_ref());
+
+        self.check_admin(&request)?;
+
+        let since = request.into_inner().since_sequence;
+        let (replay, mut live) = self.user_events.watch(since).map_err(|e| {
+            Status::out_of_range(format!(
+                "Sequence {} is no longer buffered (oldest is {}), reload all accounts and watch again",
+                since, e.oldest
+            ))
+        })?;
+
+        // The log never waits on this task. If the watcher reads too slowly, the task falls behind the log and the
+        // stream ends with an error instead.
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            for event in replay {
+                if tx.send(Ok(event.into())).await.is_err() {
+                    return;
+                }
+            }
+            loop {
+                let received = tokio::select! {
+                    received = live.recv() => received,
+                    _ = tx.closed() => return,
+                };
+                match received {
+                    Ok(event) if event.sequence < since => {}
+                    Ok(event) => {
+                        if tx.send(Ok(event.into())).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(RecvError::Lagged(missed)) => {
+                        println!("Dropping user event watcher {} events behind", missed);
+                        let status = Status::resource_exhausted("Watcher fell too far behind, reload all accounts and watch again");
+                        let _ = tx.send(Err(status)).await;
+                        return;
+                    }
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+impl From<user_events::UserEvent> for UserEvent {
+    fn from(event: user_events::UserEvent) -> Self {
+        let kind: UserEventKind = match event.kind {
+            user_events::UserEventKind::Created => UserEventKind::Created,
+            user_events::UserEventKind::Deleted => UserEventKind::Deleted,
+        };
+        UserEvent{
+            sequence : event.sequence,
+            kind : kind.into(),
+            user_uuid : event.user_uuid,
+            username : event.username,
+        }
+    }
+}
+
+// `validate_signup`, which every RPC that creates an account runs before anything is hashed. Violations
+// are logged as `action` rejected and answered with INVALID_ARGUMENT.
+#[allow(clippy::result_large_err)]
+fn validated(action: &str, input: &SignUpInput) -> Result<NormalizedSignUp, Status> {
+    validate_signup(input).map_err(|violations| {
+        let violations: Vec<String> = violations.iter().map(|violation| violation.to_string()).collect();
+        println!("{} rejected: {}", action, violations.join("; "));
+        Status::invalid_argument(violations.join("; "))
+    })
+}
+
+fn users_status(e: &UsersError) -> StatusCode {
+    match e {
+        UsersError::UsernameReserved { .. } => StatusCode::UsernameReserved,
+        UsersError::UsernameNotAllowed => StatusCode::UsernameNotAllowed,
+        UsersError::EmailTaken => StatusCode::EmailTaken,
+        UsersError::InvalidEmail => StatusCode::EmailInvalid,
+        UsersError::NoEmail => StatusCode::NoEmail,
+        UsersError::VerificationFailed => StatusCode::VerificationFailed,
+        UsersError::VerificationExpired => StatusCode::VerificationExpired,
+        UsersError::ResetTokenInvalid => StatusCode::ResetTokenInvalid,
+        UsersError::PasswordTooShort { .. } => StatusCode::PasswordTooShort,
+        UsersError::NotAGuest => StatusCode::NotAGuest,
+        UsersError::DirectoryManaged => StatusCode::DirectoryManaged,
+        UsersError::WrongPassword => StatusCode::WrongPassword,
+        _ => StatusCode::Failure,
+    }
+}
+
+// Seconds since the epoch, 0 for None.
+fn epoch_secs(at: Option<SystemTime>) -> u64 {
+    at.and_then(|at| at.duration_since(SystemTime::UNIX_EPOCH).ok()).map_or(0, |since| since.as_secs())
+}
+
+// Enough of a binding hash to tell sessions from different clients apart, not enough to be worth anything else.
+fn fingerprint_prefix(fingerprint: &Fingerprint) -> String {
+    fingerprint[..8].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn invitation_status(e: InvitationError) -> StatusCode {
+    match e {
+        InvitationError::Unknown => StatusCode::InvitationUnknown,
+        InvitationError::Used => StatusCode::InvitationUsed,
+        InvitationError::Expired => StatusCode::InvitationExpired,
+        InvitationError::UsernameMismatch => StatusCode::InvitationUsernameMismatch,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio_stream::StreamExt;
+
+    use crate::{authentication::Credential, binding::{BindingMode, SessionBinding}, clock::ManualClock, purger::{PurgeBudget, PurgeRun}, events::{Event, RecordingEvents}, fixtures::UsersFixture, gates::TestGate, shedding::ShedThresholds, store::IntegrityReport, users::{CredentialReport, GuestPurgeReport, HashParameterScan, ResetToken, UserStats, UserView, UsersImpl, VerificationToken}, sessions::SessionsImpl, tokens::{KeySet, TokenSigner}, config::ConfigSource};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn sign_in_should_fail_if_user_not_found() {
+        let users_service: Arc<dyn Users + Send + Sync> = Arc::new(UsersImpl::default());
+        let sessions_service = Arc::new(Mutex::new(SessionsImpl::default()));
+
+        let auth_service = AuthService::new(users_service, sessions_service, HashingPool::new(2, 8));
+
+        let request = tonic::Request::new(SignInRequest {
+            username: "123456".to_owned(),
+            password: "654321".to_owned(),
+        });
+
+        let result = auth_service.sign_in(request).await.unwrap().into_inner();
+
+        assert_eq!(result.status_code, StatusCode::Failure.into());
+        assert!(result.user_uuid.is_empty());
+        assert!(result.session_token.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sign_in_should_fail_if_incorrect_password() {
+        let users_service = UsersImpl::default();
+
+        let _ = users_service.create_user("123456".to_owned(), "654321".into(), None);
+
+        let users_service: Arc<dyn Users + Send + Sync> = Arc::new(users_service);
+        let sessions_service = Arc::new(Mutex::new(SessionsImpl::default()));
+
+        let auth_service = AuthService::new(users_service, sessions_service, HashingPool::new(2, 8));
+
+        let request = tonic::Request::new(SignInRequest {
+            username: "123456".to_owned(),
+            password: "wrong password".to_owned(),
+        });
+
+        let result = auth_service.sign_in(request).await.unwrap().into_inner();
+
+        assert_eq!(result.status_code, StatusCode::Failure.into());
+        assert!(result.user_uuid.is_empty());
+        assert!(result.session_token.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sign_in_should_succeed() {
+        let fixture = UsersFixture::new().with_user("123456", "654321").build();
+
+        let users_service: Arc<dyn Users + Send + Sync> = Arc::new(fixture.users);
+        let sessions_service = Arc::new(Mutex::new(fixture.sessions));
+
+        let auth_service = AuthService::new(users_service, sessions_service, HashingPool::new(2, 8));
+
+        let request = tonic::Request::new(SignInRequest {
+            username: "123456".to_owned(),
+            password: "654321".to_owned(),
+        });
+
+        let result = auth_service.sign_in(request).await.unwrap().into_inner();
+
+        assert_eq!(result.status_code, StatusCode::Success.into());
+        assert_eq!(result.user_uuid, fixture.uuids["123456"]);
+        assert!(!result.session_token.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sign_up_should_fail_if_username_exists() {
+        let fixture = UsersFixture::new().with_user("123456", "654321").build();
+
+        let users_service: Arc<dyn Users + Send + Sync> = Arc::new(fixture.users);
+        let sessions_service = Arc::new(Mutex::new(fixture.sessions));
+
+        let auth_service = AuthService::new(users_service, sessions_service, HashingPool::new(2, 8));
+
+        let request = tonic::Request::new(SignUpRequest {
+            username: "123456".to_owned(),
+            password: "654321".to_owned(),
+            ..Default::default()
+        });
+
+        let result = auth_service.sign_up(request).await.unwrap();
+
+        assert_eq!(result.into_inner().status_code, StatusCode::Failure.into());
+    }
+
+    #[tokio::test]
+    async fn sign_up_should_create_one_account_for_concurrent_requests_with_same_username() {
+        let users_service = Arc::new(UsersImpl::with_hash_rounds(1_000));
+        let auth_service = Arc::new(AuthService::new(
+            users_service.clone(),
+            Arc::new(Mutex::new(SessionsImpl::default())),
+            HashingPool::new(8, 64),
+        ));
+
+        let handles: Vec<_> = (0..32)
+            .map(|_| {
+                let auth_service = auth_service.clone();
+                tokio::spawn(async move { auth_service.sign_up(sign_up_request("alice", "")).await })
+            })
+            .collect();
+        let mut status_codes = Vec::new();
+        for handle in handles {
+            // Losing the race is an ordinary failed sign up, never an error status.
+            status_codes.push(handle.await.unwrap().unwrap().into_inner().status_code);
+        }
+
+        assert_eq!(status_codes.iter().filter(|code| **code == i32::from(StatusCode::Success)).count(), 1);
+        assert_eq!(status_codes.iter().filter(|code| **code == i32::from(StatusCode::Failure)).count(), 31);
+        assert_eq!(users_service.stats().users, 1);
+    }
+
+    fn keyed_sign_up_request(username: &str, idempotency_key: &str) -> Request<SignUpRequest> {
+        let mut request = sign_up_request(username, "");
+        request.metadata_mut().insert("idempotency-key", idempotency_key.parse().unwrap());
+        request
+    }
+
+    #[tokio::test]
+    async fn sign_up_should_replay_success_for_retry_with_same_idempotency_key() {
+        let users_service = Arc::new(UsersImpl::with_hash_rounds(1_000));
+        let auth_service = AuthService::new(users_service.clone(), Arc::new(Mutex::new(SessionsImpl::default())), HashingPool::new(2, 8));
+
+        let first = auth_service.sign_up(keyed_sign_up_request("alice", "retry-1")).await.unwrap().into_inner();
+        let retry = auth_service.sign_up(keyed_sign_up_request("alice", "retry-1")).await.unwrap().into_inner();
+        assert_eq!(first.status_code, StatusCode::Success.into());
+        assert_eq!(retry, first); // Including the uuid.
+        // Without the key it's an ordinary second sign up.
+        let result = auth_service.sign_up(sign_up_request("alice", "")).await.unwrap().into_inner();
+        assert_eq!((result.status_code, result.user_uuid.as_str()), (StatusCode::Failure.into(), ""));
+
+        let status = auth_service.sign_up(keyed_sign_up_request("bob", "retry-1")).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::AlreadyExists);
+        let mut other_password = keyed_sign_up_request("alice", "retry-1");
+        other_password.get_mut().password = "other password".to_owned();
+        assert_eq!(auth_service.sign_up(other_password).await.unwrap_err().code(), tonic::Code::AlreadyExists);
+        assert_eq!(users_service.stats().users, 1);
+    }
+
+    #[tokio::test]
+    async fn sign_up_should_create_once_for_concurrent_requests_with_same_idempotency_key() {
+        let users_service = Arc::new(UsersImpl::with_hash_rounds(1_000));
+        let auth_service = Arc::new(AuthService::new(
+            users_service.clone(),
+            Arc::new(Mutex::new(SessionsImpl::default())),
+            HashingPool::new(8, 64),
+        ));
+
+        let handles: Vec<_> = (0..16)
+            .map(|_| {
+                let auth_service = auth_service.clone();
+                tokio::spawn(async move { auth_service.sign_up(keyed_sign_up_request("alice", "retry-1")).await })
+            })
+            .collect();
+        for handle in handles {
+            let result = handle.await.unwrap().unwrap().into_inner();
+            assert_eq!(result.status_code, StatusCode::Success.into());
+        }
+        assert_eq!(users_service.stats().users, 1);
+    }
+
+    #[tokio::test]
+    async fn sign_up_should_forget_idempotency_key_after_ttl() {
+        let clock = Arc::new(ManualClock::new());
+        let sign_up_keys = IdempotencyKeys::new(Duration::from_secs(60), 100).with_clock(clock.clone());
+        let auth_service = invite_only_auth_service().with_invite_only(false).with_sign_up_keys(Some(sign_up_keys));
+
+        auth_service.sign_up(keyed_sign_up_request("alice", "retry-1")).await.unwrap();
+        clock.advance(Duration::from_secs(60));
+
+        let result = auth_service.sign_up(keyed_sign_up_request("alice", "retry-1")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Failure.into());
+        let result = auth_service.sign_up(keyed_sign_up_request("bob", "retry-1")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success.into());
+    }
+
+    #[tokio::test]
+    async fn sign_up_should_succeed() {
+        let users_service: Arc<dyn Users + Send + Sync> = Arc::new(UsersImpl::default());
+        let sessions_service = Arc::new(Mutex::new(SessionsImpl::default()));
+
+        let auth_service = AuthService::new(users_service.clone(), sessions_service, HashingPool::new(2, 8));
+
+        let request = tonic::Request::new(SignUpRequest {
+            username: "123456".to_owned(),
+            password: "654321".to_owned(),
+            ..Default::default()
+        });
+
+        let result = auth_service.sign_up(request).await.unwrap().into_inner();
+
+        assert_eq!(result.status_code, StatusCode::Success.into());
+        assert_eq!(users_service.get_user(&result.user_uuid).unwrap().username, "123456");
+    }
+
+    #[tokio::test]
+    async fn sign_out_should_revoke_the_session() {
+        let fixture = UsersFixture::new().with_user("123456", "654321").build();
+        let users_service: Arc<dyn Users + Send + Sync> = Arc::new(fixture.users);
+        let auth_service = AuthService::new(users_service, Arc::new(Mutex::new(fixture.sessions)), HashingPool::new(2, 8));
+
+        let sign_in = SignInRequest { username: "123456".to_owned(), password: "654321".to_owned() };
+        let session_token = auth_service.sign_in(tonic::Request::new(sign_in)).await.unwrap().into_inner().session_token;
+        let sign_out = |session_token: &str| tonic::Request::new(SignOutRequest { session_token: session_token.to_owned() });
+        let verify = || tonic::Request::new(VerifyRequest { session_token: session_token.clone() });
+        assert_eq!(auth_service.verify(verify()).await.unwrap().into_inner().status_code, StatusCode::Success.into());
+
+        let result = auth_service.sign_out(sign_out(&session_token)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success.into());
+        assert_eq!(auth_service.verify(verify()).await.unwrap().into_inner().status_code, StatusCode::Failure.into());
+
+        // Neither the used token again, nor anything else, signs anyone out.
+        assert_eq!(auth_service.sign_out(sign_out(&session_token)).await.unwrap().into_inner().status_code, StatusCode::Failure.into());
+        assert_eq!(auth_service.sign_out(sign_out("")).await.unwrap().into_inner().status_code, StatusCode::Failure.into());
+    }
+
+    #[tokio::test]
+    async fn sign_out_should_not_accept_a_user_uuid_for_a_token() {
+        let users_service: Arc<dyn Users + Send + Sync> = Arc::new(UsersImpl::with_hash_rounds(1_000));
+        let auth_service = AuthService::new(users_service, Arc::new(Mutex::new(SessionsImpl::default())), HashingPool::new(2, 8));
+        auth_service.sign_up(sign_up_request("alice", "")).await.unwrap();
+        let sign_in = SignInRequest { username: "alice".to_owned(), password: "654321".to_owned() };
+        let SignInResponse { user_uuid, session_token, .. } = auth_service.sign_in(tonic::Request::new(sign_in)).await.unwrap().into_inner();
+
+        let result = auth_service.sign_out(tonic::Request::new(SignOutRequest { session_token: user_uuid })).await.unwrap();
+        assert_eq!(result.into_inner().status_code, StatusCode::Failure.into());
+        let result = auth_service.verify(tonic::Request::new(VerifyRequest { session_token })).await.unwrap();
+        assert_eq!(result.into_inner().status_code, StatusCode::Success.into());
+    }
+
+    #[tokio::test]
+    async fn verify_should_fail_for_unknown_session() {
+        let users_service: Arc<dyn Users + Send + Sync> = Arc::new(UsersImpl::default());
+        let sessions_service = Arc::new(Mutex::new(SessionsImpl::default()));
+
+        let auth_service = AuthService::new(users_service, sessions_service, HashingPool::new(2, 8));
+
+        let request = tonic::Request::new(VerifyRequest {
+            session_token: "unknown".to_owned()
+        });
+
+        let result = auth_service.verify(request).await.unwrap().into_inner();
+
+        assert_eq!(result.status_code, StatusCode::Failure.into());
+        assert!(result.user_uuid.is_empty());
+    }
+
+    #[tokio::test]
+    async fn verify_should_succeed_for_signed_in_user() {
+        let signed_sessions = SessionsImpl::with_signer(TokenSigner::new(KeySet::single(b"key"), Duration::from_secs(60)));
+        let fixture = UsersFixture::new()
+            .with_signed_in_user("123456", "654321")
+            .build_with_sessions(signed_sessions);
+
+        let users_service: Arc<dyn Users + Send + Sync> = Arc::new(fixture.users);
+        let sessions_service = Arc::new(Mutex::new(fixture.sessions));
+
+        let auth_service = AuthService::new(users_service, sessions_service, HashingPool::new(2, 8));
+
+        let request = tonic::Request::new(VerifyRequest {
+            session_token: fixture.sessions_by_user["123456"].clone(),
+        });
+
+        let result = auth_service.verify(request).await.unwrap().into_inner();
+
+        assert_eq!(result.status_code, StatusCode::Success.into());
+        assert_eq!(result.user_uuid, fixture.uuids["123456"]);
+    }
+
+    #[tokio::test]
+    async fn verify_should_reject_session_from_other_client_when_binding_enforced() {
+        let users_service: Arc<dyn Users + Send + Sync> = Arc::new(UsersImpl::with_hash_rounds(1_000));
+        users_service.create_user("alice".to_owned(), "password".into(), None).unwrap();
+        let sessions = SessionsImpl::default().with_binding(SessionBinding::new(BindingMode::Enforce));
+        let auth_service = AuthService::new(users_service, Arc::new(Mutex::new(sessions)), HashingPool::new(2, 8));
+        fn from<T>(user_agent: &str, request: T) -> tonic::Request<T> {
+            let mut request = tonic::Request::new(request);
+            request.metadata_mut().insert("user-agent", user_agent.parse().unwrap());
+            request
+        }
+
+        let sign_in = SignInRequest { username: "alice".to_owned(), password: "password".to_owned() };
+        let session_token = auth_service.sign_in(from("app/1.0", sign_in)).await.unwrap().into_inner().session_token;
+
+        let verify = VerifyRequest { session_token };
+        let result = auth_service.verify(from("app/1.0", verify.clone())).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success.into());
+        let result = auth_service.verify(from("curl/8.0", verify)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::SessionBindingMismatch.into());
+    }
+
+    #[tokio::test]
+    async fn verify_should_report_which_session_limit_was_hit() {
+        let clock = Arc::new(ManualClock::new());
+        let mut sessions = SessionsImpl::default()
+            .with_idle_timeout(Some(Duration::from_secs(30 * 60)))
+            .with_absolute_lifetime(Some(Duration::from_secs(12 * 60 * 60)))
+            .with_clock(clock.clone());
+        let idle_session = sessions.create_session("idle user");
+        let busy_session = sessions.create_session("busy user");
+
+        let users_service: Arc<dyn Users + Send + Sync> = Arc::new(UsersImpl::with_hash_rounds(1_000));
+        let auth_service = AuthService::new(users_service, Arc::new(Mutex::new(sessions)), HashingPool::new(2, 8));
+        let verify = |session_token: &str| tonic::Request::new(VerifyRequest { session_token: session_token.to_owned() });
+
+        for _ in 0..(12 * 60 / 20) {
+            clock.advance(Duration::from_secs(20 * 60));
+            auth_service.verify(verify(&busy_session)).await.unwrap();
+        }
+
+        let result = auth_service.verify(verify(&idle_session)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::SessionIdleTimeout.into());
+        let result = auth_service.verify(verify(&busy_session)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::SessionExpired.into());
+    }
+
+    #[tokio::test]
+    async fn verify_should_report_email_verified_after_confirmation() {
+        let fixture = UsersFixture::new().with_signed_in_user("123456", "654321").build();
+        let user_uuid = fixture.uuids["123456"].clone();
+        let session_token = fixture.sessions_by_user["123456"].clone();
+
+        let events = Arc::new(RecordingEvents::default());
+        fixture.users.set_email(user_uuid, Some("user@example.com".to_owned())).unwrap();
+        let users_service: Arc<dyn Users + Send + Sync> = Arc::new(fixture.users.with_events(events.clone()));
+        let sessions_service = Arc::new(Mutex::new(fixture.sessions));
+
+        let auth_service = AuthService::new(users_service, sessions_service, HashingPool::new(2, 8));
+        let verify = || tonic::Request::new(VerifyRequest { session_token: session_token.clone() });
+
+        assert!(!auth_service.verify(verify()).await.unwrap().into_inner().email_verified);
+
+        let result = auth_service
+            .start_email_verification(tonic::Request::new(StartEmailVerificationRequest {
+                session_token: session_token.clone(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(result.status_code, StatusCode::Success.into());
+
+        let Event::EmailVerificationRequested { token, .. } = events.0.lock().unwrap()[0].clone() else {
+            panic!("expected an email verification event");
+        };
+        let confirm = |verification_token: &str| {
+            tonic::Request::new(ConfirmEmailRequest {
+                session_token: session_token.clone(),
+                verification_token: verification_token.to_owned(),
+            })
+        };
+
+        let result = auth_service.confirm_email(confirm("wrong")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::VerificationFailed.into());
+
+        let result = auth_service.confirm_email(confirm(&token)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success.into());
+        assert!(auth_service.verify(verify()).await.unwrap().into_inner().email_verified);
+    }
+
+    // Signed in as "alice", with an email to verify, on a manual clock.
+    async fn signed_in_with_reauth_max_age(reauth_max_age: Option<Duration>) -> (AuthService, Arc<ManualClock>, String) {
+        let clock = Arc::new(ManualClock::new());
+        let sessions_service = Arc::new(Mutex::new(SessionsImpl::default().with_clock(clock.clone())));
+        let users_service: Arc<dyn Users + Send + Sync> = Arc::new(UsersImpl::with_hash_rounds(1_000));
+        let auth_service =
+            AuthService::new(users_service, sessions_service, HashingPool::new(2, 8)).with_reauth_max_age(reauth_max_age);
+        let alice = SignUpRequest {
+            username: "alice".to_owned(),
+            password: "654321".to_owned(),
+            email: "alice@example.com".to_owned(),
+            ..Default::default()
+        };
+        auth_service.sign_up(tonic::Request::new(alice)).await.unwrap();
+        let sign_in = SignInRequest { username: "alice".to_owned(), password: "654321".to_owned() };
+        let session_token = auth_service.sign_in(tonic::Request::new(sign_in)).await.unwrap().into_inner().session_token;
+        (auth_service, clock, session_token)
+    }
+
+    async fn start_email_verification_status(auth_service: &AuthService, session_token: &str) -> i32 {
+        let request = StartEmailVerificationRequest { session_token: session_token.to_owned() };
+        auth_service.start_email_verification(tonic::Request::new(request)).await.unwrap().into_inner().status_code
+    }
+
+    fn reauthenticate_request(session_token: &str, password: &str) -> tonic::Request<ReauthenticateRequest> {
+        tonic::Request::new(ReauthenticateRequest { session_token: session_token.to_owned(), password: password.to_owned() })
+    }
+
+    #[tokio::test]
+    async fn stale_session_should_reauthenticate_before_sensitive_rpcs() {
+        let (auth_service, clock, session_token) = signed_in_with_reauth_max_age(Some(Duration::from_secs(5 * 60))).await;
+        assert_eq!(start_email_verification_status(&auth_service, &session_token).await, StatusCode::Success.into());
+
+        clock.advance(Duration::from_secs(6 * 60));
+        assert_eq!(start_email_verification_status(&auth_service, &session_token).await, StatusCode::ReauthenticationRequired.into());
+        let confirm = ConfirmEmailRequest { session_token: session_token.clone(), verification_token: "token".to_owned() };
+        let result = auth_service.confirm_email(tonic::Request::new(confirm)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::ReauthenticationRequired.into());
+        // The session itself still works.
+        let verify = VerifyRequest { session_token: session_token.clone() };
+        assert_eq!(auth_service.verify(tonic::Request::new(verify)).await.unwrap().into_inner().status_code, 1);
+
+        let result = auth_service.reauthenticate(reauthenticate_request(&session_token, "wrong")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::WrongPassword.into());
+        assert_eq!(start_email_verification_status(&auth_service, &session_token).await, StatusCode::ReauthenticationRequired.into());
+
+        let result = auth_service.reauthenticate(reauthenticate_request(&session_token, "654321")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success.into());
+        assert_eq!(start_email_verification_status(&auth_service, &session_token).await, StatusCode::Success.into());
+
+        let result = auth_service.reauthenticate(reauthenticate_request("unknown", "654321")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Failure.into());
+    }
+
+    #[tokio::test]
+    async fn reauthentication_max_age_should_be_configurable() {
+        let (auth_service, clock, session_token) = signed_in_with_reauth_max_age(Some(Duration::from_secs(10 * 60))).await;
+        clock.advance(Duration::from_secs(6 * 60));
+        assert_eq!(start_email_verification_status(&auth_service, &session_token).await, StatusCode::Success.into());
+        clock.advance(Duration::from_secs(5 * 60));
+        assert_eq!(start_email_verification_status(&auth_service, &session_token).await, StatusCode::ReauthenticationRequired.into());
+
+        let (auth_service, clock, session_token) = signed_in_with_reauth_max_age(None).await;
+        clock.advance(Duration::from_secs(24 * 60 * 60));
+        assert_eq!(start_email_verification_status(&auth_service, &session_token).await, StatusCode::Success.into());
+    }
+
+    #[tokio::test]
+    async fn guest_session_should_never_be_refreshed() {
+        let (auth_service, clock, _) = signed_in_with_reauth_max_age(Some(Duration::from_secs(5 * 60))).await;
+        let guest = auth_service.create_guest(tonic::Request::new(CreateGuestRequest {})).await.unwrap().into_inner();
+
+        clock.advance(Duration::from_secs(6 * 60));
+        for password in ["", "654321"] {
+            let result = auth_service.reauthenticate(reauthenticate_request(&guest.session_token, password)).await.unwrap().into_inner();
+            assert_eq!(result.status_code, StatusCode::Failure.into());
+        }
+        assert_eq!(start_email_verification_status(&auth_service, &guest.session_token).await, StatusCode::ReauthenticationRequired.into());
+    }
+
+    #[tokio::test]
+    async fn password_reset_should_not_reveal_whether_account_exists() {
+        let fixture = UsersFixture::new().with_user("123456", "654321").build();
+        let events = Arc::new(RecordingEvents::default());
+        let users_service: Arc<dyn Users + Send + Sync> = Arc::new(fixture.users.with_events(events.clone()));
+        let auth_service = AuthService::new(users_service, Arc::new(Mutex::new(fixture.sessions)), HashingPool::new(2, 8));
+
+        let start = |username_or_email: &str| {
+            tonic::Request::new(StartPasswordResetRequest {
+                username_or_email: username_or_email.to_owned(),
+            })
+        };
+        let known = auth_service.start_password_reset(start("123456")).await.unwrap().into_inner();
+        let unknown = auth_service.start_password_reset(start("nobody")).await.unwrap().into_inner();
+
+        assert_eq!(known, unknown);
+        assert_eq!(events.0.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn identifiers_should_match_however_typed() {
+        let events = Arc::new(RecordingEvents::default());
+        let users_service: Arc<dyn Users + Send + Sync> = Arc::new(UsersImpl::with_hash_rounds(1_000).with_events(events.clone()));
+        let auth_service = AuthService::new(users_service, Arc::new(Mutex::new(SessionsImpl::default())), HashingPool::new(2, 8));
+        auth_service.sign_up(sign_up_request("Alice", "")).await.unwrap();
+        let bob = SignUpRequest {
+            username: "bob".to_owned(),
+            password: "654321".to_owned(),
+            email: "Bob@Example.com".to_owned(),
+            ..Default::default()
+        };
+        auth_service.sign_up(tonic::Request::new(bob)).await.unwrap();
+
+        let sign_in = SignInRequest { username: " alice".to_owned(), password: "654321".to_owned() };
+        let result = auth_service.sign_in(tonic::Request::new(sign_in)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success.into());
+
+        for username_or_email in [" alice", "BOB@example.COM "] {
+            let start = StartPasswordResetRequest { username_or_email: username_or_email.to_owned() };
+            auth_service.start_password_reset(tonic::Request::new(start)).await.unwrap();
+        }
+        let reset_for: Vec<Option<String>> = events
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|event| match event {
+                Event::PasswordResetRequested { email, .. } => email.clone(),
+                other => panic!("unexpected event {other:?}"),
+            })
+            .collect();
+        assert_eq!(reset_for, vec![None, Some("bob@example.com".to_owned())]);
+    }
+
+    #[tokio::test]
+    async fn password_reset_should_revoke_sessions() {
+        let fixture = UsersFixture::new().with_signed_in_user("123456", "654321").build();
+        let session_token = fixture.sessions_by_user["123456"].clone();
+        let events = Arc::new(RecordingEvents::default());
+        let users_service: Arc<dyn Users + Send + Sync> = Arc::new(fixture.users.with_events(events.clone()));
+        let auth_service = AuthService::new(users_service, Arc::new(Mutex::new(fixture.sessions)), HashingPool::new(2, 8));
+
+        auth_service
+            .start_password_reset(tonic::Request::new(StartPasswordResetRequest {
+                username_or_email: "123456".to_owned(),
+            }))
+            .await
+            .unwrap();
+        let Event::PasswordResetRequested { token, .. } = events.0.lock().unwrap()[0].clone() else {
+            panic!("expected a password reset event");
+        };
+
+        let complete = || {
+            tonic::Request::new(CompletePasswordResetRequest {
+                reset_token: token.clone(),
+                new_password: "new password".to_owned(),
+            })
+        };
+        let result = auth_service.complete_password_reset(complete()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success.into());
+
+        let verify = tonic::Request::new(VerifyRequest { session_token });
+        assert_eq!(auth_service.verify(verify).await.unwrap().into_inner().status_code, StatusCode::Failure.into());
+
+        let result = auth_service.complete_password_reset(complete()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::ResetTokenInvalid.into());
+    }
+
+    #[tokio::test]
+    async fn guest_should_keep_session_and_uuid_through_upgrade() {
+        let users_service: Arc<dyn Users + Send + Sync> = Arc::new(UsersImpl::with_hash_rounds(1_000));
+        let sessions_service = Arc::new(Mutex::new(SessionsImpl::default()));
+        let auth_service = AuthService::new(users_service, sessions_service, HashingPool::new(2, 8));
+
+        let guest = auth_service
+            .create_guest(tonic::Request::new(CreateGuestRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(guest.status_code, StatusCode::Success.into());
+
+        let verify = || tonic::Request::new(VerifyRequest { session_token: guest.session_token.clone() });
+        let result = auth_service.verify(verify()).await.unwrap().into_inner();
+        assert_eq!(result.user_uuid, guest.user_uuid);
+        assert!(result.guest);
+
+        let upgrade = || tonic::Request::new(UpgradeGuestRequest {
+            session_token: guest.session_token.clone(),
+            username: "alice".to_owned(),
+            password: "654321".to_owned(),
+        });
+        let result = auth_service.upgrade_guest(upgrade()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success.into());
+
+        let result = auth_service.verify(verify()).await.unwrap().into_inner();
+        assert_eq!(result.user_uuid, guest.user_uuid);
+        assert!(!result.guest);
+
+        let result = auth_service.upgrade_guest(upgrade()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::NotAGuest.into());
+
+        let sign_in = tonic::Request::new(SignInRequest {
+            username: "alice".to_owned(),
+            password: "654321".to_owned(),
+        });
+        assert_eq!(auth_service.sign_in(sign_in).await.unwrap().into_inner().user_uuid, guest.user_uuid);
+    }
+
+    #[tokio::test]
+    async fn upgrade_guest_should_validate_like_sign_up() {
+        let users_service: Arc<dyn Users + Send + Sync> = Arc::new(UsersImpl::with_hash_rounds(1_000));
+        let sessions_service = Arc::new(Mutex::new(SessionsImpl::default()));
+        let auth_service = AuthService::new(users_service, sessions_service, HashingPool::new(2, 8));
+        let guest = auth_service.create_guest(tonic::Request::new(CreateGuestRequest {})).await.unwrap().into_inner();
+
+        let upgrade = |username: &str, password: &str| tonic::Request::new(UpgradeGuestRequest {
+            session_token: guest.session_token.clone(),
+            username: username.to_owned(),
+            password: password.to_owned(),
+        });
+        let long_username = "a".repeat(MAX_USERNAME_BYTES + 1);
+        let long_password = "p".repeat(MAX_PASSWORD_BYTES + 1);
+        for (username, password, field) in [
+            ("  ", "654321", "username"),
+            (long_username.as_str(), "654321", "username"),
+            ("al\u{7}ice", "654321", "username"),
+            ("alice", long_password.as_str(), "password"),
+        ] {
+            let status = auth_service.upgrade_guest(upgrade(username, password)).await.unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+            assert!(status.message().starts_with(field), "{}", status.message());
+        }
+
+        let verify = tonic::Request::new(VerifyRequest { session_token: guest.session_token.clone() });
+        assert!(auth_service.verify(verify).await.unwrap().into_inner().guest);
+        let result = auth_service.upgrade_guest(upgrade(" alice ", "654321")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success.into());
+    }
+
+    #[tokio::test]
+    async fn create_guest_should_be_disabled_when_invite_only() {
+        let auth_service = invite_only_auth_service();
+
+        let result = auth_service.create_guest(tonic::Request::new(CreateGuestRequest {})).await;
+
+        assert_eq!(result.unwrap_err().code(), tonic::Code::PermissionDenied);
+    }
+
+    fn sign_up_request(username: &str, invitation_code: &str) -> Request<SignUpRequest> {
+        tonic::Request::new(SignUpRequest {
+            username: username.to_owned(),
+            password: "654321".to_owned(),
+            invitation_code: invitation_code.to_owned(),
+            ..Default::default()
+        })
+    }
+
+    fn mint_invitation_request(admin_token: &str, max_uses: u32, username: &str) -> Request<MintInvitationRequest> {
+        let mut request = tonic::Request::new(MintInvitationRequest {
+            max_uses,
+            ttl_secs: 0,
+            username: username.to_owned(),
+        });
+        request
+            .metadata_mut()
+            .insert("authorization", format!("Bearer {admin_token}").parse().unwrap());
+        request
+    }
+
+    fn watching_auth_service(buffer: usize) -> (AuthService, Arc<UserEventLog>) {
+        let user_events = Arc::new(UserEventLog::new(buffer));
+        let users_service: Arc<dyn Users + Send + Sync> =
+            Arc::new(UsersImpl::with_hash_rounds(1_000).with_user_events(user_events.clone()));
+        let sessions_service = Arc::new(Mutex::new(SessionsImpl::default()));
+
+        let auth_service = AuthService::new(users_service, sessions_service, HashingPool::new(2, 8))
+            .with_admin_token(Some("admin".to_owned()))
+            .with_user_events(user_events.clone());
+        (auth_service, user_events)
+    }
+
+    fn watch_request(since_sequence: u64) -> Request<WatchUserEventsRequest> {
+        let mut request = tonic::Request::new(WatchUserEventsRequest { since_sequence });
+        request.metadata_mut().insert("authorization", "Bearer admin".parse().unwrap());
+        request
+    }
+
+    async fn next_event(stream: &mut ReceiverStream<Result<UserEvent, Status>>) -> Option<Result<UserEvent, Status>> {
+        tokio::time::timeout(Duration::from_secs(5), stream.next()).await.expect("stream should not stall")
+    }
+
+    #[tokio::test]
+    async fn watch_user_events_should_replay_then_stream_live_events() {
+        let (auth_service, _) = watching_auth_service(8);
+        auth_service.sign_up(sign_up_request("alice", "")).await.unwrap();
+
+        let mut stream = auth_service.watch_user_events(watch_request(0)).await.unwrap().into_inner();
+        let replayed = next_event(&mut stream).await.unwrap().unwrap();
+        assert_eq!((replayed.sequence, replayed.username.as_str()), (0, "alice"));
+        assert_eq!(replayed.kind, UserEventKind::Created as i32);
+
+        auth_service.sign_up(sign_up_request("bob", "")).await.unwrap();
+        let live = next_event(&mut stream).await.unwrap().unwrap();
+        assert_eq!((live.sequence, live.username.as_str()), (1, "bob"));
+    }
+
+    #[tokio::test]
+    async fn watch_user_events_should_reject_sequence_no_longer_buffered() {
+        let (auth_service, user_events) = watching_auth_service(2);
+        for username in ["alice", "bob", "carol"] {
+            user_events.record(user_events::UserEventKind::Created, "uuid", username);
+        }
+
+        let status = auth_service.watch_user_events(watch_request(0)).await.unwrap_err();
+
+        assert_eq!(status.code(), tonic::Code::OutOfRange);
+        assert!(auth_service.watch_user_events(watch_request(1)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn watch_user_events_should_drop_watcher_that_falls_behind() {
+        let (auth_service, user_events) = watching_auth_service(4);
+        let mut stream = auth_service.watch_user_events(watch_request(0)).await.unwrap().into_inner();
+
+        // Nobody reads while these are recorded, so the watcher can't keep up.
+        for i in 0..64 {
+            user_events.record(user_events::UserEventKind::Created, "uuid", &format!("user{i}"));
+        }
+
+        let mut last = None;
+        while let Some(item) = next_event(&mut stream).await {
+            last = Some(item);
+        }
+        assert_eq!(last.unwrap().unwrap_err().code(), tonic::Code::ResourceExhausted);
+    }
+
+    #[tokio::test]
+    async fn watch_user_events_should_require_admin_token() {
+        let (auth_service, _) = watching_auth_service(8);
+
+        let status = auth_service
+            .watch_user_events(tonic::Request::new(WatchUserEventsRequest { since_sequence: 0 }))
+            .await
+            .unwrap_err();
+
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    fn reload_config_request(admin_token: &str) -> Request<ReloadConfigRequest> {
+        let mut request = tonic::Request::new(ReloadConfigRequest {});
+        request
+            .metadata_mut()
+            .insert("authorization", format!("Bearer {admin_token}").parse().unwrap());
+        request
+    }
+
+    #[tokio::test]
+    async fn reload_config_should_apply_session_ttl_and_list_ignored_settings() {
+        let users_service: Arc<dyn Users + Send + Sync> = Arc::new(UsersImpl::with_hash_rounds(1_000));
+        let clock = Arc::new(ManualClock::new());
+        let mut sessions = SessionsImpl::default()
+            .with_absolute_lifetime(Some(Duration::from_secs(60 * 60)))
+            .with_clock(clock.clone());
+        let old_session = sessions.create_session("old user");
+        let config_source = ConfigSource::parse("AUTH_SESSION_ABSOLUTE_LIFETIME_SECS=3600\nAUTH_HASH_ROUNDS=1000").unwrap();
+        let config_reloader = ConfigReloader::new(config_source, sessions.limits()).with_loader(|| {
+            ConfigSource::parse("AUTH_SESSION_ABSOLUTE_LIFETIME_SECS=60\nAUTH_HASH_ROUNDS=2000")
+        });
+        let auth_service = AuthService::new(users_service, Arc::new(Mutex::new(sessions)), HashingPool::new(2, 8))
+            .with_admin_token(Some("admin".to_owned()))
+            .with_config_reloader(Some(Arc::new(config_reloader)));
+
+        let response = auth_service.reload_config(reload_config_request("admin")).await.unwrap().into_inner();
+        assert_eq!(response.ignored_settings, vec!["AUTH_HASH_ROUNDS"]);
+        assert!(!response.keyset_reloaded);
+
+        auth_service.sign_up(sign_up_request("alice", "")).await.unwrap();
+        let sign_in = SignInRequest { username: "alice".to_owned(), password: "654321".to_owned() };
+        let new_session = auth_service.sign_in(tonic::Request::new(sign_in)).await.unwrap().into_inner().session_token;
+        clock.advance(Duration::from_secs(2 * 60));
+
+        let verify = |session_token: &str| tonic::Request::new(VerifyRequest { session_token: session_token.to_owned() });
+        let result = auth_service.verify(verify(&new_session)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::SessionExpired.into());
+        let result = auth_service.verify(verify(&old_session)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success.into());
+    }
+
+    #[tokio::test]
+    async fn reload_config_should_require_admin_token_and_reloader() {
+        let auth_service = invite_only_auth_service();
+
+        let status = auth_service.reload_config(reload_config_request("wrong")).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let status = auth_service.reload_config(reload_config_request("admin")).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unimplemented);
+    }
+
+    fn admin<T>(admin_token: &str, request: T) -> tonic::Request<T> {
+        let mut request = tonic::Request::new(request);
+        request
+            .metadata_mut()
+            .insert("authorization", format!("Bearer {admin_token}").parse().unwrap());
+        request
+    }
+
+    #[tokio::test]
+    async fn sign_in_should_require_password_change_when_flagged() {
+        let auth_service = invite_only_auth_service().with_invite_only(false);
+        for username in ["alice", "bob"] {
+            auth_service.sign_up(sign_up_request(username, "")).await.unwrap();
+        }
+        let sign_in = |username: &str, password: &str| {
+            tonic::Request::new(SignInRequest { username: username.to_owned(), password: password.to_owned() })
+        };
+
+        let listed = auth_service
+            .list_users_by_hash_algorithm(admin("admin", ListUsersByHashAlgorithmRequest { algorithm: "pbkdf2-sha256".to_owned() }))
+            .await
+            .unwrap()
+            .into_inner()
+            .users;
+        assert_eq!(listed.iter().map(|user| user.username.as_str()).collect::<Vec<_>>(), vec!["alice", "bob"]);
+        let alice = listed[0].user_uuid.clone();
+
+        let response = auth_service
+            .require_password_change(admin("admin", RequirePasswordChangeRequest { user_uuids: vec![alice.clone()] }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.flagged, 1);
+
+        let result = auth_service.sign_in(sign_in("alice", "654321")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::PasswordChangeRequired.into());
+        assert!(result.session_token.is_empty());
+        let result = auth_service.sign_in(sign_in("bob", "654321")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success.into());
+
+        let change = ChangePasswordRequest {
+            username: "alice".to_owned(),
+            password: "654321".to_owned(),
+            new_password: "new password".to_owned(),
+        };
+        let result = auth_service.change_password(tonic::Request::new(change)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success.into());
+
+        let result = auth_service.sign_in(sign_in("alice", "new password")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success.into());
+        assert_eq!(result.user_uuid, alice);
+    }
+
+    #[tokio::test]
+    async fn hash_migration_rpcs_should_require_admin_token() {
+        let auth_service = invite_only_auth_service();
+
+        let status = auth_service
+            .list_users_by_hash_algorithm(admin("wrong", ListUsersByHashAlgorithmRequest::default()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        let status = auth_service
+            .require_password_change(tonic::Request::new(RequirePasswordChangeRequest::default()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn create_user_should_bypass_ban_list_only_when_asked() {
+        let ban_list = Arc::new(std::sync::RwLock::new(crate::banlist::UsernameBanList::reserved()));
+        let users_service: Arc<dyn Users + Send + Sync> = Arc::new(UsersImpl::with_hash_rounds(1_000).with_ban_list(ban_list));
+        let auth_service = AuthService::new(users_service, Arc::new(Mutex::new(SessionsImpl::default())), HashingPool::new(2, 8))
+            .with_admin_token(Some("admin".to_owned()));
+        let create_user = |allow_banned_username: bool| CreateUserRequest {
+            username: "support".to_owned(),
+            password: "password".to_owned(),
+            email: "".to_owned(),
+            allow_banned_username,
+        };
+
+        let response = auth_service.sign_up(sign_up_request("Support", "")).await.unwrap().into_inner();
+        assert_eq!(response.status_code, StatusCode::UsernameNotAllowed.into());
+        let response = auth_service.create_user(admin("admin", create_user(false))).await.unwrap().into_inner();
+        assert_eq!(response.status_code, StatusCode::UsernameNotAllowed.into());
+
+        let response = auth_service.create_user(admin("admin", create_user(true))).await.unwrap().into_inner();
+        assert_eq!(response.status_code, StatusCode::Success.into());
+        let status = auth_service.create_user(admin("wrong", create_user(true))).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn verify_credentials_should_report_each_pair_in_order() {
+        let auth_service = invite_only_auth_service().with_invite_only(false);
+        let alice = auth_service.sign_up(sign_up_request("alice", "")).await.unwrap().into_inner().user_uuid;
+        let credential = |username: &str, password: &str| Credential { username: username.to_owned(), password: password.to_owned() };
+        let request = |credentials: Vec<Credential>| VerifyCredentialsRequest { credentials };
+
+        let response = auth_service
+            .verify_credentials(admin("admin", request(vec![credential("bob", "654321"), credential("alice", "wrong"), credential("alice", "654321")])))
+            .await
+            .unwrap()
+            .into_inner();
+
+        let results: Vec<(i32, &str)> = response.results.iter().map(|result| (result.status_code, result.user_uuid.as_str())).collect();
+        assert_eq!(
+            results,
+            vec![(StatusCode::UserNotFound.into(), ""), (StatusCode::WrongPassword.into(), ""), (StatusCode::Success.into(), alice.as_str())]
+        );
+
+        let too_many = request(vec![credential("alice", "654321"); MAX_CREDENTIALS_PER_BATCH + 1]);
+        assert_eq!(auth_service.verify_credentials(admin("admin", too_many)).await.unwrap_err().code(), tonic::Code::InvalidArgument);
+        let status = auth_service.verify_credentials(admin("wrong", request(vec![]))).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn purge_guests_should_only_delete_when_not_a_dry_run() {
+        let users_service = Arc::new(UsersImpl::with_hash_rounds(1_000));
+        let guest = users_service.create_guest();
+        let auth_service = AuthService::new(users_service.clone(), Arc::new(Mutex::new(SessionsImpl::default())), HashingPool::new(2, 8))
+            .with_admin_token(Some("admin".to_owned()));
+        let purge = |dry_run: bool| PurgeGuestsRequest { older_than_secs: 0, dry_run };
+
+        let response = auth_service.purge_guests(admin("admin", purge(true))).await.unwrap().into_inner();
+        assert_eq!((response.guests, response.user_uuids, response.dry_run), (1, vec![guest.clone()], true));
+        assert!(users_service.get_user(&guest).is_some());
+
+        let response = auth_service.purge_guests(admin("admin", purge(false))).await.unwrap().into_inner();
+        assert_eq!((response.guests, response.dry_run), (1, false));
+        assert!(users_service.get_user(&guest).is_none());
+
+        let status = auth_service.purge_guests(admin("wrong", purge(true))).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn maintenance_mode_should_only_pause_account_changes() {
+        let auth_service = invite_only_auth_service().with_invite_only(false);
+        auth_service.sign_up(sign_up_request("alice", "")).await.unwrap();
+        let maintenance = |enabled: bool| SetMaintenanceModeRequest { enabled, duration_secs: 60 };
+
+        let response = auth_service.set_maintenance_mode(admin("admin", maintenance(true))).await.unwrap().into_inner();
+        assert_eq!(response.remaining_secs, 60);
+
+        let status = auth_service.sign_up(sign_up_request("bob", "")).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        assert_eq!(status.metadata().get("retry-after").unwrap(), "60");
+        let change = ChangePasswordRequest {
+            username: "alice".to_owned(),
+            password: "654321".to_owned(),
+            new_password: "new password".to_owned(),
+        };
+        let status = auth_service.change_password(tonic::Request::new(change)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        let status = auth_service.create_guest(tonic::Request::new(CreateGuestRequest {})).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+
+        // Reads, sign ins and sign outs keep working.
+        let sign_in = SignInRequest { username: "alice".to_owned(), password: "654321".to_owned() };
+        let result = auth_service.sign_in(tonic::Request::new(sign_in)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success.into());
+        let verify = VerifyRequest { session_token: result.session_token.clone() };
+        let verified = auth_service.verify(tonic::Request::new(verify)).await.unwrap().into_inner();
+        assert_eq!(verified.status_code, StatusCode::Success.into());
+        let sign_out = SignOutRequest { session_token: result.session_token };
+        let signed_out = auth_service.sign_out(tonic::Request::new(sign_out)).await.unwrap().into_inner();
+        assert_eq!(signed_out.status_code, StatusCode::Success.into());
+        let purge = PurgeGuestsRequest { older_than_secs: 0, dry_run: true };
+        assert!(auth_service.purge_guests(admin("admin", purge)).await.is_ok());
+
+        let response = auth_service.set_maintenance_mode(admin("admin", maintenance(false))).await.unwrap().into_inner();
+        assert_eq!(response.remaining_secs, 0);
+        let result = auth_service.sign_up(sign_up_request("bob", "")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success.into());
+
+        let status = auth_service.set_maintenance_mode(admin("wrong", maintenance(true))).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn overload_should_shed_sign_ins_and_sign_ups_but_not_verify() {
+        let clock = Arc::new(ManualClock::new());
+        let thresholds = ShedThresholds { p95: Some(Duration::from_millis(500)), queue_length: None };
+        let load_shedder = Arc::new(LoadShedder::new(thresholds, Duration::from_secs(4)).with_clock(clock.clone()));
+        let auth_service = invite_only_auth_service().with_invite_only(false).with_load_shedder(Some(load_shedder.clone()));
+        auth_service.sign_up(sign_up_request("alice", "")).await.unwrap();
+        let sign_in = || tonic::Request::new(SignInRequest { username: "alice".to_owned(), password: "654321".to_owned() });
+        let mut session_token = auth_service.sign_in(sign_in()).await.unwrap().into_inner().session_token;
+
+        for _ in 0..20 {
+            load_shedder.observe(Duration::from_secs(2));
+        }
+        for _ in 0..4 {
+            clock.advance(Duration::from_secs(1));
+            load_shedder.admit(0).ok();
+        }
+
+        let mut shed = 0;
+        for _ in 0..10 {
+            match auth_service.sign_in(sign_in()).await {
+                // Replaces the session signed in before.
+                Ok(response) => session_token = response.into_inner().session_token,
+                Err(status) => {
+                    assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+                    assert_eq!(status.metadata().get("retry-after").unwrap(), "5");
+                    shed += 1;
+                }
+            }
+        }
+        assert_eq!(shed, 9);
+        let status = auth_service.sign_up(sign_up_request("bob", "")).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        for _ in 0..10 {
+            let verify = VerifyRequest { session_token: session_token.clone() };
+            let verified = auth_service.verify(tonic::Request::new(verify)).await.unwrap().into_inner();
+            assert_eq!(verified.status_code, StatusCode::Success.into());
+        }
+    }
+
+    fn security_report_request(user_uuid: &str) -> GetUserSecurityReportRequest {
+        GetUserSecurityReportRequest { user_uuid: user_uuid.to_owned(), max_events: 0 }
+    }
+
+    #[tokio::test]
+    async fn security_report_should_gather_account_session_and_events() {
+        let user_events = Arc::new(UserEventLog::default());
+        let users_service: Arc<dyn Users + Send + Sync> =
+            Arc::new(UsersImpl::with_hash_rounds(1_000).with_user_events(user_events.clone()));
+        let auth_service = AuthService::new(users_service, Arc::new(Mutex::new(SessionsImpl::default())), HashingPool::new(2, 8))
+            .with_admin_token(Some("admin".to_owned()))
+            .with_user_events(user_events)
+            .with_login_notifier(Some(Arc::new(LoginNotifier::new(b"ip key", 10))));
+        auth_service.sign_up(sign_up_request("alice", "")).await.unwrap();
+        let sign_in = SignInRequest { username: "alice".to_owned(), password: "654321".to_owned() };
+        let signed_in = auth_service.sign_in(tonic::Request::new(sign_in)).await.unwrap().into_inner();
+
+        let report = auth_service
+            .get_user_security_report(admin("admin", security_report_request(&signed_in.user_uuid)))
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert_eq!(report.status_code, StatusCode::Success.into());
+        let account = report.account.clone().unwrap();
+        assert_eq!((account.username.as_str(), account.kind.as_str()), ("alice", "local"));
+        assert!(!account.hash_algorithm.is_empty());
+        assert!(account.created_at_secs > 0);
+        assert_eq!(account.password_reset_pending_until_secs, 0);
+        assert_eq!(report.sessions.len(), 1);
+        assert!(report.sessions[0].live);
+        assert_eq!(report.known_devices, 1);
+        let kinds: Vec<i32> = report.recent_events.iter().map(|event| event.kind).collect();
+        assert_eq!(kinds, vec![i32::from(UserEventKind::Created)]);
+        assert_eq!(report.unavailable, vec!["roles", "mfa", "api_keys", "failed_logins"]);
+        // Nothing that could be used to sign in as the user.
+        assert!(!format!("{:?}", report).contains(&signed_in.session_token));
+    }
+
+    #[tokio::test]
+    async fn security_report_should_say_what_it_leaves_out() {
+        let auth_service = invite_only_auth_service().with_invite_only(false);
+        let user_uuid = auth_service.sign_up(sign_up_request("alice", "")).await.unwrap().into_inner().user_uuid;
+
+        let report =
+            auth_service.get_user_security_report(admin("admin", security_report_request(&user_uuid))).await.unwrap().into_inner();
+
+        assert!(report.sessions.is_empty());
+        assert!(report.unavailable.contains(&"known_devices".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn security_report_should_fail_for_unknown_user_or_non_admin() {
+        let auth_service = invite_only_auth_service();
+
+        let report =
+            auth_service.get_user_security_report(admin("admin", security_report_request("unknown"))).await.unwrap().into_inner();
+        assert_eq!(report.status_code, StatusCode::Failure.into());
+        assert_eq!(report.account, None);
+
+        let status = auth_service.get_user_security_report(admin("wrong", security_report_request("unknown"))).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    fn invite_only_auth_service() -> AuthService {
+        let users_service: Arc<dyn Users + Send + Sync> = Arc::new(UsersImpl::with_hash_rounds(1_000));
+        let sessions_service = Arc::new(Mutex::new(SessionsImpl::default()));
+
+        AuthService::new(users_service, sessions_service, HashingPool::new(2, 8))
+            .with_invite_only(true)
+            .with_admin_token(Some("admin".to_owned()))
+    }
+
+    async fn mint(auth_service: &AuthService, max_uses: u32, username: &str) -> String {
+        let response = auth_service
+            .mint_invitation(mint_invitation_request("admin", max_uses, username))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.status_code, StatusCode::Success.into());
+        response.invitation_code
+    }
+
+    #[tokio::test]
+    async fn mint_invitation_should_require_admin_token() {
+        let auth_service = invite_only_auth_service();
+
+        let status = auth_service
+            .mint_invitation(mint_invitation_request("wrong", 1, ""))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let status = auth_service
+            .mint_invitation(tonic::Request::new(MintInvitationRequest::default()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn mint_invitation_should_be_disabled_without_admin_token() {
+        let auth_service = invite_only_auth_service().with_admin_token(None);
+
+        let status = auth_service
+            .mint_invitation(mint_invitation_request("admin", 1, ""))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn sign_up_should_require_invitation_when_invite_only() {
+        let auth_service = invite_only_auth_service();
+
+        let result = auth_service.sign_up(sign_up_request("alice", "")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::InvitationUnknown.into());
+
+        let result = auth_service.sign_up(sign_up_request("alice", "made up")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::InvitationUnknown.into());
+    }
+
+    #[tokio::test]
+    async fn sign_up_should_consume_single_use_invitation() {
+        let auth_service = invite_only_auth_service();
+        let code = mint(&auth_service, 1, "").await;
+
+        let result = auth_service.sign_up(sign_up_request("alice", &code)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success.into());
+
+        let result = auth_service.sign_up(sign_up_request("bob", &code)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::InvitationUsed.into());
+    }
+
+    #[tokio::test]
+    async fn sign_up_should_give_invitation_back_when_sign_up_fails() {
+        let auth_service = invite_only_auth_service();
+        let first_code = mint(&auth_service, 1, "").await;
+        let second_code = mint(&auth_service, 1, "").await;
+
+        auth_service.sign_up(sign_up_request("alice", &first_code)).await.unwrap();
+
+        // Username is taken, so the code must still be usable afterwards.
+        let result = auth_service.sign_up(sign_up_request("alice", &second_code)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Failure.into());
+
+        let result = auth_service.sign_up(sign_up_request("bob", &second_code)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success.into());
+    }
+
+    #[tokio::test]
+    async fn sign_up_should_reject_malformed_input_before_using_invitation() {
+        let auth_service = invite_only_auth_service();
+        let code = mint(&auth_service, 1, "").await;
+
+        let mut request = sign_up_request(&"a".repeat(4 * 1024 * 1024), &code);
+        request.get_mut().password = "pass\u{0}word".to_owned();
+        let status = auth_service.sign_up(request).await.unwrap_err();
+
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(status.message().starts_with("username: must be at most"), "{}", status.message());
+        assert!(status.message().contains("; password: must not contain control characters"));
+
+        let result = auth_service.sign_up(sign_up_request(" alice ", &code)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success.into());
+        let result = auth_service.sign_up(sign_up_request("alice", &code)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::InvitationUsed.into());
+    }
+
+    #[tokio::test]
+    async fn sign_up_should_enforce_username_bound_to_invitation() {
+        let auth_service = invite_only_auth_service();
+        let code = mint(&auth_service, 1, "alice").await;
+
+        let result = auth_service.sign_up(sign_up_request("bob", &code)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::InvitationUsernameMismatch.into());
+
+        let result = auth_service.sign_up(sign_up_request("alice", &code)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success.into());
+    }
+
+    #[tokio::test]
+    async fn sign_up_should_ignore_invitation_when_not_invite_only() {
+        let auth_service = invite_only_auth_service().with_invite_only(false);
+
+        let result = auth_service.sign_up(sign_up_request("alice", "whatever")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success.into());
+    }
+
+    #[tokio::test]
+    async fn sign_up_should_return_dedicated_codes_for_email_problems() {
+        let auth_service = invite_only_auth_service().with_invite_only(false);
+        let with_email = |username: &str, email: &str| {
+            let mut request = sign_up_request(username, "");
+            request.get_mut().email = email.to_owned();
+            request
+        };
+
+        let result = auth_service.sign_up(with_email("alice", "Alice@Example.com")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success.into());
+
+        let result = auth_service.sign_up(with_email("bob", "alice@example.com")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::EmailTaken.into());
+
+        let result = auth_service.sign_up(with_email("bob", "bob")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::EmailInvalid.into());
+
+        let result = auth_service.sign_up(with_email("alice", "")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Failure.into());
+    }
+
+    fn gated_auth_service(gates: &[(&'static str, Option<&'static str>)]) -> (AuthService, Arc<Mutex<Vec<String>>>) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let signup_gates = gates
+            .iter()
+            .map(|&(name, reject_with)| {
+                Box::new(TestGate { name, reject_with, seen: seen.clone() }) as Box<dyn SignupGate + Send + Sync>
+            })
+            .collect();
+        let auth_service = invite_only_auth_service()
+            .with_invite_only(false)
+            .with_signup_gates(signup_gates);
+        (auth_service, seen)
+    }
+
+    #[tokio::test]
+    async fn sign_up_should_run_gates_in_order() {
+        let (auth_service, seen) = gated_auth_service(&[("captcha", None), ("fraud", None)]);
+
+        let result = auth_service.sign_up(sign_up_request("alice", "")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success.into());
+        assert_eq!(*seen.lock().unwrap(), vec!["captcha:alice", "fraud:alice"]);
+    }
+
+    #[tokio::test]
+    async fn sign_up_should_stop_at_first_gate_rejection() {
+        let (auth_service, seen) = gated_auth_service(&[("captcha", Some("captcha_failed")), ("fraud", None)]);
+
+        let status = auth_service.sign_up(sign_up_request("alice", "")).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        assert_eq!(status.message(), "captcha_failed");
+        assert_eq!(*seen.lock().unwrap(), vec!["captcha:alice"]);
+
+        // No user was created.
+        assert_eq!(auth_service.users_service.get_user_uuid("alice".to_owned(), "654321".into()), None);
+    }
+
+    #[tokio::test]
+    async fn sign_up_should_not_use_invitation_when_gate_rejects() {
+        let (auth_service, _) = gated_auth_service(&[("captcha", Some("captcha_failed"))]);
+        let auth_service = auth_service.with_invite_only(true);
+        let code = mint(&auth_service, 1, "").await;
+
+        auth_service.sign_up(sign_up_request("alice", &code)).await.unwrap_err();
+
+        let auth_service = auth_service.with_signup_gates(Vec::new());
+        let result = auth_service.sign_up(sign_up_request("alice", &code)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success.into());
+    }
+
+    // Users implementation whose "hashing" just sleeps, used to saturate the hashing pool.
+    struct SlowUsers;
+
+    impl Users for SlowUsers {
+        fn create_user(&self, _username: String, _password: Password, _email: Option<String>) -> Result<String, UsersError> {
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            Ok("123456".to_owned())
+        }
+
+        fn get_user_uuid(&self, _username: String, _password: Password) -> Option<String> {
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            Some("123456".to_owned())
+        }
+
+        fn get_user(&self, _user_uuid: &str) -> Option<UserView> {
+            None
+        }
+
+        fn create_reserved_user(&self, _username: String, _password: Password, _email: Option<String>) -> Result<String, UsersError> {
+            Ok("123456".to_owned())
+        }
+
+        fn create_federated_user(&self, _username: String, _email: Option<String>) -> Result<String, UsersError> {
+            Ok("123456".to_owned())
+        }
+
+        fn find_user_by_email(&self, _email: &str) -> Option<UserView> {
+            None
+        }
+
+        fn set_email(&self, _user_uuid: String, _email: Option<String>) -> Result<(), UsersError> {
+            Ok(())
+        }
+
+        fn start_email_verification(&self, _user_uuid: String) -> Result<VerificationToken, UsersError> {
+            Err(UsersError::NoEmail)
+        }
+
+        fn confirm_email(&self, _user_uuid: String, _token: &str) -> Result<(), UsersError> {
+            Err(UsersError::VerificationFailed)
+        }
+
+        fn start_password_reset(&self, _username_or_email: String) -> Option<ResetToken> {
+            None
+        }
+
+        fn complete_password_reset(&self, _token: String, _new_password: Password) -> Result<String, UsersError> {
+            Err(UsersError::ResetTokenInvalid)
+        }
+
+        fn change_password(&self, _username: String, _password: Password, _new_password: Password) -> Result<String, UsersError> {
+            Err(UsersError::WrongPassword)
+        }
+
+        fn delete_user(&self, _user_uuid: String) {}
+
+        fn restore_user(&self, _user_uuid: String, _username: String, _password: Password, _email: Option<String>) -> Result<(), UsersError> {
+            Ok(())
+        }
+
+        fn create_guest(&self) -> String {
+            "123456".to_owned()
+        }
+
+        fn upgrade_guest(&self, _user_uuid: String, _username: String, _password: Password) -> Result<(), UsersError> {
+            Err(UsersError::NotAGuest)
+        }
+
+        fn purge_guests(&self, _older_than: Duration, dry_run: bool) -> GuestPurgeReport {
+            GuestPurgeReport { dry_run, ..GuestPurgeReport::default() }
+        }
+
+        fn scan_hash_parameters(&self) -> HashParameterScan {
+            HashParameterScan::default()
+        }
+
+        fn list_users_by_hash_algorithm(&self, _algorithm: &str) -> Vec<UserView> {
+            Vec::new()
+        }
+
+        fn require_password_change_for(&self, _user_uuids: &[String]) -> usize {
+            0
+        }
+
+        fn stats(&self) -> UserStats {
+            UserStats::default()
+        }
+
+        fn check_store_integrity(&self) -> Option<IntegrityReport> {
+            None
+        }
+
+        fn purge_reservations(&self, _budget: &PurgeBudget) -> PurgeRun {
+            PurgeRun::default()
+        }
+
+        fn credential_report(&self, _user_uuid: &str) -> Option<CredentialReport> {
+            None
+        }
+
+        fn verify_credentials_batch(&self, pairs: &[(String, Password)], _threads: &BatchThreads) -> Vec<Result<String, CredentialFailure>> {
+            pairs.iter().map(|_| Err(CredentialFailure::UnknownUser)).collect()
+        }
+    }
+
+    fn sign_in_request() -> Request<SignInRequest> {
+        tonic::Request::new(SignInRequest {
+            username: "123456".to_owned(),
+            password: "654321".to_owned(),
+        })
+    }
+
+    #[tokio::test]
+    async fn sign_in_should_return_resource_exhausted_when_hashing_queue_is_full() {
+        let users_service: Arc<dyn Users + Send + Sync> = Arc::new(SlowUsers);
+        let sessions_service = Arc::new(Mutex::new(SessionsImpl::default()));
+
+        let auth_service = Arc::new(AuthService::new(users_service, sessions_service, HashingPool::new(1, 1)));
+
+        // One request occupies the worker and one waits in the queue.
+        let mut pending = Vec::new();
+        for _ in 0..2 {
+            let auth_service = auth_service.clone();
+            pending.push(tokio::spawn(async move { auth_service.sign_in(sign_in_request()).await }));
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+
+        let start = std::time::Instant::now();
+        let status = auth_service.sign_in(sign_in_request()).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert!(start.elapsed() < std::time::Duration::from_millis(100));
+
+        for request in pending {
+            let result = request.await.unwrap().unwrap().into_inner();
+            assert_eq!(result.status_code, StatusCode::Success.into());
+        }
+
+        // Once the queue has drained requests are accepted again.
+        let result = auth_service.sign_in(sign_in_request()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success.into());
+    }
+
+    #[tokio::test]
+    async fn sign_in_should_record_phases_and_count_requests_over_budget() {
+        let users_service: Arc<dyn Users + Send + Sync> = Arc::new(SlowUsers);
+        let sessions_service = Arc::new(Mutex::new(SessionsImpl::default()));
+
+        let auth_service = Arc::new(
+            AuthService::new(users_service, sessions_service, HashingPool::new(1, 4))
+                .with_sign_in_latency(Arc::new(SignInLatency::new(Some(Duration::from_millis(100))))),
+        );
+
+        // The second request waits for the first to leave the only worker.
+        let first = {
+            let auth_service = auth_service.clone();
+            tokio::spawn(async move { auth_service.sign_in(sign_in_request()).await })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let result = auth_service.sign_in(sign_in_request()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success.into());
+        first.await.unwrap().unwrap();
+
+        let latency = &auth_service.sign_in_latency;
+        assert_eq!(latency.over_budget(), 2);
+        assert!(latency.histogram(SignInPhase::HashVerification).sum() >= Duration::from_millis(400));
+        assert!(latency.histogram(SignInPhase::PoolWait).sum() >= Duration::from_millis(100));
+        assert_eq!(latency.histogram(SignInPhase::SessionCreation).count(), 2);
+    }
+
+    #[tokio::test]
+    async fn sign_in_should_publish_login_only_on_success() {
+        let fixture = UsersFixture::new().with_user("123456", "654321").build();
+        let events = Arc::new(RecordingEvents::default());
+
+        let users_service: Arc<dyn Users + Send + Sync> = Arc::new(fixture.users);
+        let sessions_service = Arc::new(Mutex::new(fixture.sessions));
+        let auth_service = AuthService::new(users_service, sessions_service, HashingPool::new(2, 8))
+            .with_login_notifier(Some(Arc::new(LoginNotifier::new(b"ip key", 10).with_events(events.clone()))));
+
+        let sign_in = |password: &str| {
+            let mut request = tonic::Request::new(SignInRequest {
+                username: "123456".to_owned(),
+                password: password.to_owned(),
+            });
+            request.metadata_mut().insert("user-agent", "grpc-rust/0.9".parse().unwrap());
+            request
+        };
+        auth_service.sign_in(sign_in("wrong password")).await.unwrap();
+        let result = auth_service.sign_in(sign_in("654321")).await.unwrap().into_inner();
+        auth_service.sign_in(sign_in("654321")).await.unwrap();
+
+        let events = events.0.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(matches!(
+            &events[0],
+            Event::LoginOccurred { user_uuid, ip_hash: None, user_agent_family: "gRPC", new_device: true, .. }
+                if *user_uuid == result.user_uuid
+        ));
+        assert!(matches!(&events[1], Event::LoginOccurred { new_device: false, .. }));
     }
 }
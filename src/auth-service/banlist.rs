@@ -0,0 +1,109 @@
+use std::fs;
+
+use crate::skeleton::skeleton;
+
+// Banned no matter what the list file says.
+pub const RESERVED_USERNAMES: [&str; 6] = ["admin", "administrator", "root", "support", "system", "security"];
+
+// Rules hold skeletons, so case, lookalike and invisible characters don't get a name past them.
+#[derive(Clone, Debug, PartialEq)]
+enum Rule {
+    Exact(String),
+    Prefix(String),
+    Contains(String),
+}
+
+// Usernames nobody may sign up with: the reserved ones, brand names and offensive terms.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UsernameBanList {
+    rules: Vec<Rule>,
+}
+
+impl UsernameBanList {
+    pub fn reserved() -> Self {
+        Self {
+            rules: RESERVED_USERNAMES.iter().map(|name| Rule::Exact(skeleton(name))).collect(),
+        }
+    }
+
+    // The reserved names plus one rule per line: `exact <name>`, `prefix <name>` or `contains <term>`. Blank lines
+    // and lines starting with '#' are skipped.
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut list = Self::reserved();
+
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((kind, name)) = line.split_once(char::is_whitespace) else {
+                return Err(format!("Line {}: expected `<exact|prefix|contains> <name>`", number + 1));
+            };
+            let name = skeleton(name.trim());
+            if name.is_empty() {
+                return Err(format!("Line {}: empty name", number + 1));
+            }
+            list.rules.push(match kind {
+                "exact" => Rule::Exact(name),
+                "prefix" => Rule::Prefix(name),
+                "contains" => Rule::Contains(name),
+                other => return Err(format!("Line {}: unknown rule {other:?}", number + 1)),
+            });
+        }
+        Ok(list)
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("Failed to read username ban list {path}: {e}"))?;
+        Self::parse(&contents)
+    }
+
+    pub fn is_banned(&self, username: &str) -> bool {
+        let username = skeleton(username);
+        self.rules.iter().any(|rule| match rule {
+            Rule::Exact(name) => username == *name,
+            Rule::Prefix(name) => username.starts_with(name.as_str()),
+            Rule::Contains(term) => username.contains(term.as_str()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_ban_reserved_names_and_their_lookalikes() {
+        let list = UsernameBanList::reserved();
+
+        for username in ["admin", "ADMIN", "\u{0391}dmin", "ad\u{200B}min", "r00t", "support"] {
+            assert!(list.is_banned(username), "{username:?}");
+        }
+        assert!(!list.is_banned("administrators"));
+        assert!(!list.is_banned("alice"));
+        assert!(!UsernameBanList::default().is_banned("admin"));
+    }
+
+    #[test]
+    fn should_apply_each_rule_type() {
+        let list = UsernameBanList::parse("# Brand names\nexact acme\nprefix staff\n\ncontains badword\n").unwrap();
+
+        assert!(list.is_banned("Acme"));
+        assert!(!list.is_banned("acmefan"));
+        assert!(list.is_banned("staff"));
+        assert!(list.is_banned("StaffAlice"));
+        assert!(!list.is_banned("bigstaff"));
+        assert!(list.is_banned("xxBADWORDxx"));
+        assert!(list.is_banned("b\u{0430}dword")); // Cyrillic 'а'
+        assert!(list.is_banned("root"));
+    }
+
+    #[test]
+    fn should_reject_malformed_lines() {
+        assert!(UsernameBanList::parse("acme").unwrap_err().starts_with("Line 1:"));
+        assert!(UsernameBanList::parse("exact acme\nsuffix acme").unwrap_err().starts_with("Line 2:"));
+        assert!(UsernameBanList::parse("prefix \u{200B}").is_err());
+        assert!(UsernameBanList::load("/nonexistent/banlist").unwrap_err().contains("/nonexistent/banlist"));
+    }
+}
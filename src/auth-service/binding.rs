@@ -0,0 +1,196 @@
+use sha2::{Digest, Sha256};
+use tonic::metadata::MetadataMap;
+
+use crate::metrics::Counter;
+
+// What the service knows about the client making a request.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub device_id: Option<String>, // Sent by clients that keep a stable id, in the x-device-id header.
+}
+
+impl ClientInfo {
+    pub fn from_metadata(metadata: &MetadataMap) -> Self {
+        let header = |name: &str| metadata.get(name).and_then(|value| value.to_str().ok()).map(str::to_owned);
+        Self {
+            user_agent: header("user-agent"),
+            device_id: header("x-device-id"),
+        }
+    }
+
+    // Hash of the chosen parts, length-prefixed so no two different clients hash the same input.
+    pub fn fingerprint(&self, user_agent: bool, device_id: bool) -> Fingerprint {
+        let mut hasher = Sha256::new();
+        for (used, label, value) in [
+            (user_agent, "user-agent", &self.user_agent),
+            (device_id, "device-id", &self.device_id),
+        ] {
+            if used {
+                let value = value.as_deref().unwrap_or("");
+                hasher.update(label.as_bytes());
+                hasher.update((value.len() as u64).to_be_bytes());
+                hasher.update(value.as_bytes());
+            }
+        }
+        hasher.finalize().into()
+    }
+}
+
+// Only the hash is kept with the session, never the user agent or device id themselves.
+pub type Fingerprint = [u8; 32];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BindingMode {
+    Off,
+    LogOnly, // Mismatches are logged and counted, the session still works.
+    Enforce,
+}
+
+// Ties sessions to the client they were created for, so a token presented by an obviously different client can
+// be caught.
+pub struct SessionBinding {
+    mode: BindingMode,
+    user_agent: bool, // Which parts of ClientInfo go into the fingerprint.
+    device_id: bool,
+    revoke_on_mismatch: bool, // Enforce only: sign the session out instead of just refusing the request.
+    mismatches: Counter,
+}
+
+impl Default for SessionBinding {
+    fn default() -> Self {
+        Self {
+            mode: BindingMode::Off,
+            user_agent: true,
+            device_id: true,
+            revoke_on_mismatch: false,
+            mismatches: Counter::default(),
+        }
+    }
+}
+
+impl SessionBinding {
+    pub fn new(mode: BindingMode) -> Self {
+        Self { mode, ..Self::default() }
+    }
+
+    pub fn with_components(mut self, user_agent: bool, device_id: bool) -> Self {
+        self.user_agent = user_agent;
+        self.device_id = device_id;
+        self
+    }
+
+    pub fn with_revoke_on_mismatch(mut self, revoke_on_mismatch: bool) -> Self {
+        self.revoke_on_mismatch = revoke_on_mismatch;
+        self
+    }
+
+    pub fn mode(&self) -> BindingMode {
+        self.mode
+    }
+
+    pub fn revoke_on_mismatch(&self) -> bool {
+        self.revoke_on_mismatch
+    }
+
+    // None when binding is off, so sessions created then are never checked.
+    pub fn fingerprint(&self, client: &ClientInfo) -> Option<Fingerprint> {
+        (self.mode != BindingMode::Off).then(|| client.fingerprint(self.user_agent, self.device_id))
+    }
+
+    pub fn record_mismatch(&self, user_uuid: &str) {
+        self.mismatches.inc();
+        println!("Session binding mismatch for user {} ({:?})", user_uuid, self.mode);
+    }
+
+    #[cfg(test)]
+    pub fn mismatches(&self) -> u64 {
+        self.mismatches.get()
+    }
+}
+
+// Parses the AUTH_SESSION_BINDING* settings: a mode ("off", "log-only" or "enforce") and a comma separated list
+// of components ("user-agent", "device-id").
+pub fn session_binding(mode: &str, components: &str, revoke_on_mismatch: bool) -> Result<SessionBinding, String> {
+    let mode = match mode {
+        "off" => BindingMode::Off,
+        "log-only" => BindingMode::LogOnly,
+        "enforce" => BindingMode::Enforce,
+        other => return Err(format!("Unknown session binding mode {other:?}, expected off, log-only or enforce")),
+    };
+
+    let (mut user_agent, mut device_id) = (false, false);
+    for component in components.split(',').map(str::trim).filter(|component| !component.is_empty()) {
+        match component {
+            "user-agent" => user_agent = true,
+            "device-id" => device_id = true,
+            other => return Err(format!("Unknown session binding component {other:?}")),
+        }
+    }
+    if mode != BindingMode::Off && !user_agent && !device_id {
+        return Err("Session binding needs at least one component".to_owned());
+    }
+
+    Ok(SessionBinding::new(mode)
+        .with_components(user_agent, device_id)
+        .with_revoke_on_mismatch(revoke_on_mismatch))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(user_agent: &str, device_id: Option<&str>) -> ClientInfo {
+        ClientInfo {
+            user_agent: Some(user_agent.to_owned()),
+            device_id: device_id.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn should_only_hash_configured_components() {
+        let binding = SessionBinding::new(BindingMode::Enforce).with_components(false, true);
+
+        assert_eq!(
+            binding.fingerprint(&client("Firefox", Some("phone"))),
+            binding.fingerprint(&client("Chrome", Some("phone")))
+        );
+        assert_ne!(
+            binding.fingerprint(&client("Firefox", Some("phone"))),
+            binding.fingerprint(&client("Firefox", Some("laptop")))
+        );
+        assert_eq!(SessionBinding::new(BindingMode::Off).fingerprint(&client("Firefox", None)), None);
+    }
+
+    #[test]
+    fn should_not_confuse_components() {
+        let binding = SessionBinding::new(BindingMode::Enforce);
+        assert_ne!(
+            binding.fingerprint(&client("ab", Some("c"))),
+            binding.fingerprint(&client("a", Some("bc")))
+        );
+    }
+
+    #[test]
+    fn should_read_client_from_metadata() {
+        let mut metadata = MetadataMap::new();
+        metadata.insert("user-agent", "grpc-rust/0.9".parse().unwrap());
+        metadata.insert("x-device-id", "phone".parse().unwrap());
+
+        assert_eq!(ClientInfo::from_metadata(&metadata), client("grpc-rust/0.9", Some("phone")));
+        assert_eq!(ClientInfo::from_metadata(&MetadataMap::new()), ClientInfo::default());
+    }
+
+    #[test]
+    fn should_parse_settings() {
+        let binding = session_binding("enforce", "user-agent, device-id", true).unwrap();
+        assert_eq!(binding.mode(), BindingMode::Enforce);
+        assert!(binding.revoke_on_mismatch());
+
+        assert_eq!(session_binding("log-only", "user-agent", false).unwrap().mode(), BindingMode::LogOnly);
+        assert!(session_binding("off", "", false).is_ok());
+        assert!(session_binding("strict", "user-agent", false).is_err());
+        assert!(session_binding("enforce", "ip", false).is_err());
+        assert!(session_binding("enforce", "", false).is_err());
+    }
+}
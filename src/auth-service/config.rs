@@ -0,0 +1,330 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::str::FromStr;
+
+// Lowest AUTH_HASH_ROUNDS accepted. Lower values are raised to it, so a typo can't leave new hashes cheap to crack.
+pub const MIN_HASH_ROUNDS: u32 = 10_000;
+
+// Settings for the auth service. Every field can be overridden with an environment variable, which is how the
+// service is configured in Docker, or with a line in the file named by AUTH_CONFIG_FILE (see `ConfigSource`).
+// Only the fields listed in `reload::RELOADABLE` change without a restart.
+pub struct AuthConfig {
+    pub hash_rounds: u32,           // AUTH_HASH_ROUNDS
+    pub hashing_workers: usize,     // AUTH_HASHING_WORKERS
+    pub hashing_queue_depth: usize, // AUTH_HASHING_QUEUE_DEPTH
+    // Key for HMAC-signed session tokens. Sessions are random UUIDs when neither this nor the keyset file is set.
+    pub session_signing_key: Option<String>, // AUTH_SESSION_SIGNING_KEY
+    // File of rotatable signing keys (see `KeySet::parse`), read again on reload. Takes precedence over the single key.
+    pub session_keyset_file: Option<String>, // AUTH_SESSION_KEYSET_FILE
+    // Username ban rules on top of the reserved names, see `UsernameBanList::parse`. Reread on reload.
+    pub username_ban_list_file: Option<String>, // AUTH_USERNAME_BAN_LIST_FILE
+    pub session_ttl_secs: u64,                // AUTH_SESSION_TTL_SECS
+    // Sessions stop working after this long unused, and this long after sign in however used. 0 disables either.
+    pub session_idle_timeout_secs: u64,      // AUTH_SESSION_IDLE_TIMEOUT_SECS
+    pub session_absolute_lifetime_secs: u64, // AUTH_SESSION_ABSOLUTE_LIFETIME_SECS
+    // "off", "log-only" or "enforce": whether a session presented by a different client than the one it was created
+    // for is let through, logged or refused. The client is told apart by the comma separated components.
+    pub session_binding: String,            // AUTH_SESSION_BINDING
+    pub session_binding_components: String, // AUTH_SESSION_BINDING_COMPONENTS
+    pub session_binding_revoke: bool,       // AUTH_SESSION_BINDING_REVOKE, also sign a mismatched session out
+    // Sensitive RPCs answer REAUTHENTICATION_REQUIRED when the session's password was last proven longer ago than
+    // this. 0 never asks again.
+    pub reauth_max_age_secs: u64, // AUTH_REAUTH_MAX_AGE_SECS
+    // Bearer token for admin RPCs. Admin RPCs are disabled when unset.
+    pub admin_token: Option<String>, // AUTH_ADMIN_TOKEN
+    // When set, SignUp requires an invitation code minted through MintInvitation.
+    pub invite_only: bool, // AUTH_INVITE_ONLY
+    // URL the signup context is POSTed to before creating an account (see `HttpCallbackGate`). No check when unset.
+    pub signup_gate_url: Option<String>, // AUTH_SIGNUP_GATE_URL
+    pub signup_gate_timeout_ms: u64,     // AUTH_SIGNUP_GATE_TIMEOUT_MS
+    // Let signups through when the callback can't be reached.
+    pub signup_gate_fail_open: bool, // AUTH_SIGNUP_GATE_FAIL_OPEN
+    pub email_verification_ttl_secs: u64, // AUTH_EMAIL_VERIFICATION_TTL_SECS
+    pub password_reset_ttl_secs: u64,     // AUTH_PASSWORD_RESET_TTL_SECS
+    pub min_password_length: usize,       // AUTH_MIN_PASSWORD_LENGTH
+    // How long the username of a deleted account stays unavailable to others. 0 disables the reservation.
+    pub username_reservation_secs: u64, // AUTH_USERNAME_RESERVATION_SECS
+    // Password verifications slower than this are logged with the hash parameters. 0 disables the warning.
+    pub slow_verification_ms: u64, // AUTH_SLOW_VERIFICATION_MS
+    // SignIns slower than this are logged with a per-phase breakdown. 0 disables the warning.
+    pub sign_in_budget_ms: u64, // AUTH_SIGN_IN_BUDGET_MS
+    // Devices remembered per user to tell new-device logins apart in LoginOccurred events. 0 disables the events.
+    pub login_known_devices: usize, // AUTH_LOGIN_KNOWN_DEVICES
+    // Key for the IP hash in LoginOccurred events. A random one is used when unset, so hashes change on restart.
+    pub login_ip_hash_key: Option<String>, // AUTH_LOGIN_IP_HASH_KEY
+    // Longest maintenance mode lasts before ending by itself, and how long SIGUSR1 turns it on for.
+    pub maintenance_max_secs: u64, // AUTH_MAINTENANCE_MAX_SECS
+    // Start in maintenance mode, e.g. when deploying mid-migration.
+    pub maintenance_on_start: bool, // AUTH_MAINTENANCE_ON_START
+    // How long a successful SignUp's idempotency-key is remembered, and how many keys at most. 0 keys ignores the
+    // metadata.
+    pub sign_up_key_ttl_secs: u64, // AUTH_SIGN_UP_KEY_TTL_SECS
+    pub sign_up_max_keys: usize, // AUTH_SIGN_UP_MAX_KEYS
+    // Start shedding SignIns and SignUps when the p95 of SignIn latency or the hashing queue goes over these. 0
+    // ignores that signal, both 0 never sheds. The shed share takes `shed_ramp_secs` to go from none to the most.
+    pub shed_p95_ms: u64, // AUTH_SHED_P95_MS
+    pub shed_queue_length: usize, // AUTH_SHED_QUEUE_LENGTH
+    pub shed_ramp_secs: u64, // AUTH_SHED_RAMP_SECS
+    // Most threads VerifyCredentials checks passwords on, shared by concurrent calls. They are on top of the hashing
+    // workers, so keep it low enough to leave SignIns their cores.
+    pub batch_verify_parallelism: usize, // AUTH_BATCH_VERIFY_PARALLELISM
+    // How often to log `scan_hash_parameters`. 0 disables the scan.
+    pub hash_scan_interval_secs: u64, // AUTH_HASH_SCAN_INTERVAL_SECS
+    // Guests never upgraded are deleted once this old. 0 keeps them forever.
+    pub guest_max_age_secs: u64, // AUTH_GUEST_MAX_AGE_SECS
+    // How often expired sessions, lapsed username reservations, expired invitation codes and the known devices of
+    // deleted accounts are purged. 0 disables that purge.
+    pub session_purge_interval_secs: u64, // AUTH_SESSION_PURGE_INTERVAL_SECS
+    pub reservation_purge_interval_secs: u64, // AUTH_RESERVATION_PURGE_INTERVAL_SECS
+    pub invitation_purge_interval_secs: u64, // AUTH_INVITATION_PURGE_INTERVAL_SECS
+    pub login_device_purge_interval_secs: u64, // AUTH_LOGIN_DEVICE_PURGE_INTERVAL_SECS
+    // Most one purge run may drop, and the longest it may take. The rest waits for the next run.
+    pub purge_max_items: usize, // AUTH_PURGE_MAX_ITEMS
+    pub purge_max_ms: u64, // AUTH_PURGE_MAX_MS
+    // Account changes kept for WatchUserEvents to replay. Also how far behind a watcher may fall before it's dropped.
+    pub user_event_buffer: usize, // AUTH_USER_EVENT_BUFFER
+    // "v4" for random ids, "v7" for time-ordered ones, for user uuids, unsigned sessions and token nonces alike.
+    pub uuid_version: String, // AUTH_UUID_VERSION
+    // "unified" for one username namespace, "per-kind" to let a local and a federated account share a username.
+    pub username_scope: String, // AUTH_USERNAME_SCOPE
+    // How often store sizes are refreshed and logged. 0 disables it.
+    pub store_stats_interval_secs: u64, // AUTH_STORE_STATS_INTERVAL_SECS
+    // Address gRPC is served on.
+    pub listen_addr: String, // AUTH_LISTEN_ADDR
+    // Address GET /metrics is served on, e.g. "[::0]:9090". Not served when unset.
+    pub metrics_addr: Option<String>, // AUTH_METRICS_ADDR
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            hash_rounds: pbkdf2::Params::RECOMMENDED_ROUNDS as u32,
+            hashing_workers: std::thread::available_parallelism().map(|n| n.get()).unwrap_or(4),
+            hashing_queue_depth: 64,
+            session_signing_key: None,
+            session_keyset_file: None,
+            username_ban_list_file: None,
+            session_ttl_secs: 24 * 60 * 60,
+            session_idle_timeout_secs: 30 * 60,
+            session_absolute_lifetime_secs: 12 * 60 * 60,
+            session_binding: "off".to_owned(),
+            session_binding_components: "user-agent,device-id".to_owned(),
+            session_binding_revoke: false,
+            reauth_max_age_secs: 5 * 60,
+            admin_token: None,
+            invite_only: false,
+            signup_gate_url: None,
+            signup_gate_timeout_ms: 2_000,
+            signup_gate_fail_open: false,
+            email_verification_ttl_secs: 24 * 60 * 60,
+            password_reset_ttl_secs: 30 * 60,
+            min_password_length: 1,
+            username_reservation_secs: 30 * 24 * 60 * 60,
+            slow_verification_ms: 1_000,
+            sign_in_budget_ms: 2_000,
+            login_known_devices: 10,
+            login_ip_hash_key: None,
+            maintenance_max_secs: 60 * 60,
+            maintenance_on_start: false,
+            sign_up_key_ttl_secs: 10 * 60,
+            sign_up_max_keys: 10_000,
+            shed_p95_ms: 0,
+            shed_queue_length: 0,
+            shed_ramp_secs: 10,
+            batch_verify_parallelism: 2,
+            hash_scan_interval_secs: 24 * 60 * 60,
+            guest_max_age_secs: 30 * 24 * 60 * 60,
+            session_purge_interval_secs: 60,
+            reservation_purge_interval_secs: 60 * 60,
+            invitation_purge_interval_secs: 60 * 60,
+            login_device_purge_interval_secs: 60 * 60,
+            purge_max_items: 1_000,
+            purge_max_ms: 50,
+            user_event_buffer: 1024,
+            uuid_version: "v4".to_owned(),
+            username_scope: "unified".to_owned(),
+            store_stats_interval_secs: 60,
+            listen_addr: "[::0]:50051".to_owned(),
+            metrics_addr: None,
+        }
+    }
+}
+
+impl AuthConfig {
+    pub fn from_source(source: &ConfigSource) -> Self {
+        let default = Self::default();
+        Self {
+            hash_rounds: at_least_min_hash_rounds(source.parse_or("AUTH_HASH_ROUNDS", default.hash_rounds)),
+            hashing_workers: source.parse_or("AUTH_HASHING_WORKERS", default.hashing_workers),
+            hashing_queue_depth: source.parse_or("AUTH_HASHING_QUEUE_DEPTH", default.hashing_queue_depth),
+            session_signing_key: source.get("AUTH_SESSION_SIGNING_KEY"),
+            session_keyset_file: source.get("AUTH_SESSION_KEYSET_FILE"),
+            username_ban_list_file: source.get("AUTH_USERNAME_BAN_LIST_FILE"),
+            session_ttl_secs: source.parse_or("AUTH_SESSION_TTL_SECS", default.session_ttl_secs),
+            session_idle_timeout_secs: source.parse_or("AUTH_SESSION_IDLE_TIMEOUT_SECS", default.session_idle_timeout_secs),
+            session_absolute_lifetime_secs: source.parse_or(
+                "AUTH_SESSION_ABSOLUTE_LIFETIME_SECS",
+                default.session_absolute_lifetime_secs,
+            ),
+            session_binding: source.get("AUTH_SESSION_BINDING").unwrap_or(default.session_binding),
+            session_binding_components: source
+                .get("AUTH_SESSION_BINDING_COMPONENTS")
+                .unwrap_or(default.session_binding_components),
+            session_binding_revoke: source.parse_or("AUTH_SESSION_BINDING_REVOKE", default.session_binding_revoke),
+            reauth_max_age_secs: source.parse_or("AUTH_REAUTH_MAX_AGE_SECS", default.reauth_max_age_secs),
+            admin_token: source.get("AUTH_ADMIN_TOKEN"),
+            invite_only: source.parse_or("AUTH_INVITE_ONLY", default.invite_only),
+            signup_gate_url: source.get("AUTH_SIGNUP_GATE_URL"),
+            signup_gate_timeout_ms: source.parse_or("AUTH_SIGNUP_GATE_TIMEOUT_MS", default.signup_gate_timeout_ms),
+            signup_gate_fail_open: source.parse_or("AUTH_SIGNUP_GATE_FAIL_OPEN", default.signup_gate_fail_open),
+            email_verification_ttl_secs: source.parse_or("AUTH_EMAIL_VERIFICATION_TTL_SECS", default.email_verification_ttl_secs),
+            password_reset_ttl_secs: source.parse_or("AUTH_PASSWORD_RESET_TTL_SECS", default.password_reset_ttl_secs),
+            min_password_length: source.parse_or("AUTH_MIN_PASSWORD_LENGTH", default.min_password_length),
+            username_reservation_secs: source.parse_or("AUTH_USERNAME_RESERVATION_SECS", default.username_reservation_secs),
+            slow_verification_ms: source.parse_or("AUTH_SLOW_VERIFICATION_MS", default.slow_verification_ms),
+            sign_in_budget_ms: source.parse_or("AUTH_SIGN_IN_BUDGET_MS", default.sign_in_budget_ms),
+            login_known_devices: source.parse_or("AUTH_LOGIN_KNOWN_DEVICES", default.login_known_devices),
+            login_ip_hash_key: source.get("AUTH_LOGIN_IP_HASH_KEY"),
+            maintenance_max_secs: source.parse_or("AUTH_MAINTENANCE_MAX_SECS", default.maintenance_max_secs),
+            maintenance_on_start: source.parse_or("AUTH_MAINTENANCE_ON_START", default.maintenance_on_start),
+            sign_up_key_ttl_secs: source.parse_or("AUTH_SIGN_UP_KEY_TTL_SECS", default.sign_up_key_ttl_secs),
+            sign_up_max_keys: source.parse_or("AUTH_SIGN_UP_MAX_KEYS", default.sign_up_max_keys),
+            shed_p95_ms: source.parse_or("AUTH_SHED_P95_MS", default.shed_p95_ms),
+            shed_queue_length: source.parse_or("AUTH_SHED_QUEUE_LENGTH", default.shed_queue_length),
+            shed_ramp_secs: source.parse_or("AUTH_SHED_RAMP_SECS", default.shed_ramp_secs),
+            batch_verify_parallelism: source.parse_or("AUTH_BATCH_VERIFY_PARALLELISM", default.batch_verify_parallelism),
+            hash_scan_interval_secs: source.parse_or("AUTH_HASH_SCAN_INTERVAL_SECS", default.hash_scan_interval_secs),
+            guest_max_age_secs: source.parse_or("AUTH_GUEST_MAX_AGE_SECS", default.guest_max_age_secs),
+            session_purge_interval_secs: source.parse_or("AUTH_SESSION_PURGE_INTERVAL_SECS", default.session_purge_interval_secs),
+            reservation_purge_interval_secs: source.parse_or("AUTH_RESERVATION_PURGE_INTERVAL_SECS", default.reservation_purge_interval_secs),
+            invitation_purge_interval_secs: source.parse_or("AUTH_INVITATION_PURGE_INTERVAL_SECS", default.invitation_purge_interval_secs),
+            login_device_purge_interval_secs: source.parse_or("AUTH_LOGIN_DEVICE_PURGE_INTERVAL_SECS", default.login_device_purge_interval_secs),
+            purge_max_items: source.parse_or("AUTH_PURGE_MAX_ITEMS", default.purge_max_items),
+            purge_max_ms: source.parse_or("AUTH_PURGE_MAX_MS", default.purge_max_ms),
+            user_event_buffer: source.parse_or("AUTH_USER_EVENT_BUFFER", default.user_event_buffer),
+            uuid_version: source.get("AUTH_UUID_VERSION").unwrap_or(default.uuid_version),
+            username_scope: source.get("AUTH_USERNAME_SCOPE").unwrap_or(default.username_scope),
+            store_stats_interval_secs: source.parse_or("AUTH_STORE_STATS_INTERVAL_SECS", default.store_stats_interval_secs),
+            listen_addr: source.get("AUTH_LISTEN_ADDR").unwrap_or(default.listen_addr),
+            metrics_addr: source.get("AUTH_METRICS_ADDR"),
+        }
+    }
+}
+
+// Raw settings by variable name: the AUTH_* environment variables, overridden by the lines of the file named by
+// AUTH_CONFIG_FILE when it is set. The file is read again on reload; the environment can't change while running.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConfigSource {
+    values: BTreeMap<String, String>,
+}
+
+impl ConfigSource {
+    pub fn load() -> Result<Self, String> {
+        let mut values: BTreeMap<String, String> = env::vars().filter(|(name, _)| name.starts_with("AUTH_")).collect();
+        if let Some(path) = values.get("AUTH_CONFIG_FILE").cloned() {
+            let contents = fs::read_to_string(&path).map_err(|e| format!("Failed to read config file {path}: {e}"))?;
+            values.extend(Self::parse(&contents)?.values);
+        }
+        Ok(Self { values })
+    }
+
+    // Parses lines of `AUTH_NAME=value`. Blank lines and lines starting with '#' are skipped.
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut values = BTreeMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((name, value)) = line.split_once('=') else {
+                return Err(format!("Line {}: expected `AUTH_NAME=value`", number + 1));
+            };
+            let name = name.trim();
+            if !name.starts_with("AUTH_") || name == "AUTH_CONFIG_FILE" {
+                return Err(format!("Line {}: unexpected setting {name}", number + 1));
+            }
+            values.insert(name.to_owned(), value.trim().to_owned());
+        }
+        Ok(Self { values })
+    }
+
+    // Names whose value differs between the two sources, including ones set in only one of them.
+    pub fn changed(&self, other: &Self) -> Vec<String> {
+        let mut names: Vec<String> = self.values.keys().chain(other.values.keys()).cloned().collect();
+        names.sort();
+        names.dedup();
+        names.retain(|name| self.values.get(name) != other.values.get(name));
+        names
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        self.values.get(name).cloned()
+    }
+
+    // Parses a setting, falling back to `default` if it is unset or invalid.
+    fn parse_or<T: FromStr>(&self, name: &str, default: T) -> T {
+        match self.values.get(name) {
+            Some(value) => value.parse().unwrap_or_else(|_| {
+                println!("Ignoring invalid value {:?} for {}", value, name);
+                default
+            }),
+            None => default,
+        }
+    }
+}
+
+fn at_least_min_hash_rounds(hash_rounds: u32) -> u32 {
+    if hash_rounds < MIN_HASH_ROUNDS {
+        println!("AUTH_HASH_ROUNDS={} is below the minimum, using {}", hash_rounds, MIN_HASH_ROUNDS);
+        return MIN_HASH_ROUNDS;
+    }
+    hash_rounds
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_config_file() {
+        let source = ConfigSource::parse("# Sessions\nAUTH_SESSION_TTL_SECS = 60\n\nAUTH_INVITE_ONLY=true\n").unwrap();
+        let config = AuthConfig::from_source(&source);
+
+        assert_eq!(config.session_ttl_secs, 60);
+        assert!(config.invite_only);
+        assert_eq!(config.hash_rounds, AuthConfig::default().hash_rounds);
+    }
+
+    #[test]
+    fn should_reject_invalid_config_file() {
+        assert!(ConfigSource::parse("AUTH_SESSION_TTL_SECS").is_err());
+        assert!(ConfigSource::parse("HOME=/root").is_err());
+        assert!(ConfigSource::parse("AUTH_CONFIG_FILE=other").is_err());
+    }
+
+    #[test]
+    fn should_fall_back_to_default_for_invalid_value() {
+        let source = ConfigSource::parse("AUTH_SESSION_TTL_SECS=soon").unwrap();
+        assert_eq!(AuthConfig::from_source(&source).session_ttl_secs, AuthConfig::default().session_ttl_secs);
+    }
+
+    #[test]
+    fn should_raise_hash_rounds_to_minimum() {
+        let config = |rounds: &str| AuthConfig::from_source(&ConfigSource::parse(&format!("AUTH_HASH_ROUNDS={rounds}")).unwrap());
+
+        assert_eq!(config("1").hash_rounds, MIN_HASH_ROUNDS);
+        assert_eq!(config("0").hash_rounds, MIN_HASH_ROUNDS);
+        assert_eq!(config("250000").hash_rounds, 250_000);
+    }
+
+    #[test]
+    fn should_list_changed_settings() {
+        let old = ConfigSource::parse("AUTH_SESSION_TTL_SECS=60\nAUTH_INVITE_ONLY=true").unwrap();
+        let new = ConfigSource::parse("AUTH_SESSION_TTL_SECS=120\nAUTH_INVITE_ONLY=true\nAUTH_HASH_ROUNDS=1000").unwrap();
+
+        assert_eq!(old.changed(&new), vec!["AUTH_HASH_ROUNDS", "AUTH_SESSION_TTL_SECS"]);
+        assert!(new.changed(&new).is_empty());
+    }
+}
@@ -0,0 +1,179 @@
+use std::sync::Arc;
+
+use crate::hashing::PasswordScheme;
+use crate::password::Password;
+use crate::store::User;
+
+// Decides whether a password is right for a username on sign in.
+pub trait CredentialVerifier: Send + Sync {
+    // `user` is the local account stored for `username`, if there is one. Errors mean the check couldn't be made,
+    // e.g. the directory is unreachable.
+    fn verify(&self, username: &str, password: &Password, user: Option<&User>) -> Result<bool, String>;
+    // Whether passwords are kept in the local store. When not, accounts are provisioned on their first sign in and
+    // their password can't be set or reset locally.
+    fn is_local(&self) -> bool;
+}
+
+// Checks the password against the hash stored on the local account.
+pub struct LocalVerifier {
+    scheme: Arc<dyn PasswordScheme>,
+}
+
+impl LocalVerifier {
+    pub fn new(scheme: Arc<dyn PasswordScheme>) -> Self {
+        Self { scheme }
+    }
+}
+
+impl CredentialVerifier for LocalVerifier {
+    fn verify(&self, _username: &str, password: &Password, user: Option<&User>) -> Result<bool, String> {
+        Ok(user.is_some_and(|user| self.scheme.verify(password, &user.password)))
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+}
+
+// An external directory that checks credentials by binding as the user, e.g. an LDAP server.
+pub trait Directory: Send + Sync {
+    // Whether a simple bind as `dn` with `password` succeeds.
+    fn bind(&self, dn: &str, password: &str) -> Result<bool, String>;
+}
+
+// Checks credentials by binding to the directory as `<username_attribute>=<username>,<base_dn>`.
+// No directory client ships with the service yet; deployments plug one in through `Directory`.
+#[allow(dead_code)]
+pub struct DirectoryVerifier {
+    directory: Box<dyn Directory>,
+    base_dn: String,            // e.g. "ou=people,dc=example,dc=com"
+    username_attribute: String, // e.g. "uid"
+}
+
+#[allow(dead_code)]
+impl DirectoryVerifier {
+    pub fn new(directory: Box<dyn Directory>, base_dn: &str, username_attribute: &str) -> Self {
+        Self {
+            directory,
+            base_dn: base_dn.to_owned(),
+            username_attribute: username_attribute.to_owned(),
+        }
+    }
+
+    fn bind_dn(&self, username: &str) -> String {
+        format!("{}={},{}", self.username_attribute, escape_dn_value(username), self.base_dn)
+    }
+}
+
+impl CredentialVerifier for DirectoryVerifier {
+    fn verify(&self, username: &str, password: &Password, _user: Option<&User>) -> Result<bool, String> {
+        // A simple bind with an empty password is an unauthenticated bind (RFC 4513), which servers accept.
+        if username.is_empty() || password.expose().is_empty() {
+            return Ok(false);
+        }
+        self.directory.bind(&self.bind_dn(username), password.expose())
+    }
+
+    fn is_local(&self) -> bool {
+        false
+    }
+}
+
+// Escapes an attribute value for use in a DN (RFC 4514), so a username can't add RDNs or change the base.
+fn escape_dn_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            '"' | '+' | ',' | ';' | '<' | '>' | '\\' | '=' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '#' if i == 0 => out.push_str("\\#"),
+            ' ' if i == 0 || i == last => out.push_str("\\ "),
+            '\0' => out.push_str("\\00"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// Directory for tests: accepts binds whose dn and password are in the map.
+#[cfg(test)]
+#[derive(Default)]
+pub struct FakeDirectory {
+    pub entries: std::collections::HashMap<String, String>, // dn -> password
+    pub unavailable: bool,
+    pub binds: std::sync::Mutex<Vec<String>>, // Every dn bound, in order.
+}
+
+#[cfg(test)]
+impl Directory for FakeDirectory {
+    fn bind(&self, dn: &str, password: &str) -> Result<bool, String> {
+        self.binds.lock().unwrap().push(dn.to_owned());
+        if self.unavailable {
+            return Err("connection refused".to_owned());
+        }
+        Ok(self.entries.get(dn).is_some_and(|expected| expected == password))
+    }
+}
+
+// Lets a test keep a handle on the directory it hands to a verifier.
+#[cfg(test)]
+impl<D: Directory> Directory for Arc<D> {
+    fn bind(&self, dn: &str, password: &str) -> Result<bool, String> {
+        (**self).bind(dn, password)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn verifier(entries: &[(&str, &str)]) -> DirectoryVerifier {
+        let directory = FakeDirectory {
+            entries: entries.iter().map(|(dn, password)| (dn.to_string(), password.to_string())).collect::<HashMap<_, _>>(),
+            ..Default::default()
+        };
+        DirectoryVerifier::new(Box::new(directory), "ou=people,dc=example,dc=com", "uid")
+    }
+
+    #[test]
+    fn should_bind_as_user_under_base_dn() {
+        let verifier = verifier(&[("uid=alice,ou=people,dc=example,dc=com", "secret")]);
+
+        assert_eq!(verifier.verify("alice", &Password::from("secret"), None), Ok(true));
+        assert_eq!(verifier.verify("alice", &Password::from("wrong"), None), Ok(false));
+        assert_eq!(verifier.verify("bob", &Password::from("secret"), None), Ok(false));
+    }
+
+    #[test]
+    fn should_never_bind_with_empty_password() {
+        let verifier = verifier(&[("uid=alice,ou=people,dc=example,dc=com", "")]);
+        assert_eq!(verifier.verify("alice", &Password::from(""), None), Ok(false));
+    }
+
+    #[test]
+    fn should_escape_usernames_in_bind_dn() {
+        let verifier = verifier(&[]);
+
+        assert_eq!(
+            verifier.bind_dn("admin,ou=admins"),
+            "uid=admin\\,ou\\=admins,ou=people,dc=example,dc=com"
+        );
+        assert_eq!(verifier.bind_dn("#a b "), "uid=\\#a b\\ ,ou=people,dc=example,dc=com");
+    }
+
+    #[test]
+    fn should_surface_unavailable_directory() {
+        let directory = FakeDirectory {
+            unavailable: true,
+            ..Default::default()
+        };
+        let verifier = DirectoryVerifier::new(Box::new(directory), "dc=example,dc=com", "uid");
+
+        assert!(verifier.verify("alice", &Password::from("secret"), None).is_err());
+    }
+}
@@ -0,0 +1,104 @@
+use unicode_normalization::UnicodeNormalization;
+
+#[derive(Debug, PartialEq)]
+pub struct InvalidEmail;
+
+/// Checks `email` is a plausible address and maps it to the form accounts store and compare.
+///
+/// Surrounding whitespace is trimmed, and the whole address is composed (NFC) and lowercased. RFC 5321 lets the local part be case
+/// sensitive, but in practice no provider treats it that way, and "Foo@Gmail.com" signing up next to
+/// "foo@gmail.com" is far more likely a mistake or an impersonation attempt. Nothing else (dots, "+tags") is
+/// rewritten, since those rules are provider specific.
+///
+/// Only the common `local@domain.tld` shape is accepted: no quoted local parts, comments or IP literals.
+pub fn normalize_email(email: &str) -> Result<String, InvalidEmail> {
+    let email: String = email.trim().nfc().flat_map(char::to_lowercase).collect();
+    if email.len() > 254 {
+        return Err(InvalidEmail);
+    }
+
+    let (local, domain) = email.split_once('@').ok_or(InvalidEmail)?;
+    if local.is_empty() || local.len() > 64 || !local.chars().all(is_local_char) {
+        return Err(InvalidEmail);
+    }
+    if local.starts_with('.') || local.ends_with('.') || local.contains("..") {
+        return Err(InvalidEmail);
+    }
+
+    let labels: Vec<&str> = domain.split('.').collect();
+    if labels.len() < 2 || !labels.iter().all(|label| is_domain_label(label)) {
+        return Err(InvalidEmail);
+    }
+
+    Ok(email)
+}
+
+// RFC 5322 dot-atom characters, plus any non-ASCII character (RFC 6531).
+fn is_local_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~.".contains(c) || !c.is_ascii()
+}
+
+fn is_domain_label(label: &str) -> bool {
+    !label.is_empty()
+        && label.len() <= 63
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+        && label.chars().all(|c| c.is_alphanumeric() || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_lowercase_local_part_and_domain() {
+        assert_eq!(normalize_email("Foo@Gmail.com"), normalize_email("foo@gmail.com"));
+        assert_eq!(normalize_email("Foo@Gmail.com"), Ok("foo@gmail.com".to_owned()));
+    }
+
+    #[test]
+    fn should_trim_whitespace() {
+        assert_eq!(normalize_email("  alice@example.com\n"), Ok("alice@example.com".to_owned()));
+    }
+
+    #[test]
+    fn should_keep_dots_and_tags() {
+        assert_eq!(normalize_email("a.lice+news@example.com"), Ok("a.lice+news@example.com".to_owned()));
+        assert_ne!(normalize_email("a.lice@example.com"), normalize_email("alice@example.com"));
+    }
+
+    #[test]
+    fn should_accept_internationalized_addresses() {
+        assert!(normalize_email("jörg@bücher.example").is_ok());
+    }
+
+    #[test]
+    fn should_reject_malformed_addresses() {
+        for email in [
+            "",
+            "alice",
+            "alice@",
+            "@example.com",
+            "alice@example",
+            "alice@@example.com",
+            "al ice@example.com",
+            "alice@exa mple.com",
+            ".alice@example.com",
+            "al..ice@example.com",
+            "alice@-example.com",
+            "alice@example..com",
+            "\"alice\"@example.com",
+        ] {
+            assert_eq!(normalize_email(email), Err(InvalidEmail), "{email:?}");
+        }
+    }
+
+    #[test]
+    fn should_reject_overlong_addresses() {
+        let local = "a".repeat(65);
+        assert_eq!(normalize_email(&format!("{local}@example.com")), Err(InvalidEmail));
+
+        let domain = format!("{}.com", "a".repeat(250));
+        assert_eq!(normalize_email(&format!("alice@{domain}")), Err(InvalidEmail));
+    }
+}
@@ -0,0 +1,54 @@
+use std::time::SystemTime;
+
+// Things other services may want to react to, e.g. a notification service sending mail.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    // `token` has to reach the user at `email` for them to confirm it.
+    EmailVerificationRequested { user_uuid: String, email: String, token: String },
+    // `token` resets the password and has to reach the account owner, e.g. at `email` if they have one.
+    PasswordResetRequested { user_uuid: String, email: Option<String>, token: String },
+    // A successful sign in, so the user can be told about ones from a `new_device`. `ip_hash` is a keyed hash of
+    // the client's network, never the address itself.
+    LoginOccurred {
+        user_uuid: String,
+        at: SystemTime,
+        ip_hash: Option<String>,
+        user_agent_family: &'static str,
+        new_device: bool,
+    },
+}
+
+pub trait EventSink: Send + Sync {
+    fn publish(&self, event: Event);
+}
+
+// Logs events, leaving out secrets. Used until a real sink is configured.
+pub struct LogEvents;
+
+impl EventSink for LogEvents {
+    fn publish(&self, event: Event) {
+        match event {
+            Event::EmailVerificationRequested { user_uuid, .. } => {
+                println!("Event: email verification requested for {}", user_uuid)
+            }
+            Event::PasswordResetRequested { user_uuid, .. } => {
+                println!("Event: password reset requested for {}", user_uuid)
+            }
+            Event::LoginOccurred { user_uuid, user_agent_family, new_device, .. } => {
+                println!("Event: {} signed in ({}, new device: {})", user_uuid, user_agent_family, new_device)
+            }
+        }
+    }
+}
+
+// Sink for tests that keeps every event published.
+#[cfg(test)]
+#[derive(Default)]
+pub struct RecordingEvents(pub std::sync::Mutex<Vec<Event>>);
+
+#[cfg(test)]
+impl EventSink for RecordingEvents {
+    fn publish(&self, event: Event) {
+        self.0.lock().unwrap().push(event);
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::sessions::{Sessions, SessionsImpl};
+use crate::users::{Users, UsersImpl};
+use crate::uuids::SequentialGenerator;
+
+// Cheap enough to keep tests fast; production uses the recommended count.
+const FIXTURE_HASH_ROUNDS: u32 = 1_000;
+
+// Builds pre-populated stores for tests, e.g.
+//
+//     let fixture = UsersFixture::new().with_user("alice", "pw").with_signed_in_user("bob", "pw").build();
+//     let alice_uuid = &fixture.uuids["alice"];
+//     let bob_session = &fixture.sessions_by_user["bob"];
+pub struct UsersFixture {
+    users: Vec<(String, String, bool)>, // (username, password, signed in)
+}
+
+pub struct Fixture {
+    pub users: UsersImpl,
+    pub sessions: SessionsImpl,
+    pub uuids: HashMap<String, String>,            // username -> user uuid
+    pub sessions_by_user: HashMap<String, String>, // username -> session token, for signed in users
+}
+
+impl UsersFixture {
+    pub fn new() -> Self {
+        Self { users: Vec::new() }
+    }
+
+    pub fn with_user(mut self, username: &str, password: &str) -> Self {
+        self.users.push((username.to_owned(), password.to_owned(), false));
+        self
+    }
+
+    // Same as `with_user`, but also mints a session for the user.
+    pub fn with_signed_in_user(mut self, username: &str, password: &str) -> Self {
+        self.users.push((username.to_owned(), password.to_owned(), true));
+        self
+    }
+
+    pub fn build(self) -> Fixture {
+        self.build_with_sessions(SessionsImpl::default().with_uuid_generator(Arc::new(SequentialGenerator::default())))
+    }
+
+    // Same as `build`, but mints sessions with the given store (e.g. one with a token signer).
+    pub fn build_with_sessions(self, mut sessions: SessionsImpl) -> Fixture {
+        // Deterministic uuids, so failures reproduce with the same ids.
+        let users = UsersImpl::with_hash_rounds(FIXTURE_HASH_ROUNDS).with_uuid_generator(Arc::new(SequentialGenerator::default()));
+        let mut uuids = HashMap::new();
+        let mut sessions_by_user = HashMap::new();
+
+        for (username, password, signed_in) in self.users {
+            let user_uuid = users
+                .create_user(username.clone(), password.into(), None)
+                .unwrap_or_else(|e| panic!("fixture user {username}: {e}"));
+
+            if signed_in {
+                sessions_by_user.insert(username.clone(), sessions.create_session(&user_uuid));
+            }
+            uuids.insert(username, user_uuid);
+        }
+
+        Fixture {
+            users,
+            sessions,
+            uuids,
+            sessions_by_user,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_create_users_and_report_their_uuids() {
+        let fixture = UsersFixture::new()
+            .with_user("alice", "alice password")
+            .with_user("bob", "bob password")
+            .build();
+
+        assert_eq!(fixture.uuids.len(), 2);
+        assert_eq!(
+            fixture.users.get_user_uuid("alice".to_owned(), "alice password".into()),
+            Some(fixture.uuids["alice"].clone())
+        );
+        assert_eq!(
+            fixture.users.get_user_uuid("bob".to_owned(), "bob password".into()),
+            Some(fixture.uuids["bob"].clone())
+        );
+        assert!(fixture.sessions_by_user.is_empty());
+    }
+
+    #[test]
+    fn should_mint_sessions_for_signed_in_users() {
+        let mut fixture = UsersFixture::new()
+            .with_user("alice", "alice password")
+            .with_signed_in_user("bob", "bob password")
+            .build();
+
+        assert_eq!(fixture.sessions_by_user.len(), 1);
+        assert_eq!(
+            fixture.sessions.get_user_uuid_for_session(&fixture.sessions_by_user["bob"]),
+            Some(fixture.uuids["bob"].clone())
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "fixture user alice")]
+    fn should_panic_on_duplicate_username() {
+        UsersFixture::new()
+            .with_user("alice", "pw")
+            .with_user("alice", "pw")
+            .build();
+    }
+}
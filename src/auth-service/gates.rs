@@ -0,0 +1,245 @@
+use std::time::Duration;
+
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Uri};
+
+// What a signup gate gets to look at before an account is created.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SignupContext {
+    pub username: String,
+    pub client_addr: Option<String>, // IP of the caller, when the transport knows it.
+    pub challenge_response: String,  // Opaque to the service, e.g. a CAPTCHA token.
+}
+
+#[derive(Debug, PartialEq)]
+pub struct GateRejection {
+    pub code: String, // Short machine-readable reason, returned to the caller.
+    pub message: String,
+}
+
+impl GateRejection {
+    pub fn new(code: &str, message: impl Into<String>) -> Self {
+        Self {
+            code: code.to_owned(),
+            message: message.into(),
+        }
+    }
+}
+
+// A check run before account creation, e.g. a CAPTCHA or a fraud check. SignUp runs every configured gate in order
+// and stops at the first rejection.
+#[tonic::async_trait]
+pub trait SignupGate {
+    async fn check(&self, ctx: SignupContext) -> Result<(), GateRejection>;
+}
+
+// Lets every signup through.
+#[allow(dead_code)]
+pub struct NoopGate;
+
+#[tonic::async_trait]
+impl SignupGate for NoopGate {
+    async fn check(&self, _ctx: SignupContext) -> Result<(), GateRejection> {
+        Ok(())
+    }
+}
+
+// POSTs the context as JSON to `url`. A 200 lets the signup through, any other status rejects it. When the callback
+// can't be reached (or doesn't answer within `timeout`), the signup goes through only if `fail_open` is set.
+// Only plain HTTP is supported, so the callback should be a sidecar or an internal service.
+pub struct HttpCallbackGate {
+    client: Client<HttpConnector>,
+    url: Uri,
+    timeout: Duration,
+    fail_open: bool,
+}
+
+impl HttpCallbackGate {
+    pub fn new(url: Uri, timeout: Duration, fail_open: bool) -> Self {
+        Self {
+            client: Client::new(),
+            url,
+            timeout,
+            fail_open,
+        }
+    }
+
+    async fn post(&self, ctx: &SignupContext) -> Result<hyper::StatusCode, String> {
+        let body = format!(
+            r#"{{"username":{},"client_addr":{},"challenge_response":{}}}"#,
+            json_string(&ctx.username),
+            ctx.client_addr.as_deref().map_or("null".to_owned(), json_string),
+            json_string(&ctx.challenge_response),
+        );
+        let request = hyper::Request::builder()
+            .method(Method::POST)
+            .uri(self.url.clone())
+            .header("content-type", "application/json")
+            .body(Body::from(body))
+            .map_err(|e| e.to_string())?;
+
+        match tokio::time::timeout(self.timeout, self.client.request(request)).await {
+            Ok(Ok(response)) => Ok(response.status()),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err("timed out".to_owned()),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl SignupGate for HttpCallbackGate {
+    async fn check(&self, ctx: SignupContext) -> Result<(), GateRejection> {
+        match self.post(&ctx).await {
+            Ok(hyper::StatusCode::OK) => Ok(()),
+            Ok(status) => Err(GateRejection::new(
+                "callback_rejected",
+                format!("Signup check answered {}", status.as_u16()),
+            )),
+            Err(e) if self.fail_open => {
+                println!("Signup check unavailable, letting signup through: {}", e);
+                Ok(())
+            }
+            Err(e) => {
+                println!("Signup check unavailable, rejecting signup: {}", e);
+                Err(GateRejection::new("callback_unavailable", "Signup check unavailable"))
+            }
+        }
+    }
+}
+
+// Encodes `s` as a JSON string literal.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Gate for tests: records the usernames it saw under `name` and answers with a fixed code.
+#[cfg(test)]
+pub struct TestGate {
+    pub name: &'static str,
+    pub reject_with: Option<&'static str>,
+    pub seen: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+#[cfg(test)]
+#[tonic::async_trait]
+impl SignupGate for TestGate {
+    async fn check(&self, ctx: SignupContext) -> Result<(), GateRejection> {
+        self.seen.lock().unwrap().push(format!("{}:{}", self.name, ctx.username));
+        match self.reject_with {
+            Some(code) => Err(GateRejection::new(code, format!("Rejected by {}", self.name))),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server};
+
+    use super::*;
+
+    fn context() -> SignupContext {
+        SignupContext {
+            username: "alice".to_owned(),
+            client_addr: Some("127.0.0.1".to_owned()),
+            challenge_response: "captcha token".to_owned(),
+        }
+    }
+
+    // Serves `status` to every request on a local port, recording request bodies.
+    fn serve(status: u16) -> (Uri, Arc<Mutex<Vec<String>>>) {
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+        let recorded = bodies.clone();
+        let make_service = make_service_fn(move |_| {
+            let bodies = bodies.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: hyper::Request<Body>| {
+                    let bodies = bodies.clone();
+                    async move {
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        bodies.lock().unwrap().push(String::from_utf8(body.to_vec()).unwrap());
+                        Ok::<_, Infallible>(Response::builder().status(status).body(Body::empty()).unwrap())
+                    }
+                }))
+            }
+        });
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let uri = format!("http://{}/check", server.local_addr()).parse().unwrap();
+        tokio::spawn(server);
+        (uri, recorded)
+    }
+
+    // Address nothing listens on.
+    fn unreachable_uri() -> Uri {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        format!("http://{}/check", addr).parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn noop_gate_should_accept() {
+        assert_eq!(NoopGate.check(context()).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn http_gate_should_post_context_and_accept_on_200() {
+        let (uri, bodies) = serve(200);
+        let gate = HttpCallbackGate::new(uri, Duration::from_secs(5), false);
+
+        assert_eq!(gate.check(context()).await, Ok(()));
+
+        assert_eq!(
+            bodies.lock().unwrap()[0],
+            r#"{"username":"alice","client_addr":"127.0.0.1","challenge_response":"captcha token"}"#
+        );
+    }
+
+    #[test]
+    fn should_escape_json_strings() {
+        assert_eq!(json_string("al\"ice\\\n\u{1}"), r#""al\"ice\\\n\u0001""#);
+    }
+
+    #[tokio::test]
+    async fn http_gate_should_reject_on_other_status_even_when_fail_open() {
+        let (uri, _) = serve(403);
+        let gate = HttpCallbackGate::new(uri, Duration::from_secs(5), true);
+
+        let rejection = gate.check(context()).await.unwrap_err();
+        assert_eq!(rejection.code, "callback_rejected");
+    }
+
+    #[tokio::test]
+    async fn http_gate_should_accept_unreachable_callback_when_fail_open() {
+        let gate = HttpCallbackGate::new(unreachable_uri(), Duration::from_secs(5), true);
+        assert_eq!(gate.check(context()).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn http_gate_should_reject_unreachable_callback_when_fail_closed() {
+        let gate = HttpCallbackGate::new(unreachable_uri(), Duration::from_secs(5), false);
+
+        let rejection = gate.check(context()).await.unwrap_err();
+        assert_eq!(rejection.code, "callback_unavailable");
+    }
+}
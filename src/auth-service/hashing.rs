@@ -0,0 +1,126 @@
+use pbkdf2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Params, Pbkdf2,
+};
+use rand_core::OsRng;
+
+use crate::password::Password;
+
+// How password hashes are made and checked. Hashes are PHC strings ("$pbkdf2-sha256$i=...$salt$hash"), so they
+// carry their own algorithm and parameters and stay verifiable after the scheme's settings change.
+pub trait PasswordScheme: Send + Sync {
+    fn hash(&self, password: &Password) -> Result<String, String>;
+    fn verify(&self, password: &Password, hash: &str) -> bool;
+}
+
+// Stored hashes asking for more rounds are rejected without being computed, so a corrupted or planted hash (say
+// "i=4294967295") can't tie up a hashing worker indefinitely.
+const MAX_VERIFY_ROUNDS: u32 = 10 * Params::RECOMMENDED_ROUNDS as u32;
+
+pub struct Pbkdf2Scheme {
+    rounds: u32, // For new hashes. Existing hashes are verified with the rounds they were created with.
+}
+
+impl Pbkdf2Scheme {
+    pub fn new(rounds: u32) -> Self {
+        Self { rounds }
+    }
+}
+
+impl PasswordScheme for Pbkdf2Scheme {
+    fn hash(&self, password: &Password) -> Result<String, String> {
+        let salt = SaltString::generate(&mut OsRng);
+
+        let params = Params {
+            rounds: self.rounds,
+            ..Params::default()
+        };
+        Pbkdf2
+            .hash_password_customized(password.expose().as_bytes(), None, None, params, &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    fn verify(&self, password: &Password, hash: &str) -> bool {
+        match PasswordHash::new(hash) {
+            Ok(parsed_hash) if parsed_hash.params.get_decimal("i").is_some_and(|rounds| rounds > MAX_VERIFY_ROUNDS) => false,
+            Ok(parsed_hash) => Pbkdf2.verify_password(password.expose().as_bytes(), &parsed_hash).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+// Algorithm and parameters of a stored hash, without the salt and hash output.
+#[derive(Debug, PartialEq)]
+pub struct HashInfo {
+    pub algorithm: String, // PHC identifier, e.g. "pbkdf2-sha256".
+    pub params: String,    // PHC parameter string, e.g. "i=600000,l=32".
+    pub rounds: Option<u32>,
+}
+
+pub fn describe_hash(hash: &str) -> Option<HashInfo> {
+    let parsed_hash = PasswordHash::new(hash).ok()?;
+    Some(HashInfo {
+        algorithm: parsed_hash.algorithm.to_string(),
+        params: parsed_hash.params.to_string(),
+        rounds: parsed_hash.params.get_decimal("i"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_verify_own_hashes() {
+        let scheme = Pbkdf2Scheme::new(1_000);
+        let hash = scheme.hash(&Password::from("password")).unwrap();
+
+        assert!(scheme.verify(&Password::from("password"), &hash));
+        assert!(!scheme.verify(&Password::from("wrong"), &hash));
+        assert!(!scheme.verify(&Password::from("password"), "not a hash"));
+    }
+
+    #[test]
+    fn should_verify_hashes_made_with_other_rounds() {
+        let hash = Pbkdf2Scheme::new(1_000).hash(&Password::from("password")).unwrap();
+        assert!(Pbkdf2Scheme::new(2_000).verify(&Password::from("password"), &hash));
+    }
+
+    #[test]
+    fn should_describe_hash_parameters() {
+        let hash = Pbkdf2Scheme::new(1_000).hash(&Password::from("password")).unwrap();
+
+        assert_eq!(
+            describe_hash(&hash),
+            Some(HashInfo {
+                algorithm: "pbkdf2-sha256".to_owned(),
+                params: "i=1000,l=32".to_owned(),
+                rounds: Some(1_000),
+            })
+        );
+        assert_eq!(describe_hash("not a hash"), None);
+    }
+
+    // Stored hashes the phc_hash fuzz target exercises.
+    #[test]
+    fn should_refuse_to_verify_hash_with_excessive_rounds() {
+        let hash = Pbkdf2Scheme::new(1_000).hash(&Password::from("password")).unwrap();
+        let planted = hash.replacen("i=1000", "i=4294967295", 1);
+
+        let start = std::time::Instant::now();
+        assert!(!Pbkdf2Scheme::new(1_000).verify(&Password::from("password"), &planted));
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    }
+
+    #[test]
+    fn should_not_verify_hashes_missing_salt_or_output() {
+        let hash = Pbkdf2Scheme::new(1_000).hash(&Password::from("password")).unwrap();
+        let (without_output, _) = hash.rsplit_once('$').unwrap();
+        let parts: Vec<&str> = hash.split('$').collect();
+
+        assert!(!Pbkdf2Scheme::new(1_000).verify(&Password::from("password"), without_output));
+        assert!(!Pbkdf2Scheme::new(1_000).verify(&Password::from("password"), &format!("$pbkdf2-sha256${}$${}", parts[2], parts[4])));
+        assert!(!Pbkdf2Scheme::new(1_000).verify(&Password::from("password"), &format!("{hash}$")));
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use tokio::sync::watch;
+
+use crate::clock::{Clock, SystemClock};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Keyed hash of a request's payload, so the same key with a different payload can be told apart without keeping
+// the payload (and its password) around.
+pub type PayloadFingerprint = [u8; 32];
+
+enum Entry<R> {
+    // The first request with the key is still running. Waiters are woken when `done`'s sender is dropped.
+    InFlight { fingerprint: PayloadFingerprint, claim: u64, done: watch::Receiver<()> },
+    Succeeded { fingerprint: PayloadFingerprint, reply: R, expires_at: SystemTime },
+}
+
+pub enum Claim<R> {
+    New(PendingKey<R>), // Run the request, then report success through the key.
+    Untracked,          // Every slot is taken by requests still running, so run the request without protection.
+    Replay(R),          // A request with the key and payload succeeded, answer with its reply again.
+    Conflict,           // The key was used for a different payload.
+}
+
+// Remembers client-supplied idempotency keys of successful requests, and their replies, for `ttl`, so a retry of
+// a request that went through gets the original answer instead of a confusing error. Only successes are
+// remembered: after a failure the key is free again and a retry runs for real.
+pub struct IdempotencyKeys<R> {
+    entries: Arc<Mutex<HashMap<String, Entry<R>>>>,
+    ttl: Duration,
+    max_keys: usize, // Past this, the remembered success closest to expiry is forgotten.
+    fingerprint_key: [u8; 32],
+    claims: AtomicU64,
+    clock: Arc<dyn Clock>,
+}
+
+impl<R: Clone> IdempotencyKeys<R> {
+    pub fn new(ttl: Duration, max_keys: usize) -> Self {
+        let mut fingerprint_key = [0; 32];
+        OsRng.fill_bytes(&mut fingerprint_key);
+        Self {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            ttl,
+            max_keys: max_keys.max(1),
+            fingerprint_key,
+            claims: AtomicU64::new(0),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // Length-prefixed, so no two different payloads hash the same input.
+    pub fn fingerprint(&self, parts: &[&str]) -> PayloadFingerprint {
+        let mut mac = HmacSha256::new_from_slice(&self.fingerprint_key).expect("HMAC accepts keys of any length");
+        for part in parts {
+            mac.update(&(part.len() as u64).to_be_bytes());
+            mac.update(part.as_bytes());
+        }
+        mac.finalize().into_bytes().into()
+    }
+
+    // Waits while another request with the same key and payload is running, so concurrent duplicates run once.
+    pub async fn claim(&self, key: &str, fingerprint: PayloadFingerprint) -> Claim<R> {
+        loop {
+            let mut done = {
+                let mut entries = self.entries.lock().unwrap();
+                let now = self.clock.now();
+                match entries.get(key) {
+                    Some(Entry::Succeeded { fingerprint: seen, reply, expires_at }) if *expires_at > now => {
+                        return if *seen == fingerprint { Claim::Replay(reply.clone()) } else { Claim::Conflict };
+                    }
+                    Some(Entry::InFlight { fingerprint: seen, done, .. }) => {
+                        if *seen != fingerprint {
+                            return Claim::Conflict;
+                        }
+                        done.clone()
+                    }
+                    _ => {
+                        if entries.len() >= self.max_keys && !self.make_room(&mut entries, now) {
+                            return Claim::Untracked;
+                        }
+                        let claim = self.claims.fetch_add(1, Ordering::Relaxed);
+                        let (sender, done) = watch::channel(());
+                        entries.insert(key.to_owned(), Entry::InFlight { fingerprint, claim, done });
+                        return Claim::New(PendingKey {
+                            entries: self.entries.clone(),
+                            key: key.to_owned(),
+                            fingerprint,
+                            claim,
+                            expires_at: now + self.ttl,
+                            _done: sender,
+                        });
+                    }
+                }
+            };
+            // Errs once the running request is done with the key, whichever way it went.
+            let _ = done.changed().await;
+        }
+    }
+
+    // Drops expired successes, then the one closest to expiry. False when all that's left is still running.
+    fn make_room(&self, entries: &mut HashMap<String, Entry<R>>, now: SystemTime) -> bool {
+        entries.retain(|_, entry| !matches!(entry, Entry::Succeeded { expires_at, .. } if *expires_at <= now));
+        if entries.len() < self.max_keys {
+            return true;
+        }
+        let oldest = entries
+            .iter()
+            .filter_map(|(key, entry)| match entry {
+                Entry::Succeeded { expires_at, .. } => Some((*expires_at, key)),
+                Entry::InFlight { .. } => None,
+            })
+            .min()
+            .map(|(_, key)| key.clone());
+        match oldest {
+            Some(key) => {
+                entries.remove(&key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+}
+
+// A key claimed by a running request. Dropping it without `succeeded` frees the key, e.g. when the request fails
+// or is cancelled, and wakes any duplicates waiting on it.
+pub struct PendingKey<R> {
+    entries: Arc<Mutex<HashMap<String, Entry<R>>>>,
+    key: String,
+    fingerprint: PayloadFingerprint,
+    claim: u64,
+    expires_at: SystemTime,
+    _done: watch::Sender<()>,
+}
+
+impl<R> PendingKey<R> {
+    pub fn succeeded(self, reply: R) {
+        let entry = Entry::Succeeded { fingerprint: self.fingerprint, reply, expires_at: self.expires_at };
+        self.entries.lock().unwrap().insert(self.key.clone(), entry);
+    }
+}
+
+impl<R> Drop for PendingKey<R> {
+    fn drop(&mut self) {
+        let mut entries = self.entries.lock().unwrap();
+        if matches!(entries.get(&self.key), Some(Entry::InFlight { claim, .. }) if *claim == self.claim) {
+            entries.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn new_keys(max_keys: usize) -> (IdempotencyKeys<i32>, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new());
+        (IdempotencyKeys::new(10 * MINUTE, max_keys).with_clock(clock.clone()), clock)
+    }
+
+    async fn succeed(keys: &IdempotencyKeys<i32>, key: &str, fingerprint: PayloadFingerprint) {
+        let Claim::New(pending) = keys.claim(key, fingerprint).await else {
+            panic!("expected {key} to be new");
+        };
+        pending.succeeded(1);
+    }
+
+    #[tokio::test]
+    async fn should_replay_success_for_same_payload_only() {
+        let (keys, _) = new_keys(10);
+        let (alice, bob) = (keys.fingerprint(&["alice", "secret"]), keys.fingerprint(&["bob", "secret"]));
+        succeed(&keys, "key", alice).await;
+
+        assert!(matches!(keys.claim("key", alice).await, Claim::Replay(1)));
+        assert!(matches!(keys.claim("key", bob).await, Claim::Conflict));
+        assert!(matches!(keys.claim("other key", bob).await, Claim::New(_)));
+    }
+
+    #[tokio::test]
+    async fn should_free_key_when_request_fails() {
+        let (keys, _) = new_keys(10);
+        let fingerprint = keys.fingerprint(&["alice"]);
+
+        let Claim::New(pending) = keys.claim("key", fingerprint).await else { panic!("expected a new key") };
+        drop(pending);
+
+        assert!(matches!(keys.claim("key", fingerprint).await, Claim::New(_)));
+    }
+
+    #[tokio::test]
+    async fn should_forget_key_after_ttl() {
+        let (keys, clock) = new_keys(10);
+        let fingerprint = keys.fingerprint(&["alice"]);
+        succeed(&keys, "key", fingerprint).await;
+
+        clock.advance(10 * MINUTE - Duration::from_secs(1));
+        assert!(matches!(keys.claim("key", fingerprint).await, Claim::Replay(_)));
+        clock.advance(Duration::from_secs(1));
+        assert!(matches!(keys.claim("key", keys.fingerprint(&["bob"])).await, Claim::New(_)));
+    }
+
+    #[tokio::test]
+    async fn should_stay_bounded_by_forgetting_oldest_success() {
+        let (keys, clock) = new_keys(2);
+        let fingerprint = keys.fingerprint(&["alice"]);
+        for key in ["first", "second", "third"] {
+            succeed(&keys, key, fingerprint).await;
+            clock.advance(MINUTE);
+        }
+
+        assert_eq!(keys.len(), 2);
+        assert!(matches!(keys.claim("second", fingerprint).await, Claim::Replay(_)));
+        assert!(matches!(keys.claim("third", fingerprint).await, Claim::Replay(_)));
+
+        // Nothing to forget while every slot is a request still running.
+        let (keys, _) = new_keys(1);
+        let _running = keys.claim("running", fingerprint).await;
+        assert!(matches!(keys.claim("other", fingerprint).await, Claim::Untracked));
+    }
+
+    #[tokio::test]
+    async fn should_make_concurrent_duplicates_wait_for_first() {
+        let (keys, _) = new_keys(10);
+        let keys = Arc::new(keys);
+        let fingerprint = keys.fingerprint(&["alice"]);
+        let Claim::New(pending) = keys.claim("key", fingerprint).await else { panic!("expected a new key") };
+
+        let duplicate = {
+            let keys = keys.clone();
+            tokio::spawn(async move { matches!(keys.claim("key", fingerprint).await, Claim::Replay(1)) })
+        };
+        tokio::task::yield_now().await;
+        assert!(!duplicate.is_finished());
+        assert!(matches!(keys.claim("key", keys.fingerprint(&["bob"])).await, Claim::Conflict));
+
+        pending.succeeded(1);
+        assert!(duplicate.await.unwrap());
+    }
+}
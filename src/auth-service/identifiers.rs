@@ -0,0 +1,91 @@
+use unicode_normalization::UnicodeNormalization;
+
+use crate::email::normalize_email;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IdentifierKind {
+    Username,
+    Email,
+}
+
+/// Maps an identifier as typed to the form lookups compare, so "Alice " at sign in finds the account created as
+/// "Alice". None when nothing could match, e.g. an empty username or a malformed email.
+///
+/// Usernames are trimmed like at sign up, composed (NFC) so "e" plus a combining accent equals "é", and
+/// lowercased. Accounts keep the username as typed at sign up, so this is only a comparison key. Emails go through
+/// `normalize_email`, which is also what they are stored as.
+pub fn normalize_identifier(kind: IdentifierKind, raw: &str) -> Option<String> {
+    match kind {
+        IdentifierKind::Username => {
+            let username: String = raw.trim().nfc().flat_map(char::to_lowercase).collect();
+            Some(username).filter(|username| !username.is_empty())
+        }
+        IdentifierKind::Email => normalize_email(raw).ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn username(raw: &str) -> Option<String> {
+        normalize_identifier(IdentifierKind::Username, raw)
+    }
+
+    fn email(raw: &str) -> Option<String> {
+        normalize_identifier(IdentifierKind::Email, raw)
+    }
+
+    #[test]
+    fn should_trim_usernames() {
+        assert_eq!(username(" alice"), username("alice"));
+        assert_eq!(username("alice \t\n"), username("alice"));
+        assert_eq!(username("alice smith"), Some("alice smith".to_owned())); // Inner spaces are part of the name.
+    }
+
+    #[test]
+    fn should_ignore_username_case() {
+        assert_eq!(username("Alice"), Some("alice".to_owned()));
+        assert_eq!(username("ALICE"), username("alice"));
+        assert_eq!(username("ÉLODIE"), username("élodie"));
+        assert_eq!(username("ΣΟΦΙΑ"), username("σοφια"));
+    }
+
+    #[test]
+    fn should_compose_usernames() {
+        assert_eq!(username("e\u{0301}lodie"), username("\u{00E9}lodie"));
+        assert_eq!(username("E\u{0301}lodie"), username("\u{00E9}lodie"));
+    }
+
+    #[test]
+    fn should_keep_lookalike_usernames_apart() {
+        // Lookalikes can't be created next to each other, but typing one is no way to name the other account.
+        assert_ne!(username("\u{0430}lice"), username("alice")); // Cyrillic 'а'
+        assert_ne!(username("a\u{200B}lice"), username("alice"));
+    }
+
+    #[test]
+    fn should_not_name_any_username_with_blank_input() {
+        assert_eq!(username(""), None);
+        assert_eq!(username("   "), None);
+    }
+
+    #[test]
+    fn should_normalize_emails_like_at_sign_up() {
+        assert_eq!(email(" Alice@Example.COM "), Some("alice@example.com".to_owned()));
+        assert_eq!(email("alice+tag@example.com"), Some("alice+tag@example.com".to_owned()));
+        assert_eq!(email("Alice@Example.com"), normalize_email("alice@example.com").ok());
+    }
+
+    #[test]
+    fn should_compose_emails() {
+        assert_eq!(email("jose\u{0301}@example.com"), email("jos\u{00E9}@example.com"));
+    }
+
+    #[test]
+    fn should_not_name_any_email_with_malformed_input() {
+        assert_eq!(email(""), None);
+        assert_eq!(email("alice"), None);
+        assert_eq!(email("alice@localhost"), None);
+    }
+}
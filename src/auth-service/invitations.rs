@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+
+use crate::purger::{PurgeBudget, PurgeRun};
+
+#[derive(Debug, PartialEq)]
+pub enum InvitationError {
+    Unknown,
+    Used, // Every use of the code has been consumed.
+    Expired,
+    UsernameMismatch, // The code is bound to a different username.
+}
+
+pub trait Invitations {
+    // Mints a code usable `max_uses` times, optionally expiring after `ttl` and only usable for `username`. The
+    // username is bound trimmed, as sign up trims the one it's compared with.
+    fn mint(&self, max_uses: u32, ttl: Option<Duration>, username: Option<String>) -> String;
+    // Atomically takes one use of the code for signing up `username`.
+    fn consume(&self, code: &str, username: &str) -> Result<(), InvitationError>;
+    // Gives back a use taken by `consume`, for when the signup it was taken for fails.
+    fn release(&self, code: &str);
+    // Drops expired codes, as many as `budget` allows. A dropped code is then reported as unknown, not expired.
+    fn purge_lapsed(&self, budget: &PurgeBudget) -> PurgeRun;
+}
+
+struct Invitation {
+    uses_left: u32,
+    expires_at: Option<SystemTime>,
+    username: Option<String>,
+}
+
+// Codes are only stored as SHA-256 hashes. They are 128 random bits, so a fast hash is enough.
+#[derive(Default)]
+pub struct InvitationsImpl {
+    hash_to_invitation: Mutex<HashMap<String, Invitation>>,
+}
+
+impl InvitationsImpl {
+    fn mint_at(&self, max_uses: u32, ttl: Option<Duration>, username: Option<String>, now: SystemTime) -> String {
+        let mut bytes = [0u8; 16];
+        OsRng.fill_bytes(&mut bytes);
+        let code = URL_SAFE_NO_PAD.encode(bytes);
+
+        let invitation = Invitation {
+            uses_left: max_uses,
+            expires_at: ttl.map(|ttl| now + ttl),
+            username: username.map(|username| username.trim().to_owned()),
+        };
+        self.hash_to_invitation
+            .lock()
+            .unwrap()
+            .insert(hash_code(&code), invitation);
+
+        code
+    }
+
+    fn consume_at(&self, code: &str, username: &str, now: SystemTime) -> Result<(), InvitationError> {
+        let mut hash_to_invitation = self.hash_to_invitation.lock().unwrap();
+        let invitation = hash_to_invitation
+            .get_mut(&hash_code(code))
+            .ok_or(InvitationError::Unknown)?;
+
+        if invitation.expires_at.is_some_and(|expires_at| now >= expires_at) {
+            return Err(InvitationError::Expired);
+        }
+        if invitation.uses_left == 0 {
+            return Err(InvitationError::Used);
+        }
+        if invitation.username.as_ref().is_some_and(|bound| bound != username) {
+            return Err(InvitationError::UsernameMismatch);
+        }
+
+        invitation.uses_left -= 1;
+        Ok(())
+    }
+
+    fn purge_lapsed_at(&self, budget: &PurgeBudget, now: SystemTime) -> PurgeRun {
+        let mut hash_to_invitation = self.hash_to_invitation.lock().unwrap();
+        let mut run = PurgeRun::default();
+        // Stops scanning once the budget is spent, so a large backlog doesn't hold the lock for a full pass.
+        let mut lapsed = Vec::new();
+        for (hash, invitation) in hash_to_invitation.iter() {
+            if invitation.expires_at.is_none_or(|expires_at| now < expires_at) {
+                continue;
+            }
+            if budget.spent(lapsed.len()) {
+                run.truncated = true;
+                break;
+            }
+            lapsed.push(hash.clone());
+        }
+
+        for hash in lapsed {
+            hash_to_invitation.remove(&hash);
+            run.purged += 1;
+        }
+        run
+    }
+}
+
+impl Invitations for InvitationsImpl {
+    fn mint(&self, max_uses: u32, ttl: Option<Duration>, username: Option<String>) -> String {
+        self.mint_at(max_uses, ttl, username, SystemTime::now())
+    }
+
+    fn consume(&self, code: &str, username: &str) -> Result<(), InvitationError> {
+        self.consume_at(code, username, SystemTime::now())
+    }
+
+    fn release(&self, code: &str) {
+        if let Some(invitation) = self.hash_to_invitation.lock().unwrap().get_mut(&hash_code(code)) {
+            invitation.uses_left += 1;
+        }
+    }
+
+    fn purge_lapsed(&self, budget: &PurgeBudget) -> PurgeRun {
+        self.purge_lapsed_at(budget, SystemTime::now())
+    }
+}
+
+fn hash_code(code: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::clock::SystemClock;
+
+    #[test]
+    fn should_store_only_hashed_codes() {
+        let invitations = InvitationsImpl::default();
+        let code = invitations.mint(1, None, None);
+
+        let hash_to_invitation = invitations.hash_to_invitation.lock().unwrap();
+        assert!(!hash_to_invitation.contains_key(&code));
+        assert!(hash_to_invitation.contains_key(&hash_code(&code)));
+    }
+
+    #[test]
+    fn should_consume_code_up_to_max_uses() {
+        let invitations = InvitationsImpl::default();
+        let code = invitations.mint(2, None, None);
+
+        assert_eq!(invitations.consume(&code, "alice"), Ok(()));
+        assert_eq!(invitations.consume(&code, "bob"), Ok(()));
+        assert_eq!(invitations.consume(&code, "carol"), Err(InvitationError::Used));
+    }
+
+    #[test]
+    fn should_reject_unknown_code() {
+        let invitations = InvitationsImpl::default();
+        invitations.mint(1, None, None);
+        assert_eq!(invitations.consume("not a code", "alice"), Err(InvitationError::Unknown));
+    }
+
+    #[test]
+    fn should_reject_expired_code() {
+        let invitations = InvitationsImpl::default();
+        let now = SystemTime::now();
+        let code = invitations.mint_at(1, Some(Duration::from_secs(60)), None, now);
+
+        assert_eq!(
+            invitations.consume_at(&code, "alice", now + Duration::from_secs(60)),
+            Err(InvitationError::Expired)
+        );
+        assert_eq!(invitations.consume_at(&code, "alice", now + Duration::from_secs(59)), Ok(()));
+    }
+
+    #[test]
+    fn should_purge_only_expired_codes() {
+        let invitations = InvitationsImpl::default();
+        let now = SystemTime::now();
+        let expired = invitations.mint_at(1, Some(Duration::from_secs(60)), None, now);
+        let lasting = invitations.mint_at(1, Some(Duration::from_secs(120)), None, now);
+        let forever = invitations.mint_at(1, None, None, now);
+
+        let later = now + Duration::from_secs(60);
+        assert_eq!(invitations.purge_lapsed_at(&PurgeBudget::unlimited(), later), PurgeRun { purged: 1, truncated: false });
+        assert_eq!(invitations.consume_at(&expired, "alice", later), Err(InvitationError::Unknown));
+        assert_eq!(invitations.consume_at(&lasting, "alice", later), Ok(()));
+        assert_eq!(invitations.consume_at(&forever, "alice", later), Ok(()));
+    }
+
+    #[test]
+    fn should_purge_expired_codes_within_budget() {
+        let invitations = InvitationsImpl::default();
+        let now = SystemTime::now();
+        for _ in 0..5 {
+            invitations.mint_at(1, Some(Duration::from_secs(60)), None, now);
+        }
+        invitations.mint_at(1, None, None, now);
+
+        let later = now + Duration::from_secs(60);
+        let budget = || PurgeBudget::new(2, Duration::from_secs(60), Arc::new(SystemClock));
+        let runs: Vec<PurgeRun> = (0..4).map(|_| invitations.purge_lapsed_at(&budget(), later)).collect();
+
+        let run = |purged, truncated| PurgeRun { purged, truncated };
+        assert_eq!(runs, vec![run(2, true), run(2, true), run(1, false), run(0, false)]);
+        assert_eq!(invitations.hash_to_invitation.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn should_only_accept_bound_username() {
+        let invitations = InvitationsImpl::default();
+        let code = invitations.mint(1, None, Some("alice".to_owned()));
+
+        assert_eq!(invitations.consume(&code, "bob"), Err(InvitationError::UsernameMismatch));
+        assert_eq!(invitations.consume(&code, "alice"), Ok(()));
+    }
+
+    #[test]
+    fn should_bind_username_as_sign_up_compares_it() {
+        let invitations = InvitationsImpl::default();
+        let code = invitations.mint(1, None, Some(" alice\t".to_owned()));
+
+        assert_eq!(invitations.consume(&code, "alice"), Ok(()));
+    }
+
+    #[test]
+    fn should_give_use_back_on_release() {
+        let invitations = InvitationsImpl::default();
+        let code = invitations.mint(1, None, None);
+
+        invitations.consume(&code, "alice").unwrap();
+        invitations.release(&code);
+        assert_eq!(invitations.consume(&code, "bob"), Ok(()));
+    }
+
+    #[test]
+    fn should_allow_single_use_code_only_once_under_concurrency() {
+        let invitations = Arc::new(InvitationsImpl::default());
+        let code = invitations.mint(1, None, None);
+
+        let handles: Vec<_> = (0..16)
+            .map(|i| {
+                let invitations = invitations.clone();
+                let code = code.clone();
+                std::thread::spawn(move || invitations.consume(&code, &format!("user{i}")))
+            })
+            .collect();
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+        assert!(results.iter().all(|r| r.is_ok() || *r == Err(InvitationError::Used)));
+    }
+}
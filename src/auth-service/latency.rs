@@ -0,0 +1,161 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::metrics::{Counter, Histogram};
+
+// The parts of SignIn timed separately, in the order they run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SignInPhase {
+    PoolWait, // Queued for a hashing worker.
+    StoreLookup,
+    HashVerification,
+    SessionCreation,
+}
+
+impl SignInPhase {
+    pub const ALL: [SignInPhase; 4] = [
+        SignInPhase::PoolWait,
+        SignInPhase::StoreLookup,
+        SignInPhase::HashVerification,
+        SignInPhase::SessionCreation,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SignInPhase::PoolWait => "pool_wait",
+            SignInPhase::StoreLookup => "store_lookup",
+            SignInPhase::HashVerification => "hash_verification",
+            SignInPhase::SessionCreation => "session_creation",
+        }
+    }
+}
+
+// Time spent in each phase of one request. Fixed size, so timing a phase doesn't allocate.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PhaseTimings([Duration; SignInPhase::ALL.len()]);
+
+impl PhaseTimings {
+    pub fn add(&mut self, phase: SignInPhase, elapsed: Duration) {
+        self.0[phase as usize] += elapsed;
+    }
+
+    pub fn time<T>(&mut self, phase: SignInPhase, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.add(phase, start.elapsed());
+        result
+    }
+
+    pub fn get(&self, phase: SignInPhase) -> Duration {
+        self.0[phase as usize]
+    }
+}
+
+// "pool_wait_ms=0 store_lookup_ms=0 hash_verification_ms=52 session_creation_ms=0"
+impl fmt::Display for PhaseTimings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, phase) in SignInPhase::ALL.iter().enumerate() {
+            let separator = if i == 0 { "" } else { " " };
+            write!(f, "{}{}_ms={}", separator, phase.name(), self.get(*phase).as_millis())?;
+        }
+        Ok(())
+    }
+}
+
+// Per-phase SignIn durations, and the budget a whole request should fit in.
+pub struct SignInLatency {
+    phases: [Histogram; SignInPhase::ALL.len()],
+    budget: Option<Duration>,
+    over_budget: Counter,
+}
+
+impl SignInLatency {
+    pub fn new(budget: Option<Duration>) -> Self {
+        Self {
+            phases: Default::default(),
+            budget,
+            over_budget: Counter::default(),
+        }
+    }
+
+    // Records a finished request. `total` is the whole handler, so it also covers time between phases.
+    pub fn observe(&self, timings: &PhaseTimings, total: Duration) {
+        for phase in SignInPhase::ALL {
+            self.phases[phase as usize].observe(timings.get(phase));
+        }
+        if let Some(breakdown) = self.breakdown(timings, total) {
+            self.over_budget.inc();
+            println!("{}", breakdown);
+        }
+    }
+
+    // The log line for a request over budget, None when it's within it.
+    fn breakdown(&self, timings: &PhaseTimings, total: Duration) -> Option<String> {
+        let budget = self.budget.filter(|budget| total > *budget)?;
+        Some(format!(
+            "WARN slow_sign_in total_ms={} budget_ms={} {}",
+            total.as_millis(),
+            budget.as_millis(),
+            timings
+        ))
+    }
+
+    pub fn histogram(&self, phase: SignInPhase) -> &Histogram {
+        &self.phases[phase as usize]
+    }
+
+    pub fn over_budget(&self) -> u64 {
+        self.over_budget.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timings(ms: [u64; 4]) -> PhaseTimings {
+        let mut timings = PhaseTimings::default();
+        for (phase, ms) in SignInPhase::ALL.into_iter().zip(ms) {
+            timings.add(phase, Duration::from_millis(ms));
+        }
+        timings
+    }
+
+    #[test]
+    fn should_log_breakdown_only_over_budget() {
+        let latency = SignInLatency::new(Some(Duration::from_millis(100)));
+
+        assert_eq!(latency.breakdown(&timings([1, 2, 50, 3]), Duration::from_millis(60)), None);
+        assert_eq!(
+            latency.breakdown(&timings([40, 2, 150, 3]), Duration::from_millis(200)).unwrap(),
+            "WARN slow_sign_in total_ms=200 budget_ms=100 pool_wait_ms=40 store_lookup_ms=2 hash_verification_ms=150 session_creation_ms=3"
+        );
+        assert_eq!(SignInLatency::new(None).breakdown(&timings([0, 0, 5_000, 0]), Duration::from_secs(5)), None);
+    }
+
+    #[test]
+    fn should_record_each_phase() {
+        let latency = SignInLatency::new(Some(Duration::from_millis(100)));
+
+        latency.observe(&timings([1, 2, 50, 3]), Duration::from_millis(60));
+        latency.observe(&timings([1, 2, 150, 3]), Duration::from_millis(160));
+
+        assert_eq!(latency.histogram(SignInPhase::HashVerification).sum(), Duration::from_millis(200));
+        assert_eq!(latency.histogram(SignInPhase::SessionCreation).count(), 2);
+        assert_eq!(latency.over_budget(), 1);
+    }
+
+    #[test]
+    fn should_add_up_repeated_phases() {
+        let mut timings = PhaseTimings::default();
+        timings.add(SignInPhase::StoreLookup, Duration::from_millis(2));
+        let value = timings.time(SignInPhase::StoreLookup, || {
+            std::thread::sleep(Duration::from_millis(5));
+            7
+        });
+
+        assert_eq!(value, 7);
+        assert!(timings.get(SignInPhase::StoreLookup) >= Duration::from_millis(7));
+        assert_eq!(timings.get(SignInPhase::PoolWait), Duration::ZERO);
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::binding::{ClientInfo, Fingerprint};
+use crate::clock::{Clock, SystemClock};
+use crate::events::{Event, EventSink, LogEvents};
+use crate::purger::{PurgeBudget, PurgeRun};
+use crate::users::Users;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Publishes `Event::LoginOccurred` for every successful sign in, telling a notification service whether the
+// client is one the user has signed in from before.
+pub struct LoginNotifier {
+    ip_key: Vec<u8>, // Keys the IP hash, so hashes can't be reversed by hashing every address.
+    max_known_devices: usize, // Per user. The least recently seen device is forgotten past this.
+    known_devices: Mutex<HashMap<String, VecDeque<Fingerprint>>>, // User uuid -> devices, most recently seen last.
+    forget_cursor: Mutex<Option<String>>, // Last user uuid `forget_deleted_users` checked.
+    events: Arc<dyn EventSink>,
+    clock: Arc<dyn Clock>,
+}
+
+impl LoginNotifier {
+    pub fn new(ip_key: &[u8], max_known_devices: usize) -> Self {
+        Self {
+            ip_key: ip_key.to_vec(),
+            max_known_devices: max_known_devices.max(1),
+            known_devices: Mutex::new(HashMap::new()),
+            forget_cursor: Mutex::new(None),
+            events: Arc::new(LogEvents),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_events(mut self, events: Arc<dyn EventSink>) -> Self {
+        self.events = events;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn record_login(&self, user_uuid: &str, client: &ClientInfo, addr: Option<IpAddr>) {
+        let new_device = self.remember_device(user_uuid, client.fingerprint(true, true));
+        self.events.publish(Event::LoginOccurred {
+            user_uuid: user_uuid.to_owned(),
+            at: self.clock.now(),
+            ip_hash: addr.map(|addr| self.hash_ip(addr)),
+            user_agent_family: user_agent_family(client.user_agent.as_deref()),
+            new_device,
+        });
+    }
+
+    pub fn known_devices(&self, user_uuid: &str) -> usize {
+        self.known_devices.lock().unwrap().get(user_uuid).map_or(0, VecDeque::len)
+    }
+
+    // Forgets the devices of accounts `users` no longer has, deleted or purged, as many as `budget` allows. Checks
+    // at least one account per run, and goes on after the last one the previous run checked, so runs the budget cuts
+    // short still get round to every account. Sign ins aren't held up while `users` is asked.
+    pub fn forget_deleted_users(&self, users: &(dyn Users + Send + Sync), budget: &PurgeBudget) -> PurgeRun {
+        let mut user_uuids: Vec<String> = self.known_devices.lock().unwrap().keys().cloned().collect();
+        user_uuids.sort_unstable();
+        let mut cursor = self.forget_cursor.lock().unwrap();
+        if let Some(last) = cursor.as_deref() {
+            let next = user_uuids.partition_point(|user_uuid| user_uuid.as_str() <= last);
+            user_uuids.rotate_left(next);
+        }
+
+        let mut run = PurgeRun::default();
+        let mut deleted = Vec::new();
+        for (i, user_uuid) in user_uuids.iter().enumerate() {
+            if users.get_user(user_uuid).is_none() {
+                deleted.push(user_uuid);
+            }
+            *cursor = Some(user_uuid.clone());
+            if budget.spent(deleted.len()) {
+                run.truncated = i + 1 < user_uuids.len();
+                break;
+            }
+        }
+
+        let mut known_devices = self.known_devices.lock().unwrap();
+        for user_uuid in deleted {
+            known_devices.remove(user_uuid);
+            run.purged += 1;
+        }
+        run
+    }
+
+    // Marks the device as the user's most recently seen one, returning whether it's new to them.
+    fn remember_device(&self, user_uuid: &str, fingerprint: Fingerprint) -> bool {
+        let mut known_devices = self.known_devices.lock().unwrap();
+        let devices = known_devices.entry(user_uuid.to_owned()).or_default();
+
+        let known = match devices.iter().position(|device| *device == fingerprint) {
+            Some(i) => {
+                devices.remove(i);
+                true
+            }
+            None => false,
+        };
+        if devices.len() == self.max_known_devices {
+            devices.pop_front();
+        }
+        devices.push_back(fingerprint);
+        !known
+    }
+
+    // Keyed hash of the network the address is in (/24 for IPv4, /48 for IPv6), so the event can tell networks
+    // apart without carrying the address or pinpointing the host.
+    fn hash_ip(&self, addr: IpAddr) -> String {
+        let network: Vec<u8> = match addr {
+            IpAddr::V4(addr) => addr.octets()[..3].to_vec(),
+            IpAddr::V6(addr) => match addr.to_ipv4_mapped() {
+                Some(addr) => addr.octets()[..3].to_vec(),
+                None => addr.octets()[..6].to_vec(),
+            },
+        };
+        let mut mac = HmacSha256::new_from_slice(&self.ip_key).expect("HMAC accepts keys of any length");
+        mac.update(&network);
+        URL_SAFE_NO_PAD.encode(&mac.finalize().into_bytes()[..16])
+    }
+}
+
+// Coarse browser or client family from a user agent. Order matters: Edge and Opera also claim to be Chrome, and
+// Chrome claims to be Safari.
+pub fn user_agent_family(user_agent: Option<&str>) -> &'static str {
+    const FAMILIES: [(&str, &str); 7] = [
+        ("Edg/", "Edge"),
+        ("OPR/", "Opera"),
+        ("Firefox/", "Firefox"),
+        ("Chrome/", "Chrome"),
+        ("Safari/", "Safari"),
+        ("grpc-", "gRPC"),
+        ("curl/", "curl"),
+    ];
+    match user_agent {
+        None => "unknown",
+        Some(user_agent) => FAMILIES
+            .iter()
+            .find(|(marker, _)| user_agent.contains(marker))
+            .map_or("other", |(_, family)| family),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::events::RecordingEvents;
+    use crate::users::UsersImpl;
+
+    fn client(user_agent: &str, device_id: &str) -> ClientInfo {
+        ClientInfo {
+            user_agent: Some(user_agent.to_owned()),
+            device_id: Some(device_id.to_owned()),
+        }
+    }
+
+    fn notifier(max_known_devices: usize) -> (LoginNotifier, Arc<RecordingEvents>) {
+        let events = Arc::new(RecordingEvents::default());
+        let notifier = LoginNotifier::new(b"ip key", max_known_devices).with_events(events.clone());
+        (notifier, events)
+    }
+
+    fn new_devices(events: &RecordingEvents) -> Vec<bool> {
+        events
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|event| match event {
+                Event::LoginOccurred { new_device, .. } => *new_device,
+                other => panic!("unexpected event {other:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_tell_new_devices_from_known_ones() {
+        let (notifier, events) = notifier(10);
+        let phone = client("Firefox/126.0", "phone");
+
+        notifier.record_login("alice", &phone, None);
+        notifier.record_login("alice", &phone, None);
+        notifier.record_login("alice", &client("Firefox/126.0", "laptop"), None);
+        notifier.record_login("bob", &phone, None); // Known to alice, not to bob.
+
+        assert_eq!(new_devices(&events), vec![true, false, true, true]);
+    }
+
+    #[test]
+    fn should_forget_least_recently_seen_device_past_bound() {
+        let (notifier, events) = notifier(2);
+        let [a, b, c] = ["a", "b", "c"].map(|device| client("curl/8.0", device));
+
+        notifier.record_login("alice", &a, None);
+        notifier.record_login("alice", &b, None);
+        notifier.record_login("alice", &a, None); // Makes b the least recently seen.
+        notifier.record_login("alice", &c, None); // Evicts b.
+        notifier.record_login("alice", &a, None);
+        notifier.record_login("alice", &b, None);
+
+        assert_eq!(new_devices(&events), vec![true, true, false, true, false, true]);
+        assert_eq!(notifier.known_devices.lock().unwrap()["alice"].len(), 2);
+    }
+
+    #[test]
+    fn should_forget_devices_of_deleted_users() {
+        let users = UsersImpl::with_hash_rounds(1_000);
+        let alice = users.create_user("alice".to_owned(), "password".into(), None).unwrap();
+        let bob = users.create_user("bob".to_owned(), "password".into(), None).unwrap();
+        let (notifier, _) = notifier(10);
+        for user_uuid in [&alice, &bob, "purged"] {
+            notifier.record_login(user_uuid, &client("curl/8.0", "laptop"), None);
+        }
+        users.delete_user(bob.clone());
+
+        let run = notifier.forget_deleted_users(&users, &PurgeBudget::unlimited());
+
+        assert_eq!(run, PurgeRun { purged: 2, truncated: false });
+        assert_eq!((notifier.known_devices(&alice), notifier.known_devices(&bob)), (1, 0));
+        assert_eq!(notifier.known_devices("purged"), 0);
+    }
+
+    #[test]
+    fn should_go_on_where_the_last_truncated_run_stopped() {
+        let users = UsersImpl::with_hash_rounds(1_000);
+        let alice = users.create_user("alice".to_owned(), "password".into(), None).unwrap();
+        let (notifier, _) = notifier(10);
+        for user_uuid in [&alice, "purged-1", "purged-2"] {
+            notifier.record_login(user_uuid, &client("curl/8.0", "laptop"), None);
+        }
+        // Already spent, so every run checks a single account.
+        let spent = PurgeBudget::new(usize::MAX, Duration::ZERO, Arc::new(ManualClock::new()));
+
+        let runs: Vec<PurgeRun> = (0..3).map(|_| notifier.forget_deleted_users(&users, &spent)).collect();
+
+        assert_eq!(runs.iter().map(|run| run.purged).collect::<Vec<_>>(), vec![0, 1, 1]);
+        assert!(runs.iter().all(|run| run.truncated));
+        assert_eq!(notifier.known_devices(&alice), 1);
+        assert_eq!((notifier.known_devices("purged-1"), notifier.known_devices("purged-2")), (0, 0));
+    }
+
+    #[test]
+    fn should_publish_coarse_context_without_raw_address() {
+        let clock = Arc::new(ManualClock::new());
+        let (notifier, events) = notifier(10);
+        let notifier = notifier.with_clock(clock.clone());
+        let browser = client("Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 Chrome/125.0 Safari/537.36", "laptop");
+
+        notifier.record_login("alice", &browser, Some("203.0.113.7".parse().unwrap()));
+        notifier.record_login("alice", &browser, Some("203.0.113.99".parse().unwrap()));
+        notifier.record_login("alice", &browser, Some("198.51.100.7".parse().unwrap()));
+
+        let events = events.0.lock().unwrap();
+        let Event::LoginOccurred { user_uuid, at, ip_hash, user_agent_family, new_device } = &events[0] else {
+            panic!("unexpected event {:?}", events[0]);
+        };
+        assert_eq!((user_uuid.as_str(), *at, *user_agent_family, *new_device), ("alice", clock.now(), "Chrome", true));
+        let ip_hash = ip_hash.clone().unwrap();
+        assert!(!ip_hash.contains("203"));
+        // Same network, same hash.
+        assert!(matches!(&events[1], Event::LoginOccurred { ip_hash: Some(same), new_device: false, .. } if *same == ip_hash));
+        assert!(matches!(&events[2], Event::LoginOccurred { ip_hash: Some(other), .. } if *other != ip_hash));
+    }
+
+    #[test]
+    fn should_classify_user_agents() {
+        assert_eq!(user_agent_family(Some("Mozilla/5.0 Chrome/125.0 Safari/537.36 Edg/125.0")), "Edge");
+        assert_eq!(user_agent_family(Some("Mozilla/5.0 (Macintosh) AppleWebKit/605.1.15 Version/17.4 Safari/605.1.15")), "Safari");
+        assert_eq!(user_agent_family(Some("grpc-rust/0.9")), "gRPC");
+        assert_eq!(user_agent_family(Some("MyApp/1.0")), "other");
+        assert_eq!(user_agent_family(None), "unknown");
+    }
+}
@@ -1,24 +1,218 @@
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use rand_core::{OsRng, RngCore};
 
 mod auth;
+mod banlist;
+mod binding;
+mod clock;
+mod config;
+mod credentials;
+mod email;
+mod events;
+#[cfg(test)]
+mod fixtures;
+mod gates;
+mod hashing;
+mod idempotency;
+mod identifiers;
+mod invitations;
+mod latency;
+mod logins;
+mod maintenance;
+mod metrics;
+mod password;
+mod pool;
+mod purger;
+mod reload;
+mod selftest;
 mod sessions;
+mod shedding;
+mod skeleton;
+mod store;
+mod store_stats;
+mod tokens;
+mod user_events;
 mod users;
+mod uuids;
+mod validation;
 
 use auth::*;
+use banlist::UsernameBanList;
+use config::{AuthConfig, ConfigSource};
+use gates::{HttpCallbackGate, SignupGate};
+use idempotency::IdempotencyKeys;
+use latency::SignInLatency;
+use logins::LoginNotifier;
+use maintenance::MaintenanceMode;
+use invitations::{Invitations, InvitationsImpl};
+use pool::HashingPool;
+use purger::Purger;
+use reload::ConfigReloader;
+use tokens::{KeySet, TokenSigner};
 use sessions::{SessionsImpl, Sessions};
-use users::{UsersImpl, Users};
+use shedding::{LoadShedder, ShedThresholds};
+use store_stats::StoreGauges;
+use user_events::UserEventLog;
+use users::{HashingMetrics, Users};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Here we are using ip 0.0.0.0 so the service is listening on all the configured network interfaces. This is needed for Docker to work, which we will add later on.
     // See: https://stackoverflow.com/questions/39525820/docker-port-forwarding-not-working
     // Port 50051 is the recommended gRPC port.
-    let addr = "[::0]:50051".parse()?;
+    let config_source = ConfigSource::load()?;
+    let config = AuthConfig::from_source(&config_source);
+    let addr = config.listen_addr.parse()?;
+
+    let uuids = uuids::uuid_generator(&config.uuid_version)?;
+    let username_scope = store::username_scope(&config.username_scope)?;
+    let ban_list = Arc::new(RwLock::new(match &config.username_ban_list_file {
+        Some(path) => UsernameBanList::load(path)?,
+        None => UsernameBanList::reserved(),
+    }));
+    let user_events = Arc::new(UserEventLog::new(config.user_event_buffer));
+    let hashing_metrics = Arc::new(HashingMetrics::default());
+    let users_service: Arc<dyn Users + Send + Sync + 'static> = users::users_from_config(&config, user_events.clone(), uuids.clone(), username_scope, ban_list.clone(), hashing_metrics.clone()); // Create user service instance
+    if config.hash_scan_interval_secs > 0 {
+        tokio::spawn(users::log_hash_parameters(users_service.clone(), Duration::from_secs(config.hash_scan_interval_secs)));
+    }
+    if config.guest_max_age_secs > 0 {
+        tokio::spawn(users::purge_guests_periodically(users_service.clone(), Duration::from_secs(config.guest_max_age_secs)));
+    }
+    let session_ttl = Duration::from_secs(config.session_ttl_secs);
+    let mut reloaded_keyset = None;
+    let sessions_impl = match (&config.session_keyset_file, &config.session_signing_key) {
+        (Some(path), _) => {
+            let signer = TokenSigner::new(KeySet::load(path)?, session_ttl).with_uuid_generator(uuids.clone());
+            reloaded_keyset = Some((path.clone(), signer.keyset()));
+            SessionsImpl::with_signer(signer)
+        }
+        (None, Some(key)) => SessionsImpl::with_signer(
+            TokenSigner::new(KeySet::single(key.as_bytes()), session_ttl).with_uuid_generator(uuids.clone()),
+        ),
+        (None, None) => SessionsImpl::default(),
+    };
+    let session_binding = binding::session_binding(
+        &config.session_binding,
+        &config.session_binding_components,
+        config.session_binding_revoke,
+    )?;
+    let sessions_impl = sessions_impl
+        .with_uuid_generator(uuids.clone())
+        .with_binding(session_binding)
+        .with_idle_timeout((config.session_idle_timeout_secs > 0).then(|| Duration::from_secs(config.session_idle_timeout_secs)))
+        .with_absolute_lifetime(
+            (config.session_absolute_lifetime_secs > 0).then(|| Duration::from_secs(config.session_absolute_lifetime_secs)),
+        );
+    let mut config_reloader = ConfigReloader::new(config_source, sessions_impl.limits());
+    if let Some((path, keyset)) = reloaded_keyset {
+        config_reloader = config_reloader.with_keyset(path, keyset);
+    }
+    if let Some(path) = &config.username_ban_list_file {
+        config_reloader = config_reloader.with_ban_list(path.clone(), ban_list.clone());
+    }
+    let config_reloader = Arc::new(config_reloader);
+    tokio::spawn(reload::reload_on_sighup(config_reloader.clone()));
+    let sessions_service: Arc<Mutex<dyn Sessions + Send + Sync + 'static>> = Arc::new(Mutex::new(sessions_impl)); //Create session service instance
+    if std::env::args().any(|arg| arg == "--self-test") {
+        // Before serving anything, so a broken deployment never takes traffic. On its own users built from the
+        // same config, so the throwaway account's events, reservation and hash timings never reach the real ones.
+        let users = users::users_from_config(&config, Arc::new(UserEventLog::new(config.user_event_buffer)), uuids.clone(), username_scope, ban_list.clone(), Arc::default());
+        let sessions = sessions_service.clone();
+        let report = tokio::task::spawn_blocking(move || selftest::run_self_test(&*users, &mut *sessions.lock().unwrap())).await?;
+        println!("{}", report);
+        if !report.passed() {
+            std::process::exit(1);
+        }
+    }
+    let maintenance = Arc::new(MaintenanceMode::new(Duration::from_secs(config.maintenance_max_secs)));
+    if config.maintenance_on_start {
+        maintenance.enter(Duration::ZERO, "config");
+    }
+    tokio::spawn(maintenance::toggle_on_sigusr1(maintenance.clone()));
+    let load_shedder = (config.shed_p95_ms > 0 || config.shed_queue_length > 0).then(|| {
+        let thresholds = ShedThresholds {
+            p95: (config.shed_p95_ms > 0).then(|| Duration::from_millis(config.shed_p95_ms)),
+            queue_length: (config.shed_queue_length > 0).then_some(config.shed_queue_length),
+        };
+        Arc::new(LoadShedder::new(thresholds, Duration::from_secs(config.shed_ramp_secs)))
+    });
+    let login_notifier = (config.login_known_devices > 0).then(|| {
+        let ip_key = match &config.login_ip_hash_key {
+            Some(key) => key.as_bytes().to_vec(),
+            None => {
+                let mut key = vec![0; 32];
+                OsRng.fill_bytes(&mut key);
+                key
+            }
+        };
+        Arc::new(LoginNotifier::new(&ip_key, config.login_known_devices))
+    });
+    let invitations_service: Arc<dyn Invitations + Send + Sync> = Arc::new(InvitationsImpl::default());
+    let mut purger = Purger::new(config.purge_max_items, Duration::from_millis(config.purge_max_ms));
+    if config.session_purge_interval_secs > 0 {
+        let interval = Duration::from_secs(config.session_purge_interval_secs);
+        purger = purger.with_job(purger::ExpiredSessions(sessions_service.clone()), interval);
+    }
+    if config.reservation_purge_interval_secs > 0 {
+        let interval = Duration::from_secs(config.reservation_purge_interval_secs);
+        purger = purger.with_job(purger::LapsedReservations(users_service.clone()), interval);
+    }
+    if config.invitation_purge_interval_secs > 0 {
+        let interval = Duration::from_secs(config.invitation_purge_interval_secs);
+        purger = purger.with_job(purger::LapsedInvitations(invitations_service.clone()), interval);
+    }
+    if let Some(login_notifier) = login_notifier.as_ref().filter(|_| config.login_device_purge_interval_secs > 0) {
+        let interval = Duration::from_secs(config.login_device_purge_interval_secs);
+        purger = purger.with_job(purger::DeletedUserDevices(login_notifier.clone(), users_service.clone()), interval);
+    }
+    let sign_in_latency = Arc::new(SignInLatency::new(
+        (config.sign_in_budget_ms > 0).then(|| Duration::from_millis(config.sign_in_budget_ms)),
+    ));
+    let store_gauges = Arc::new(
+        StoreGauges::default()
+            .with_maintenance(maintenance.clone())
+            .with_load_shedder(load_shedder.clone())
+            .with_hashing_metrics(hashing_metrics)
+            .with_sign_in_latency(sign_in_latency.clone())
+            .with_purge_metrics(purger.metrics()),
+    );
+    if config.store_stats_interval_secs > 0 {
+        let interval = Duration::from_secs(config.store_stats_interval_secs);
+        tokio::spawn(store_stats::refresh_store_gauges(users_service.clone(), sessions_service.clone(), store_gauges.clone(), interval));
+    }
+    if let Some(metrics_addr) = &config.metrics_addr {
+        tokio::spawn(store_stats::serve_metrics(metrics_addr.parse()?, store_gauges));
+    }
+    tokio::spawn(purger::run_purger(purger, Duration::from_secs(1)));
+    let hashing_pool = HashingPool::new(config.hashing_workers, config.hashing_queue_depth);
+
+    let mut signup_gates: Vec<Box<dyn SignupGate + Send + Sync>> = Vec::new();
+    if let Some(url) = &config.signup_gate_url {
+        let timeout = Duration::from_millis(config.signup_gate_timeout_ms);
+        signup_gates.push(Box::new(HttpCallbackGate::new(url.parse()?, timeout, config.signup_gate_fail_open)));
+    }
 
-    let users_service: Box<Mutex<dyn Users + Send + Sync + 'static>> = Box::new(Mutex::new(UsersImpl::default())); // Create user service instance
-    let sessions_service: Box<Mutex<dyn Sessions + Send + Sync + 'static>> = Box::new(Mutex::new(SessionsImpl::default())); //Create session service instance
 
-    let auth_service = AuthService::new(users_service, sessions_service);
+    let auth_service = AuthService::new(users_service, sessions_service, hashing_pool)
+        .with_invitations(invitations_service)
+        .with_invite_only(config.invite_only)
+        .with_admin_token(config.admin_token.clone())
+        .with_signup_gates(signup_gates)
+        .with_user_events(user_events)
+        .with_config_reloader(Some(config_reloader))
+        .with_sign_in_latency(sign_in_latency)
+        .with_login_notifier(login_notifier)
+        .with_maintenance(maintenance)
+        .with_load_shedder(load_shedder)
+        .with_batch_verify_parallelism(config.batch_verify_parallelism)
+        .with_reauth_max_age((config.reauth_max_age_secs > 0).then(|| Duration::from_secs(config.reauth_max_age_secs)))
+        .with_sign_up_keys(
+            (config.sign_up_max_keys > 0)
+                .then(|| IdempotencyKeys::new(Duration::from_secs(config.sign_up_key_ttl_secs), config.sign_up_max_keys)),
+        );
 
 
     
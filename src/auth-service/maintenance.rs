@@ -0,0 +1,125 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::clock::{Clock, SystemClock};
+
+// Freezes account changes, e.g. during a migration, while sign in and session checks keep working. Always
+// time-boxed: the mode ends by itself after at most `max_duration`, so a forgotten toggle can't freeze sign ups for
+// good.
+pub struct MaintenanceMode {
+    until: Mutex<Option<SystemTime>>,
+    max_duration: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+impl MaintenanceMode {
+    pub fn new(max_duration: Duration) -> Self {
+        Self {
+            until: Mutex::new(None),
+            max_duration,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // Starts or extends maintenance for `duration`, capped at the maximum. Zero means the maximum. `source` says who
+    // asked, for the log. Returns how long it will last.
+    pub fn enter(&self, duration: Duration, source: &str) -> Duration {
+        let duration = if duration.is_zero() { self.max_duration } else { duration.min(self.max_duration) };
+        *self.until.lock().unwrap() = Some(self.clock.now() + duration);
+        println!("AUDIT maintenance_mode=on source={} duration_secs={}", source, duration.as_secs());
+        duration
+    }
+
+    pub fn leave(&self, source: &str) {
+        if self.until.lock().unwrap().take().is_some() {
+            println!("AUDIT maintenance_mode=off source={}", source);
+        }
+    }
+
+    // Leaves maintenance if on, enters it for the maximum otherwise.
+    pub fn toggle(&self, source: &str) {
+        if self.remaining().is_some() {
+            self.leave(source);
+        } else {
+            self.enter(self.max_duration, source);
+        }
+    }
+
+    // Time left in maintenance, None when not in it.
+    pub fn remaining(&self) -> Option<Duration> {
+        let mut until = self.until.lock().unwrap();
+        let remaining = until.and_then(|until| until.duration_since(self.clock.now()).ok()).filter(|left| !left.is_zero());
+        if remaining.is_none() && until.take().is_some() {
+            println!("AUDIT maintenance_mode=off source=expired");
+        }
+        remaining
+    }
+}
+
+// Toggles maintenance mode on every SIGUSR1.
+pub async fn toggle_on_sigusr1(maintenance: Arc<MaintenanceMode>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            println!("Failed to listen for SIGUSR1, maintenance mode can't be toggled by signal: {e}");
+            return;
+        }
+    };
+
+    while signals.recv().await.is_some() {
+        maintenance.toggle("signal");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn maintenance() -> (MaintenanceMode, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new());
+        (MaintenanceMode::new(10 * MINUTE).with_clock(clock.clone()), clock)
+    }
+
+    #[test]
+    fn should_end_by_itself_after_duration() {
+        let (maintenance, clock) = maintenance();
+        assert_eq!(maintenance.remaining(), None);
+
+        maintenance.enter(2 * MINUTE, "test");
+        clock.advance(MINUTE);
+        assert_eq!(maintenance.remaining(), Some(MINUTE));
+
+        clock.advance(MINUTE);
+        assert_eq!(maintenance.remaining(), None);
+    }
+
+    #[test]
+    fn should_cap_duration_at_maximum() {
+        let (maintenance, _) = maintenance();
+
+        assert_eq!(maintenance.enter(Duration::from_secs(24 * 60 * 60), "test"), 10 * MINUTE);
+        assert_eq!(maintenance.enter(Duration::ZERO, "test"), 10 * MINUTE);
+        assert_eq!(maintenance.enter(MINUTE, "test"), MINUTE);
+    }
+
+    #[test]
+    fn should_toggle() {
+        let (maintenance, _) = maintenance();
+
+        maintenance.toggle("test");
+        assert_eq!(maintenance.remaining(), Some(10 * MINUTE));
+        maintenance.toggle("test");
+        assert_eq!(maintenance.remaining(), None);
+    }
+}
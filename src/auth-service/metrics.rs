@@ -0,0 +1,188 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+// Monotonically increasing count, e.g. number of rejected requests.
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Value that can go up and down, e.g. current queue length.
+#[derive(Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Upper bounds of the duration buckets. Observations above the last one only land in the overflow bucket.
+const DURATION_BUCKETS_MS: [u64; 12] = [1, 5, 10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000];
+
+// Distribution of durations, e.g. how long password hashing takes.
+pub struct Histogram {
+    buckets: [AtomicU64; DURATION_BUCKETS_MS.len() + 1], // Last one is the overflow bucket.
+    sum_micros: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let ms = duration.as_millis();
+        let bucket = DURATION_BUCKETS_MS
+            .iter()
+            .position(|&bound| ms <= bound as u128)
+            .unwrap_or(DURATION_BUCKETS_MS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).sum()
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_micros.load(Ordering::Relaxed))
+    }
+
+    // Cumulative counts per upper bound, Prometheus style. `None` is the +Inf bucket.
+    pub fn buckets(&self) -> Vec<(Option<Duration>, u64)> {
+        let mut cumulative = 0;
+        self.buckets
+            .iter()
+            .enumerate()
+            .map(|(i, bucket)| {
+                cumulative += bucket.load(Ordering::Relaxed);
+                (DURATION_BUCKETS_MS.get(i).map(|&ms| Duration::from_millis(ms)), cumulative)
+            })
+            .collect()
+    }
+
+    // Prometheus text lines for one series of `name`, in seconds. The caller writes HELP and TYPE, which come
+    // once per name rather than once per label set.
+    pub fn write_series(&self, out: &mut String, name: &str, labels: &[(&str, &str)]) {
+        let labels: Vec<String> = labels.iter().map(|(key, value)| format!("{key}=\"{value}\"")).collect();
+        for (bound, count) in self.buckets() {
+            let le = bound.map_or("+Inf".to_owned(), |bound| bound.as_secs_f64().to_string());
+            let bucket_labels = labels.iter().cloned().chain([format!("le=\"{le}\"")]).collect::<Vec<_>>().join(",");
+            let _ = writeln!(out, "{name}_bucket{{{bucket_labels}}} {count}");
+        }
+        let labels = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels.join(",")) };
+        let _ = writeln!(out, "{name}_sum{labels} {}", self.sum().as_secs_f64());
+        let _ = writeln!(out, "{name}_count{labels} {}", self.count());
+    }
+}
+
+// Histograms keyed by label values, e.g. ["verify", "pbkdf2-sha256"] for (operation, algorithm).
+#[derive(Default)]
+pub struct HistogramVec(RwLock<BTreeMap<Vec<String>, Arc<Histogram>>>);
+
+impl HistogramVec {
+    pub fn with_labels(&self, labels: &[&str]) -> Arc<Histogram> {
+        let key: Vec<String> = labels.iter().map(|label| label.to_string()).collect();
+        if let Some(histogram) = self.0.read().unwrap().get(&key) {
+            return histogram.clone();
+        }
+        self.0.write().unwrap().entry(key).or_default().clone()
+    }
+
+    // Every label combination observed so far.
+    pub fn labels(&self) -> Vec<Vec<String>> {
+        self.0.read().unwrap().keys().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_bucket_durations_cumulatively() {
+        let histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_millis(5));
+        histogram.observe(Duration::from_millis(700));
+        histogram.observe(Duration::from_secs(60));
+
+        assert_eq!(histogram.count(), 4);
+        assert_eq!(histogram.sum(), Duration::from_millis(60_708));
+
+        let buckets = histogram.buckets();
+        assert_eq!(buckets[0], (Some(Duration::from_millis(1)), 0));
+        assert_eq!(buckets[1], (Some(Duration::from_millis(5)), 2));
+        assert_eq!(buckets[8], (Some(Duration::from_millis(1_000)), 3));
+        assert_eq!(buckets[12], (None, 4));
+    }
+
+    #[test]
+    fn should_write_series_in_seconds() {
+        let histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(500));
+        histogram.observe(Duration::from_millis(1_500));
+
+        let mut out = String::new();
+        histogram.write_series(&mut out, "hashing_seconds", &[("operation", "hash")]);
+
+        for line in [
+            "hashing_seconds_bucket{operation=\"hash\",le=\"0.25\"} 0",
+            "hashing_seconds_bucket{operation=\"hash\",le=\"0.5\"} 1",
+            "hashing_seconds_bucket{operation=\"hash\",le=\"2.5\"} 2",
+            "hashing_seconds_bucket{operation=\"hash\",le=\"+Inf\"} 2",
+            "hashing_seconds_sum{operation=\"hash\"} 2",
+            "hashing_seconds_count{operation=\"hash\"} 2",
+        ] {
+            assert!(out.lines().any(|l| l == line), "missing {line} in {out}");
+        }
+
+        let mut out = String::new();
+        Histogram::default().write_series(&mut out, "empty_seconds", &[]);
+        assert!(out.contains("empty_seconds_bucket{le=\"+Inf\"} 0\nempty_seconds_sum 0\nempty_seconds_count 0\n"));
+    }
+
+    #[test]
+    fn should_keep_one_histogram_per_label_set() {
+        let histograms = HistogramVec::default();
+        histograms.with_labels(&["verify", "pbkdf2-sha256"]).observe(Duration::from_millis(1));
+        histograms.with_labels(&["verify", "pbkdf2-sha256"]).observe(Duration::from_millis(1));
+        histograms.with_labels(&["hash", "pbkdf2-sha256"]).observe(Duration::from_millis(1));
+
+        assert_eq!(histograms.with_labels(&["verify", "pbkdf2-sha256"]).count(), 2);
+        assert_eq!(histograms.labels().len(), 2);
+    }
+}
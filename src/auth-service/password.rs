@@ -0,0 +1,63 @@
+use std::fmt;
+
+// A plaintext password on its way to being hashed or checked. Debug and Display never show it, so a stray `{:?}`
+// can't put it in the logs; `expose` is the only way to the text. Deliberately not Serialize-able.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Password(String);
+
+impl Password {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Password {
+    fn from(password: String) -> Self {
+        Self(password)
+    }
+}
+
+impl From<&str> for Password {
+    fn from(password: &str) -> Self {
+        Self(password.to_owned())
+    }
+}
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+impl fmt::Display for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_redact_when_formatted() {
+        let password = Password::from("hunter2");
+
+        assert_eq!(format!("{password:?}"), "[REDACTED]");
+        assert_eq!(password.to_string(), "[REDACTED]");
+        assert_eq!(password.expose(), "hunter2");
+    }
+
+    #[test]
+    fn should_not_leak_through_containing_structs() {
+        #[derive(Debug)]
+        #[allow(dead_code)] // Only read through Debug.
+        struct Credentials {
+            username: String,
+            password: Password,
+        }
+
+        let logged = format!("{:?}", Credentials { username: "alice".to_owned(), password: "hunter2".into() });
+        assert_eq!(logged, "Credentials { username: \"alice\", password: [REDACTED] }");
+    }
+}
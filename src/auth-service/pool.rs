@@ -0,0 +1,289 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use tokio::sync::oneshot;
+
+use crate::metrics::{Counter, Gauge};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+#[derive(Debug, PartialEq)]
+pub enum PoolError {
+    QueueFull,
+    WorkerFailed, // The job panicked before producing a result.
+}
+
+#[derive(Default)]
+pub struct PoolMetrics {
+    pub queue_length: Gauge,
+    pub rejections: Counter,
+}
+
+// Fixed set of threads that run password hashing and verification, so a burst of SignUp/SignIn requests can't
+// tie up the async runtime or tokio's blocking pool. Jobs wait in a bounded queue; once it is full new jobs are
+// rejected instead of queuing unboundedly.
+pub struct HashingPool {
+    sender: SyncSender<Job>,
+    metrics: Arc<PoolMetrics>,
+}
+
+impl HashingPool {
+    pub fn new(workers: usize, queue_depth: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue_depth);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let metrics = Arc::new(PoolMetrics::default());
+
+        for i in 0..workers.max(1) {
+            let receiver = receiver.clone();
+            let metrics = metrics.clone();
+            thread::Builder::new()
+                .name(format!("hashing-worker-{i}"))
+                .spawn(move || worker_loop(receiver, metrics))
+                .expect("failed to spawn hashing worker");
+        }
+
+        Self { sender, metrics }
+    }
+
+    // Queues `job` and waits for its result. Fails straight away with `QueueFull` if the queue has no room.
+    pub async fn run<F, T>(&self, job: F) -> Result<T, PoolError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (result_sender, result_receiver) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            let _ = result_sender.send(job());
+        });
+
+        self.metrics.queue_length.inc();
+        match self.sender.try_send(job) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                self.metrics.queue_length.dec();
+                self.metrics.rejections.inc();
+                return Err(PoolError::QueueFull);
+            }
+        }
+
+        result_receiver.await.map_err(|_| PoolError::WorkerFailed)
+    }
+
+    pub fn metrics(&self) -> &PoolMetrics {
+        &self.metrics
+    }
+}
+
+// Threads that batch jobs, which shouldn't queue in front of SignIns in the pool, run on between them. Shared by
+// every caller, so concurrent batches split the threads instead of each taking as many. A batch gets the threads
+// free when it starts, waiting for one if none are, and keeps them until it is done.
+pub struct BatchThreads {
+    free: Mutex<usize>,
+    released: Condvar,
+}
+
+impl BatchThreads {
+    pub fn new(max_threads: usize) -> Self {
+        Self {
+            free: Mutex::new(max_threads.max(1)),
+            released: Condvar::new(),
+        }
+    }
+
+    // Runs `f` on every item, returning the results in input order. Blocks, so call from a blocking context.
+    pub fn map<T: Sync, R: Send>(&self, items: &[T], f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+        if items.is_empty() {
+            return Vec::new();
+        }
+        let threads = self.acquire(items.len());
+        map_parallel(items, threads.count, f)
+    }
+
+    fn acquire(&self, wanted: usize) -> BatchThreadsGuard<'_> {
+        let mut free = self.released.wait_while(self.free.lock().unwrap(), |free| *free == 0).unwrap();
+        let count = wanted.min(*free);
+        *free -= count;
+        BatchThreadsGuard { threads: self, count }
+    }
+}
+
+// Hands its threads back when the batch is done, or has panicked.
+struct BatchThreadsGuard<'a> {
+    threads: &'a BatchThreads,
+    count: usize,
+}
+
+impl Drop for BatchThreadsGuard<'_> {
+    fn drop(&mut self) {
+        *self.threads.free.lock().unwrap() += self.count;
+        self.threads.released.notify_all();
+    }
+}
+
+// Runs `f` on every item on at most `max_threads` threads of its own, returning the results in input order.
+fn map_parallel<T: Sync, R: Send>(items: &[T], max_threads: usize, f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<Option<R>> = std::iter::repeat_with(|| None).take(items.len()).collect();
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..max_threads.clamp(1, items.len().max(1)))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(i) else {
+                            return done;
+                        };
+                        done.push((i, f(item)));
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            for (i, result) in worker.join().expect("batch job panicked") {
+                results[i] = Some(result);
+            }
+        }
+    });
+    results.into_iter().map(|result| result.expect("every item is taken by a worker")).collect()
+}
+
+fn worker_loop(receiver: Arc<Mutex<Receiver<Job>>>, metrics: Arc<PoolMetrics>) {
+    loop {
+        // Only hold the lock while waiting for the next job, not while running it.
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return, // Pool was dropped.
+        };
+        metrics.queue_length.dec();
+
+        // A panicking job drops its result sender, which surfaces as `WorkerFailed` to the caller.
+        let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(job));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn slow_job(millis: u64) -> impl FnOnce() -> u64 + Send + 'static {
+        move || {
+            thread::sleep(Duration::from_millis(millis));
+            millis
+        }
+    }
+
+    #[tokio::test]
+    async fn should_run_job_and_return_result() {
+        let pool = HashingPool::new(2, 4);
+        assert_eq!(pool.run(|| 1 + 1).await, Ok(2));
+        assert_eq!(pool.metrics().queue_length.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn should_reject_fast_when_queue_is_full_and_drain_afterwards() {
+        let pool = Arc::new(HashingPool::new(1, 1));
+
+        // One job occupies the worker, the next one fills the queue.
+        let running = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.run(slow_job(300)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let queued = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.run(slow_job(300)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(pool.metrics().queue_length.get(), 1);
+
+        let start = Instant::now();
+        let rejected = pool.run(slow_job(300)).await;
+        assert_eq!(rejected, Err(PoolError::QueueFull));
+        assert!(start.elapsed() < Duration::from_millis(100));
+        assert_eq!(pool.metrics().rejections.get(), 1);
+
+        assert_eq!(running.await.unwrap(), Ok(300));
+        assert_eq!(queued.await.unwrap(), Ok(300));
+        assert_eq!(pool.metrics().queue_length.get(), 0);
+
+        // Once drained the pool accepts jobs again.
+        assert_eq!(pool.run(slow_job(10)).await, Ok(10));
+    }
+
+    #[tokio::test]
+    async fn should_report_panicking_job_as_worker_failure() {
+        let pool = HashingPool::new(1, 1);
+        let result: Result<(), PoolError> = pool.run(|| panic!("hashing blew up")).await;
+        assert_eq!(result, Err(PoolError::WorkerFailed));
+
+        // The worker survives the panic.
+        assert_eq!(pool.run(|| 3).await, Ok(3));
+    }
+
+    #[test]
+    fn should_map_in_parallel_keeping_input_order() {
+        // Later items finish first.
+        let items: Vec<u64> = (0..8).map(|i| 80 - i * 10).collect();
+        assert_eq!(map_parallel(&items, 4, |millis| slow_job(*millis)()), items);
+        assert_eq!(map_parallel(&[] as &[u64], 4, |millis| *millis), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn should_map_on_at_most_max_threads() {
+        let (running, most_running) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        map_parallel(&[20; 12], 3, |millis| {
+            most_running.fetch_max(running.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+            slow_job(*millis)();
+            running.fetch_sub(1, Ordering::SeqCst);
+        });
+        assert_eq!(most_running.load(Ordering::SeqCst), 3);
+
+        // 0 still makes progress, on one thread.
+        let threads = map_parallel(&[(); 4], 0, |_| thread::current().id());
+        assert!(threads.iter().all(|id| *id == threads[0]));
+    }
+
+    #[test]
+    fn should_share_batch_threads_between_concurrent_batches() {
+        let batch_threads = BatchThreads::new(3);
+        let (running, most_running) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        let results: Vec<Vec<usize>> = thread::scope(|scope| {
+            let batches: Vec<_> = (0..4)
+                .map(|batch| {
+                    let (batch_threads, running, most_running) = (&batch_threads, &running, &most_running);
+                    scope.spawn(move || {
+                        batch_threads.map(&[batch; 6], |batch| {
+                            most_running.fetch_max(running.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                            slow_job(20)();
+                            running.fetch_sub(1, Ordering::SeqCst);
+                            *batch
+                        })
+                    })
+                })
+                .collect();
+            batches.into_iter().map(|batch| batch.join().unwrap()).collect()
+        });
+
+        assert_eq!(most_running.load(Ordering::SeqCst), 3, "four batches together stay within the three threads");
+        for (batch, result) in results.iter().enumerate() {
+            assert_eq!(result, &vec![batch; 6]);
+        }
+        assert_eq!(*batch_threads.free.lock().unwrap(), 3, "every thread is handed back");
+        assert_eq!(batch_threads.map(&[] as &[u64], |millis| *millis), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn should_hand_batch_threads_back_after_a_panic() {
+        let batch_threads = BatchThreads::new(2);
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            batch_threads.map(&[1, 2], |_| -> u64 { panic!("verification blew up") })
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(batch_threads.map(&[1, 2, 3], |n| n * 2), vec![2, 4, 6]);
+        assert_eq!(*batch_threads.free.lock().unwrap(), 2);
+    }
+}
@@ -0,0 +1,312 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::clock::{Clock, SystemClock};
+use crate::invitations::Invitations;
+use crate::logins::LoginNotifier;
+use crate::metrics::{Counter, Gauge, Histogram};
+use crate::sessions::Sessions;
+use crate::users::Users;
+
+// How much one run of a job may purge. Jobs check it as they go and stop early, leaving the rest for the next run,
+// so a large backlog never holds a store lock for long.
+pub struct PurgeBudget {
+    max_items: usize,
+    deadline: SystemTime,
+    clock: Arc<dyn Clock>,
+}
+
+impl PurgeBudget {
+    pub fn new(max_items: usize, max_duration: Duration, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            max_items,
+            deadline: clock.now() + max_duration,
+            clock,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn unlimited() -> Self {
+        Self::new(usize::MAX, Duration::from_secs(24 * 60 * 60), Arc::new(SystemClock))
+    }
+
+    // Whether a job that purged `purged` items so far must stop.
+    pub fn spent(&self, purged: usize) -> bool {
+        purged >= self.max_items || self.clock.now() >= self.deadline
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PurgeRun {
+    pub purged: usize,
+    pub truncated: bool, // Stopped by the budget with more left to purge.
+}
+
+// Periodic cleanup a subsystem registers with the purger.
+pub trait MaintenanceJob: Send {
+    fn name(&self) -> &'static str;
+    fn run(&self, budget: &PurgeBudget) -> PurgeRun;
+}
+
+#[derive(Default)]
+pub struct JobMetrics {
+    pub purged: Counter,
+    pub durations: Histogram,
+    pub truncated: Gauge, // 1 when the last run hit its budget.
+}
+
+struct ScheduledJob {
+    job: Box<dyn MaintenanceJob>,
+    interval: Duration,
+    next_run_at: Option<SystemTime>, // None until the first run, which happens on the first check.
+    metrics: Arc<JobMetrics>, // Shared with the metrics endpoint.
+}
+
+// Runs each registered job on its own interval, all from one task, each run within the same budget.
+pub struct Purger {
+    jobs: Vec<ScheduledJob>,
+    max_items: usize,
+    max_duration: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+impl Purger {
+    pub fn new(max_items: usize, max_duration: Duration) -> Self {
+        Self {
+            jobs: Vec::new(),
+            max_items,
+            max_duration,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_job(mut self, job: impl MaintenanceJob + 'static, interval: Duration) -> Self {
+        self.jobs.push(ScheduledJob {
+            job: Box::new(job),
+            interval,
+            next_run_at: None,
+            metrics: Arc::default(),
+        });
+        self
+    }
+
+    pub fn is_due(&self) -> bool {
+        let now = self.clock.now();
+        self.jobs.iter().any(|job| job.next_run_at.is_none_or(|at| at <= now))
+    }
+
+    // Runs every job that is due, in registration order, returning what each did.
+    pub fn run_due(&mut self) -> Vec<(&'static str, PurgeRun)> {
+        let mut runs = Vec::new();
+        for scheduled in &mut self.jobs {
+            let started_at = self.clock.now();
+            if scheduled.next_run_at.is_some_and(|at| at > started_at) {
+                continue;
+            }
+
+            let budget = PurgeBudget::new(self.max_items, self.max_duration, self.clock.clone());
+            let run = scheduled.job.run(&budget);
+            let took = self.clock.now().duration_since(started_at).unwrap_or_default();
+
+            scheduled.next_run_at = Some(started_at + scheduled.interval);
+            scheduled.metrics.purged.add(run.purged as u64);
+            scheduled.metrics.durations.observe(took);
+            scheduled.metrics.truncated.set(run.truncated as i64);
+            if run.purged > 0 {
+                println!(
+                    "Purge job {}: {} purged in {:?}{}",
+                    scheduled.job.name(),
+                    run.purged,
+                    took,
+                    if run.truncated { ", stopped by budget" } else { "" }
+                );
+            }
+            runs.push((scheduled.job.name(), run));
+        }
+        runs
+    }
+
+    // Metrics of every registered job, by job name.
+    pub fn metrics(&self) -> Vec<(&'static str, Arc<JobMetrics>)> {
+        self.jobs.iter().map(|job| (job.job.name(), job.metrics.clone())).collect()
+    }
+}
+
+// Checks for due jobs every `tick` and runs them off the async threads, since they take store locks.
+pub async fn run_purger(mut purger: Purger, tick: Duration) {
+    let mut ticks = tokio::time::interval(tick);
+    loop {
+        ticks.tick().await;
+        if !purger.is_due() {
+            continue;
+        }
+        purger = match tokio::task::spawn_blocking(move || {
+            purger.run_due();
+            purger
+        })
+        .await
+        {
+            Ok(purger) => purger,
+            Err(e) => {
+                println!("Purger stopped, a job panicked: {}", e);
+                return;
+            }
+        };
+    }
+}
+
+// Drops sessions past their idle timeout or lifetime.
+pub struct ExpiredSessions(pub Arc<Mutex<dyn Sessions + Send + Sync>>);
+
+impl MaintenanceJob for ExpiredSessions {
+    fn name(&self) -> &'static str {
+        "sessions"
+    }
+
+    fn run(&self, budget: &PurgeBudget) -> PurgeRun {
+        self.0.lock().unwrap().purge_expired(budget)
+    }
+}
+
+// Drops username reservations whose window has passed.
+pub struct LapsedReservations(pub Arc<dyn Users + Send + Sync>);
+
+impl MaintenanceJob for LapsedReservations {
+    fn name(&self) -> &'static str {
+        "reservations"
+    }
+
+    fn run(&self, budget: &PurgeBudget) -> PurgeRun {
+        self.0.purge_reservations(budget)
+    }
+}
+
+// Drops expired invitation codes.
+pub struct LapsedInvitations(pub Arc<dyn Invitations + Send + Sync>);
+
+impl MaintenanceJob for LapsedInvitations {
+    fn name(&self) -> &'static str {
+        "invitations"
+    }
+
+    fn run(&self, budget: &PurgeBudget) -> PurgeRun {
+        self.0.purge_lapsed(budget)
+    }
+}
+
+// Forgets the devices of deleted and purged accounts.
+pub struct DeletedUserDevices(pub Arc<LoginNotifier>, pub Arc<dyn Users + Send + Sync>);
+
+impl MaintenanceJob for DeletedUserDevices {
+    fn name(&self) -> &'static str {
+        "login_devices"
+    }
+
+    fn run(&self, budget: &PurgeBudget) -> PurgeRun {
+        self.0.forget_deleted_users(&*self.1, budget)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    // Has `backlog` items to purge, each taking `per_item` on the clock.
+    struct Backlog {
+        name: &'static str,
+        backlog: Arc<Mutex<usize>>,
+        per_item: Duration,
+        clock: Arc<ManualClock>,
+    }
+
+    impl MaintenanceJob for Backlog {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn run(&self, budget: &PurgeBudget) -> PurgeRun {
+            let mut backlog = self.backlog.lock().unwrap();
+            let mut run = PurgeRun::default();
+            while *backlog > 0 {
+                if budget.spent(run.purged) {
+                    run.truncated = true;
+                    break;
+                }
+                self.clock.advance(self.per_item);
+                *backlog -= 1;
+                run.purged += 1;
+            }
+            run
+        }
+    }
+
+    fn job_metrics(purger: &Purger, name: &str) -> Arc<JobMetrics> {
+        purger.metrics().into_iter().find(|(job, _)| *job == name).unwrap().1
+    }
+
+    fn backlog(name: &'static str, items: usize, per_item: Duration, clock: &Arc<ManualClock>) -> (Backlog, Arc<Mutex<usize>>) {
+        let left = Arc::new(Mutex::new(items));
+        (Backlog { name, backlog: left.clone(), per_item, clock: clock.clone() }, left)
+    }
+
+    #[test]
+    fn should_run_each_job_on_its_own_interval() {
+        let clock = Arc::new(ManualClock::new());
+        let (fast, fast_left) = backlog("fast", 10, Duration::ZERO, &clock);
+        let (slow, slow_left) = backlog("slow", 10, Duration::ZERO, &clock);
+        let mut purger = Purger::new(1, SECOND)
+            .with_clock(clock.clone())
+            .with_job(fast, 10 * SECOND)
+            .with_job(slow, 60 * SECOND);
+
+        let names = |runs: Vec<(&'static str, PurgeRun)>| runs.into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+        assert_eq!(names(purger.run_due()), vec!["fast", "slow"]);
+        assert!(!purger.is_due());
+        clock.advance(10 * SECOND);
+        assert_eq!(names(purger.run_due()), vec!["fast"]);
+        clock.advance(50 * SECOND);
+        assert_eq!(names(purger.run_due()), vec!["fast", "slow"]);
+
+        assert_eq!((*fast_left.lock().unwrap(), *slow_left.lock().unwrap()), (7, 8));
+    }
+
+    #[test]
+    fn should_chip_away_at_backlog_within_item_budget() {
+        let clock = Arc::new(ManualClock::new());
+        let (job, left) = backlog("big", 250, Duration::ZERO, &clock);
+        let mut purger = Purger::new(100, SECOND).with_clock(clock.clone()).with_job(job, SECOND);
+
+        let mut runs = Vec::new();
+        for _ in 0..4 {
+            runs.extend(purger.run_due().into_iter().map(|(_, run)| run));
+            clock.advance(SECOND);
+        }
+
+        let run = |purged, truncated| PurgeRun { purged, truncated };
+        assert_eq!(runs, vec![run(100, true), run(100, true), run(50, false), run(0, false)]);
+        assert_eq!(*left.lock().unwrap(), 0);
+        let metrics = job_metrics(&purger, "big");
+        assert_eq!((metrics.purged.get(), metrics.truncated.get()), (250, 0));
+    }
+
+    #[test]
+    fn should_stop_job_that_runs_past_time_budget() {
+        let clock = Arc::new(ManualClock::new());
+        let (job, left) = backlog("slow", 1_000, Duration::from_millis(10), &clock);
+        let mut purger = Purger::new(usize::MAX, Duration::from_millis(50)).with_clock(clock.clone()).with_job(job, SECOND);
+
+        assert_eq!(purger.run_due(), vec![("slow", PurgeRun { purged: 5, truncated: true })]);
+        assert_eq!(*left.lock().unwrap(), 995);
+        let metrics = job_metrics(&purger, "slow");
+        assert_eq!((metrics.truncated.get(), metrics.durations.sum()), (1, Duration::from_millis(50)));
+    }
+}
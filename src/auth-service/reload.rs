@@ -0,0 +1,224 @@
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::banlist::UsernameBanList;
+use crate::config::{AuthConfig, ConfigSource};
+use crate::sessions::SessionLimits;
+use crate::tokens::KeySet;
+
+// Settings applied without a restart. Sessions already handed out keep the limits they were created with.
+pub const RELOADABLE: [&str; 3] = [
+    "AUTH_SESSION_TTL_SECS",
+    "AUTH_SESSION_IDLE_TIMEOUT_SECS",
+    "AUTH_SESSION_ABSOLUTE_LIFETIME_SECS",
+];
+
+#[derive(Debug, PartialEq)]
+pub struct ReloadReport {
+    // Settings that differ from the ones the service started with but only take effect after a restart.
+    pub ignored: Vec<String>,
+    pub keyset_reloaded: bool,
+    pub ban_list_reloaded: bool,
+}
+
+// Reads the configuration again (on SIGHUP or ReloadConfig) and applies the reloadable settings. The signing
+// keyset and username ban list files, when there are ones, are read again too.
+pub struct ConfigReloader {
+    started_with: ConfigSource,
+    load: Box<dyn Fn() -> Result<ConfigSource, String> + Send + Sync>,
+    session_limits: Arc<SessionLimits>,
+    keyset: Option<(String, Arc<RwLock<KeySet>>)>, // (path, keyset the signer uses)
+    ban_list: Option<(String, Arc<RwLock<UsernameBanList>>)>, // (path, list the users service checks)
+}
+
+impl ConfigReloader {
+    pub fn new(started_with: ConfigSource, session_limits: Arc<SessionLimits>) -> Self {
+        Self {
+            started_with,
+            load: Box::new(ConfigSource::load),
+            session_limits,
+            keyset: None,
+            ban_list: None,
+        }
+    }
+
+    pub fn with_keyset(mut self, path: String, keyset: Arc<RwLock<KeySet>>) -> Self {
+        self.keyset = Some((path, keyset));
+        self
+    }
+
+    pub fn with_ban_list(mut self, path: String, ban_list: Arc<RwLock<UsernameBanList>>) -> Self {
+        self.ban_list = Some((path, ban_list));
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_loader(mut self, load: impl Fn() -> Result<ConfigSource, String> + Send + Sync + 'static) -> Self {
+        self.load = Box::new(load);
+        self
+    }
+
+    // Nothing is applied unless the configuration and both files load.
+    pub fn reload(&self) -> Result<ReloadReport, String> {
+        let source = (self.load)()?;
+        let keyset = match &self.keyset {
+            Some((path, _)) => Some(KeySet::load(path)?),
+            None => None,
+        };
+        let ban_list = match &self.ban_list {
+            Some((path, _)) => Some(UsernameBanList::load(path)?),
+            None => None,
+        };
+
+        let config = AuthConfig::from_source(&source);
+        self.session_limits.set_token_ttl(Duration::from_secs(config.session_ttl_secs));
+        self.session_limits.set_idle_timeout(duration(config.session_idle_timeout_secs));
+        self.session_limits.set_absolute_lifetime(duration(config.session_absolute_lifetime_secs));
+
+        let keyset_reloaded = match (&self.keyset, keyset) {
+            (Some((_, current)), Some(keyset)) => {
+                *current.write().unwrap() = keyset;
+                true
+            }
+            _ => false,
+        };
+        let ban_list_reloaded = match (&self.ban_list, ban_list) {
+            (Some((_, current)), Some(ban_list)) => {
+                *current.write().unwrap() = ban_list;
+                true
+            }
+            _ => false,
+        };
+
+        // Compared with the startup values, so a pending change is reported on every reload until the restart.
+        let ignored: Vec<String> = self
+            .started_with
+            .changed(&source)
+            .into_iter()
+            .filter(|name| !RELOADABLE.contains(&name.as_str()))
+            .collect();
+        if !ignored.is_empty() {
+            println!("Changes to {} take effect after a restart", ignored.join(", "));
+        }
+
+        Ok(ReloadReport { ignored, keyset_reloaded, ban_list_reloaded })
+    }
+}
+
+// 0 disables a limit.
+fn duration(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+// Reloads every time the process receives SIGHUP. A configuration that fails to load is logged and the current
+// one is kept.
+pub async fn reload_on_sighup(reloader: Arc<ConfigReloader>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            println!("Failed to listen for SIGHUP, config reloading disabled: {e}");
+            return;
+        }
+    };
+
+    while hangups.recv().await.is_some() {
+        match reloader.reload() {
+            Ok(report) => println!("Reloaded config: {:?}", report),
+            Err(e) => println!("Keeping current config: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::sessions::{SessionError, Sessions, SessionsImpl};
+
+    // Reloader over a config "file" the test can rewrite.
+    fn reloader(started_with: &str, limits: Arc<SessionLimits>) -> (ConfigReloader, Arc<Mutex<String>>) {
+        let file = Arc::new(Mutex::new(started_with.to_owned()));
+        let current = file.clone();
+        let reloader = ConfigReloader::new(ConfigSource::parse(started_with).unwrap(), limits)
+            .with_loader(move || ConfigSource::parse(&current.lock().unwrap()));
+        (reloader, file)
+    }
+
+    #[test]
+    fn should_apply_reloaded_session_limits_to_new_sessions() {
+        let clock = Arc::new(crate::clock::ManualClock::new());
+        let mut sessions = SessionsImpl::default()
+            .with_absolute_lifetime(Some(Duration::from_secs(60 * 60)))
+            .with_clock(clock.clone());
+        let (reloader, file) = reloader("AUTH_SESSION_ABSOLUTE_LIFETIME_SECS=3600", sessions.limits());
+        let old_session = sessions.create_session("alice");
+
+        *file.lock().unwrap() = "AUTH_SESSION_ABSOLUTE_LIFETIME_SECS=600\nAUTH_SESSION_IDLE_TIMEOUT_SECS=0".to_owned();
+        let report = reloader.reload().unwrap();
+        let new_session = sessions.create_session("bob");
+
+        assert!(report.ignored.is_empty());
+        clock.advance(Duration::from_secs(15 * 60));
+        assert_eq!(sessions.check_session(&new_session), Err(SessionError::Expired));
+        assert_eq!(sessions.check_session(&old_session), Ok("alice".to_owned()));
+    }
+
+    #[test]
+    fn should_report_changes_needing_restart() {
+        let (reloader, file) = reloader("AUTH_HASH_ROUNDS=1000\nAUTH_SESSION_TTL_SECS=60", Arc::default());
+
+        *file.lock().unwrap() = "AUTH_HASH_ROUNDS=2000\nAUTH_SESSION_TTL_SECS=120\nAUTH_INVITE_ONLY=true".to_owned();
+        assert_eq!(
+            reloader.reload().unwrap().ignored,
+            vec!["AUTH_HASH_ROUNDS", "AUTH_INVITE_ONLY"]
+        );
+        // Still pending on the next reload.
+        assert_eq!(reloader.reload().unwrap().ignored.len(), 2);
+    }
+
+    #[test]
+    fn should_keep_current_limits_when_config_fails_to_load() {
+        let clock = Arc::new(crate::clock::ManualClock::new());
+        let mut sessions = SessionsImpl::default()
+            .with_idle_timeout(Some(Duration::from_secs(60)))
+            .with_clock(clock.clone());
+        let (reloader, file) = reloader("AUTH_SESSION_IDLE_TIMEOUT_SECS=60", sessions.limits());
+
+        *file.lock().unwrap() = "not a setting".to_owned();
+        assert!(reloader.reload().is_err());
+
+        let session = sessions.create_session("alice");
+        clock.advance(Duration::from_secs(2 * 60));
+        assert_eq!(sessions.check_session(&session), Err(SessionError::IdleTimeout));
+    }
+
+    #[test]
+    fn should_not_apply_anything_when_keyset_fails_to_load() {
+        let keyset = Arc::new(RwLock::new(KeySet::single(b"current key")));
+        let (reloader, _) = reloader("AUTH_SESSION_TTL_SECS=60", Arc::default());
+        let reloader = reloader.with_keyset("/nonexistent/keyset".to_owned(), keyset);
+
+        assert!(reloader.reload().unwrap_err().contains("/nonexistent/keyset"));
+    }
+
+    #[test]
+    fn should_pick_up_new_ban_list_entries() {
+        let path = std::env::temp_dir().join(format!("banlist-{}", std::process::id()));
+        std::fs::write(&path, "exact acme\n").unwrap();
+        let ban_list = Arc::new(RwLock::new(UsernameBanList::load(path.to_str().unwrap()).unwrap()));
+        let (reloader, _) = reloader("AUTH_SESSION_TTL_SECS=60", Arc::default());
+        let reloader = reloader.with_ban_list(path.to_str().unwrap().to_owned(), ban_list.clone());
+        assert!(!ban_list.read().unwrap().is_banned("widgetco"));
+
+        std::fs::write(&path, "exact acme\nprefix widget\n").unwrap();
+        let report = reloader.reload().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(report.ban_list_reloaded);
+        assert!(ban_list.read().unwrap().is_banned("widgetco"));
+        assert!(ban_list.read().unwrap().is_banned("acme"));
+    }
+}
@@ -0,0 +1,170 @@
+use std::fmt;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand_core::{OsRng, RngCore};
+
+use crate::binding::ClientInfo;
+use crate::password::Password;
+use crate::sessions::Sessions;
+use crate::users::Users;
+
+// Outcome of each step that ran, in order. Steps after a failure are skipped, cleanup always runs.
+#[derive(Debug, Default, PartialEq)]
+pub struct SelfTestReport(pub Vec<(&'static str, Result<(), String>)>);
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.0.iter().all(|(_, result)| result.is_ok())
+    }
+
+    fn record(&mut self, step: &'static str, result: Result<(), String>) -> bool {
+        let ok = result.is_ok();
+        self.0.push((step, result));
+        ok
+    }
+}
+
+// "self_test result=fail create_account=pass sign_in=fail("wrong uuid") cleanup=pass"
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "self_test result={}", if self.passed() { "pass" } else { "fail" })?;
+        for (step, result) in &self.0 {
+            match result {
+                Ok(()) => write!(f, " {}=pass", step)?,
+                Err(e) => write!(f, " {}=fail({:?})", step, e)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Signs a throwaway account up, in and out through the configured services, so broken hashing parameters or
+// signing keys show at deploy time instead of on the first real sign in, then checks the store's indices. Hashes with the production parameters,
+// so it takes as long as a real SignUp and SignIn.
+//
+// The account starts as a guest, so its uuid is known before anything can fail and it is always deleted. Its
+// events and the reservation of its random username stay in `users`, so run this on an instance of its own.
+pub fn run_self_test(users: &(dyn Users + Send + Sync), sessions: &mut (dyn Sessions + Send + Sync)) -> SelfTestReport {
+    let mut report = SelfTestReport::default();
+    let username = format!("self-test-{}", random_string(12));
+    let password = Password::from(random_string(48));
+    let user_uuid = users.create_guest();
+
+    let created = users.upgrade_guest(user_uuid.clone(), username.clone(), password.clone());
+    if report.record("create_account", created.map_err(|e| e.to_string()))
+        && report.record("sign_in", check_sign_in(users, &user_uuid, username, password))
+    {
+        report.record("session", check_session(sessions, &user_uuid));
+    }
+
+    sessions.delete_session(&user_uuid);
+    users.delete_user(user_uuid.clone());
+    report.record(
+        "cleanup",
+        match users.get_user(&user_uuid) {
+            None => Ok(()),
+            Some(_) => Err("account still stored".to_owned()),
+        },
+    );
+    // Last, so indices the steps above left inconsistent show up too.
+    if let Some(integrity) = users.check_store_integrity() {
+        report.record("store_integrity", if integrity.0.is_empty() { Ok(()) } else { Err(integrity.to_string()) });
+    }
+    report
+}
+
+fn check_sign_in(users: &(dyn Users + Send + Sync), user_uuid: &str, username: String, password: Password) -> Result<(), String> {
+    match users.get_user_uuid(username, password) {
+        Some(found) if found == user_uuid => Ok(()),
+        Some(_) => Err("signed in to another account".to_owned()),
+        None => Err("password not accepted".to_owned()),
+    }
+}
+
+fn check_session(sessions: &mut (dyn Sessions + Send + Sync), user_uuid: &str) -> Result<(), String> {
+    let client = ClientInfo::default();
+    let session_token = sessions.create_session_for(user_uuid, &client);
+    match sessions.check_session_from(&session_token, &client) {
+        Ok(found) if found == user_uuid => Ok(()),
+        Ok(_) => Err("session belongs to another account".to_owned()),
+        Err(e) => Err(format!("session not accepted: {:?}", e)),
+    }
+}
+
+fn random_string(bytes: usize) -> String {
+    let mut buffer = vec![0; bytes];
+    OsRng.fill_bytes(&mut buffer);
+    URL_SAFE_NO_PAD.encode(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sessions::SessionsImpl;
+    use crate::store::{AccountKind, MemoryUserStore, StoreError, User, UserCounts, UserStore, UsernameScope};
+    use crate::users::UsersImpl;
+
+    // Reads and deletes work, writes after the first insert fail, like a database that lost write access.
+    #[derive(Default)]
+    struct ReadOnlyStore(MemoryUserStore);
+
+    impl UserStore for ReadOnlyStore {
+        fn insert(&self, user: User) -> Result<(), StoreError> {
+            self.0.insert(user)
+        }
+        fn username_scope(&self) -> UsernameScope {
+            self.0.username_scope()
+        }
+        fn get_by_username(&self, kind: AccountKind, username: &str) -> Option<User> {
+            self.0.get_by_username(kind, username)
+        }
+        fn get_by_skeleton(&self, kind: AccountKind, username_skeleton: &str) -> Option<User> {
+            self.0.get_by_skeleton(kind, username_skeleton)
+        }
+        fn get_by_email(&self, email: &str) -> Option<User> {
+            self.0.get_by_email(email)
+        }
+        fn get_by_uuid(&self, user_uuid: &str) -> Option<User> {
+            self.0.get_by_uuid(user_uuid)
+        }
+        fn remove(&self, user_uuid: &str) -> Option<User> {
+            self.0.remove(user_uuid)
+        }
+        fn users(&self) -> Vec<User> {
+            self.0.users()
+        }
+        fn update(&self, _user: User) -> Result<(), StoreError> {
+            Err(StoreError::NotFound)
+        }
+        fn counts(&self) -> UserCounts {
+            self.0.counts()
+        }
+    }
+
+    #[test]
+    fn should_pass_and_leave_nothing_behind() {
+        let users = UsersImpl::with_hash_rounds(1_000);
+        let mut sessions = SessionsImpl::default();
+
+        let report = run_self_test(&users, &mut sessions);
+
+        assert!(report.passed(), "{}", report);
+        let steps: Vec<&str> = report.0.iter().map(|(step, _)| *step).collect();
+        assert_eq!(steps, vec!["create_account", "sign_in", "session", "cleanup", "store_integrity"]);
+        assert_eq!((users.stats().users, users.stats().guests), (0, 0));
+    }
+
+    #[test]
+    fn should_fail_and_still_clean_up_with_broken_store() {
+        let users = UsersImpl::with_store(ReadOnlyStore::default(), 1_000);
+        let mut sessions = SessionsImpl::default();
+
+        let report = run_self_test(&users, &mut sessions);
+
+        assert!(!report.passed());
+        assert!(report.0[0].1.is_err());
+        assert_eq!(report.0.last(), Some(&("cleanup", Ok(()))));
+        assert!(report.to_string().starts_with("self_test result=fail create_account=fail("));
+        assert_eq!((users.stats().users, users.stats().guests), (0, 0));
+    }
+}
@@ -1,36 +1,393 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use uuid::Uuid;
+use crate::binding::{BindingMode, ClientInfo, Fingerprint, SessionBinding};
+use crate::clock::{Clock, SystemClock};
+use crate::purger::{PurgeBudget, PurgeRun};
+use crate::tokens::TokenSigner;
+use crate::uuids::{UuidGenerator, V4Generator};
+
+#[derive(Debug, PartialEq)]
+pub enum SessionError {
+    Unknown,         // Never issued, signed out, replaced by a newer session or tampered with.
+    IdleTimeout,     // Unused for longer than the idle timeout.
+    Expired,         // Older than the absolute lifetime, however much it was used.
+    BindingMismatch, // Presented by a different client than the one it was created for, with binding enforced.
+    Stale,           // Still valid, but the password was last proven longer ago than the operation allows.
+}
 
 pub trait Sessions {
-    fn create_session(&mut self, user_uuid: &str) -> String;
+    // `client` is who signed in. The session is bound to it when session binding is on.
+    fn create_session_for(&mut self, user_uuid: &str, client: &ClientInfo) -> String;
     fn delete_session(&mut self, user_uuid: &str);
+    // Returns the session's user and marks the session as used, or says why it can't be used. `client` is who
+    // presented the token.
+    fn check_session_from(&mut self, session_token: &str, client: &ClientInfo) -> Result<String, SessionError>;
+    // As `check_session_from`, and also refuses a session whose password was last proven more than `max_age` ago.
+    // None accepts any session.
+    fn check_fresh_session_from(&mut self, session_token: &str, client: &ClientInfo, max_age: Option<Duration>) -> Result<String, SessionError>;
+    // Records that the password was just proven again on the session.
+    fn record_reauthentication(&mut self, session_token: &str) -> Result<(), SessionError>;
+
+    // For tests that don't care about the client. A bound session never matches an unknown client.
+    #[cfg(test)]
+    fn create_session(&mut self, user_uuid: &str) -> String {
+        self.create_session_for(user_uuid, &ClientInfo::default())
+    }
+
+    #[cfg(test)]
+    fn check_session(&mut self, session_token: &str) -> Result<String, SessionError> {
+        self.check_session_from(session_token, &ClientInfo::default())
+    }
+
+    #[cfg(test)]
+    fn get_user_uuid_for_session(&mut self, session_token: &str) -> Option<String> {
+        self.check_session(session_token).ok()
+    }
+
+    // Counts of the sessions held, without looking at each one.
+    fn stats(&self) -> SessionStats;
+
+    // Drops sessions past a limit, as many as `budget` allows.
+    fn purge_expired(&mut self, budget: &PurgeBudget) -> PurgeRun;
+
+    // The user's session as support may see it, without the token. None when they have none.
+    fn session_report(&self, user_uuid: &str) -> Option<SessionReport>;
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionReport {
+    pub created_at: SystemTime,
+    pub last_seen_at: SystemTime,
+    pub ends_at: Option<SystemTime>, // When it stops working unless it's used before then.
+    pub live: bool,
+    pub signed: bool,
+    pub fingerprint: Option<Fingerprint>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SessionStats {
+    pub live: usize,
+    pub expired: usize, // Past a limit but still held until sign out, replacement or the next purge.
+}
+
+struct Session {
+    token: String,
+    created_at: SystemTime,
+    last_seen_at: SystemTime, // Last successful check.
+    // Last time the password was proven: sign in, or reauthentication since. A guest's session counts from creation,
+    // there is no password to prove.
+    authenticated_at: SystemTime,
+    // Limits in force when the session was created, so changing them doesn't affect sessions already handed out.
+    idle_timeout: Option<Duration>,
+    expires_at: Option<SystemTime>,
+    token_expires_at: Option<SystemTime>, // Signed sessions only.
+    fingerprint: Option<Fingerprint>, // Client the session was created for, when binding was on.
+}
+
+impl Session {
+    // When the session stops working unless it's used before then.
+    fn dead_at(&self) -> Option<SystemTime> {
+        let idle_at = self.idle_timeout.map(|idle_timeout| self.last_seen_at + idle_timeout);
+        [idle_at, self.expires_at, self.token_expires_at].into_iter().flatten().min()
+    }
+}
+
+// Limits for sessions created from now on. Shared with the config reloader, so they can change while the service
+// runs; handlers only ever load them. Whole seconds, 0 meaning no limit.
 #[derive(Default)]
+pub struct SessionLimits {
+    token_ttl_secs: AtomicU64, // Only used with a signer.
+    idle_timeout_secs: AtomicU64,
+    absolute_lifetime_secs: AtomicU64,
+}
+
+impl SessionLimits {
+    pub fn set_token_ttl(&self, ttl: Duration) {
+        store_secs(&self.token_ttl_secs, Some(ttl));
+    }
+
+    pub fn set_idle_timeout(&self, idle_timeout: Option<Duration>) {
+        store_secs(&self.idle_timeout_secs, idle_timeout);
+    }
+
+    pub fn set_absolute_lifetime(&self, absolute_lifetime: Option<Duration>) {
+        store_secs(&self.absolute_lifetime_secs, absolute_lifetime);
+    }
+
+    fn token_ttl(&self) -> Duration {
+        load_secs(&self.token_ttl_secs).unwrap_or_default()
+    }
+
+    fn idle_timeout(&self) -> Option<Duration> {
+        load_secs(&self.idle_timeout_secs)
+    }
+
+    fn absolute_lifetime(&self) -> Option<Duration> {
+        load_secs(&self.absolute_lifetime_secs)
+    }
+}
+
+fn store_secs(secs: &AtomicU64, value: Option<Duration>) {
+    secs.store(value.map_or(0, |value| value.as_secs()), Ordering::Relaxed);
+}
+
+fn load_secs(secs: &AtomicU64) -> Option<Duration> {
+    Some(secs.load(Ordering::Relaxed)).filter(|secs| *secs > 0).map(Duration::from_secs)
+}
+
 pub struct SessionsImpl {
-    uuid_to_session: HashMap<String, String>,
+    uuid_to_session: HashMap<String, Session>,
+    // When set, sessions are HMAC-signed tokens instead of random UUIDs.
+    signer: Option<TokenSigner>,
+    limits: Arc<SessionLimits>,
+    // How many sessions stop working at each instant, so expired sessions can be counted without a scan. Sessions
+    // without any limit aren't in here.
+    deadlines: BTreeMap<SystemTime, usize>,
+    clock: Arc<dyn Clock>,
+    uuids: Arc<dyn UuidGenerator>, // Ids of unsigned sessions.
+    binding: SessionBinding,
+}
+
+impl Default for SessionsImpl {
+    fn default() -> Self {
+        Self {
+            uuid_to_session: HashMap::new(),
+            signer: None,
+            limits: Arc::new(SessionLimits::default()),
+            deadlines: BTreeMap::new(),
+            clock: Arc::new(SystemClock),
+            uuids: Arc::new(V4Generator),
+            binding: SessionBinding::default(),
+        }
+    }
+}
+
+impl SessionsImpl {
+    pub fn with_signer(signer: TokenSigner) -> Self {
+        let sessions = Self::default();
+        sessions.limits.set_token_ttl(signer.ttl());
+        Self {
+            signer: Some(signer),
+            ..sessions
+        }
+    }
+
+    // Sessions unused for this long stop working.
+    pub fn with_idle_timeout(self, idle_timeout: Option<Duration>) -> Self {
+        self.limits.set_idle_timeout(idle_timeout);
+        self
+    }
+
+    // Sessions stop working this long after sign in, however much they are used. Independent of the signed token
+    // TTL, and applies to unsigned sessions too.
+    pub fn with_absolute_lifetime(self, absolute_lifetime: Option<Duration>) -> Self {
+        self.limits.set_absolute_lifetime(absolute_lifetime);
+        self
+    }
+
+    // Handle for changing the limits of new sessions while the service is running.
+    pub fn limits(&self) -> Arc<SessionLimits> {
+        self.limits.clone()
+    }
+
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_uuid_generator(mut self, uuids: Arc<dyn UuidGenerator>) -> Self {
+        self.uuids = uuids;
+        self
+    }
+
+    // Off unless set.
+    pub fn with_binding(mut self, binding: SessionBinding) -> Self {
+        self.binding = binding;
+        self
+    }
+
+    fn find_user_uuid(&self, session_token: &str) -> Option<String> {
+        match &self.signer {
+            Some(signer) => {
+                // Signature and expiry are checked before the map is touched; the map is only needed to catch
+                // sessions that were signed out.
+                let user_uuid = signer.verify(session_token).ok()?;
+                (self.uuid_to_session.get(&user_uuid)?.token == session_token).then_some(user_uuid)
+            }
+            None => self
+                .uuid_to_session
+                .iter()
+                .find(|(_, session)| session.token == session_token)
+                .map(|(user_uuid, _)| user_uuid.clone()),
+        }
+    }
 }
 
 impl Sessions for SessionsImpl {
-    fn create_session(&mut self, user_uuid: &str) -> String {
-        let session: String = Uuid::new_v4().to_string(); // Create a new session using Uuid::new_v4().
+    fn create_session_for(&mut self, user_uuid: &str, client: &ClientInfo) -> String {
+        let session: String = match &self.signer {
+            Some(signer) => signer.issue_for(user_uuid, self.limits.token_ttl()),
+            None => self.uuids.generate().to_string(),
+        };
 
         // TODO: Insert session into `uuid_to_session`.
-        self.uuid_to_session.insert(user_uuid.to_string(), session.clone());
+        let now = self.clock.now();
+        let new_session = Session {
+            token: session.clone(),
+            created_at: now,
+            last_seen_at: now,
+            authenticated_at: now,
+            idle_timeout: self.limits.idle_timeout(),
+            expires_at: self.limits.absolute_lifetime().map(|lifetime| now + lifetime),
+            token_expires_at: self.signer.as_ref().map(|_| now + self.limits.token_ttl()),
+            fingerprint: self.binding.fingerprint(client),
+        };
+        track(&mut self.deadlines, new_session.dead_at());
+        if let Some(replaced) = self.uuid_to_session.insert(user_uuid.to_string(), new_session) {
+            untrack(&mut self.deadlines, replaced.dead_at());
+        }
 
         session
     }
 
     fn delete_session(&mut self, user_uuid: &str) {
         // TODO: Delete session from `uuid_to_session`.
-        self.uuid_to_session.remove(user_uuid);
+        if let Some(session) = self.uuid_to_session.remove(user_uuid) {
+            untrack(&mut self.deadlines, session.dead_at());
+        }
+    }
+
+    fn check_session_from(&mut self, session_token: &str, client: &ClientInfo) -> Result<String, SessionError> {
+        let user_uuid = self.find_user_uuid(session_token).ok_or(SessionError::Unknown)?;
+        let now = self.clock.now();
+        let session = self.uuid_to_session.get_mut(&user_uuid).ok_or(SessionError::Unknown)?;
+
+        let idle_since = session.idle_timeout.map(|idle_timeout| session.last_seen_at + idle_timeout).filter(|at| now >= *at);
+        let expired_since = session.expires_at.filter(|at| now >= *at);
+
+        // When both limits have passed, report the one that was reached first.
+        match (idle_since, expired_since) {
+            (Some(idle_since), Some(expired_since)) if idle_since < expired_since => return Err(SessionError::IdleTimeout),
+            (_, Some(_)) => return Err(SessionError::Expired),
+            (Some(_), None) => return Err(SessionError::IdleTimeout),
+            (None, None) => {}
+        }
+
+        let mismatch = session.fingerprint.is_some_and(|bound_to| self.binding.fingerprint(client) != Some(bound_to));
+        if mismatch {
+            self.binding.record_mismatch(&user_uuid);
+            if self.binding.mode() == BindingMode::Enforce {
+                if self.binding.revoke_on_mismatch() {
+                    self.delete_session(&user_uuid);
+                }
+                return Err(SessionError::BindingMismatch);
+            }
+        }
+
+        untrack(&mut self.deadlines, session.dead_at());
+        session.last_seen_at = now;
+        track(&mut self.deadlines, session.dead_at());
+        Ok(user_uuid)
+    }
+
+    fn check_fresh_session_from(&mut self, session_token: &str, client: &ClientInfo, max_age: Option<Duration>) -> Result<String, SessionError> {
+        let user_uuid = self.check_session_from(session_token, client)?;
+        let authenticated_at = self.uuid_to_session[&user_uuid].authenticated_at;
+        let age = self.clock.now().duration_since(authenticated_at).unwrap_or_default();
+        if max_age.is_some_and(|max_age| age > max_age) {
+            return Err(SessionError::Stale);
+        }
+        Ok(user_uuid)
+    }
+
+    fn record_reauthentication(&mut self, session_token: &str) -> Result<(), SessionError> {
+        let user_uuid = self.find_user_uuid(session_token).ok_or(SessionError::Unknown)?;
+        let now = self.clock.now();
+        self.uuid_to_session.get_mut(&user_uuid).ok_or(SessionError::Unknown)?.authenticated_at = now;
+        Ok(())
+    }
+
+    fn stats(&self) -> SessionStats {
+        let now = self.clock.now();
+        // Only walks the deadlines already passed, one entry per distinct instant.
+        let expired = self.deadlines.range(..=now).map(|(_, count)| count).sum();
+        SessionStats {
+            live: self.uuid_to_session.len() - expired,
+            expired,
+        }
+    }
+
+    fn session_report(&self, user_uuid: &str) -> Option<SessionReport> {
+        let session = self.uuid_to_session.get(user_uuid)?;
+        let ends_at = session.dead_at();
+        Some(SessionReport {
+            created_at: session.created_at,
+            last_seen_at: session.last_seen_at,
+            ends_at,
+            live: ends_at.is_none_or(|at| at > self.clock.now()),
+            signed: session.token_expires_at.is_some(),
+            fingerprint: session.fingerprint,
+        })
+    }
+
+    fn purge_expired(&mut self, budget: &PurgeBudget) -> PurgeRun {
+        let now = self.clock.now();
+        let mut run = PurgeRun::default();
+        // The deadlines tell cheaply whether there is anything to find.
+        if self.deadlines.range(..=now).next().is_none() {
+            return run;
+        }
+
+        let expired: Vec<String> = self
+            .uuid_to_session
+            .iter()
+            .filter(|(_, session)| session.dead_at().is_some_and(|at| at <= now))
+            .map(|(user_uuid, _)| user_uuid.clone())
+            .collect();
+        for user_uuid in expired {
+            if budget.spent(run.purged) {
+                run.truncated = true;
+                break;
+            }
+            self.delete_session(&user_uuid);
+            run.purged += 1;
+        }
+        run
+    }
+}
+
+fn track(deadlines: &mut BTreeMap<SystemTime, usize>, at: Option<SystemTime>) {
+    if let Some(at) = at {
+        *deadlines.entry(at).or_default() += 1;
+    }
+}
+
+fn untrack(deadlines: &mut BTreeMap<SystemTime, usize>, at: Option<SystemTime>) {
+    if let Some(at) = at {
+        if let Some(count) = deadlines.get_mut(&at) {
+            *count -= 1;
+            if *count == 0 {
+                deadlines.remove(&at);
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use super::*;
+    use crate::clock::ManualClock;
+    use crate::tokens::KeySet;
+
+    fn signed_sessions() -> SessionsImpl {
+        SessionsImpl::with_signer(TokenSigner::new(KeySet::single(b"test signing key"), Duration::from_secs(60)))
+    }
 
     #[test]
     fn should_create_session() {
@@ -38,7 +395,7 @@ mod tests {
         assert_eq!(session_service.uuid_to_session.len(), 0);
         let session = session_service.create_session("123456");
         assert_eq!(session_service.uuid_to_session.len(), 1);
-        assert_eq!(session_service.uuid_to_session.get("123456").unwrap(), &session);
+        assert_eq!(session_service.uuid_to_session.get("123456").unwrap().token, session);
     }
 
     #[test]
@@ -48,4 +405,297 @@ mod tests {
         session_service.delete_session("123456");
         assert_eq!(session_service.uuid_to_session.len(), 0);
     }
+
+    #[test]
+    fn should_get_user_uuid_for_session() {
+        let mut session_service = SessionsImpl::default();
+        let session = session_service.create_session("123456");
+        assert_eq!(session_service.get_user_uuid_for_session(&session), Some("123456".to_owned()));
+        assert_eq!(session_service.get_user_uuid_for_session("unknown"), None);
+    }
+
+    #[test]
+    fn should_get_user_uuid_for_signed_session() {
+        let mut session_service = signed_sessions();
+        let session = session_service.create_session("123456");
+        assert!(uuid::Uuid::parse_str(&session).is_err());
+        assert_eq!(session_service.get_user_uuid_for_session(&session), Some("123456".to_owned()));
+    }
+
+    #[test]
+    fn should_reject_signed_session_from_other_key_even_if_stored() {
+        let mut session_service = signed_sessions();
+        session_service.create_session("123456");
+
+        // A forged token for a user with a live session must fail on the signature, not match the map.
+        let forged = TokenSigner::new(KeySet::single(b"other key"), Duration::from_secs(60)).issue("123456");
+        session_service.uuid_to_session.get_mut("123456").unwrap().token = forged.clone();
+
+        assert_eq!(session_service.get_user_uuid_for_session(&forged), None);
+    }
+
+    #[test]
+    fn should_reject_revoked_signed_session() {
+        let mut session_service = signed_sessions();
+        let session = session_service.create_session("123456");
+        session_service.delete_session("123456");
+
+        assert_eq!(session_service.get_user_uuid_for_session(&session), None);
+    }
+
+    #[test]
+    fn should_reject_replaced_signed_session() {
+        let mut session_service = signed_sessions();
+        let old_session = session_service.create_session("123456");
+        let new_session = session_service.create_session("123456");
+
+        assert_eq!(session_service.get_user_uuid_for_session(&old_session), None);
+        assert_eq!(session_service.get_user_uuid_for_session(&new_session), Some("123456".to_owned()));
+    }
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn limited_sessions(idle_timeout: Option<Duration>, absolute_lifetime: Option<Duration>) -> (SessionsImpl, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new());
+        let sessions = SessionsImpl::default()
+            .with_idle_timeout(idle_timeout)
+            .with_absolute_lifetime(absolute_lifetime)
+            .with_clock(clock.clone());
+        (sessions, clock)
+    }
+
+    #[test]
+    fn should_end_session_left_idle() {
+        let (mut session_service, clock) = limited_sessions(Some(30 * MINUTE), None);
+        let session = session_service.create_session("123456");
+
+        clock.advance(29 * MINUTE);
+        assert_eq!(session_service.check_session(&session), Ok("123456".to_owned()));
+        clock.advance(29 * MINUTE); // Idle for 29 minutes since the last check.
+        assert_eq!(session_service.check_session(&session), Ok("123456".to_owned()));
+
+        clock.advance(30 * MINUTE);
+        assert_eq!(session_service.check_session(&session), Err(SessionError::IdleTimeout));
+    }
+
+    #[test]
+    fn should_end_session_at_absolute_lifetime_however_active() {
+        let (mut session_service, clock) = limited_sessions(None, Some(12 * 60 * MINUTE));
+        let session = session_service.create_session("123456");
+
+        for _ in 0..(12 * 60 / 10 - 1) {
+            clock.advance(10 * MINUTE);
+            assert_eq!(session_service.check_session(&session), Ok("123456".to_owned()));
+        }
+
+        clock.advance(10 * MINUTE);
+        assert_eq!(session_service.check_session(&session), Err(SessionError::Expired));
+    }
+
+    #[test]
+    fn should_apply_changed_limits_to_new_sessions_only() {
+        let (mut session_service, clock) = limited_sessions(Some(30 * MINUTE), Some(60 * MINUTE));
+        let old_session = session_service.create_session("alice");
+
+        let limits = session_service.limits();
+        limits.set_idle_timeout(None);
+        limits.set_absolute_lifetime(Some(10 * MINUTE));
+        let new_session = session_service.create_session("bob");
+
+        clock.advance(15 * MINUTE);
+        assert_eq!(session_service.check_session(&new_session), Err(SessionError::Expired));
+        assert_eq!(session_service.check_session(&old_session), Ok("alice".to_owned()));
+
+        // Still under the idle timeout it started with.
+        clock.advance(30 * MINUTE);
+        assert_eq!(session_service.check_session(&old_session), Err(SessionError::IdleTimeout));
+    }
+
+    #[test]
+    fn should_sign_new_sessions_with_changed_ttl() {
+        let mut session_service = signed_sessions();
+        session_service.limits().set_token_ttl(Duration::from_secs(1));
+
+        let session = session_service.create_session("123456");
+        assert_eq!(session_service.get_user_uuid_for_session(&session), Some("123456".to_owned()));
+        std::thread::sleep(Duration::from_millis(2_100)); // Expiry has a one second resolution.
+        assert_eq!(session_service.get_user_uuid_for_session(&session), None);
+    }
+
+    #[test]
+    fn should_count_expired_sessions_as_they_go_idle() {
+        let (mut session_service, clock) = limited_sessions(Some(30 * MINUTE), None);
+        let busy = session_service.create_session("busy user");
+        session_service.create_session("idle user");
+        session_service.create_session("signed out user");
+        session_service.delete_session("signed out user");
+
+        clock.advance(20 * MINUTE);
+        session_service.check_session(&busy).unwrap(); // Pushes its deadline to minute 50.
+        clock.advance(20 * MINUTE);
+        assert_eq!(session_service.stats(), SessionStats { live: 1, expired: 1 });
+
+        clock.advance(10 * MINUTE);
+        assert_eq!(session_service.stats(), SessionStats { live: 0, expired: 2 });
+        assert!(session_service.check_session(&busy).is_err()); // Failed checks don't revive it.
+        assert_eq!(session_service.stats(), SessionStats { live: 0, expired: 2 });
+    }
+
+    #[test]
+    fn should_purge_only_expired_sessions_within_budget() {
+        let (mut session_service, clock) = limited_sessions(Some(30 * MINUTE), None);
+        for user_uuid in ["a", "b", "c"] {
+            session_service.create_session(user_uuid);
+        }
+        clock.advance(10 * MINUTE);
+        let live = session_service.create_session("live user");
+        assert_eq!(session_service.purge_expired(&PurgeBudget::unlimited()), PurgeRun::default());
+
+        clock.advance(20 * MINUTE); // Exactly at the idle timeout of the first three.
+        let budget = PurgeBudget::new(2, Duration::from_secs(60), clock.clone());
+        assert_eq!(session_service.purge_expired(&budget), PurgeRun { purged: 2, truncated: true });
+        assert_eq!(session_service.stats(), SessionStats { live: 1, expired: 1 });
+        assert_eq!(session_service.purge_expired(&budget), PurgeRun { purged: 1, truncated: false });
+        assert_eq!(session_service.stats(), SessionStats { live: 1, expired: 0 });
+        assert_eq!(session_service.check_session(&live), Ok("live user".to_owned()));
+    }
+
+    #[test]
+    fn should_report_session_without_token() {
+        let (mut session_service, clock) = limited_sessions(Some(30 * MINUTE), None);
+        assert_eq!(session_service.session_report("123456"), None);
+        session_service.create_session("123456");
+        let created_at = clock.now();
+
+        clock.advance(10 * MINUTE);
+        let report = session_service.session_report("123456").unwrap();
+        assert_eq!((report.created_at, report.last_seen_at), (created_at, created_at));
+        assert_eq!(report.ends_at, Some(created_at + 30 * MINUTE));
+        assert!(report.live && !report.signed);
+
+        clock.advance(20 * MINUTE);
+        assert!(!session_service.session_report("123456").unwrap().live);
+    }
+
+    #[test]
+    fn should_report_limit_reached_first() {
+        // Idle since minute 30, while the lifetime ends at minute 60.
+        let (mut session_service, clock) = limited_sessions(Some(30 * MINUTE), Some(60 * MINUTE));
+        let session = session_service.create_session("123456");
+        clock.advance(90 * MINUTE);
+        assert_eq!(session_service.check_session(&session), Err(SessionError::IdleTimeout));
+
+        // Used at minutes 25 and 50, so it would only go idle at minute 80, after the lifetime ends.
+        let session = session_service.create_session("123456");
+        for _ in 0..2 {
+            clock.advance(25 * MINUTE);
+            session_service.check_session(&session).unwrap();
+        }
+        clock.advance(20 * MINUTE);
+        assert_eq!(session_service.check_session(&session), Err(SessionError::Expired));
+    }
+
+    #[test]
+    fn should_not_count_failed_checks_as_activity() {
+        let (mut session_service, clock) = limited_sessions(Some(30 * MINUTE), None);
+        let session = session_service.create_session("123456");
+
+        clock.advance(31 * MINUTE);
+        assert_eq!(session_service.check_session(&session), Err(SessionError::IdleTimeout));
+        assert_eq!(session_service.check_session(&session), Err(SessionError::IdleTimeout));
+        assert_eq!(session_service.check_session("unknown"), Err(SessionError::Unknown));
+    }
+
+    #[test]
+    fn should_require_recent_password_proof_for_fresh_sessions() {
+        let (mut session_service, clock) = limited_sessions(Some(30 * MINUTE), None);
+        let session = session_service.create_session("123456");
+        let fresh = |session_service: &mut SessionsImpl, max_age| {
+            session_service.check_fresh_session_from(&session, &ClientInfo::default(), max_age)
+        };
+
+        clock.advance(5 * MINUTE);
+        assert_eq!(fresh(&mut session_service, Some(5 * MINUTE)), Ok("123456".to_owned()));
+        clock.advance(MINUTE);
+        assert_eq!(fresh(&mut session_service, Some(5 * MINUTE)), Err(SessionError::Stale));
+        assert_eq!(fresh(&mut session_service, Some(10 * MINUTE)), Ok("123456".to_owned()));
+        assert_eq!(fresh(&mut session_service, None), Ok("123456".to_owned()));
+        // Using the session doesn't make it fresh, proving the password does.
+        assert_eq!(session_service.check_session(&session), Ok("123456".to_owned()));
+        assert_eq!(fresh(&mut session_service, Some(5 * MINUTE)), Err(SessionError::Stale));
+
+        session_service.record_reauthentication(&session).unwrap();
+        assert_eq!(fresh(&mut session_service, Some(5 * MINUTE)), Ok("123456".to_owned()));
+        assert_eq!(session_service.record_reauthentication("unknown"), Err(SessionError::Unknown));
+
+        // Limits of the session itself still come first.
+        clock.advance(31 * MINUTE);
+        assert_eq!(fresh(&mut session_service, Some(5 * MINUTE)), Err(SessionError::IdleTimeout));
+    }
+
+    fn client(user_agent: &str) -> ClientInfo {
+        ClientInfo {
+            user_agent: Some(user_agent.to_owned()),
+            device_id: Some("phone".to_owned()),
+        }
+    }
+
+    fn bound_sessions(binding: SessionBinding) -> SessionsImpl {
+        SessionsImpl::default().with_binding(binding.with_components(true, true))
+    }
+
+    #[test]
+    fn should_ignore_client_when_binding_is_off() {
+        let mut session_service = bound_sessions(SessionBinding::new(BindingMode::Off));
+        let session = session_service.create_session_for("123456", &client("Firefox"));
+
+        assert_eq!(session_service.uuid_to_session["123456"].fingerprint, None);
+        assert_eq!(session_service.check_session_from(&session, &client("curl")), Ok("123456".to_owned()));
+        assert_eq!(session_service.binding.mismatches(), 0);
+    }
+
+    #[test]
+    fn should_only_log_changed_client_in_log_only_mode() {
+        let mut session_service = bound_sessions(SessionBinding::new(BindingMode::LogOnly));
+        let session = session_service.create_session_for("123456", &client("Firefox/119"));
+
+        // The browser updated itself mid-session.
+        assert_eq!(session_service.check_session_from(&session, &client("Firefox/119")), Ok("123456".to_owned()));
+        assert_eq!(session_service.check_session_from(&session, &client("Firefox/120")), Ok("123456".to_owned()));
+        assert_eq!(session_service.check_session_from(&session, &client("Firefox/120")), Ok("123456".to_owned()));
+        assert_eq!(session_service.binding.mismatches(), 2);
+    }
+
+    #[test]
+    fn should_reject_other_client_when_enforced() {
+        let mut session_service = bound_sessions(SessionBinding::new(BindingMode::Enforce));
+        let session = session_service.create_session_for("123456", &client("Firefox"));
+
+        assert_eq!(session_service.check_session_from(&session, &client("curl")), Err(SessionError::BindingMismatch));
+        assert_eq!(session_service.check_session(&session), Err(SessionError::BindingMismatch));
+        // Still works for the client it was created for.
+        assert_eq!(session_service.check_session_from(&session, &client("Firefox")), Ok("123456".to_owned()));
+        assert_eq!(session_service.binding.mismatches(), 2);
+    }
+
+    #[test]
+    fn should_revoke_session_on_mismatch_when_configured() {
+        let mut session_service =
+            bound_sessions(SessionBinding::new(BindingMode::Enforce).with_revoke_on_mismatch(true));
+        let session = session_service.create_session_for("123456", &client("Firefox"));
+
+        assert_eq!(session_service.check_session_from(&session, &client("curl")), Err(SessionError::BindingMismatch));
+        assert_eq!(session_service.check_session_from(&session, &client("Firefox")), Err(SessionError::Unknown));
+        assert_eq!(session_service.stats(), SessionStats::default());
+    }
+
+    #[test]
+    fn should_not_store_client_details() {
+        let mut session_service = bound_sessions(SessionBinding::new(BindingMode::Enforce));
+        session_service.create_session_for("123456", &client("Firefox"));
+
+        let fingerprint = session_service.uuid_to_session["123456"].fingerprint.unwrap();
+        assert!(!fingerprint.windows(b"Firefox".len()).any(|window| window == b"Firefox"));
+        assert_eq!(Some(fingerprint), session_service.binding.fingerprint(&client("Firefox")));
+    }
 }
@@ -0,0 +1,277 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::clock::{Clock, SystemClock};
+
+// Never shed everything, so latency keeps being measured and recovery is noticed.
+pub const MAX_SHED_FRACTION: f64 = 0.9;
+// Latency samples older than this no longer count towards the p95.
+const SAMPLE_WINDOW: Duration = Duration::from_secs(30);
+const MAX_SAMPLES: usize = 1_000;
+// Most time one adjustment accounts for, so the first request after a quiet spell can't jump the fraction.
+const MAX_ADJUST_STEP: Duration = Duration::from_secs(1);
+// Below this share of both thresholds counts as healthy. In between, the fraction holds, so it doesn't oscillate
+// around a threshold.
+const RECOVERY_RATIO: f64 = 0.8;
+
+// When to start shedding. None ignores that signal.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ShedThresholds {
+    pub p95: Option<Duration>,      // Of whole SignIn requests.
+    pub queue_length: Option<usize>, // Of the hashing pool.
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Trend {
+    Up,
+    Hold,
+    Down,
+}
+
+struct State {
+    samples: VecDeque<(SystemTime, Duration)>,
+    fraction: f64,
+    credit: f64, // Accumulates the fraction, one request is shed each time it reaches 1.
+    trend: Trend,
+    adjusted_at: SystemTime,
+}
+
+// Turns away a share of new SignIns and SignUps while the service is overloaded, so the rest complete instead of
+// all of them queueing until they time out. The share ramps between 0 and `MAX_SHED_FRACTION` over `ramp`, up while
+// a threshold is exceeded and down once both signals are well below theirs.
+pub struct LoadShedder {
+    thresholds: ShedThresholds,
+    ramp: Duration,
+    state: Mutex<State>,
+    clock: Arc<dyn Clock>,
+}
+
+impl LoadShedder {
+    pub fn new(thresholds: ShedThresholds, ramp: Duration) -> Self {
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        Self {
+            thresholds,
+            ramp: ramp.max(Duration::from_millis(1)),
+            state: Mutex::new(State {
+                samples: VecDeque::new(),
+                fraction: 0.0,
+                credit: 0.0,
+                trend: Trend::Hold,
+                adjusted_at: clock.now(),
+            }),
+            clock,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.state.get_mut().unwrap().adjusted_at = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    // Records how long a request that wasn't shed took.
+    pub fn observe(&self, latency: Duration) {
+        let mut state = self.state.lock().unwrap();
+        if state.samples.len() == MAX_SAMPLES {
+            state.samples.pop_front();
+        }
+        state.samples.push_back((self.clock.now(), latency));
+    }
+
+    // Whether to let a new request in, given how many jobs wait for the hashing pool. Err with how long the client
+    // should wait before retrying.
+    pub fn admit(&self, queue_length: usize) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        self.adjust(&mut state, queue_length);
+
+        state.credit += state.fraction;
+        if state.credit < 1.0 {
+            return Ok(());
+        }
+        state.credit -= 1.0;
+        // Longer waits the harder the service is shedding, from 1 to 5 seconds.
+        Err(Duration::from_secs(1 + (state.fraction / MAX_SHED_FRACTION * 4.0).round() as u64))
+    }
+
+    // Share of new requests currently shed.
+    pub fn fraction(&self) -> f64 {
+        self.state.lock().unwrap().fraction
+    }
+
+    fn adjust(&self, state: &mut State, queue_length: usize) {
+        let now = self.clock.now();
+        while state.samples.front().is_some_and(|(at, _)| now.duration_since(*at).unwrap_or_default() > SAMPLE_WINDOW) {
+            state.samples.pop_front();
+        }
+        let p95 = p95(&state.samples);
+
+        let over = |value: f64, threshold: Option<f64>| threshold.is_some_and(|threshold| value > threshold);
+        let under = |value: f64, threshold: Option<f64>| threshold.is_none_or(|threshold| value <= threshold * RECOVERY_RATIO);
+        let p95_ms = p95.as_secs_f64() * 1_000.0;
+        let p95_threshold = self.thresholds.p95.map(|p95| p95.as_secs_f64() * 1_000.0);
+        let queue_threshold = self.thresholds.queue_length.map(|length| length as f64);
+        let trend = if over(p95_ms, p95_threshold) || over(queue_length as f64, queue_threshold) {
+            Trend::Up
+        } else if under(p95_ms, p95_threshold) && under(queue_length as f64, queue_threshold) {
+            Trend::Down
+        } else {
+            Trend::Hold
+        };
+
+        let direction = match trend {
+            Trend::Up => Some("ramping_up"),
+            Trend::Down if state.fraction > 0.0 => Some("ramping_down"),
+            _ => None,
+        };
+        if let Some(direction) = direction.filter(|_| trend != state.trend) {
+            println!(
+                "WARN load_shedding={} fraction={:.2} p95_ms={} queue_length={}",
+                direction,
+                state.fraction,
+                p95.as_millis(),
+                queue_length
+            );
+        }
+        state.trend = trend;
+
+        let elapsed = now.duration_since(state.adjusted_at).unwrap_or_default().min(MAX_ADJUST_STEP);
+        state.adjusted_at = now;
+        let step = MAX_SHED_FRACTION * elapsed.as_secs_f64() / self.ramp.as_secs_f64();
+        let was_shedding = state.fraction > 0.0;
+        state.fraction = match trend {
+            Trend::Up => (state.fraction + step).min(MAX_SHED_FRACTION),
+            Trend::Hold => state.fraction,
+            Trend::Down => (state.fraction - step).max(0.0),
+        };
+        if was_shedding && state.fraction == 0.0 {
+            state.credit = 0.0;
+            println!("WARN load_shedding=off");
+        }
+    }
+}
+
+// Zero without samples.
+fn p95(samples: &VecDeque<(SystemTime, Duration)>) -> Duration {
+    let mut latencies: Vec<Duration> = samples.iter().map(|(_, latency)| *latency).collect();
+    if latencies.is_empty() {
+        return Duration::ZERO;
+    }
+    latencies.sort_unstable();
+    latencies[(latencies.len() * 95).div_ceil(100) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    const SECOND: Duration = Duration::from_secs(1);
+    const SLOW: Duration = Duration::from_millis(900);
+    const FAST: Duration = Duration::from_millis(50);
+
+    fn shedder(thresholds: ShedThresholds) -> (LoadShedder, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new());
+        (LoadShedder::new(thresholds, 4 * SECOND).with_clock(clock.clone()), clock)
+    }
+
+    fn by_latency() -> ShedThresholds {
+        ShedThresholds { p95: Some(Duration::from_millis(500)), queue_length: None }
+    }
+
+    fn observe(shedder: &LoadShedder, latency: Duration, times: usize) {
+        for _ in 0..times {
+            shedder.observe(latency);
+        }
+    }
+
+    // How many of `requests` admitted in a row are shed.
+    fn shed(shedder: &LoadShedder, queue_length: usize, requests: usize) -> usize {
+        (0..requests).filter(|_| shedder.admit(queue_length).is_err()).count()
+    }
+
+    #[test]
+    fn should_ramp_up_gradually_while_slow() {
+        let (shedder, clock) = shedder(by_latency());
+        observe(&shedder, SLOW, 20);
+
+        let mut fractions = Vec::new();
+        for _ in 0..5 {
+            clock.advance(SECOND);
+            shedder.admit(0).ok();
+            fractions.push((shedder.fraction() * 1_000.0).round() / 1_000.0);
+        }
+
+        assert_eq!(fractions, vec![0.225, 0.45, 0.675, 0.9, 0.9]);
+        assert_eq!(shed(&shedder, 0, 100), 90);
+    }
+
+    #[test]
+    fn should_ramp_down_once_healthy_and_stay_at_zero() {
+        let (shedder, clock) = shedder(by_latency());
+        observe(&shedder, SLOW, 20);
+        for _ in 0..4 {
+            clock.advance(SECOND);
+            shedder.admit(0).ok();
+        }
+        assert_eq!(shedder.fraction(), MAX_SHED_FRACTION);
+
+        // Old samples age out, new ones are fast.
+        clock.advance(SAMPLE_WINDOW);
+        observe(&shedder, FAST, 20);
+        shedder.admit(0).ok();
+        assert!(shedder.fraction() < MAX_SHED_FRACTION && shedder.fraction() > 0.0);
+        for _ in 0..10 {
+            clock.advance(SECOND);
+            shedder.admit(0).ok();
+        }
+
+        assert_eq!(shedder.fraction(), 0.0);
+        assert_eq!(shed(&shedder, 0, 100), 0);
+    }
+
+    #[test]
+    fn should_hold_between_recovery_and_threshold() {
+        let (shedder, clock) = shedder(by_latency());
+        observe(&shedder, SLOW, 20);
+        clock.advance(2 * SECOND);
+        shedder.admit(0).ok();
+        let fraction = shedder.fraction();
+
+        clock.advance(SAMPLE_WINDOW);
+        observe(&shedder, Duration::from_millis(450), 20); // Under 500ms, over 80% of it.
+        shedder.admit(0).ok();
+        clock.advance(SECOND);
+        shedder.admit(0).ok();
+
+        assert_eq!(shedder.fraction(), fraction);
+    }
+
+    #[test]
+    fn should_shed_on_hashing_queue_length() {
+        let (shedder, clock) = shedder(ShedThresholds { p95: None, queue_length: Some(10) });
+        observe(&shedder, SLOW, 20); // Ignored without a latency threshold.
+
+        for _ in 0..4 {
+            clock.advance(SECOND);
+            shedder.admit(5).ok();
+        }
+        assert_eq!(shedder.fraction(), 0.0);
+
+        for _ in 0..4 {
+            clock.advance(SECOND);
+            shedder.admit(11).ok();
+        }
+        assert_eq!(shedder.fraction(), MAX_SHED_FRACTION);
+        let retry_after = (0..2).find_map(|_| shedder.admit(11).err()).unwrap();
+        assert_eq!(retry_after, 5 * SECOND);
+    }
+
+    #[test]
+    fn should_not_shed_without_samples() {
+        let (shedder, clock) = shedder(by_latency());
+        clock.advance(SECOND);
+        assert_eq!(shed(&shedder, 0, 100), 0);
+    }
+}
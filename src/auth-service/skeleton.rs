@@ -0,0 +1,59 @@
+use unicode_skeleton::UnicodeSkeleton;
+
+// Characters that render as nothing. The confusables table leaves them alone, so "ad\u{200B}min"
+// would otherwise get a different skeleton than "admin".
+fn is_invisible(c: char) -> bool {
+    matches!(c,
+        '\u{00AD}' | '\u{034F}' | '\u{061C}' | '\u{115F}' | '\u{1160}' | '\u{17B4}' | '\u{17B5}'
+        | '\u{180B}'..='\u{180F}' | '\u{200B}'..='\u{200F}' | '\u{202A}'..='\u{202E}'
+        | '\u{2060}'..='\u{206F}' | '\u{3164}' | '\u{FE00}'..='\u{FE0F}' | '\u{FEFF}' | '\u{FFA0}'
+        | '\u{1BCA0}'..='\u{1BCA3}' | '\u{E0000}'..='\u{E0FFF}')
+}
+
+/// Maps a username to the form used to detect lookalikes (UTS #39 skeleton).
+///
+/// The username is case folded first so the check composes with case-insensitive comparison, then
+/// mapped through the confusables table and folded again, since some prototypes are uppercase
+/// ('0' maps to 'O').
+pub fn skeleton(username: &str) -> String {
+    let folded: String = username
+        .chars()
+        .filter(|c| !is_invisible(*c))
+        .flat_map(char::to_lowercase)
+        .collect();
+
+    folded
+        .skeleton_chars()
+        .filter(|c| !is_invisible(*c))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_map_cyrillic_lookalikes_to_latin() {
+        assert_eq!(skeleton("\u{0430}dmin"), skeleton("admin")); // Cyrillic 'а'
+        assert_eq!(skeleton("\u{0440}\u{0430}y\u{0440}\u{0430}l"), skeleton("paypal")); // Cyrillic 'р' and 'а'
+    }
+
+    #[test]
+    fn should_ignore_zero_width_characters() {
+        assert_eq!(skeleton("ad\u{200B}min"), skeleton("admin"));
+        assert_eq!(skeleton("\u{FEFF}admin\u{200D}"), skeleton("admin"));
+    }
+
+    #[test]
+    fn should_ignore_case() {
+        assert_eq!(skeleton("ADMIN"), skeleton("admin"));
+        assert_eq!(skeleton("\u{0410}dmin"), skeleton("admin")); // Cyrillic capital 'А'
+    }
+
+    #[test]
+    fn should_keep_distinct_usernames_apart() {
+        assert_ne!(skeleton("admin"), skeleton("administrator"));
+        assert_ne!(skeleton("alice"), skeleton("bob"));
+    }
+}
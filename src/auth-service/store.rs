@@ -0,0 +1,843 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+use uuid::Uuid;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct User {
+    pub user_uuid: String,
+    pub username: String,
+    pub username_skeleton: String, // See `skeleton::skeleton`. Unique across the store, like the username.
+    pub password: String,           // PHC hash string.
+    pub email: Option<String>,      // Normalized by `email::normalize_email`. Unique across the store when set.
+    pub email_verified: bool,
+    pub email_verification: Option<PendingVerification>, // Outstanding token for `email`, if one was requested.
+    pub password_reset: Option<PendingVerification>,     // Outstanding password reset token, if one was requested.
+    // Guests have no credentials yet. They aren't indexed by username or skeleton, so they can't sign in with a
+    // password and don't hold any username.
+    pub guest: bool,
+    // Provisioned on first sign in through an external directory, which checks the password. `password` is empty.
+    pub directory: bool,
+    pub account_kind: AccountKind,
+    // Set by an admin to move the account off its hash algorithm. Sign in is refused until the password is changed.
+    pub password_change_required: bool,
+    pub created_at: SystemTime,
+}
+
+impl User {
+    // Whether `password` is a hash this service checks, i.e. not a guest, directory or federated account.
+    pub fn has_local_password(&self) -> bool {
+        !self.guest && !self.directory && self.account_kind == AccountKind::Local
+    }
+}
+
+// How an account signs in. Directory accounts are local ones: they sign in with a password, checked elsewhere.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum AccountKind {
+    #[default]
+    Local,
+    Federated, // Signs in through an identity provider. `password` is empty and never matches.
+}
+
+// Which accounts a username (and its skeleton) has to be unique among.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum UsernameScope {
+    #[default]
+    Unified, // Every account, whatever its kind.
+    PerKind, // Accounts of the same kind, so "alice" can exist once as a local and once as a federated account.
+}
+
+impl UsernameScope {
+    // The namespace the usernames of `kind` accounts are unique within.
+    pub fn namespace(&self, kind: AccountKind) -> AccountKind {
+        match self {
+            UsernameScope::Unified => AccountKind::Local,
+            UsernameScope::PerKind => kind,
+        }
+    }
+}
+
+// Parses AUTH_USERNAME_SCOPE: "unified" or "per-kind".
+pub fn username_scope(value: &str) -> Result<UsernameScope, String> {
+    match value {
+        "unified" => Ok(UsernameScope::Unified),
+        "per-kind" => Ok(UsernameScope::PerKind),
+        other => Err(format!("Unknown username scope {other:?}, expected unified or per-kind")),
+    }
+}
+
+// A single-use token waiting to be redeemed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingVerification {
+    pub token_hash: String, // SHA-256 of the token, base64. The token itself is never stored.
+    pub expires_at: SystemTime,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StoreError {
+    UsernameTaken,
+    SkeletonTaken { username: String }, // Username of the user that already has this skeleton.
+    UuidTaken,
+    EmailTaken,
+    NotFound,
+}
+
+// Storage behind `UsersImpl`. Implementations only persist and index users; hashing, normalization and policy live
+// in `UsersImpl` so every backend behaves the same. Uniqueness of uuid, username, skeleton and email must be enforced
+// atomically by `insert` and `update`, since callers check and write without holding a lock in between.
+//
+// Usernames and skeletons are unique within `username_scope().namespace(kind)`. Lookups by either return the account
+// holding it in the namespace of `kind`, which with a unified scope may be of another kind; callers check
+// `account_kind` when it matters.
+//
+// Every implementation must pass `user_store_conformance_tests!`.
+pub trait UserStore: Send + Sync {
+    fn insert(&self, user: User) -> Result<(), StoreError>;
+    fn username_scope(&self) -> UsernameScope;
+    fn get_by_username(&self, kind: AccountKind, username: &str) -> Option<User>;
+    fn get_by_skeleton(&self, kind: AccountKind, username_skeleton: &str) -> Option<User>;
+    fn get_by_email(&self, email: &str) -> Option<User>;
+    fn get_by_uuid(&self, user_uuid: &str) -> Option<User>;
+    fn remove(&self, user_uuid: &str) -> Option<User>;
+    // Snapshot of every user, in no particular order. For admin reports, not request paths.
+    fn users(&self) -> Vec<User>;
+    // Replaces the user with the same uuid.
+    fn update(&self, user: User) -> Result<(), StoreError>;
+    // How many accounts are stored. Cheap enough to call on a timer, e.g. kept alongside the data or a COUNT query.
+    fn counts(&self) -> UserCounts;
+    // Cross-checks the store's indices. None for stores that have nothing to check or can't, e.g. a database
+    // enforcing them with constraints.
+    fn check_integrity(&self) -> Option<IntegrityReport> {
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct UserCounts {
+    pub users: usize, // Accounts with a username, i.e. not guests.
+    pub guests: usize,
+}
+
+#[derive(Default)]
+pub struct MemoryUserStore {
+    state: RwLock<MemoryState>,
+}
+
+impl MemoryUserStore {
+    pub fn with_username_scope(scope: UsernameScope) -> Self {
+        Self {
+            state: RwLock::new(MemoryState { scope, ..MemoryState::default() }),
+        }
+    }
+}
+
+// Username and skeleton indices are keyed by namespace, see `UsernameScope::namespace`.
+#[derive(Default)]
+struct MemoryState {
+    scope: UsernameScope,
+    uuid_to_user: HashMap<String, User>,
+    username_to_user: HashMap<(AccountKind, String), User>,
+    skeleton_to_username: HashMap<(AccountKind, String), String>,
+    email_to_uuid: HashMap<String, String>,
+}
+
+impl MemoryState {
+    fn key(&self, kind: AccountKind, name: &str) -> (AccountKind, String) {
+        (self.scope.namespace(kind), name.to_owned())
+    }
+
+    // Checks `user` can be stored once the record it replaces (if any) is gone.
+    fn check_unique(&self, user: &User, replacing: Option<&User>) -> Result<(), StoreError> {
+        let namespace = self.scope.namespace(user.account_kind);
+        let is_replaced = |username: &str| {
+            replacing.is_some_and(|old| old.username == username && self.scope.namespace(old.account_kind) == namespace)
+        };
+
+        if !user.guest {
+            if self.username_to_user.contains_key(&self.key(user.account_kind, &user.username)) && !is_replaced(&user.username) {
+                return Err(StoreError::UsernameTaken);
+            }
+            if let Some(username) = self.skeleton_to_username.get(&self.key(user.account_kind, &user.username_skeleton)) {
+                if !is_replaced(username) {
+                    return Err(StoreError::SkeletonTaken {
+                        username: username.clone(),
+                    });
+                }
+            }
+        }
+        if let Some(email) = &user.email {
+            if self.email_to_uuid.get(email).is_some_and(|uuid| *uuid != user.user_uuid) {
+                return Err(StoreError::EmailTaken);
+            }
+        }
+        Ok(())
+    }
+
+    fn index(&mut self, user: User) {
+        if let Some(email) = &user.email {
+            self.email_to_uuid.insert(email.clone(), user.user_uuid.clone());
+        }
+        if !user.guest {
+            self.skeleton_to_username
+                .insert(self.key(user.account_kind, &user.username_skeleton), user.username.clone());
+            self.username_to_user.insert(self.key(user.account_kind, &user.username), user.clone());
+        }
+        self.uuid_to_user.insert(user.user_uuid.clone(), user);
+    }
+
+    fn unindex(&mut self, user_uuid: &str) -> Option<User> {
+        let user = self.uuid_to_user.remove(user_uuid)?;
+        if !user.guest {
+            self.username_to_user.remove(&self.key(user.account_kind, &user.username));
+            self.skeleton_to_username.remove(&self.key(user.account_kind, &user.username_skeleton));
+        }
+        if let Some(email) = &user.email {
+            self.email_to_uuid.remove(email);
+        }
+        Some(user)
+    }
+}
+
+// Something `MemoryUserStore::verify_integrity` found wrong with the indices.
+#[derive(Debug, PartialEq)]
+pub enum IntegrityProblem {
+    InvalidUuid { user_uuid: String },
+    // The uuid index filed the record under a different uuid than its own.
+    MisfiledUuid { key: String, user_uuid: String },
+    // A user with a username that the username or skeleton index doesn't lead back to.
+    NotIndexed { user_uuid: String, index: &'static str },
+    // An index entry with no matching user, or a stale copy of one.
+    Orphan { index: &'static str, key: String },
+}
+
+impl fmt::Display for IntegrityProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntegrityProblem::InvalidUuid { user_uuid } => write!(f, "invalid uuid {user_uuid:?}"),
+            IntegrityProblem::MisfiledUuid { key, user_uuid } => write!(f, "user {user_uuid} filed under uuid {key}"),
+            IntegrityProblem::NotIndexed { user_uuid, index } => write!(f, "user {user_uuid} missing from {index} index"),
+            IntegrityProblem::Orphan { index, key } => write!(f, "orphaned {index} index entry {key:?}"),
+        }
+    }
+}
+
+// Problems in no particular order, one per line when displayed. Empty when the store is consistent.
+#[derive(Debug, Default, PartialEq)]
+pub struct IntegrityReport(pub Vec<IntegrityProblem>);
+
+impl fmt::Display for IntegrityReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "store is consistent");
+        }
+        writeln!(f, "{} integrity problems:", self.0.len())?;
+        for problem in &self.0 {
+            writeln!(f, "  {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+impl MemoryUserStore {
+    // Cross-checks the indices against the uuid index, which holds the records themselves. Run by the self-test;
+    // nothing in the request path calls it.
+    pub fn verify_integrity(&self) -> IntegrityReport {
+        let state = self.state.read().unwrap();
+        let mut problems = Vec::new();
+
+        for (key, user) in &state.uuid_to_user {
+            if Uuid::parse_str(&user.user_uuid).is_err() {
+                problems.push(IntegrityProblem::InvalidUuid { user_uuid: user.user_uuid.clone() });
+            }
+            if *key != user.user_uuid {
+                problems.push(IntegrityProblem::MisfiledUuid { key: key.clone(), user_uuid: user.user_uuid.clone() });
+            }
+            if !user.guest {
+                let indexed = state.username_to_user.get(&state.key(user.account_kind, &user.username));
+                if indexed.map(|indexed| &indexed.user_uuid) != Some(&user.user_uuid) {
+                    problems.push(IntegrityProblem::NotIndexed { user_uuid: user.user_uuid.clone(), index: "username" });
+                }
+                if state.skeleton_to_username.get(&state.key(user.account_kind, &user.username_skeleton)) != Some(&user.username) {
+                    problems.push(IntegrityProblem::NotIndexed { user_uuid: user.user_uuid.clone(), index: "skeleton" });
+                }
+            }
+            if let Some(email) = &user.email {
+                if state.email_to_uuid.get(email) != Some(&user.user_uuid) {
+                    problems.push(IntegrityProblem::NotIndexed { user_uuid: user.user_uuid.clone(), index: "email" });
+                }
+            }
+        }
+
+        // Copies kept by the username index must match the record, or lookups by username see old data.
+        for ((namespace, username), indexed) in &state.username_to_user {
+            if state.uuid_to_user.get(&indexed.user_uuid) != Some(indexed)
+                || indexed.username != *username
+                || state.scope.namespace(indexed.account_kind) != *namespace
+            {
+                problems.push(IntegrityProblem::Orphan { index: "username", key: username.clone() });
+            }
+        }
+        for ((namespace, skeleton), username) in &state.skeleton_to_username {
+            let indexed = state.username_to_user.get(&(*namespace, username.clone()));
+            if indexed.is_none_or(|user| user.username_skeleton != *skeleton) {
+                problems.push(IntegrityProblem::Orphan { index: "skeleton", key: skeleton.clone() });
+            }
+        }
+        for (email, user_uuid) in &state.email_to_uuid {
+            if state.uuid_to_user.get(user_uuid).is_none_or(|user| user.email.as_ref() != Some(email)) {
+                problems.push(IntegrityProblem::Orphan { index: "email", key: email.clone() });
+            }
+        }
+
+        IntegrityReport(problems)
+    }
+}
+
+#[cfg(test)]
+impl MemoryUserStore {
+    // Number of users, checking every index agrees on it.
+    pub fn len(&self) -> usize {
+        let state = self.state.read().unwrap();
+        let non_guests = state.uuid_to_user.values().filter(|user| !user.guest).count();
+        assert_eq!(state.username_to_user.len(), non_guests);
+        assert_eq!(state.skeleton_to_username.len(), non_guests);
+        assert_eq!(
+            state.email_to_uuid.len(),
+            state.uuid_to_user.values().filter(|user| user.email.is_some()).count()
+        );
+        state.uuid_to_user.len()
+    }
+}
+
+impl UserStore for MemoryUserStore {
+    fn insert(&self, user: User) -> Result<(), StoreError> {
+        let mut state = self.state.write().unwrap();
+        if state.uuid_to_user.contains_key(&user.user_uuid) {
+            return Err(StoreError::UuidTaken);
+        }
+        state.check_unique(&user, None)?;
+        state.index(user);
+        Ok(())
+    }
+
+    fn username_scope(&self) -> UsernameScope {
+        self.state.read().unwrap().scope
+    }
+
+    fn get_by_username(&self, kind: AccountKind, username: &str) -> Option<User> {
+        let state = self.state.read().unwrap();
+        state.username_to_user.get(&state.key(kind, username)).cloned()
+    }
+
+    fn get_by_skeleton(&self, kind: AccountKind, username_skeleton: &str) -> Option<User> {
+        let state = self.state.read().unwrap();
+        let username = state.skeleton_to_username.get(&state.key(kind, username_skeleton))?;
+        state.username_to_user.get(&state.key(kind, username)).cloned()
+    }
+
+    fn get_by_email(&self, email: &str) -> Option<User> {
+        let state = self.state.read().unwrap();
+        let user_uuid = state.email_to_uuid.get(email)?;
+        state.uuid_to_user.get(user_uuid).cloned()
+    }
+
+    fn get_by_uuid(&self, user_uuid: &str) -> Option<User> {
+        self.state.read().unwrap().uuid_to_user.get(user_uuid).cloned()
+    }
+
+    fn remove(&self, user_uuid: &str) -> Option<User> {
+        self.state.write().unwrap().unindex(user_uuid)
+    }
+
+    fn users(&self) -> Vec<User> {
+        self.state.read().unwrap().uuid_to_user.values().cloned().collect()
+    }
+
+    fn update(&self, user: User) -> Result<(), StoreError> {
+        let mut state = self.state.write().unwrap();
+        let old = state.uuid_to_user.get(&user.user_uuid).ok_or(StoreError::NotFound)?;
+        state.check_unique(&user, Some(old))?;
+        state.unindex(&user.user_uuid);
+        state.index(user);
+        Ok(())
+    }
+
+    fn counts(&self) -> UserCounts {
+        // Only non-guests are indexed by username, so the index sizes are the counts.
+        let state = self.state.read().unwrap();
+        UserCounts {
+            users: state.username_to_user.len(),
+            guests: state.uuid_to_user.len() - state.username_to_user.len(),
+        }
+    }
+
+    fn check_integrity(&self) -> Option<IntegrityReport> {
+        Some(self.verify_integrity())
+    }
+}
+
+// Behavioral contract every `UserStore` implementation must satisfy. Expands to a set of tests in the calling
+// module, each building a fresh store with `$factory`:
+//
+//     mod memory_store_conformance {
+//         user_store_conformance_tests!(MemoryUserStore::default);
+//     }
+#[cfg(test)]
+macro_rules! user_store_conformance_tests {
+    ($factory:expr) => {
+        use crate::store::{AccountKind, StoreError, User, UserStore};
+
+        fn user(user_uuid: &str, username: &str) -> User {
+            User {
+                user_uuid: user_uuid.to_owned(),
+                username: username.to_owned(),
+                username_skeleton: format!("skeleton-{}", username.to_lowercase()),
+                password: format!("hash-{user_uuid}"),
+                email: None,
+                email_verified: false,
+                email_verification: None,
+                password_reset: None,
+                guest: false,
+                directory: false,
+                account_kind: AccountKind::Local,
+                password_change_required: false,
+                created_at: std::time::SystemTime::UNIX_EPOCH,
+            }
+        }
+
+        fn guest(user_uuid: &str) -> User {
+            User {
+                username: String::new(),
+                username_skeleton: String::new(),
+                password: String::new(),
+                guest: true,
+                ..user(user_uuid, "")
+            }
+        }
+
+        fn user_with_email(user_uuid: &str, username: &str, email: &str) -> User {
+            User {
+                email: Some(email.to_owned()),
+                ..user(user_uuid, username)
+            }
+        }
+
+        #[test]
+        fn should_get_inserted_user_by_username_uuid_and_skeleton() {
+            let store = $factory();
+            store.insert(user("1", "alice")).unwrap();
+
+            assert_eq!(store.get_by_username(AccountKind::Local, "alice"), Some(user("1", "alice")));
+            assert_eq!(store.get_by_uuid("1"), Some(user("1", "alice")));
+            assert_eq!(store.get_by_skeleton(AccountKind::Local, "skeleton-alice"), Some(user("1", "alice")));
+            assert_eq!(store.get_by_username(AccountKind::Local, "bob"), None);
+            assert_eq!(store.get_by_uuid("2"), None);
+            assert_eq!(store.get_by_skeleton(AccountKind::Local, "skeleton-bob"), None);
+        }
+
+        #[test]
+        fn should_reject_duplicate_username() {
+            let store = $factory();
+            store.insert(user("1", "alice")).unwrap();
+            assert_eq!(store.insert(user("2", "alice")), Err(StoreError::UsernameTaken));
+            assert_eq!(store.get_by_uuid("2"), None);
+        }
+
+        #[test]
+        fn should_reject_duplicate_skeleton() {
+            let store = $factory();
+            store.insert(user("1", "alice")).unwrap();
+            assert_eq!(
+                store.insert(user("2", "ALICE")),
+                Err(StoreError::SkeletonTaken {
+                    username: "alice".to_owned()
+                })
+            );
+            assert_eq!(store.get_by_username(AccountKind::Local, "ALICE"), None);
+        }
+
+        #[test]
+        fn should_reject_duplicate_uuid() {
+            let store = $factory();
+            store.insert(user("1", "alice")).unwrap();
+            assert_eq!(store.insert(user("1", "bob")), Err(StoreError::UuidTaken));
+            assert_eq!(store.get_by_username(AccountKind::Local, "bob"), None);
+        }
+
+        #[test]
+        fn should_remove_user_from_every_index() {
+            let store = $factory();
+            store.insert(user("1", "alice")).unwrap();
+
+            assert_eq!(store.remove("1"), Some(user("1", "alice")));
+            assert_eq!(store.get_by_uuid("1"), None);
+            assert_eq!(store.get_by_username(AccountKind::Local, "alice"), None);
+            assert_eq!(store.get_by_skeleton(AccountKind::Local, "skeleton-alice"), None);
+
+            // Username, skeleton and uuid can be reused.
+            store.insert(user("1", "alice")).unwrap();
+        }
+
+        #[test]
+        fn should_return_none_removing_unknown_user() {
+            let store = $factory();
+            assert_eq!(store.remove("1"), None);
+        }
+
+        #[test]
+        fn should_update_user_in_place() {
+            let store = $factory();
+            store.insert(user("1", "alice")).unwrap();
+
+            let mut updated = user("1", "alice");
+            updated.password = "new hash".to_owned();
+            store.update(updated.clone()).unwrap();
+
+            assert_eq!(store.get_by_uuid("1"), Some(updated.clone()));
+            assert_eq!(store.get_by_username(AccountKind::Local, "alice"), Some(updated));
+        }
+
+        #[test]
+        fn should_reindex_user_when_username_changes() {
+            let store = $factory();
+            store.insert(user("1", "alice")).unwrap();
+
+            store.update(user("1", "alicia")).unwrap();
+
+            assert_eq!(store.get_by_username(AccountKind::Local, "alice"), None);
+            assert_eq!(store.get_by_skeleton(AccountKind::Local, "skeleton-alice"), None);
+            assert_eq!(store.get_by_username(AccountKind::Local, "alicia"), Some(user("1", "alicia")));
+            assert_eq!(store.get_by_skeleton(AccountKind::Local, "skeleton-alicia"), Some(user("1", "alicia")));
+        }
+
+        #[test]
+        fn should_reject_update_taking_another_users_username() {
+            let store = $factory();
+            store.insert(user("1", "alice")).unwrap();
+            store.insert(user("2", "bob")).unwrap();
+
+            assert_eq!(store.update(user("2", "alice")), Err(StoreError::UsernameTaken));
+            assert_eq!(
+                store.update(user("2", "Alice")),
+                Err(StoreError::SkeletonTaken {
+                    username: "alice".to_owned()
+                })
+            );
+            assert_eq!(store.get_by_username(AccountKind::Local, "bob"), Some(user("2", "bob")));
+        }
+
+        #[test]
+        fn should_get_user_by_email() {
+            let store = $factory();
+            store.insert(user_with_email("1", "alice", "alice@example.com")).unwrap();
+            store.insert(user("2", "bob")).unwrap();
+
+            assert_eq!(
+                store.get_by_email("alice@example.com"),
+                Some(user_with_email("1", "alice", "alice@example.com"))
+            );
+            assert_eq!(store.get_by_email("bob@example.com"), None);
+        }
+
+        #[test]
+        fn should_reject_duplicate_email() {
+            let store = $factory();
+            store.insert(user_with_email("1", "alice", "shared@example.com")).unwrap();
+
+            assert_eq!(
+                store.insert(user_with_email("2", "bob", "shared@example.com")),
+                Err(StoreError::EmailTaken)
+            );
+            store.insert(user("2", "bob")).unwrap();
+            assert_eq!(
+                store.update(user_with_email("2", "bob", "shared@example.com")),
+                Err(StoreError::EmailTaken)
+            );
+            assert_eq!(store.get_by_uuid("2"), Some(user("2", "bob")));
+        }
+
+        #[test]
+        fn should_reindex_email_on_update_and_remove() {
+            let store = $factory();
+            store.insert(user_with_email("1", "alice", "old@example.com")).unwrap();
+
+            store.update(user_with_email("1", "alicia", "new@example.com")).unwrap();
+            assert_eq!(store.get_by_email("old@example.com"), None);
+            assert_eq!(
+                store.get_by_email("new@example.com"),
+                Some(user_with_email("1", "alicia", "new@example.com"))
+            );
+
+            // Keeping the same email across an update isn't a conflict with itself.
+            store.update(user_with_email("1", "alice", "new@example.com")).unwrap();
+
+            store.remove("1");
+            assert_eq!(store.get_by_email("new@example.com"), None);
+            store.insert(user_with_email("2", "bob", "new@example.com")).unwrap();
+        }
+
+        #[test]
+        fn should_not_index_guests_by_username() {
+            let store = $factory();
+            store.insert(guest("1")).unwrap();
+            store.insert(guest("2")).unwrap();
+
+            assert_eq!(store.get_by_uuid("1"), Some(guest("1")));
+            assert_eq!(store.get_by_username(AccountKind::Local, ""), None);
+            assert_eq!(store.get_by_skeleton(AccountKind::Local, ""), None);
+
+            store.remove("1");
+            assert_eq!(store.get_by_uuid("2"), Some(guest("2")));
+        }
+
+        #[test]
+        fn should_index_guest_once_upgraded() {
+            let store = $factory();
+            store.insert(user("1", "alice")).unwrap();
+            store.insert(guest("2")).unwrap();
+
+            assert_eq!(store.update(user("2", "alice")), Err(StoreError::UsernameTaken));
+            store.update(user("2", "bob")).unwrap();
+
+            assert_eq!(store.get_by_username(AccountKind::Local, "bob"), Some(user("2", "bob")));
+            assert_eq!(store.get_by_skeleton(AccountKind::Local, "skeleton-bob"), Some(user("2", "bob")));
+        }
+
+        #[test]
+        fn should_list_every_user() {
+            let store = $factory();
+            assert_eq!(store.users(), vec![]);
+
+            store.insert(user("1", "alice")).unwrap();
+            store.insert(user("2", "bob")).unwrap();
+            store.remove("1");
+            store.insert(user("3", "carol")).unwrap();
+
+            let mut users = store.users();
+            users.sort_by(|a, b| a.user_uuid.cmp(&b.user_uuid));
+            assert_eq!(users, vec![user("2", "bob"), user("3", "carol")]);
+        }
+
+        #[test]
+        fn should_count_users_and_guests() {
+            use crate::store::UserCounts;
+
+            let store = $factory();
+            store.insert(user("1", "alice")).unwrap();
+            store.insert(guest("2")).unwrap();
+            store.insert(guest("3")).unwrap();
+            assert_eq!(store.counts(), UserCounts { users: 1, guests: 2 });
+
+            store.update(user("2", "bob")).unwrap(); // Upgraded guest.
+            store.remove("1");
+            store.remove("missing");
+            assert_eq!(store.insert(user("4", "bob")), Err(StoreError::UsernameTaken));
+            assert_eq!(store.counts(), UserCounts { users: 1, guests: 1 });
+        }
+
+        // Stores are built with the default, unified username scope.
+        #[test]
+        fn should_keep_usernames_unique_across_kinds() {
+            let store = $factory();
+            let federated = User {
+                account_kind: AccountKind::Federated,
+                password: String::new(),
+                ..user("1", "alice")
+            };
+            store.insert(federated.clone()).unwrap();
+
+            assert_eq!(store.insert(user("2", "alice")), Err(StoreError::UsernameTaken));
+            assert_eq!(store.get_by_username(AccountKind::Local, "alice"), Some(federated.clone()));
+            assert_eq!(store.get_by_skeleton(AccountKind::Federated, "skeleton-alice"), Some(federated));
+        }
+
+        #[test]
+        fn should_accept_only_one_of_concurrent_inserts_for_a_username() {
+            let store = $factory();
+
+            let results: Vec<Result<(), StoreError>> = std::thread::scope(|scope| {
+                let handles: Vec<_> = (0..32)
+                    .map(|i| {
+                        let store = &store;
+                        scope.spawn(move || store.insert(user(&i.to_string(), "alice")))
+                    })
+                    .collect();
+                handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+            });
+
+            assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+            assert!(results.iter().all(|result| result.is_ok() || *result == Err(StoreError::UsernameTaken)));
+            assert_eq!(store.users().len(), 1);
+            assert!(store.get_by_username(AccountKind::Local, "alice").is_some());
+        }
+
+        #[test]
+        fn should_reject_update_of_unknown_user() {
+            let store = $factory();
+            assert_eq!(store.update(user("1", "alice")), Err(StoreError::NotFound));
+            assert_eq!(store.get_by_username(AccountKind::Local, "alice"), None);
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod memory_store_conformance {
+        use super::MemoryUserStore;
+
+        user_store_conformance_tests!(MemoryUserStore::default);
+    }
+
+    const ALICE: &str = "00000000-0000-0000-0000-00000000000a";
+    const BOB: &str = "00000000-0000-0000-0000-00000000000b";
+    const CAROL: &str = "00000000-0000-0000-0000-00000000000c";
+
+    fn user(user_uuid: &str, username: &str) -> User {
+        User {
+            user_uuid: user_uuid.to_owned(),
+            username: username.to_owned(),
+            username_skeleton: username.to_owned(),
+            password: String::new(),
+            email: Some(format!("{username}@example.com")),
+            email_verified: false,
+            email_verification: None,
+            password_reset: None,
+            guest: false,
+            directory: false,
+            account_kind: AccountKind::Local,
+            password_change_required: false,
+            created_at: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    fn federated(user_uuid: &str, username: &str) -> User {
+        User {
+            email: None,
+            account_kind: AccountKind::Federated,
+            ..user(user_uuid, username)
+        }
+    }
+
+    fn store() -> MemoryUserStore {
+        let store = MemoryUserStore::default();
+        store.insert(user(ALICE, "alice")).unwrap();
+        store.insert(user(BOB, "bob")).unwrap();
+        store
+    }
+
+    #[test]
+    fn should_split_usernames_by_kind_with_per_kind_scope() {
+        let store = MemoryUserStore::with_username_scope(UsernameScope::PerKind);
+        store.insert(user(ALICE, "alice")).unwrap();
+        store.insert(federated(BOB, "alice")).unwrap();
+
+        assert_eq!(store.get_by_username(AccountKind::Local, "alice").unwrap().user_uuid, ALICE);
+        assert_eq!(store.get_by_username(AccountKind::Federated, "alice").unwrap().user_uuid, BOB);
+        assert_eq!(store.get_by_skeleton(AccountKind::Federated, "alice").unwrap().user_uuid, BOB);
+        assert_eq!(store.insert(federated(CAROL, "alice")), Err(StoreError::UsernameTaken));
+        assert_eq!(store.verify_integrity(), IntegrityReport::default());
+
+        // Renaming moves the account within its own namespace only.
+        store.update(User { username: "bob".to_owned(), username_skeleton: "bob".to_owned(), ..federated(BOB, "alice") }).unwrap();
+        assert_eq!(store.get_by_username(AccountKind::Federated, "alice"), None);
+        assert_eq!(store.get_by_username(AccountKind::Local, "bob"), None);
+        store.remove(ALICE);
+        assert_eq!(store.get_by_username(AccountKind::Federated, "bob").unwrap().user_uuid, BOB);
+        assert_eq!(store.verify_integrity(), IntegrityReport::default());
+    }
+
+    #[test]
+    fn should_parse_username_scope() {
+        assert_eq!(username_scope("unified"), Ok(UsernameScope::Unified));
+        assert_eq!(username_scope("per-kind"), Ok(UsernameScope::PerKind));
+        assert!(username_scope("split").is_err());
+    }
+
+    #[test]
+    fn should_find_consistent_store_consistent() {
+        let store = store();
+        store.update(User { username: "carol".to_owned(), username_skeleton: "carol".to_owned(), ..user(BOB, "bob") }).unwrap();
+        store.remove(ALICE);
+
+        assert_eq!(store.verify_integrity(), IntegrityReport::default());
+        assert_eq!(store.verify_integrity().to_string(), "store is consistent");
+    }
+
+    #[test]
+    fn should_report_invalid_and_misfiled_uuids() {
+        let store = store();
+        {
+            let mut state = store.state.write().unwrap();
+            let mut bob = state.uuid_to_user.remove(BOB).unwrap();
+            bob.user_uuid = "not-a-uuid".to_owned();
+            state.uuid_to_user.insert(BOB.to_owned(), bob);
+        }
+
+        let problems = store.verify_integrity().0;
+
+        assert!(problems.contains(&IntegrityProblem::InvalidUuid { user_uuid: "not-a-uuid".to_owned() }));
+        assert!(problems.contains(&IntegrityProblem::MisfiledUuid { key: BOB.to_owned(), user_uuid: "not-a-uuid".to_owned() }));
+    }
+
+    #[test]
+    fn should_report_users_missing_from_indices() {
+        let store = store();
+        {
+            let mut state = store.state.write().unwrap();
+            state.skeleton_to_username.remove(&(AccountKind::Local, "alice".to_owned()));
+            state.email_to_uuid.remove("bob@example.com");
+        }
+
+        let mut problems = store.verify_integrity().0;
+        problems.sort_by_key(|problem| problem.to_string());
+
+        assert_eq!(
+            problems,
+            vec![
+                IntegrityProblem::NotIndexed { user_uuid: ALICE.to_owned(), index: "skeleton" },
+                IntegrityProblem::NotIndexed { user_uuid: BOB.to_owned(), index: "email" },
+            ]
+        );
+    }
+
+    #[test]
+    fn should_report_orphaned_and_stale_index_entries() {
+        let store = store();
+        {
+            let mut state = store.state.write().unwrap();
+            // Removed from the uuid index only, as a half-applied delete would leave it.
+            state.uuid_to_user.remove(ALICE);
+            // Changed in the uuid index only, leaving a stale copy behind.
+            state.uuid_to_user.get_mut(BOB).unwrap().email_verified = true;
+        }
+
+        let mut problems = store.verify_integrity().0;
+        problems.sort_by_key(|problem| problem.to_string());
+
+        assert_eq!(
+            problems,
+            vec![
+                IntegrityProblem::Orphan { index: "email", key: "alice@example.com".to_owned() },
+                IntegrityProblem::Orphan { index: "username", key: "alice".to_owned() },
+                IntegrityProblem::Orphan { index: "username", key: "bob".to_owned() },
+            ]
+        );
+    }
+
+    #[test]
+    fn should_format_integrity_report() {
+        let report = IntegrityReport(vec![
+            IntegrityProblem::InvalidUuid { user_uuid: "x".to_owned() },
+            IntegrityProblem::Orphan { index: "email", key: "alice@example.com".to_owned() },
+        ]);
+
+        assert_eq!(
+            report.to_string(),
+            "2 integrity problems:\n  invalid uuid \"x\"\n  orphaned email index entry \"alice@example.com\"\n"
+        );
+    }
+}
@@ -0,0 +1,343 @@
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+
+use crate::latency::{SignInLatency, SignInPhase};
+use crate::maintenance::MaintenanceMode;
+use crate::metrics::Gauge;
+use crate::purger::JobMetrics;
+use crate::sessions::{SessionStats, Sessions};
+use crate::shedding::LoadShedder;
+use crate::users::{HashingMetrics, UserStats, Users};
+
+// Sizes of the user and session stores as of the last refresh.
+#[derive(Default)]
+pub struct StoreGauges {
+    users: Gauge,
+    guests: Gauge,
+    reservations: Gauge,
+    sessions_live: Gauge,
+    sessions_expired: Gauge,
+    maintenance: Option<Arc<MaintenanceMode>>, // Read when rendering, so readiness checks see changes at once.
+    load_shedder: Option<Arc<LoadShedder>>, // Same.
+    hashing_metrics: Option<Arc<HashingMetrics>>,
+    sign_in_latency: Option<Arc<SignInLatency>>,
+    purge_metrics: Vec<(&'static str, Arc<JobMetrics>)>, // By job name.
+}
+
+impl StoreGauges {
+    pub fn with_maintenance(mut self, maintenance: Arc<MaintenanceMode>) -> Self {
+        self.maintenance = Some(maintenance);
+        self
+    }
+
+    pub fn with_load_shedder(mut self, load_shedder: Option<Arc<LoadShedder>>) -> Self {
+        self.load_shedder = load_shedder;
+        self
+    }
+
+    pub fn with_hashing_metrics(mut self, hashing_metrics: Arc<HashingMetrics>) -> Self {
+        self.hashing_metrics = Some(hashing_metrics);
+        self
+    }
+
+    pub fn with_sign_in_latency(mut self, sign_in_latency: Arc<SignInLatency>) -> Self {
+        self.sign_in_latency = Some(sign_in_latency);
+        self
+    }
+
+    pub fn with_purge_metrics(mut self, purge_metrics: Vec<(&'static str, Arc<JobMetrics>)>) -> Self {
+        self.purge_metrics = purge_metrics;
+        self
+    }
+
+    pub fn update(&self, users: UserStats, sessions: SessionStats) {
+        self.users.set(users.users as i64);
+        self.guests.set(users.guests as i64);
+        self.reservations.set(users.reservations as i64);
+        self.sessions_live.set(sessions.live as i64);
+        self.sessions_expired.set(sessions.expired as i64);
+    }
+
+    // Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let gauges = [
+            ("auth_users", "Accounts with a username.", &self.users),
+            ("auth_guests", "Guest accounts not yet upgraded.", &self.guests),
+            ("auth_username_reservations", "Usernames held by deleted accounts.", &self.reservations),
+            ("auth_sessions_live", "Sessions that still work.", &self.sessions_live),
+            ("auth_sessions_expired", "Sessions past a limit but not yet dropped.", &self.sessions_expired),
+        ];
+
+        let mut out = String::new();
+        for (name, help, gauge) in gauges {
+            let _ = write!(out, "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {}\n", gauge.get());
+        }
+        if let Some(maintenance) = &self.maintenance {
+            let name = "auth_maintenance_remaining_seconds";
+            let remaining = maintenance.remaining().map_or(0, |remaining| remaining.as_secs());
+            let _ = write!(
+                out,
+                "# HELP {name} Time left in maintenance mode, 0 when account changes are allowed.\n# TYPE {name} gauge\n{name} {remaining}\n"
+            );
+        }
+        if let Some(load_shedder) = &self.load_shedder {
+            let name = "auth_shed_fraction";
+            let _ = write!(
+                out,
+                "# HELP {name} Share of new SignIns and SignUps turned away as overloaded.\n# TYPE {name} gauge\n{name} {}\n",
+                load_shedder.fraction()
+            );
+        }
+        if let Some(hashing_metrics) = &self.hashing_metrics {
+            let name = "auth_password_hashing_seconds";
+            let _ = write!(out, "# HELP {name} Time to hash or verify a password.\n# TYPE {name} histogram\n");
+            for labels in hashing_metrics.durations.labels() {
+                let histogram = hashing_metrics.durations.with_labels(&[&labels[0], &labels[1]]);
+                histogram.write_series(&mut out, name, &[("operation", &labels[0]), ("algorithm", &labels[1])]);
+            }
+            let name = "auth_slow_password_verifications_total";
+            let _ = write!(
+                out,
+                "# HELP {name} Verifications slower than the configured threshold.\n# TYPE {name} counter\n{name} {}\n",
+                hashing_metrics.slow_verifications.get()
+            );
+        }
+        if let Some(sign_in_latency) = &self.sign_in_latency {
+            let name = "auth_sign_in_phase_seconds";
+            let _ = write!(out, "# HELP {name} Time SignIn spends in each phase.\n# TYPE {name} histogram\n");
+            for phase in SignInPhase::ALL {
+                sign_in_latency.histogram(phase).write_series(&mut out, name, &[("phase", phase.name())]);
+            }
+            let name = "auth_sign_in_over_budget_total";
+            let _ = write!(
+                out,
+                "# HELP {name} SignIns that took longer than the configured budget.\n# TYPE {name} counter\n{name} {}\n",
+                sign_in_latency.over_budget()
+            );
+        }
+        if !self.purge_metrics.is_empty() {
+            let name = "auth_purged_total";
+            let _ = write!(out, "# HELP {name} Items dropped by each purge job.\n# TYPE {name} counter\n");
+            for (job, metrics) in &self.purge_metrics {
+                let _ = writeln!(out, "{name}{{job=\"{job}\"}} {}", metrics.purged.get());
+            }
+            let name = "auth_purge_truncated";
+            let _ = write!(out, "# HELP {name} 1 when the last run of the job stopped at its budget.\n# TYPE {name} gauge\n");
+            for (job, metrics) in &self.purge_metrics {
+                let _ = writeln!(out, "{name}{{job=\"{job}\"}} {}", metrics.truncated.get());
+            }
+            let name = "auth_purge_run_seconds";
+            let _ = write!(out, "# HELP {name} Time each purge run took.\n# TYPE {name} histogram\n");
+            for (job, metrics) in &self.purge_metrics {
+                metrics.durations.write_series(&mut out, name, &[("job", job)]);
+            }
+        }
+        out
+    }
+}
+
+// Refreshes `gauges` every `interval`, starting now, and logs a summary line each time.
+pub async fn refresh_store_gauges(
+    users: Arc<dyn Users + Send + Sync>,
+    sessions: Arc<Mutex<dyn Sessions + Send + Sync>>,
+    gauges: Arc<StoreGauges>,
+    interval: Duration,
+) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        let user_stats = users.stats();
+        let session_stats = sessions.lock().unwrap().stats();
+        gauges.update(user_stats, session_stats);
+        println!(
+            "Store stats: {} users, {} guests, {} reserved usernames, {} live sessions, {} expired sessions",
+            user_stats.users, user_stats.guests, user_stats.reservations, session_stats.live, session_stats.expired
+        );
+    }
+}
+
+// Serves `GET /metrics` for Prometheus to scrape.
+pub async fn serve_metrics(addr: SocketAddr, gauges: Arc<StoreGauges>) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(move |_| {
+        let gauges = gauges.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let response = respond(&request, &gauges);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+
+    Server::bind(&addr).serve(make_service).await
+}
+
+fn respond(request: &Request<Body>, gauges: &StoreGauges) -> Response<Body> {
+    let (status, body) = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => (StatusCode::OK, gauges.render()),
+        _ => (StatusCode::NOT_FOUND, "not found".to_owned()),
+    };
+
+    Response::builder()
+        .status(status)
+        .header("content-type", "text/plain; version=0.0.4")
+        .body(Body::from(body))
+        .expect("static response parts are valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::latency::PhaseTimings;
+    use crate::sessions::SessionsImpl;
+    use crate::shedding::ShedThresholds;
+    use crate::users::UsersImpl;
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    #[test]
+    fn should_keep_counts_through_account_and_session_changes() {
+        let clock = Arc::new(ManualClock::new());
+        let users = UsersImpl::with_hash_rounds(1_000)
+            .with_username_reservation(24 * HOUR)
+            .with_clock(clock.clone());
+        let mut sessions = SessionsImpl::default()
+            .with_absolute_lifetime(Some(2 * HOUR))
+            .with_clock(clock.clone());
+
+        let [alice, bob, _] = ["alice", "bob", "carol"].map(|username| users.create_user(username.to_owned(), "password".into(), None).unwrap());
+        let old_guest = users.create_guest();
+        assert!(users.create_user("alice".to_owned(), "password".into(), None).is_err());
+
+        sessions.create_session(&alice);
+        sessions.create_session(&old_guest);
+        clock.advance(HOUR);
+        let guest = users.create_guest();
+        let upgraded = users.create_guest();
+        users.upgrade_guest(upgraded.clone(), "dave".to_owned(), "password".into()).unwrap();
+        users.delete_user(bob.clone());
+        sessions.create_session(&bob);
+        sessions.create_session(&bob); // Replaces the first one.
+        sessions.create_session(&guest);
+        sessions.delete_session(&guest);
+
+        clock.advance(HOUR + Duration::from_secs(1)); // Past the lifetime of the first two sessions.
+        assert_eq!(users.purge_guests(2 * HOUR, false).guests, 1);
+
+        assert_eq!(users.stats(), UserStats { users: 3, guests: 1, reservations: 1 });
+        assert_eq!(sessions.stats(), SessionStats { live: 1, expired: 2 });
+    }
+
+    #[test]
+    fn should_render_gauges_for_scraping() {
+        let gauges = StoreGauges::default();
+        gauges.update(
+            UserStats { users: 3, guests: 1, reservations: 2 },
+            SessionStats { live: 5, expired: 4 },
+        );
+
+        let output = gauges.render();
+
+        assert!(output.contains("# TYPE auth_users gauge\nauth_users 3\n"));
+        for line in ["auth_guests 1", "auth_username_reservations 2", "auth_sessions_live 5", "auth_sessions_expired 4"] {
+            assert!(output.lines().any(|l| l == line), "missing {line} in {output}");
+        }
+
+        assert!(!output.contains("auth_maintenance"));
+
+        let request = Request::get("/metrics").body(Body::empty()).unwrap();
+        assert_eq!(respond(&request, &gauges).status(), StatusCode::OK);
+        let request = Request::get("/").body(Body::empty()).unwrap();
+        assert_eq!(respond(&request, &gauges).status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn should_render_time_left_in_maintenance() {
+        let maintenance = Arc::new(MaintenanceMode::new(HOUR));
+        let gauges = StoreGauges::default().with_maintenance(maintenance.clone());
+        assert!(gauges.render().lines().any(|l| l == "auth_maintenance_remaining_seconds 0"));
+
+        maintenance.enter(HOUR, "test");
+        let output = gauges.render();
+        let remaining = output.lines().find_map(|l| l.strip_prefix("auth_maintenance_remaining_seconds ")).unwrap();
+        assert!(remaining.parse::<u64>().unwrap() > HOUR.as_secs() - 60, "{output}");
+    }
+
+    #[test]
+    fn should_render_shed_fraction() {
+        let load_shedder = Arc::new(LoadShedder::new(ShedThresholds::default(), Duration::from_secs(10)));
+        let gauges = StoreGauges::default().with_load_shedder(Some(load_shedder));
+
+        assert!(gauges.render().contains("# TYPE auth_shed_fraction gauge\nauth_shed_fraction 0\n"));
+        assert!(!StoreGauges::default().render().contains("auth_shed_fraction"));
+    }
+
+    #[test]
+    fn should_render_hashing_durations() {
+        let hashing_metrics = Arc::new(HashingMetrics::default());
+        let gauges = StoreGauges::default().with_hashing_metrics(hashing_metrics.clone());
+        hashing_metrics.durations.with_labels(&["verify", "pbkdf2-sha256"]).observe(Duration::from_millis(40));
+        hashing_metrics.slow_verifications.inc();
+
+        let output = gauges.render();
+
+        assert!(output.contains("# TYPE auth_password_hashing_seconds histogram\n"));
+        for line in [
+            "auth_password_hashing_seconds_bucket{operation=\"verify\",algorithm=\"pbkdf2-sha256\",le=\"0.05\"} 1",
+            "auth_password_hashing_seconds_count{operation=\"verify\",algorithm=\"pbkdf2-sha256\"} 1",
+            "auth_slow_password_verifications_total 1",
+        ] {
+            assert!(output.lines().any(|l| l == line), "missing {line} in {output}");
+        }
+        assert!(!StoreGauges::default().render().contains("auth_password_hashing"));
+    }
+
+    #[test]
+    fn should_render_sign_in_phases() {
+        let sign_in_latency = Arc::new(SignInLatency::new(Some(Duration::from_millis(100))));
+        let gauges = StoreGauges::default().with_sign_in_latency(sign_in_latency.clone());
+        let mut timings = PhaseTimings::default();
+        timings.add(SignInPhase::HashVerification, Duration::from_millis(150));
+        sign_in_latency.observe(&timings, Duration::from_millis(160));
+
+        let output = gauges.render();
+
+        for line in [
+            "auth_sign_in_phase_seconds_count{phase=\"pool_wait\"} 1",
+            "auth_sign_in_phase_seconds_bucket{phase=\"hash_verification\",le=\"0.1\"} 0",
+            "auth_sign_in_phase_seconds_bucket{phase=\"hash_verification\",le=\"0.25\"} 1",
+            "auth_sign_in_over_budget_total 1",
+        ] {
+            assert!(output.lines().any(|l| l == line), "missing {line} in {output}");
+        }
+        assert!(!StoreGauges::default().render().contains("auth_sign_in"));
+    }
+
+    #[test]
+    fn should_render_purge_jobs() {
+        let sessions = Arc::new(JobMetrics::default());
+        sessions.purged.add(7);
+        sessions.truncated.set(1);
+        sessions.durations.observe(Duration::from_millis(30));
+        let gauges = StoreGauges::default().with_purge_metrics(vec![("sessions", sessions), ("invitations", Arc::default())]);
+
+        let output = gauges.render();
+
+        for line in [
+            "auth_purged_total{job=\"sessions\"} 7",
+            "auth_purged_total{job=\"invitations\"} 0",
+            "auth_purge_truncated{job=\"sessions\"} 1",
+            "auth_purge_run_seconds_count{job=\"sessions\"} 1",
+            "auth_purge_run_seconds_count{job=\"invitations\"} 0",
+        ] {
+            assert!(output.lines().any(|l| l == line), "missing {line} in {output}");
+        }
+        assert!(!StoreGauges::default().render().contains("auth_purge"));
+    }
+}
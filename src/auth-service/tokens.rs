@@ -0,0 +1,367 @@
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::uuids::{UuidGenerator, V4Generator};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const NONCE_LEN: usize = 16;
+const EXPIRY_LEN: usize = 8;
+
+#[derive(Debug, PartialEq)]
+pub enum TokenError {
+    Malformed,
+    UnknownKey, // The kid is not in the keyset, or the key has been retired.
+    BadSignature,
+    Expired,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyState {
+    Active,     // Signs new tokens. Exactly one key is active.
+    VerifyOnly, // Still accepted for tokens issued before it was demoted.
+    Retired,    // No longer accepted.
+}
+
+pub struct SigningKey {
+    kid: String,
+    key: Vec<u8>,
+    state: KeyState,
+}
+
+// The keys tokens can be signed and verified with, identified by kid.
+//
+// To rotate: add a new key as active and demote the old one to verify-only, then once the token TTL has elapsed
+// (so every token it signed has expired) retire or remove it. To react to a leaked key, retire it straight away.
+pub struct KeySet {
+    keys: Vec<SigningKey>,
+}
+
+impl KeySet {
+    // Keyset with a single active key, for deployments that configure one static key.
+    pub fn single(key: &[u8]) -> Self {
+        Self {
+            keys: vec![SigningKey {
+                kid: "default".to_owned(),
+                key: key.to_vec(),
+                state: KeyState::Active,
+            }],
+        }
+    }
+
+    // Parses one key per line as `<kid> <active|verify-only|retired> <key>`. Blank lines and lines starting with
+    // '#' are ignored.
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut keys: Vec<SigningKey> = Vec::new();
+
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [kid, state, key] = fields[..] else {
+                return Err(format!("Line {}: expected `<kid> <state> <key>`", number + 1));
+            };
+            if kid.contains('.') {
+                return Err(format!("Line {}: kid {kid} must not contain '.'", number + 1));
+            }
+            if keys.iter().any(|k| k.kid == kid) {
+                return Err(format!("Line {}: duplicate kid {kid}", number + 1));
+            }
+            let state = match state {
+                "active" => KeyState::Active,
+                "verify-only" => KeyState::VerifyOnly,
+                "retired" => KeyState::Retired,
+                other => return Err(format!("Line {}: unknown key state {other}", number + 1)),
+            };
+
+            keys.push(SigningKey {
+                kid: kid.to_owned(),
+                key: key.as_bytes().to_vec(),
+                state,
+            });
+        }
+
+        let active = keys.iter().filter(|k| k.state == KeyState::Active).count();
+        if active != 1 {
+            return Err(format!("Expected exactly one active key, found {active}"));
+        }
+
+        Ok(Self { keys })
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("Failed to read keyset {path}: {e}"))?;
+        Self::parse(&contents)
+    }
+
+    fn active(&self) -> &SigningKey {
+        self.keys
+            .iter()
+            .find(|k| k.state == KeyState::Active)
+            .expect("keyset has an active key")
+    }
+
+    fn verification_key(&self, kid: &str) -> Option<&SigningKey> {
+        self.keys
+            .iter()
+            .find(|k| k.kid == kid && k.state != KeyState::Retired)
+    }
+}
+
+// Issues and verifies session tokens of the form `kid.base64(payload).base64(hmac_sha256(kid.payload, key))`,
+// where the payload is `nonce (16 bytes) | expiry in unix seconds (8 bytes, big endian) | user uuid`.
+// Tokens can be checked for tampering and expiry without looking anything up.
+pub struct TokenSigner {
+    keyset: Arc<RwLock<KeySet>>,
+    ttl: Duration,
+    nonces: Arc<dyn UuidGenerator>, // The nonce is a uuid's bytes, so it doubles as the token's id.
+}
+
+impl TokenSigner {
+    pub fn new(keyset: KeySet, ttl: Duration) -> Self {
+        Self {
+            keyset: Arc::new(RwLock::new(keyset)),
+            ttl,
+            nonces: Arc::new(V4Generator),
+        }
+    }
+
+    pub fn with_uuid_generator(mut self, nonces: Arc<dyn UuidGenerator>) -> Self {
+        self.nonces = nonces;
+        self
+    }
+
+    // Handle for swapping in a new keyset while the service is running.
+    pub fn keyset(&self) -> Arc<RwLock<KeySet>> {
+        self.keyset.clone()
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    #[allow(dead_code)] // Sessions pass their own TTL; used by tests and the fuzz targets.
+    pub fn issue(&self, user_uuid: &str) -> String {
+        self.issue_for(user_uuid, self.ttl)
+    }
+
+    // Same as `issue`, but valid for `ttl` instead of the signer's TTL.
+    pub fn issue_for(&self, user_uuid: &str, ttl: Duration) -> String {
+        self.issue_at(user_uuid, SystemTime::now(), ttl)
+    }
+
+    // Returns the user uuid the token was issued for.
+    pub fn verify(&self, token: &str) -> Result<String, TokenError> {
+        self.verify_at(token, SystemTime::now())
+    }
+
+    fn issue_at(&self, user_uuid: &str, now: SystemTime, ttl: Duration) -> String {
+        let nonce: [u8; NONCE_LEN] = self.nonces.generate().into_bytes();
+        let expiry = unix_seconds(now + ttl);
+
+        let mut payload = Vec::with_capacity(NONCE_LEN + EXPIRY_LEN + user_uuid.len());
+        payload.extend_from_slice(&nonce);
+        payload.extend_from_slice(&expiry.to_be_bytes());
+        payload.extend_from_slice(user_uuid.as_bytes());
+
+        let keyset = self.keyset.read().unwrap();
+        let key = keyset.active();
+        let signed = format!("{}.{}", key.kid, URL_SAFE_NO_PAD.encode(&payload));
+        let signature = mac(&key.key, &signed).finalize().into_bytes();
+
+        format!("{}.{}", signed, URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    fn verify_at(&self, token: &str, now: SystemTime) -> Result<String, TokenError> {
+        let (signed, signature) = token.rsplit_once('.').ok_or(TokenError::Malformed)?;
+        let (kid, payload) = signed.split_once('.').ok_or(TokenError::Malformed)?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| TokenError::Malformed)?;
+
+        // Check the signature before trusting anything in the payload.
+        {
+            let keyset = self.keyset.read().unwrap();
+            let key = keyset.verification_key(kid).ok_or(TokenError::UnknownKey)?;
+            mac(&key.key, signed)
+                .verify_slice(&signature)
+                .map_err(|_| TokenError::BadSignature)?;
+        }
+
+        let payload = URL_SAFE_NO_PAD.decode(payload).map_err(|_| TokenError::Malformed)?;
+        if payload.len() < NONCE_LEN + EXPIRY_LEN {
+            return Err(TokenError::Malformed);
+        }
+        let (expiry, user_uuid) = payload[NONCE_LEN..].split_at(EXPIRY_LEN);
+        let expiry = u64::from_be_bytes(expiry.try_into().unwrap());
+        if unix_seconds(now) >= expiry {
+            return Err(TokenError::Expired);
+        }
+
+        String::from_utf8(user_uuid.to_vec()).map_err(|_| TokenError::Malformed)
+    }
+}
+
+fn mac(key: &[u8], signed: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(signed.as_bytes());
+    mac
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signer() -> TokenSigner {
+        TokenSigner::new(KeySet::single(b"test signing key"), Duration::from_secs(60))
+    }
+
+    fn keyset(contents: &str) -> KeySet {
+        KeySet::parse(contents).unwrap()
+    }
+
+    #[test]
+    fn should_verify_issued_token() {
+        let token = signer().issue("123456");
+        assert_eq!(signer().verify(&token), Ok("123456".to_owned()));
+    }
+
+    #[test]
+    fn should_issue_distinct_tokens_for_same_user() {
+        assert_ne!(signer().issue("123456"), signer().issue("123456"));
+    }
+
+    #[test]
+    fn should_use_generated_uuid_as_nonce() {
+        let signer = signer().with_uuid_generator(Arc::new(crate::uuids::SequentialGenerator::default()));
+        let token = signer.issue("123456");
+
+        let payload = URL_SAFE_NO_PAD.decode(token.split('.').nth(1).unwrap()).unwrap();
+        assert_eq!(payload[..NONCE_LEN], uuid::Uuid::from_u128(1).into_bytes());
+        assert_eq!(signer.verify(&token), Ok("123456".to_owned()));
+    }
+
+    #[test]
+    fn should_reject_tampered_payload() {
+        let token = signer().issue("123456");
+        let parts: Vec<&str> = token.split('.').collect();
+
+        let mut payload = URL_SAFE_NO_PAD.decode(parts[1]).unwrap();
+        *payload.last_mut().unwrap() = b'7'; // Claim to be user "123457".
+        let tampered = format!("{}.{}.{}", parts[0], URL_SAFE_NO_PAD.encode(&payload), parts[2]);
+
+        assert_eq!(signer().verify(&tampered), Err(TokenError::BadSignature));
+    }
+
+    #[test]
+    fn should_reject_tampered_signature() {
+        let token = signer().issue("123456");
+        let (signed, signature) = token.rsplit_once('.').unwrap();
+
+        let mut signature = URL_SAFE_NO_PAD.decode(signature).unwrap();
+        signature[0] ^= 1;
+        let tampered = format!("{}.{}", signed, URL_SAFE_NO_PAD.encode(&signature));
+
+        assert_eq!(signer().verify(&tampered), Err(TokenError::BadSignature));
+    }
+
+    #[test]
+    fn should_reject_token_signed_with_other_key() {
+        let other = TokenSigner::new(KeySet::single(b"other key"), Duration::from_secs(60));
+        let token = other.issue("123456");
+        assert_eq!(signer().verify(&token), Err(TokenError::BadSignature));
+    }
+
+    #[test]
+    fn should_reject_expired_token() {
+        let now = SystemTime::now();
+        let token = signer().issue_at("123456", now, signer().ttl());
+
+        assert!(signer().verify_at(&token, now + Duration::from_secs(59)).is_ok());
+        assert_eq!(signer().verify_at(&token, now + Duration::from_secs(60)), Err(TokenError::Expired));
+    }
+
+    #[test]
+    fn should_issue_token_with_given_ttl() {
+        let now = SystemTime::now();
+        let token = signer().issue_at("123456", now, Duration::from_secs(10));
+
+        assert!(signer().verify_at(&token, now + Duration::from_secs(9)).is_ok());
+        assert_eq!(signer().verify_at(&token, now + Duration::from_secs(10)), Err(TokenError::Expired));
+    }
+
+    #[test]
+    fn should_reject_malformed_tokens() {
+        assert_eq!(signer().verify(""), Err(TokenError::Malformed));
+        assert_eq!(signer().verify("no-dot"), Err(TokenError::Malformed));
+        assert_eq!(signer().verify("default.!!!.???"), Err(TokenError::Malformed));
+        assert_eq!(signer().verify(&uuid::Uuid::new_v4().to_string()), Err(TokenError::Malformed));
+    }
+
+    #[test]
+    fn should_reject_token_with_swapped_kid() {
+        let signer = TokenSigner::new(keyset("a active key-a\nb verify-only key-b"), Duration::from_secs(60));
+        let token = signer.issue("123456");
+        let swapped = format!("b{}", token.strip_prefix('a').unwrap());
+
+        assert_eq!(signer.verify(&swapped), Err(TokenError::BadSignature));
+    }
+
+    #[test]
+    fn should_sign_with_active_key() {
+        let signer = TokenSigner::new(keyset("old verify-only key-1\nnew active key-2"), Duration::from_secs(60));
+        assert!(signer.issue("123456").starts_with("new."));
+    }
+
+    #[test]
+    fn should_verify_old_key_tokens_during_grace_period_and_reject_after_retirement() {
+        let signer = TokenSigner::new(keyset("k1 active key-1"), Duration::from_secs(60));
+        let old_token = signer.issue("123456");
+
+        // Rotate: k2 signs new tokens, k1 only verifies.
+        *signer.keyset().write().unwrap() = keyset("k1 verify-only key-1\nk2 active key-2");
+        let new_token = signer.issue("123456");
+        assert!(new_token.starts_with("k2."));
+        assert_eq!(signer.verify(&old_token), Ok("123456".to_owned()));
+        assert_eq!(signer.verify(&new_token), Ok("123456".to_owned()));
+
+        // Retire k1 once the grace period is over.
+        *signer.keyset().write().unwrap() = keyset("k1 retired key-1\nk2 active key-2");
+        assert_eq!(signer.verify(&old_token), Err(TokenError::UnknownKey));
+        assert_eq!(signer.verify(&new_token), Ok("123456".to_owned()));
+
+        // Removing k1 from the file altogether has the same effect.
+        *signer.keyset().write().unwrap() = keyset("k2 active key-2");
+        assert_eq!(signer.verify(&old_token), Err(TokenError::UnknownKey));
+    }
+
+    #[test]
+    fn should_parse_keyset_file() {
+        let keys = keyset("# rotated 2026-10-01\n\nk1 verify-only key-1\nk2 active key-2\nk0 retired key-0\n");
+        assert_eq!(keys.active().kid, "k2");
+        assert!(keys.verification_key("k1").is_some());
+        assert!(keys.verification_key("k0").is_none());
+        assert!(keys.verification_key("k3").is_none());
+    }
+
+    #[test]
+    fn should_reject_invalid_keyset_files() {
+        assert!(KeySet::parse("").is_err()); // No active key.
+        assert!(KeySet::parse("k1 verify-only key-1").is_err());
+        assert!(KeySet::parse("k1 active key-1\nk2 active key-2").is_err());
+        assert!(KeySet::parse("k1 active key-1\nk1 verify-only key-2").is_err());
+        assert!(KeySet::parse("k1 active").is_err());
+        assert!(KeySet::parse("k1 enabled key-1").is_err());
+        assert!(KeySet::parse("k.1 active key-1").is_err());
+    }
+}
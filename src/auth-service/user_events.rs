@@ -0,0 +1,164 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use tokio::sync::broadcast;
+
+// Account changes other services can follow to keep their own copy of usernames.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UserEventKind {
+    Created,  // The account now has a username, including guests being upgraded.
+    Deleted,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct UserEvent {
+    pub sequence: u64, // Starts at 0, increases by one per event.
+    pub kind: UserEventKind,
+    pub user_uuid: String,
+    pub username: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SequenceTooOld {
+    pub oldest: u64, // Oldest sequence still buffered.
+}
+
+struct Buffer {
+    events: VecDeque<UserEvent>,
+    next_sequence: u64,
+}
+
+// Keeps the last `capacity` events for replay and fans new ones out to watchers. Recording never waits on a
+// watcher: one that falls more than `capacity` events behind is told it lagged when it next reads.
+pub struct UserEventLog {
+    buffer: Mutex<Buffer>,
+    capacity: usize,
+    live: broadcast::Sender<UserEvent>,
+}
+
+impl UserEventLog {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            buffer: Mutex::new(Buffer {
+                events: VecDeque::with_capacity(capacity),
+                next_sequence: 0,
+            }),
+            capacity,
+            live: broadcast::channel(capacity).0,
+        }
+    }
+
+    pub fn record(&self, kind: UserEventKind, user_uuid: &str, username: &str) {
+        let mut buffer = self.buffer.lock().unwrap();
+        let event = UserEvent {
+            sequence: buffer.next_sequence,
+            kind,
+            user_uuid: user_uuid.to_owned(),
+            username: username.to_owned(),
+        };
+        buffer.next_sequence += 1;
+
+        if buffer.events.len() == self.capacity {
+            buffer.events.pop_front();
+        }
+        buffer.events.push_back(event.clone());
+
+        // Sent under the lock, so a watcher subscribing concurrently sees each event exactly once.
+        let _ = self.live.send(event); // Fails only when nobody is watching.
+    }
+
+    // Buffered events from `since` on, and a receiver for the events recorded after them.
+    pub fn watch(&self, since: u64) -> Result<(Vec<UserEvent>, broadcast::Receiver<UserEvent>), SequenceTooOld> {
+        let buffer = self.buffer.lock().unwrap();
+        let oldest = buffer.next_sequence - buffer.events.len() as u64;
+        if since < oldest {
+            return Err(SequenceTooOld { oldest });
+        }
+
+        let replay = buffer.events.iter().filter(|event| event.sequence >= since).cloned().collect();
+        Ok((replay, self.live.subscribe()))
+    }
+}
+
+impl UserEventLog {
+    // The last `max` buffered events for the account, oldest first.
+    pub fn recent_for(&self, user_uuid: &str, max: usize) -> Vec<UserEvent> {
+        let buffer = self.buffer.lock().unwrap();
+        let mut recent: Vec<UserEvent> = buffer.events.iter().rev().filter(|event| event.user_uuid == user_uuid).take(max).cloned().collect();
+        recent.reverse();
+        recent
+    }
+}
+
+impl Default for UserEventLog {
+    fn default() -> Self {
+        Self::new(1024)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sequences(events: &[UserEvent]) -> Vec<u64> {
+        events.iter().map(|event| event.sequence).collect()
+    }
+
+    #[test]
+    fn should_replay_from_requested_sequence() {
+        let log = UserEventLog::new(8);
+        for username in ["alice", "bob", "carol"] {
+            log.record(UserEventKind::Created, "uuid", username);
+        }
+
+        let (replay, _) = log.watch(1).unwrap();
+
+        assert_eq!(sequences(&replay), vec![1, 2]);
+        assert_eq!(replay[0].username, "bob");
+    }
+
+    #[test]
+    fn should_list_recent_events_of_one_account() {
+        let log = UserEventLog::new(8);
+        for (user_uuid, username) in [("a", "alice"), ("b", "bob"), ("a", "alice2"), ("a", "alice3")] {
+            log.record(UserEventKind::Created, user_uuid, username);
+        }
+
+        let recent: Vec<String> = log.recent_for("a", 2).into_iter().map(|event| event.username).collect();
+        assert_eq!(recent, vec!["alice2", "alice3"]);
+        assert!(log.recent_for("c", 2).is_empty());
+    }
+
+    #[test]
+    fn should_reject_sequence_no_longer_buffered() {
+        let log = UserEventLog::new(2);
+        for username in ["alice", "bob", "carol"] {
+            log.record(UserEventKind::Created, "uuid", username);
+        }
+
+        assert_eq!(log.watch(0).unwrap_err(), SequenceTooOld { oldest: 1 });
+        assert_eq!(sequences(&log.watch(1).unwrap().0), vec![1, 2]);
+    }
+
+    #[test]
+    fn should_deliver_events_after_replay_live() {
+        let log = UserEventLog::new(8);
+        log.record(UserEventKind::Created, "uuid", "alice");
+
+        let (replay, mut live) = log.watch(0).unwrap();
+        log.record(UserEventKind::Deleted, "uuid", "alice");
+
+        assert_eq!(sequences(&replay), vec![0]);
+        let event = live.try_recv().unwrap();
+        assert_eq!((event.sequence, event.kind), (1, UserEventKind::Deleted));
+    }
+
+    #[test]
+    fn should_accept_future_sequence() {
+        let log = UserEventLog::new(8);
+        log.record(UserEventKind::Created, "uuid", "alice");
+
+        assert!(log.watch(5).unwrap().0.is_empty());
+    }
+}
@@ -1,155 +1,2636 @@
-use pbkdf2::{
-    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
-    Pbkdf2,
-};
-use rand_core::OsRng;
+use pbkdf2::Params;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::banlist::UsernameBanList;
+use crate::clock::{Clock, SystemClock};
+use crate::config::AuthConfig;
+use crate::credentials::{CredentialVerifier, LocalVerifier};
+use crate::email::normalize_email;
+use crate::events::{Event, EventSink, LogEvents};
+use crate::hashing::{describe_hash, PasswordScheme, Pbkdf2Scheme};
+use crate::identifiers::{normalize_identifier, IdentifierKind};
+use crate::latency::{PhaseTimings, SignInPhase};
+use crate::metrics::{Counter, HistogramVec};
+use crate::password::Password;
+use crate::pool::BatchThreads;
+use crate::purger::{PurgeBudget, PurgeRun};
+use crate::skeleton::skeleton;
+use crate::store::{AccountKind, IntegrityReport, MemoryUserStore, PendingVerification, StoreError, User, UserStore, UsernameScope};
+use crate::user_events::{UserEventKind, UserEventLog};
+use crate::uuids::{UuidGenerator, V4Generator};
 use uuid::Uuid;
 
-use std::collections::HashMap;
+#[derive(Debug, PartialEq)]
+pub enum UsersError {
+    UsernameTaken,
+    // The username looks like an existing one (e.g. Cyrillic 'а' in place of Latin 'a').
+    UsernameConfusable { conflicts_with: String },
+    // The username (or a lookalike) belonged to an account deleted recently.
+    UsernameReserved { available_at: SystemTime },
+    UsernameNotAllowed, // On the username ban list.
+    UsernameEmpty,      // Nothing left once surrounding whitespace is trimmed.
+    UserAlreadyExists, // Restoring a uuid that is still in use.
+    InvalidUuid,       // Restoring under something that isn't a uuid.
+    NotAGuest,         // Upgrading an account that already has credentials.
+    DirectoryManaged,  // Passwords are kept in the external directory, not locally.
+    EmailTaken,
+    InvalidEmail,
+    UserNotFound,
+    NoEmail, // Email verification was requested for a user without an email.
+    VerificationFailed, // Wrong, already used or superseded verification token.
+    VerificationExpired,
+    PasswordTooShort { min_length: usize },
+    WrongPassword, // The current password given to change it didn't match.
+    // Unknown, expired or already used. Deliberately one error, so callers can't tell which.
+    ResetTokenInvalid,
+    HashingFailed(String),
+}
+
+impl fmt::Display for UsersError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UsersError::UsernameTaken => write!(f, "Username already exists"),
+            UsersError::UsernameConfusable { conflicts_with } => {
+                write!(f, "Username is confusable with existing username {conflicts_with}")
+            }
+            UsersError::UsernameReserved { available_at } => {
+                let secs = available_at.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+                write!(f, "Username is reserved until {secs} (seconds since the epoch)")
+            }
+            UsersError::UsernameNotAllowed => write!(f, "Username is not allowed"),
+            UsersError::UsernameEmpty => write!(f, "Username must not be empty"),
+            UsersError::UserAlreadyExists => write!(f, "User already exists"),
+            UsersError::InvalidUuid => write!(f, "User uuid is not a valid UUID"),
+            UsersError::NotAGuest => write!(f, "User is not a guest"),
+            UsersError::DirectoryManaged => write!(f, "Password is managed by the external directory"),
+            UsersError::EmailTaken => write!(f, "Email already in use"),
+            UsersError::InvalidEmail => write!(f, "Email is not a valid address"),
+            UsersError::UserNotFound => write!(f, "User not found"),
+            UsersError::NoEmail => write!(f, "User has no email"),
+            UsersError::VerificationFailed => write!(f, "Invalid verification token"),
+            UsersError::VerificationExpired => write!(f, "Verification token expired"),
+            UsersError::PasswordTooShort { min_length } => {
+                write!(f, "Password must be at least {min_length} characters")
+            }
+            UsersError::WrongPassword => write!(f, "Current password is wrong"),
+            UsersError::ResetTokenInvalid => write!(f, "Invalid or expired password reset token"),
+            UsersError::HashingFailed(e) => write!(f, "Failed to hash password.\n{e}"),
+        }
+    }
+}
+
+// What other services may see of a user: everything but the password hash.
+#[derive(Debug, PartialEq)]
+pub struct UserView {
+    pub user_uuid: String,
+    pub username: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub guest: bool,
+    pub account_kind: AccountKind,
+    pub password_change_required: bool,
+}
+
+impl From<User> for UserView {
+    fn from(user: User) -> Self {
+        Self {
+            user_uuid: user.user_uuid,
+            username: user.username,
+            email: user.email,
+            email_verified: user.email_verified,
+            guest: user.guest,
+            account_kind: user.account_kind,
+            password_change_required: user.password_change_required,
+        }
+    }
+}
+
+// Credential state of an account, for support. Only says when tokens expire, never the hash or the tokens.
+#[derive(Debug, PartialEq)]
+pub struct CredentialReport {
+    pub user: UserView,
+    pub directory: bool,
+    pub created_at: SystemTime,
+    pub hash_algorithm: Option<String>, // PHC identifier or "unknown". None without a local password.
+    pub password_reset_pending_until: Option<SystemTime>, // None when not requested or expired, as below.
+    pub email_verification_pending_until: Option<SystemTime>,
+}
+
+// Why a pair in `verify_credentials_batch` didn't verify.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CredentialFailure {
+    UnknownUser,
+    WrongPassword,
+}
+
+// Proof of control over an email address, sent to it by whoever handles `Event::EmailVerificationRequested`.
+#[derive(Debug, PartialEq)]
+pub struct VerificationToken(pub String);
+
+// Lets whoever holds it choose a new password, sent by whoever handles `Event::PasswordResetRequested`.
+#[derive(Debug, PartialEq)]
+pub struct ResetToken(pub String);
+
+// Implementations use interior mutability so a single store can be shared as `Arc<dyn Users + Send + Sync>`.
+//
+// Every implementation must pass `users_conformance_tests!`.
+pub trait Users {
+    // `email` is optional and normalized with `normalize_email`. The username is stored trimmed, as it is looked up.
+    // Fails with `UsernameNotAllowed` for names on the ban list. Returns the new account's uuid.
+    fn create_user(&self, username: String, password: Password, email: Option<String>) -> Result<String, UsersError>;
+    // create_user without the ban list, for admins creating accounts under reserved names.
+    fn create_reserved_user(&self, username: String, password: Password, email: Option<String>) -> Result<String, UsersError>;
+    // Only ever matches local accounts: federated ones have no password to check.
+    fn get_user_uuid(&self, username: String, password: Password) -> Option<String>;
+    // get_user_uuid, adding the time spent in the store and in hash verification to `timings`. Implementations that
+    // can't tell the two apart count it all as verification.
+    fn get_user_uuid_timed(&self, username: String, password: Password, timings: &mut PhaseTimings) -> Option<String> {
+        timings.time(SignInPhase::HashVerification, || self.get_user_uuid(username, password))
+    }
+    fn get_user(&self, user_uuid: &str) -> Option<UserView>;
+    // Creates the account for someone an identity provider vouched for and returns its uuid. The username has to be
+    // free in the federated namespace, which with a unified scope is the only one. No provider integration ships yet.
+    #[allow(dead_code)]
+    fn create_federated_user(&self, username: String, email: Option<String>) -> Result<String, UsersError>;
+    // Looks the email up in its normalized form, so any casing finds the account.
+    #[allow(dead_code)]
+    fn find_user_by_email(&self, email: &str) -> Option<UserView>;
+    // Sets or, with `None`, clears the user's email. A different address has to be verified again.
+    #[allow(dead_code)]
+    fn set_email(&self, user_uuid: String, email: Option<String>) -> Result<(), UsersError>;
+    // Issues a token for the user's current email, replacing any previous one, and publishes
+    // `Event::EmailVerificationRequested` so it gets sent. The email counts as unverified until confirmed.
+    fn start_email_verification(&self, user_uuid: String) -> Result<VerificationToken, UsersError>;
+    // Marks the email verified if `token` is the latest one issued and hasn't expired. Tokens are single use.
+    fn confirm_email(&self, user_uuid: String, token: &str) -> Result<(), UsersError>;
+    // Issues a reset token for the account with this username or email, replacing any previous one, and publishes
+    // `Event::PasswordResetRequested`. Callers must not reveal to the requester whether a token was issued, or the
+    // endpoint tells anyone which accounts exist.
+    fn start_password_reset(&self, username_or_email: String) -> Option<ResetToken>;
+    // Sets the password of the account `token` was issued for and uses the token up. Returns the account's uuid,
+    // so the caller can revoke its sessions.
+    fn complete_password_reset(&self, token: String, new_password: Password) -> Result<String, UsersError>;
+    // Sets a new password for someone who knows the current one, hashed with the current scheme. Returns the
+    // account's uuid, so the caller can revoke its sessions.
+    fn change_password(&self, username: String, password: Password, new_password: Password) -> Result<String, UsersError>;
+    // Keeps the username reserved for its owner for the configured window, see `restore_user`. Does nothing for an
+    // unknown uuid, e.g. an account already deleted.
+    fn delete_user(&self, user_uuid: String);
+    // Admin path to recreate a deleted account under its original uuid. Unlike `create_user`, it may take a
+    // username that is reserved for that uuid.
+    #[allow(dead_code)]
+    fn restore_user(&self, user_uuid: String, username: String, password: Password, email: Option<String>) -> Result<(), UsersError>;
+    // Creates a guest account with no credentials, returning its uuid. Guests can't sign in with a password.
+    fn create_guest(&self) -> String;
+    // Gives a guest a username and password, keeping its uuid so data other services keep for it carries over.
+    // The username is trimmed and must not be empty, then checked against the ban list, the password policy and
+    // existing names like on `create_user`. Byte limits and character rules are up to the caller, see
+    // `validate_signup`.
+    fn upgrade_guest(&self, user_uuid: String, username: String, password: Password) -> Result<(), UsersError>;
+    // Deletes guests created at least `older_than` ago that were never upgraded. With `dry_run` nothing is deleted
+    // and the report lists the guests that would be.
+    fn purge_guests(&self, older_than: Duration, dry_run: bool) -> GuestPurgeReport;
+    // Admin report of the hash parameters in use, to spot accounts left with weak or pathological ones.
+    fn scan_hash_parameters(&self) -> HashParameterScan;
+    // Accounts whose password hash uses `algorithm` (a PHC identifier, or "unknown" as in the scan), by username.
+    fn list_users_by_hash_algorithm(&self, algorithm: &str) -> Vec<UserView>;
+    // Flags the accounts so sign in is refused until they change their password, which re-hashes it. Guests,
+    // directory and federated accounts and unknown uuids are skipped. Returns how many were flagged.
+    fn require_password_change_for(&self, user_uuids: &[String]) -> usize;
+    // Counts for monitoring, without a scan of the store.
+    fn stats(&self) -> UserStats;
+    // `UserStore::check_integrity` of the backing store. Takes a full scan, so not for request paths.
+    fn check_store_integrity(&self) -> Option<IntegrityReport>;
+    // None for an unknown uuid.
+    fn credential_report(&self, user_uuid: &str) -> Option<CredentialReport>;
+    // Checks many username and password pairs at once on `threads`, e.g. to confirm an import kept every password. Results are in input order, and unlike sign in say why a pair failed, so this is
+    // for admins only. Never provisions directory accounts.
+    fn verify_credentials_batch(&self, pairs: &[(String, Password)], threads: &BatchThreads) -> Vec<Result<String, CredentialFailure>>;
+    // Drops username reservations whose window has passed, as many as `budget` allows. Reservations are also
+    // dropped when a lookup finds them expired, so this only bounds memory for names nobody tries again.
+    fn purge_reservations(&self, budget: &PurgeBudget) -> PurgeRun;
+}
+
+// Past this many guests a purge report only counts them.
+pub const MAX_REPORTED_UUIDS: usize = 100;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GuestPurgeReport {
+    pub guests: usize,
+    pub user_uuids: Vec<String>, // Sorted. Empty when more than MAX_REPORTED_UUIDS guests are affected.
+    pub dry_run: bool,
+}
+
+impl GuestPurgeReport {
+    fn new(mut user_uuids: Vec<String>, dry_run: bool) -> Self {
+        let guests = user_uuids.len();
+        if guests > MAX_REPORTED_UUIDS {
+            user_uuids.clear();
+        }
+        user_uuids.sort();
+        Self { guests, user_uuids, dry_run }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct UserStats {
+    pub users: usize,
+    pub guests: usize,
+    pub reservations: usize, // Usernames held by deleted accounts, including lapsed ones not yet purged.
+}
+
+// How many stored hashes use each (algorithm, rounds). Hashes that don't parse are counted under "unknown".
+#[derive(Debug, Default, PartialEq)]
+pub struct HashParameterScan(pub BTreeMap<(String, Option<u32>), usize>);
+
+impl fmt::Display for HashParameterScan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let entries: Vec<String> = self
+            .0
+            .iter()
+            .map(|((algorithm, rounds), count)| match rounds {
+                Some(rounds) => format!("{algorithm} i={rounds}: {count}"),
+                None => format!("{algorithm}: {count}"),
+            })
+            .collect();
+        write!(f, "{}", entries.join(", "))
+    }
+}
+
+#[derive(Default)]
+pub struct HashingMetrics {
+    pub durations: HistogramVec, // Labeled by operation ("hash" or "verify") and algorithm.
+    pub slow_verifications: Counter,
+}
+
+// Tombstone left by a deleted account.
+struct Reservation {
+    user_uuid: String,
+    available_at: SystemTime,
+}
+
+pub struct UsersImpl<S: UserStore = MemoryUserStore> {
+    store: S,
+    scheme: Arc<dyn PasswordScheme>,
+    verifier: Box<dyn CredentialVerifier>, // Checks passwords on sign in.
+    hashing_metrics: Arc<HashingMetrics>, // Shared with the metrics endpoint.
+    slow_verification: Option<Duration>, // Verifications taking longer are logged.
+    min_password_length: usize, // In characters.
+    email_verification_ttl: Duration,
+    password_reset_ttl: Duration,
+    clock: Arc<dyn Clock>,
+    uuids: Arc<dyn UuidGenerator>, // User uuids.
+    rng: Mutex<Box<dyn RngCore + Send>>, // For verification and reset tokens.
+    events: Arc<dyn EventSink>,
+    user_events: Arc<UserEventLog>, // Account changes, for WatchUserEvents.
+    reset_lock: Mutex<()>, // Held while a password reset token is checked and used up.
+    ban_list: Arc<RwLock<UsernameBanList>>, // Shared with the config reloader, which replaces it.
+    username_reservation: Duration, // How long usernames of deleted accounts stay reserved. Zero disables it.
+    reservations: Mutex<HashMap<(AccountKind, String), Reservation>>, // (Namespace, skeleton) -> tombstone, pruned lazily.
+}
+
+impl Default for UsersImpl {
+    fn default() -> Self {
+        Self::with_hash_rounds(Params::RECOMMENDED_ROUNDS as u32)
+    }
+}
+
+impl UsersImpl {
+    pub fn with_hash_rounds(hash_rounds: u32) -> Self {
+        Self::with_store(MemoryUserStore::default(), hash_rounds)
+    }
+}
+
+impl<S: UserStore> UsersImpl<S> {
+    pub fn with_store(store: S, hash_rounds: u32) -> Self {
+        let scheme: Arc<dyn PasswordScheme> = Arc::new(Pbkdf2Scheme::new(hash_rounds));
+        Self {
+            store,
+            verifier: Box::new(LocalVerifier::new(scheme.clone())),
+            scheme,
+            hashing_metrics: Arc::default(),
+            slow_verification: None,
+            min_password_length: 1,
+            email_verification_ttl: Duration::from_secs(24 * 60 * 60),
+            password_reset_ttl: Duration::from_secs(30 * 60),
+            clock: Arc::new(SystemClock),
+            uuids: Arc::new(V4Generator),
+            rng: Mutex::new(Box::new(OsRng)),
+            events: Arc::new(LogEvents),
+            user_events: Arc::new(UserEventLog::default()),
+            reset_lock: Mutex::new(()),
+            ban_list: Arc::default(),
+            username_reservation: Duration::ZERO,
+            reservations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_slow_verification_threshold(mut self, slow_verification: Option<Duration>) -> Self {
+        self.slow_verification = slow_verification;
+        self
+    }
+
+    // Hashes and verifies local passwords with `scheme`.
+    #[cfg(test)]
+    pub fn with_scheme(mut self, scheme: Arc<dyn PasswordScheme>) -> Self {
+        self.verifier = Box::new(LocalVerifier::new(scheme.clone()));
+        self.scheme = scheme;
+        self
+    }
+
+    // Checks passwords with `verifier` instead of the local hashes. With a non-local verifier, accounts are
+    // provisioned on first sign in and local password operations fail with `DirectoryManaged`.
+    #[allow(dead_code)]
+    pub fn with_verifier(mut self, verifier: Box<dyn CredentialVerifier>) -> Self {
+        self.verifier = verifier;
+        self
+    }
+
+    pub fn with_hashing_metrics(mut self, hashing_metrics: Arc<HashingMetrics>) -> Self {
+        self.hashing_metrics = hashing_metrics;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn hashing_metrics(&self) -> &HashingMetrics {
+        &self.hashing_metrics
+    }
+
+    pub fn with_email_verification_ttl(mut self, email_verification_ttl: Duration) -> Self {
+        self.email_verification_ttl = email_verification_ttl;
+        self
+    }
+
+    // Names create_user, upgrade_guest and create_federated_user refuse. Nothing is banned without one.
+    pub fn with_ban_list(mut self, ban_list: Arc<RwLock<UsernameBanList>>) -> Self {
+        self.ban_list = ban_list;
+        self
+    }
+
+    pub fn with_username_reservation(mut self, username_reservation: Duration) -> Self {
+        self.username_reservation = username_reservation;
+        self
+    }
+
+    pub fn with_min_password_length(mut self, min_password_length: usize) -> Self {
+        self.min_password_length = min_password_length;
+        self
+    }
+
+    pub fn with_password_reset_ttl(mut self, password_reset_ttl: Duration) -> Self {
+        self.password_reset_ttl = password_reset_ttl;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_uuid_generator(mut self, uuids: Arc<dyn UuidGenerator>) -> Self {
+        self.uuids = uuids;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_rng(mut self, rng: Box<dyn RngCore + Send>) -> Self {
+        self.rng = Mutex::new(rng);
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_events(mut self, events: Arc<dyn EventSink>) -> Self {
+        self.events = events;
+        self
+    }
+
+    pub fn with_user_events(mut self, user_events: Arc<UserEventLog>) -> Self {
+        self.user_events = user_events;
+        self
+    }
+
+    // The key usernames of `kind` accounts are reserved under, see `UsernameScope::namespace`.
+    fn reservation_key(&self, kind: AccountKind, username_skeleton: &str) -> (AccountKind, String) {
+        (self.store.username_scope().namespace(kind), username_skeleton.to_owned())
+    }
+
+    // Fails if the skeleton is reserved for an account other than `user_uuid`.
+    fn check_reservation(&self, kind: AccountKind, username_skeleton: &str, user_uuid: &str) -> Result<(), UsersError> {
+        let key = self.reservation_key(kind, username_skeleton);
+        let mut reservations = self.reservations.lock().unwrap();
+        let Some(reservation) = reservations.get(&key) else {
+            return Ok(());
+        };
+
+        if reservation.available_at <= self.clock.now() {
+            reservations.remove(&key);
+            return Ok(());
+        }
+        if reservation.user_uuid == user_uuid {
+            return Ok(());
+        }
+        Err(UsersError::UsernameReserved {
+            available_at: reservation.available_at,
+        })
+    }
+
+    fn insert_user(&self, user_uuid: String, username: String, password: Password, email: Option<String>) -> Result<(), UsersError> {
+        if !self.verifier.is_local() {
+            return Err(UsersError::DirectoryManaged);
+        }
+
+        // Stored trimmed, since lookups trim what they're given and would never find the name otherwise.
+        let username = username.trim().to_owned();
+        let username_skeleton = skeleton(&username);
+        let email = normalize_optional_email(email)?;
+        self.check_password_policy(&password)?;
+
+        // Fail fast before spending time on hashing. The store enforces uniqueness again on insert.
+        self.check_username_available(AccountKind::Local, &username, &username_skeleton)?;
+        self.check_reservation(AccountKind::Local, &username_skeleton, &user_uuid)?;
+        if let Some(email) = &email {
+            self.check_email_available(email)?;
+        }
+
+        let hashed_password = self.hash_password(&password)?;
+
+        let user: User = User {
+            user_uuid: user_uuid.clone(),
+            username: username.clone(),
+            username_skeleton: username_skeleton.clone(),
+            password: hashed_password,
+            email,
+            email_verified: false,
+            email_verification: None,
+            password_reset: None,
+            guest: false,
+            directory: false,
+            account_kind: AccountKind::Local,
+            password_change_required: false,
+            created_at: self.clock.now(),
+        }; // Create new user with hashed password.
+
+        self.store.insert(user)?;
+
+        // The account is back, so its username needs no more holding.
+        self.reservations.lock().unwrap().remove(&self.reservation_key(AccountKind::Local, &username_skeleton));
+        self.user_events.record(UserEventKind::Created, &user_uuid, &username);
+
+        Ok(())
+    }
+
+    // The local account a user means by `username`, however they cased, spaced or composed it. The exact name is
+    // tried first. Otherwise the skeleton index finds the one account the name could be confused with, which is
+    // only taken if it really is the same name.
+    fn find_local_user(&self, username: &str) -> Option<User> {
+        let key = normalize_identifier(IdentifierKind::Username, username)?;
+        let username = username.trim();
+        self.store
+            .get_by_username(AccountKind::Local, username)
+            .or_else(|| {
+                self.store
+                    .get_by_skeleton(AccountKind::Local, &skeleton(username))
+                    .filter(|user| normalize_identifier(IdentifierKind::Username, &user.username).as_ref() == Some(&key))
+            })
+            .filter(|user| user.account_kind == AccountKind::Local)
+    }
+
+    fn check_username_allowed(&self, username: &str) -> Result<(), UsersError> {
+        if self.ban_list.read().unwrap().is_banned(username.trim()) {
+            return Err(UsersError::UsernameNotAllowed);
+        }
+        Ok(())
+    }
+
+    // Checks the namespace a `kind` account would go in, so with a unified scope accounts of every kind clash.
+    fn check_username_available(&self, kind: AccountKind, username: &str, username_skeleton: &str) -> Result<(), UsersError> {
+        if self.store.get_by_username(kind, username).is_some() {
+            return Err(UsersError::UsernameTaken);
+        }
+
+        // Reject usernames that only differ from an existing one by case, lookalike characters or invisible characters.
+        if let Some(existing) = self.store.get_by_skeleton(kind, username_skeleton) {
+            return Err(UsersError::UsernameConfusable {
+                conflicts_with: existing.username,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn check_password_policy(&self, password: &Password) -> Result<(), UsersError> {
+        if password.expose().chars().count() < self.min_password_length {
+            return Err(UsersError::PasswordTooShort {
+                min_length: self.min_password_length,
+            });
+        }
+        Ok(())
+    }
+
+    fn hash_password(&self, password: &Password) -> Result<String, UsersError> {
+        let start = Instant::now();
+        let hash = self.scheme.hash(password).map_err(UsersError::HashingFailed)?;
+
+        let algorithm = describe_hash(&hash).map_or("unknown".to_owned(), |info| info.algorithm);
+        self.hashing_metrics
+            .durations
+            .with_labels(&["hash", &algorithm])
+            .observe(start.elapsed());
+        Ok(hash)
+    }
+
+    fn verify_password(&self, username: &str, password: &Password, user: Option<&User>) -> bool {
+        let start = Instant::now();
+        let verified = match self.verifier.verify(username, password, user) {
+            Ok(verified) => verified,
+            Err(e) => {
+                println!("Credential check failed: {}", e);
+                false
+            }
+        };
+        let elapsed = start.elapsed();
+
+        let info = user.filter(|_| self.verifier.is_local()).and_then(|user| describe_hash(&user.password));
+        let algorithm = match &info {
+            _ if !self.verifier.is_local() => "directory",
+            Some(info) => info.algorithm.as_str(),
+            None => "unknown",
+        };
+        self.hashing_metrics
+            .durations
+            .with_labels(&["verify", algorithm])
+            .observe(elapsed);
+
+        if self.slow_verification.is_some_and(|threshold| elapsed > threshold) {
+            self.hashing_metrics.slow_verifications.inc();
+            // Uuid rather than username, so the log doesn't collect identifiers.
+            println!(
+                "WARN slow_password_verification user_uuid={} algorithm={} params={} elapsed_ms={}",
+                user.map_or("", |user| user.user_uuid.as_str()),
+                algorithm,
+                info.as_ref().map_or("", |info| info.params.as_str()),
+                elapsed.as_millis()
+            );
+        }
+        verified
+    }
+
+    // Creates the local record for a directory user signing in for the first time, returning its uuid. It has no
+    // password, so sessions and everything keyed by uuid work as for any account.
+    fn provision_directory_user(&self, username: String) -> Option<String> {
+        let username_skeleton = skeleton(&username);
+        let user_uuid = self.uuids.generate().to_string();
+        let user: User = User {
+            user_uuid: user_uuid.clone(),
+            username: username.clone(),
+            username_skeleton: username_skeleton.clone(),
+            password: String::new(),
+            email: None,
+            email_verified: false,
+            email_verification: None,
+            password_reset: None,
+            guest: false,
+            directory: true,
+            account_kind: AccountKind::Local,
+            password_change_required: false,
+            created_at: self.clock.now(),
+        };
+
+        match self.store.insert(user) {
+            Ok(_) => {
+                self.reservations.lock().unwrap().remove(&self.reservation_key(AccountKind::Local, &username_skeleton));
+                self.user_events.record(UserEventKind::Created, &user_uuid, &username);
+                Some(user_uuid)
+            }
+            // A concurrent first sign in got there first.
+            Err(StoreError::UsernameTaken) => self
+                .store
+                .get_by_username(AccountKind::Local, &username)
+                .filter(|user| user.directory)
+                .map(|user| user.user_uuid),
+            Err(e) => {
+                println!("Can't provision directory user: {:?}", e);
+                None
+            }
+        }
+    }
+
+    // Generates a random token, returning it and what to store to redeem it before `ttl` runs out.
+    fn new_token(&self, ttl: Duration) -> (String, PendingVerification) {
+        let mut bytes = [0u8; 32];
+        self.rng.lock().unwrap().fill_bytes(&mut bytes);
+        let token = URL_SAFE_NO_PAD.encode(bytes);
+
+        let pending = PendingVerification {
+            token_hash: hash_token(&token),
+            expires_at: self.clock.now() + ttl,
+        };
+        (token, pending)
+    }
+
+    fn check_email_available(&self, email: &str) -> Result<(), UsersError> {
+        match self.store.get_by_email(email) {
+            Some(_) => Err(UsersError::EmailTaken),
+            None => Ok(()),
+        }
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+}
+
+fn normalize_optional_email(email: Option<String>) -> Result<Option<String>, UsersError> {
+    email
+        .map(|email| normalize_email(&email).map_err(|_| UsersError::InvalidEmail))
+        .transpose()
+}
+
+impl From<StoreError> for UsersError {
+    fn from(e: StoreError) -> Self {
+        match e {
+            StoreError::UsernameTaken => UsersError::UsernameTaken,
+            StoreError::SkeletonTaken { username } => UsersError::UsernameConfusable {
+                conflicts_with: username,
+            },
+            StoreError::EmailTaken => UsersError::EmailTaken,
+            StoreError::NotFound => UsersError::UserNotFound,
+            StoreError::UuidTaken => UsersError::UserAlreadyExists,
+        }
+    }
+}
+
+pub fn users_from_config(
+    config: &AuthConfig,
+    user_events: Arc<UserEventLog>,
+    uuids: Arc<dyn UuidGenerator>,
+    username_scope: UsernameScope,
+    ban_list: Arc<RwLock<UsernameBanList>>,
+    hashing_metrics: Arc<HashingMetrics>,
+) -> Arc<dyn Users + Send + Sync> {
+    Arc::new(
+        UsersImpl::with_store(MemoryUserStore::with_username_scope(username_scope), config.hash_rounds)
+            .with_ban_list(ban_list)
+            .with_hashing_metrics(hashing_metrics)
+            .with_user_events(user_events)
+            .with_uuid_generator(uuids)
+            .with_min_password_length(config.min_password_length)
+            .with_slow_verification_threshold(
+                (config.slow_verification_ms > 0).then(|| Duration::from_millis(config.slow_verification_ms)),
+            )
+            .with_username_reservation(Duration::from_secs(config.username_reservation_secs))
+            .with_email_verification_ttl(Duration::from_secs(config.email_verification_ttl_secs))
+            .with_password_reset_ttl(Duration::from_secs(config.password_reset_ttl_secs)),
+    )
+}
+
+// Logs `scan_hash_parameters` every `interval`, starting now.
+pub async fn log_hash_parameters(users: Arc<dyn Users + Send + Sync>, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        let users = users.clone();
+        match tokio::task::spawn_blocking(move || users.scan_hash_parameters()).await {
+            Ok(scan) => println!("Stored hash parameters: {}", scan),
+            Err(e) => println!("Hash parameter scan failed: {}", e),
+        }
+    }
+}
+
+// Deletes guests older than `max_age` that were never upgraded, checking once an hour.
+pub async fn purge_guests_periodically(users: Arc<dyn Users + Send + Sync>, max_age: Duration) {
+    let mut ticks = tokio::time::interval(Duration::from_secs(60 * 60));
+    loop {
+        ticks.tick().await;
+        let users = users.clone();
+        match tokio::task::spawn_blocking(move || users.purge_guests(max_age, false)).await {
+            Ok(report) if report.guests == 0 => {}
+            Ok(report) => println!("Purged {} stale guest accounts", report.guests),
+            Err(e) => println!("Guest purge failed: {}", e),
+        }
+    }
+}
+
+impl<S: UserStore> Users for UsersImpl<S> {
+    fn create_user(&self, username: String, password: Password, email: Option<String>) -> Result<String, UsersError> {
+        self.check_username_allowed(&username)?;
+        self.create_reserved_user(username, password, email)
+    }
+
+    fn create_reserved_user(&self, username: String, password: Password, email: Option<String>) -> Result<String, UsersError> {
+        let user_uuid = self.uuids.generate().to_string(); // Unique, so never exempt from reservations.
+        self.insert_user(user_uuid.clone(), username, password, email)?;
+        Ok(user_uuid)
+    }
+
+    fn get_user_uuid(&self, username: String, password: Password) -> Option<String> {
+        self.get_user_uuid_timed(username, password, &mut PhaseTimings::default())
+    }
+
+    fn get_user_uuid_timed(&self, username: String, password: Password, timings: &mut PhaseTimings) -> Option<String> {
+        let user: Option<User> = timings.time(SignInPhase::StoreLookup, || self.find_local_user(&username));
+
+        // With a directory, only its accounts can sign in. A local account of the same name must not be taken over.
+        if !self.verifier.is_local() && user.as_ref().is_some_and(|user| !user.directory) {
+            return None;
+        }
+
+        // Verify passed in password matches user's password.
+        let verified = timings.time(SignInPhase::HashVerification, || {
+            self.verify_password(&username, &password, user.as_ref())
+        });
+        if !verified {
+            return None;
+        }
+        match user {
+            Some(user) => Some(user.user_uuid),
+            None => timings.time(SignInPhase::StoreLookup, || self.provision_directory_user(username)),
+        }
+    }
+
+    fn get_user(&self, user_uuid: &str) -> Option<UserView> {
+        self.store.get_by_uuid(user_uuid).map(UserView::from)
+    }
+
+    fn find_user_by_email(&self, email: &str) -> Option<UserView> {
+        let email = normalize_identifier(IdentifierKind::Email, email)?;
+        self.store.get_by_email(&email).map(UserView::from)
+    }
+
+    fn create_federated_user(&self, username: String, email: Option<String>) -> Result<String, UsersError> {
+        let user_uuid = self.uuids.generate().to_string();
+        let username = username.trim().to_owned(); // As in `insert_user`.
+        let username_skeleton = skeleton(&username);
+        let email = normalize_optional_email(email)?;
+
+        self.check_username_allowed(&username)?;
+        self.check_username_available(AccountKind::Federated, &username, &username_skeleton)?;
+        self.check_reservation(AccountKind::Federated, &username_skeleton, &user_uuid)?;
+        if let Some(email) = &email {
+            self.check_email_available(email)?;
+        }
+
+        self.store.insert(User {
+            user_uuid: user_uuid.clone(),
+            username: username.clone(),
+            username_skeleton: username_skeleton.clone(),
+            password: String::new(),
+            email,
+            email_verified: false,
+            email_verification: None,
+            password_reset: None,
+            guest: false,
+            directory: false,
+            account_kind: AccountKind::Federated,
+            password_change_required: false,
+            created_at: self.clock.now(),
+        })?;
+
+        self.reservations.lock().unwrap().remove(&self.reservation_key(AccountKind::Federated, &username_skeleton));
+        self.user_events.record(UserEventKind::Created, &user_uuid, &username);
+        Ok(user_uuid)
+    }
+
+    fn set_email(&self, user_uuid: String, email: Option<String>) -> Result<(), UsersError> {
+        let email = normalize_optional_email(email)?;
+        let user = self.store.get_by_uuid(&user_uuid).ok_or(UsersError::UserNotFound)?;
+        if user.email == email {
+            return Ok(());
+        }
+
+        self.store.update(User {
+            email,
+            email_verified: false,
+            email_verification: None,
+            ..user
+        })?;
+        Ok(())
+    }
+
+    fn start_email_verification(&self, user_uuid: String) -> Result<VerificationToken, UsersError> {
+        let user = self.store.get_by_uuid(&user_uuid).ok_or(UsersError::UserNotFound)?;
+        let email = user.email.clone().ok_or(UsersError::NoEmail)?;
+
+        let (token, pending) = self.new_token(self.email_verification_ttl);
+        self.store.update(User {
+            email_verified: false,
+            email_verification: Some(pending),
+            ..user
+        })?;
+
+        self.events.publish(Event::EmailVerificationRequested {
+            user_uuid,
+            email,
+            token: token.clone(),
+        });
+        Ok(VerificationToken(token))
+    }
+
+    fn confirm_email(&self, user_uuid: String, token: &str) -> Result<(), UsersError> {
+        let user = self.store.get_by_uuid(&user_uuid).ok_or(UsersError::UserNotFound)?;
+        let pending = user.email_verification.as_ref().ok_or(UsersError::VerificationFailed)?;
+
+        if pending.token_hash != hash_token(token) {
+            return Err(UsersError::VerificationFailed);
+        }
+        if self.clock.now() >= pending.expires_at {
+            return Err(UsersError::VerificationExpired);
+        }
+
+        self.store.update(User {
+            email_verified: true,
+            email_verification: None,
+            ..user
+        })?;
+        Ok(())
+    }
+
+    fn start_password_reset(&self, username_or_email: String) -> Option<ResetToken> {
+        let user = self.find_local_user(&username_or_email).or_else(|| {
+            let email = normalize_identifier(IdentifierKind::Email, &username_or_email)?;
+            self.store.get_by_email(&email)
+        })?;
+        if user.account_kind == AccountKind::Federated {
+            println!("Password reset requested for federated account {}, which has no password", user.user_uuid);
+            return None;
+        }
+        if user.directory {
+            println!("Password reset requested for directory account {}, which resets through the directory", user.user_uuid);
+            return None;
+        }
+
+        // The uuid tells `complete_password_reset` where to find the stored hash.
+        let (secret, pending) = self.new_token(self.password_reset_ttl);
+        let token = format!("{}.{}", user.user_uuid, secret);
+        let (user_uuid, email) = (user.user_uuid.clone(), user.email.clone());
+        self.store
+            .update(User {
+                password_reset: Some(pending),
+                ..user
+            })
+            .ok()?;
+
+        self.events.publish(Event::PasswordResetRequested {
+            user_uuid,
+            email,
+            token: token.clone(),
+        });
+        Some(ResetToken(token))
+    }
+
+    fn complete_password_reset(&self, token: String, new_password: Password) -> Result<String, UsersError> {
+        let (user_uuid, secret) = token.split_once('.').ok_or(UsersError::ResetTokenInvalid)?;
+        let token_hash = hash_token(secret);
+        let check_token = |user: &User| match &user.password_reset {
+            Some(pending) if pending.token_hash == token_hash && self.clock.now() < pending.expires_at => Ok(()),
+            _ => Err(UsersError::ResetTokenInvalid),
+        };
+
+        let user = self.store.get_by_uuid(user_uuid).ok_or(UsersError::ResetTokenInvalid)?;
+        if user.directory || !self.verifier.is_local() {
+            return Err(UsersError::DirectoryManaged);
+        }
+        check_token(&user)?;
+        self.check_password_policy(&new_password)?;
+        let password = self.hash_password(&new_password)?;
+
+        // Check again under the lock, so two requests with the same token can't both get past here.
+        let _guard = self.reset_lock.lock().unwrap();
+        let user = self.store.get_by_uuid(user_uuid).ok_or(UsersError::ResetTokenInvalid)?;
+        check_token(&user)?;
+        self.store.update(User {
+            password,
+            password_reset: None,
+            password_change_required: false,
+            ..user
+        })?;
+
+        Ok(user_uuid.to_owned())
+    }
+
+    fn change_password(&self, username: String, password: Password, new_password: Password) -> Result<String, UsersError> {
+        let user = self.find_local_user(&username);
+        if !self.verifier.is_local() || user.as_ref().is_some_and(|user| user.directory) {
+            return Err(UsersError::DirectoryManaged);
+        }
+        let user = user
+            .filter(|user| self.verify_password(&username, &password, Some(user)))
+            .ok_or(UsersError::WrongPassword)?;
+        self.check_password_policy(&new_password)?;
+        let new_password = self.hash_password(&new_password)?;
+
+        let user_uuid = user.user_uuid.clone();
+        self.store.update(User {
+            password: new_password,
+            password_reset: None,
+            password_change_required: false,
+            ..user
+        })?;
+        Ok(user_uuid)
+    }
+
+    fn credential_report(&self, user_uuid: &str) -> Option<CredentialReport> {
+        let user = self.store.get_by_uuid(user_uuid)?;
+        let now = self.clock.now();
+        let pending_until = |pending: &Option<PendingVerification>| {
+            pending.as_ref().map(|pending| pending.expires_at).filter(|expires_at| *expires_at > now)
+        };
+        Some(CredentialReport {
+            directory: user.directory,
+            created_at: user.created_at,
+            hash_algorithm: user
+                .has_local_password()
+                .then(|| describe_hash(&user.password).map_or("unknown".to_owned(), |info| info.algorithm)),
+            password_reset_pending_until: pending_until(&user.password_reset),
+            email_verification_pending_until: pending_until(&user.email_verification),
+            user: UserView::from(user),
+        })
+    }
+
+    fn verify_credentials_batch(&self, pairs: &[(String, Password)], threads: &BatchThreads) -> Vec<Result<String, CredentialFailure>> {
+        threads.map(pairs, |(username, password)| {
+            let user = self
+                .find_local_user(username)
+                .filter(|user| self.verifier.is_local() || user.directory) // As on sign in.
+                .ok_or(CredentialFailure::UnknownUser)?;
+            if self.verify_password(username, password, Some(&user)) {
+                Ok(user.user_uuid)
+            } else {
+                Err(CredentialFailure::WrongPassword)
+            }
+        })
+    }
+
+    fn purge_reservations(&self, budget: &PurgeBudget) -> PurgeRun {
+        let now = self.clock.now();
+        let mut reservations = self.reservations.lock().unwrap();
+        let lapsed: Vec<_> = reservations
+            .iter()
+            .filter(|(_, reservation)| reservation.available_at <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        let mut run = PurgeRun::default();
+        for key in lapsed {
+            if budget.spent(run.purged) {
+                run.truncated = true;
+                break;
+            }
+            reservations.remove(&key);
+            run.purged += 1;
+        }
+        run
+    }
+
+    fn delete_user(&self, user_uuid: String) {
+        let Some(user) = self.store.remove(&user_uuid) else {
+            return;
+        };
+        if !user.guest {
+            self.user_events.record(UserEventKind::Deleted, &user_uuid, &user.username);
+        }
+
+        // Guests hold no username, so there is nothing to reserve.
+        if !self.username_reservation.is_zero() && !user.guest {
+            let reservation = Reservation {
+                user_uuid,
+                available_at: self.clock.now() + self.username_reservation,
+            };
+            let key = self.reservation_key(user.account_kind, &user.username_skeleton);
+            self.reservations.lock().unwrap().insert(key, reservation);
+        }
+    }
+
+    fn restore_user(&self, user_uuid: String, username: String, password: Password, email: Option<String>) -> Result<(), UsersError> {
+        // Stored in the form the service generates, so one uuid spelled two ways can't become two accounts.
+        let user_uuid = Uuid::parse_str(&user_uuid).map_err(|_| UsersError::InvalidUuid)?.to_string();
+        self.insert_user(user_uuid, username, password, email)
+    }
+
+    fn create_guest(&self) -> String {
+        let user_uuid = self.uuids.generate().to_string();
+        let user: User = User {
+            user_uuid: user_uuid.clone(),
+            username: String::new(),
+            username_skeleton: String::new(),
+            password: String::new(), // Not a PHC string, so nothing verifies against it.
+            email: None,
+            email_verified: false,
+            email_verification: None,
+            password_reset: None,
+            guest: true,
+            directory: false,
+            account_kind: AccountKind::Local,
+            password_change_required: false,
+            created_at: self.clock.now(),
+        };
+
+        // Guests aren't indexed by username and the uuid is fresh, so there is nothing to conflict with.
+        self.store.insert(user).expect("guest insert can't conflict");
+        user_uuid
+    }
+
+    fn upgrade_guest(&self, user_uuid: String, username: String, password: Password) -> Result<(), UsersError> {
+        let guest = self.store.get_by_uuid(&user_uuid).ok_or(UsersError::UserNotFound)?;
+        if !guest.guest {
+            return Err(UsersError::NotAGuest);
+        }
+        if !self.verifier.is_local() {
+            return Err(UsersError::DirectoryManaged);
+        }
+
+        let username = username.trim().to_owned(); // As in `insert_user`.
+        if username.is_empty() {
+            return Err(UsersError::UsernameEmpty);
+        }
+        let username_skeleton = skeleton(&username);
+        self.check_username_allowed(&username)?;
+        self.check_password_policy(&password)?;
+        self.check_username_available(AccountKind::Local, &username, &username_skeleton)?;
+        self.check_reservation(AccountKind::Local, &username_skeleton, &user_uuid)?;
+        let password = self.hash_password(&password)?;
+
+        self.store.update(User {
+            username: username.clone(),
+            username_skeleton,
+            password,
+            guest: false,
+            ..guest
+        })?;
+        self.user_events.record(UserEventKind::Created, &user_uuid, &username);
+        Ok(())
+    }
+
+    fn purge_guests(&self, older_than: Duration, dry_run: bool) -> GuestPurgeReport {
+        // A dry run selects exactly as the real one does, so its report is what the real one would delete.
+        let now = self.clock.now();
+        let expired: Vec<String> = self
+            .store
+            .users()
+            .into_iter()
+            .filter(|user| user.guest && user.created_at + older_than <= now)
+            .map(|user| user.user_uuid)
+            .collect();
+        if dry_run {
+            return GuestPurgeReport::new(expired, true);
+        }
+
+        // Re-check on removal: a guest may have been upgraded since the snapshot.
+        let purged = expired
+            .into_iter()
+            .filter(|user_uuid| match self.store.get_by_uuid(user_uuid) {
+                Some(current) if current.guest => self.store.remove(user_uuid).is_some(),
+                _ => false,
+            })
+            .collect();
+        GuestPurgeReport::new(purged, false)
+    }
+
+    fn scan_hash_parameters(&self) -> HashParameterScan {
+        let mut scan = HashParameterScan::default();
+        for user in self.store.users().into_iter().filter(User::has_local_password) {
+            let key = match describe_hash(&user.password) {
+                Some(info) => (info.algorithm, info.rounds),
+                None => ("unknown".to_owned(), None),
+            };
+            *scan.0.entry(key).or_default() += 1;
+        }
+        scan
+    }
+
+    fn list_users_by_hash_algorithm(&self, algorithm: &str) -> Vec<UserView> {
+        let mut users: Vec<UserView> = self
+            .store
+            .users()
+            .into_iter()
+            .filter(User::has_local_password)
+            .filter(|user| describe_hash(&user.password).map_or("unknown".to_owned(), |info| info.algorithm) == algorithm)
+            .map(UserView::from)
+            .collect();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        users
+    }
+
+    fn require_password_change_for(&self, user_uuids: &[String]) -> usize {
+        let mut flagged = 0;
+        for user_uuid in user_uuids {
+            let Some(user) = self.store.get_by_uuid(user_uuid) else {
+                continue;
+            };
+            if !user.has_local_password() {
+                continue;
+            }
+            if self.store.update(User { password_change_required: true, ..user }).is_ok() {
+                flagged += 1;
+            }
+        }
+        flagged
+    }
+
+    fn stats(&self) -> UserStats {
+        let counts = self.store.counts();
+        UserStats {
+            users: counts.users,
+            guests: counts.guests,
+            reservations: self.reservations.lock().unwrap().len(),
+        }
+    }
+
+    fn check_store_integrity(&self) -> Option<IntegrityReport> {
+        self.store.check_integrity()
+    }
+}
+
+// Behavioral contract every `Users` implementation must satisfy, whatever stores it. Expands to a set of tests in
+// the calling module, each building a fresh backend with `$factory`, a `fn() -> Box<dyn Users + Send + Sync>` in its
+// default configuration: no ban list, no username reservation, any password length.
+//
+//     mod memory_users_conformance {
+//         users_conformance_tests!(|| Box::new(UsersImpl::with_hash_rounds(1_000)));
+//     }
+//
+// Every assertion names the clause it checks, e.g. "2.3", so a failure says which part of the contract broke:
+//
+//     1. Creation: uuids, duplicate and lookalike usernames, email normalization and uniqueness.
+//     2. Lookup: sign in however the username is cased or spaced, and nothing for wrong or unknown credentials.
+//     3. Password change: same uuid, only the new password works, errors for wrong credentials.
+//     4. Deletion: gone from every lookup, unknown uuids ignored, username free again.
+//     5. Guests: no credentials, upgraded in place.
+//     6. Ordering: lists come back in their documented order.
+//     7. Counts.
+#[cfg(test)]
+macro_rules! users_conformance_tests {
+    ($factory:expr) => {
+        use std::time::Duration;
+
+        use crate::pool::BatchThreads;
+        use crate::users::{CredentialFailure, Users, UsersError};
+
+        fn users() -> Box<dyn Users + Send + Sync> {
+            ($factory)()
+        }
+
+        fn sign_in(users: &dyn Users, username: &str, password: &str) -> Option<String> {
+            users.get_user_uuid(username.to_owned(), password.into())
+        }
+
+        fn create(users: &dyn Users, username: &str, email: Option<&str>) -> Result<String, UsersError> {
+            users.create_user(username.to_owned(), "password".into(), email.map(str::to_owned))
+        }
+
+        #[test]
+        fn contract_1_creation() {
+            let users = users();
+            let alice = create(&*users, "alice", None).expect("1.1 a free username is accepted");
+            let view = users.get_user(&alice).expect("1.1 the returned uuid finds the account");
+            assert_eq!((view.username.as_str(), view.guest), ("alice", false), "1.1 the account is as created");
+
+            let bob = create(&*users, "bob", None).unwrap();
+            assert_ne!(alice, bob, "1.2 every account gets its own uuid");
+
+            assert_eq!(create(&*users, "alice", None), Err(UsersError::UsernameTaken), "1.3 an exact duplicate is taken");
+            assert_eq!(
+                create(&*users, "ALICE", None),
+                Err(UsersError::UsernameConfusable { conflicts_with: "alice".to_owned() }),
+                "1.4 a case variant is confusable with the existing account"
+            );
+            assert_eq!(
+                create(&*users, "\u{0430}lice", None),
+                Err(UsersError::UsernameConfusable { conflicts_with: "alice".to_owned() }),
+                "1.4 a lookalike is confusable with the existing account"
+            );
+
+            let carol = create(&*users, "  carol\t", None).expect("1.5 surrounding whitespace is accepted");
+            assert_eq!(users.get_user(&carol).unwrap().username, "carol", "1.5 surrounding whitespace is trimmed");
+            assert_eq!(create(&*users, "carol", None), Err(UsersError::UsernameTaken), "1.5 the trimmed name is taken");
+
+            let dave = create(&*users, "dave", Some(" Dave@Example.COM ")).unwrap();
+            assert_eq!(users.get_user(&dave).unwrap().email.as_deref(), Some("dave@example.com"), "1.6 emails are normalized");
+            assert_eq!(
+                users.find_user_by_email("DAVE@example.com").map(|user| user.user_uuid),
+                Some(dave),
+                "1.6 an email is found however it's cased"
+            );
+            assert_eq!(create(&*users, "erin", Some("dave@EXAMPLE.com")), Err(UsersError::EmailTaken), "1.7 emails are unique");
+            assert_eq!(create(&*users, "erin", Some("not an email")), Err(UsersError::InvalidEmail), "1.8 invalid emails are refused");
+            assert!(users.get_user_uuid("erin".to_owned(), "password".into()).is_none(), "1.9 a refused account isn't created");
+        }
+
+        #[test]
+        fn contract_2_lookup() {
+            let users = users();
+            let alice = create(&*users, "Alice", None).unwrap();
+
+            assert_eq!(sign_in(&*users, "Alice", "password"), Some(alice.clone()), "2.1 the username as created signs in");
+            assert_eq!(sign_in(&*users, " alice ", "password"), Some(alice.clone()), "2.2 case and spacing don't matter");
+            assert_eq!(sign_in(&*users, "Alice", "Password"), None, "2.3 passwords are case sensitive");
+            assert_eq!(sign_in(&*users, "nobody", "password"), None, "2.4 unknown usernames don't sign in");
+            assert_eq!(sign_in(&*users, "", ""), None, "2.4 an empty username doesn't sign in");
+            assert_eq!(users.get_user("unknown"), None, "2.5 unknown uuids find nothing");
+            assert!(users.get_user(&alice).is_some(), "2.5 known uuids find their account");
+        }
+
+        #[test]
+        fn contract_3_password_change() {
+            let users = users();
+            let alice = create(&*users, "alice", None).unwrap();
+            let change = |password: &str, new_password: &str| {
+                users.change_password("alice".to_owned(), password.into(), new_password.into())
+            };
+
+            assert_eq!(change("password", "new password"), Ok(alice.clone()), "3.1 the account keeps its uuid");
+            assert_eq!(sign_in(&*users, "alice", "new password"), Some(alice.clone()), "3.2 the new password signs in");
+            assert_eq!(sign_in(&*users, "alice", "password"), None, "3.2 the old password no longer does");
+            assert_eq!(change("password", "other"), Err(UsersError::WrongPassword), "3.3 a wrong current password is refused");
+            assert_eq!(
+                users.change_password("nobody".to_owned(), "password".into(), "other".into()),
+                Err(UsersError::WrongPassword),
+                "3.3 an unknown user looks like a wrong password"
+            );
+            assert_eq!(users.get_user(&alice).unwrap().username, "alice", "3.4 nothing else about the account changes");
+        }
+
+        #[test]
+        fn contract_4_deletion() {
+            let users = users();
+            let alice = create(&*users, "alice", Some("alice@example.com")).unwrap();
+            let bob = create(&*users, "bob", None).unwrap();
+
+            users.delete_user(alice.clone());
+            assert_eq!(users.get_user(&alice), None, "4.1 a deleted account is gone by uuid");
+            assert_eq!(sign_in(&*users, "alice", "password"), None, "4.1 a deleted account no longer signs in");
+            assert_eq!(users.find_user_by_email("alice@example.com"), None, "4.1 a deleted account is gone by email");
+
+            users.delete_user("unknown".to_owned());
+            users.delete_user(alice.clone());
+            assert!(users.get_user(&bob).is_some(), "4.2 deleting an unknown uuid changes nothing");
+
+            let new_alice = create(&*users, "alice", Some("alice@example.com")).expect("4.3 the username and email are free again");
+            assert_ne!(new_alice, alice, "4.3 a new account never gets the deleted one's uuid");
+        }
+
+        #[test]
+        fn contract_5_guests() {
+            let users = users();
+            let guest = users.create_guest();
+            let view = users.get_user(&guest).expect("5.1 a guest is found by uuid");
+            assert!(view.guest && view.username.is_empty(), "5.1 a guest has no username");
+            assert_eq!(sign_in(&*users, "", "password"), None, "5.1 a guest can't sign in with a password");
+
+            users.upgrade_guest(guest.clone(), "alice".to_owned(), "password".into()).expect("5.2 a guest can be upgraded");
+            assert_eq!(sign_in(&*users, "alice", "password"), Some(guest.clone()), "5.2 an upgraded guest keeps its uuid");
+            assert_eq!(
+                users.upgrade_guest(guest, "bob".to_owned(), "password".into()),
+                Err(UsersError::NotAGuest),
+                "5.3 only guests can be upgraded"
+            );
+            assert_eq!(
+                users.upgrade_guest("unknown".to_owned(), "bob".to_owned(), "password".into()),
+                Err(UsersError::UserNotFound),
+                "5.3 unknown uuids can't be upgraded"
+            );
+        }
+
+        #[test]
+        fn contract_6_ordering() {
+            let users = users();
+            let uuids: Vec<String> = ["carol", "alice", "bob"].iter().map(|name| create(&*users, name, None).unwrap()).collect();
+            let algorithm = users.credential_report(&uuids[0]).unwrap().hash_algorithm.unwrap();
+            let names: Vec<String> = users.list_users_by_hash_algorithm(&algorithm).into_iter().map(|user| user.username).collect();
+            assert_eq!(names, vec!["alice", "bob", "carol"], "6.1 accounts by hash algorithm are sorted by username");
+
+            let mut guests: Vec<String> = (0..3).map(|_| users.create_guest()).collect();
+            let report = users.purge_guests(Duration::ZERO, true);
+            guests.sort();
+            assert_eq!(report.user_uuids, guests, "6.2 guest purge reports are sorted by uuid");
+
+            let pairs = [("bob", "password"), ("nobody", "password"), ("alice", "wrong"), ("carol", "password")];
+            let pairs: Vec<_> = pairs.iter().map(|(username, password)| (username.to_string(), (*password).into())).collect();
+            assert_eq!(
+                users.verify_credentials_batch(&pairs, &BatchThreads::new(2)),
+                vec![
+                    Ok(uuids[2].clone()),
+                    Err(CredentialFailure::UnknownUser),
+                    Err(CredentialFailure::WrongPassword),
+                    Ok(uuids[0].clone()),
+                ],
+                "6.3 batch verification answers in input order"
+            );
+        }
+
+        #[test]
+        fn contract_7_counts() {
+            let users = users();
+            assert_eq!((users.stats().users, users.stats().guests), (0, 0), "7.1 a new backend is empty");
+
+            let alice = create(&*users, "alice", None).unwrap();
+            create(&*users, "bob", None).unwrap();
+            let guest = users.create_guest();
+            create(&*users, "alice", None).unwrap_err();
+            assert_eq!((users.stats().users, users.stats().guests), (2, 1), "7.2 refused accounts aren't counted");
+
+            users.upgrade_guest(guest, "carol".to_owned(), "password".into()).unwrap();
+            users.delete_user(alice);
+            assert_eq!((users.stats().users, users.stats().guests), (2, 0), "7.3 upgrades and deletions are counted");
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::ManualClock;
+    use crate::credentials::{DirectoryVerifier, FakeDirectory};
+    use crate::events::RecordingEvents;
+    use crate::uuids::V7Generator;
+
+    use super::*;
+
+    mod memory_users_conformance {
+        use super::UsersImpl;
+
+        users_conformance_tests!(|| Box::new(UsersImpl::with_hash_rounds(1_000)));
+    }
+
+    mod per_kind_users_conformance {
+        use super::{MemoryUserStore, UsernameScope, UsersImpl};
+
+        users_conformance_tests!(|| Box::new(UsersImpl::with_store(MemoryUserStore::with_username_scope(UsernameScope::PerKind), 1_000)));
+    }
+
+    #[test]
+    fn should_create_user() {
+        let user_service = UsersImpl::default();
+        user_service
+            .create_user("username".to_owned(), "password".into(), None)
+            .expect("should create user");
+
+        assert_eq!(user_service.store.len(), 1);
+    }
+
+    #[test]
+    fn should_fail_creating_user_with_existing_username() {
+        let user_service = UsersImpl::default();
+        user_service
+            .create_user("username".to_owned(), "password".into(), None)
+            .expect("should create user");
+
+        let result = user_service.create_user("username".to_owned(), "password".into(), None);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn should_retrieve_user_uuid() {
+        let user_service = UsersImpl::default();
+        user_service
+            .create_user("username".to_owned(), "password".into(), None)
+            .expect("should create user");
+
+        assert!(user_service
+            .get_user_uuid("username".to_owned(), "password".into())
+            .is_some());
+    }
+
+    #[test]
+    fn should_fail_to_retrieve_user_uuid_with_incorrect_password() {
+        let user_service = UsersImpl::default();
+        user_service
+            .create_user("username".to_owned(), "password".into(), None)
+            .expect("should create user");
+
+        assert!(user_service
+            .get_user_uuid("username".to_owned(), "incorrect password".into())
+            .is_none());
+    }
+
+    #[test]
+    fn should_delete_user() {
+        let user_service = UsersImpl::default();
+        user_service
+            .create_user("username".to_owned(), "password".into(), None)
+            .expect("should create user");
+
+        let user_uuid = user_service
+            .get_user_uuid("username".to_owned(), "password".into())
+            .unwrap();
+
+        user_service.delete_user(user_uuid);
+
+        assert_eq!(user_service.store.len(), 0);
+    }
+
+    #[test]
+    fn should_hash_with_configured_rounds() {
+        let user_service = UsersImpl::with_hash_rounds(1_000);
+        user_service
+            .create_user("username".to_owned(), "password".into(), None)
+            .expect("should create user");
+
+        let password = user_service.store.get_by_username(AccountKind::Local, "username").unwrap().password;
+        assert!(password.starts_with("$pbkdf2-sha256$i=1000,"));
+        assert!(user_service
+            .get_user_uuid("username".to_owned(), "password".into())
+            .is_some());
+    }
+
+    #[test]
+    fn should_return_username_taken_for_existing_username() {
+        let user_service = UsersImpl::default();
+        user_service
+            .create_user("username".to_owned(), "password".into(), None)
+            .expect("should create user");
+
+        let result = user_service.create_user("username".to_owned(), "password".into(), None);
+
+        assert_eq!(result, Err(UsersError::UsernameTaken));
+    }
+
+    #[test]
+    fn should_fail_creating_user_with_cyrillic_lookalike_username() {
+        let user_service = UsersImpl::default();
+        user_service
+            .create_user("admin".to_owned(), "password".into(), None)
+            .expect("should create user");
+
+        let result = user_service.create_user("\u{0430}dmin".to_owned(), "password".into(), None);
+
+        assert_eq!(
+            result,
+            Err(UsersError::UsernameConfusable {
+                conflicts_with: "admin".to_owned()
+            })
+        );
+        assert_eq!(user_service.store.len(), 1);
+    }
+
+    #[test]
+    fn should_fail_creating_user_with_zero_width_characters() {
+        let user_service = UsersImpl::default();
+        user_service
+            .create_user("admin".to_owned(), "password".into(), None)
+            .expect("should create user");
+
+        let result = user_service.create_user("ad\u{200B}min".to_owned(), "password".into(), None);
+
+        assert_eq!(
+            result,
+            Err(UsersError::UsernameConfusable {
+                conflicts_with: "admin".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn should_fail_creating_user_differing_only_by_case() {
+        let user_service = UsersImpl::default();
+        user_service
+            .create_user("Admin".to_owned(), "password".into(), None)
+            .expect("should create user");
+
+        let result = user_service.create_user("aDMIN".to_owned(), "password".into(), None);
+
+        assert_eq!(
+            result,
+            Err(UsersError::UsernameConfusable {
+                conflicts_with: "Admin".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn should_allow_confusable_username_after_original_is_deleted() {
+        let user_service = UsersImpl::default();
+        user_service
+            .create_user("admin".to_owned(), "password".into(), None)
+            .expect("should create user");
+
+        let user_uuid = user_service
+            .get_user_uuid("admin".to_owned(), "password".into())
+            .unwrap();
+        user_service.delete_user(user_uuid);
+
+        user_service
+            .create_user("\u{0430}dmin".to_owned(), "password".into(), None)
+            .expect("should create user");
+    }
+
+    #[test]
+    fn should_create_only_one_of_concurrent_users_with_same_username() {
+        let user_service = Arc::new(UsersImpl::with_hash_rounds(1_000));
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let user_service = user_service.clone();
+                std::thread::spawn(move || user_service.create_user("username".to_owned(), "password".into(), None))
+            })
+            .collect();
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+        assert!(results.iter().all(|r| r.is_ok() || *r == Err(UsersError::UsernameTaken)));
+        assert_eq!(user_service.store.len(), 1);
+    }
+
+    #[test]
+    fn should_build_shared_users_from_config() {
+        let config = AuthConfig {
+            hash_rounds: 1_000,
+            ..AuthConfig::default()
+        };
+        let user_service: Arc<dyn Users + Send + Sync> =
+            users_from_config(&config, Arc::new(UserEventLog::default()), Arc::new(V4Generator), UsernameScope::Unified, Arc::default(), Arc::default());
+
+        user_service
+            .create_user("username".to_owned(), "password".into(), None)
+            .expect("should create user");
+        assert!(user_service
+            .get_user_uuid("username".to_owned(), "password".into())
+            .is_some());
+    }
+
+    #[test]
+    fn should_create_v4_uuids_by_default() {
+        let user_service = UsersImpl::with_hash_rounds(1_000);
+        let user_uuid = user_service.create_user("username".to_owned(), "password".into(), None).unwrap();
+
+        assert_eq!(user_service.get_user_uuid("username".to_owned(), "password".into()), Some(user_uuid.clone()));
+        assert_eq!(uuid::Uuid::parse_str(&user_uuid).unwrap().get_version(), Some(uuid::Version::Random));
+    }
+
+    #[test]
+    fn should_create_uuids_with_injected_generator() {
+        let user_service = UsersImpl::with_hash_rounds(1_000).with_uuid_generator(Arc::new(V7Generator::default()));
+        let mut user_uuids: Vec<String> = Vec::new();
+        for i in 0..20 {
+            let username = format!("user{i}");
+            user_uuids.push(user_service.create_user(username, "password".into(), None).unwrap());
+        }
+
+        // v7 uuids sort by creation time, as strings too.
+        assert!(user_uuids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(uuid::Uuid::parse_str(&user_uuids[0]).unwrap().get_version(), Some(uuid::Version::SortRand));
+    }
+
+    fn user_service_with_email(username: &str, email: &str) -> UsersImpl {
+        let user_service = UsersImpl::with_hash_rounds(1_000);
+        user_service
+            .create_user(username.to_owned(), "password".into(), Some(email.to_owned()))
+            .expect("should create user");
+        user_service
+    }
+
+    #[test]
+    fn should_store_normalized_email_and_find_user_by_it() {
+        let user_service = user_service_with_email("alice", "Foo@Gmail.com");
+        let user_uuid = user_service
+            .get_user_uuid("alice".to_owned(), "password".into())
+            .unwrap();
+
+        let expected = Some(UserView {
+            user_uuid,
+            username: "alice".to_owned(),
+            email: Some("foo@gmail.com".to_owned()),
+            email_verified: false,
+            guest: false,
+            account_kind: AccountKind::Local,
+            password_change_required: false,
+        });
+        assert_eq!(user_service.find_user_by_email("foo@gmail.com"), expected);
+        assert_eq!(user_service.find_user_by_email("FOO@gmail.COM"), expected);
+        assert_eq!(user_service.find_user_by_email("bar@gmail.com"), None);
+        assert_eq!(user_service.find_user_by_email("not an email"), None);
+    }
+
+    #[test]
+    fn should_fail_creating_user_with_email_differing_only_by_case() {
+        let user_service = user_service_with_email("alice", "Foo@Gmail.com");
+
+        let result = user_service.create_user("bob".to_owned(), "password".into(), Some("foo@gmail.com".to_owned()));
+
+        assert_eq!(result, Err(UsersError::EmailTaken));
+        assert_eq!(user_service.store.len(), 1);
+    }
+
+    #[test]
+    fn should_fail_creating_user_with_invalid_email() {
+        let user_service = UsersImpl::with_hash_rounds(1_000);
+
+        let result = user_service.create_user("alice".to_owned(), "password".into(), Some("alice".to_owned()));
+
+        assert_eq!(result, Err(UsersError::InvalidEmail));
+        assert_eq!(user_service.store.len(), 0);
+    }
+
+    #[test]
+    fn should_set_and_clear_email() {
+        let user_service = user_service_with_email("alice", "alice@example.com");
+        let user_uuid = user_service
+            .get_user_uuid("alice".to_owned(), "password".into())
+            .unwrap();
+
+        user_service
+            .set_email(user_uuid.clone(), Some("Alice@New.example".to_owned()))
+            .unwrap();
+        assert_eq!(user_service.find_user_by_email("alice@example.com"), None);
+        assert_eq!(
+            user_service.find_user_by_email("alice@new.example").map(|user| user.user_uuid),
+            Some(user_uuid.clone())
+        );
+
+        user_service.set_email(user_uuid, None).unwrap();
+        assert_eq!(user_service.find_user_by_email("alice@new.example"), None);
+    }
+
+    #[test]
+    fn should_fail_setting_email_taken_by_another_user() {
+        let user_service = user_service_with_email("alice", "alice@example.com");
+        user_service
+            .create_user("bob".to_owned(), "password".into(), None)
+            .expect("should create user");
+        let bob_uuid = user_service
+            .get_user_uuid("bob".to_owned(), "password".into())
+            .unwrap();
+
+        assert_eq!(
+            user_service.set_email(bob_uuid.clone(), Some("ALICE@example.com".to_owned())),
+            Err(UsersError::EmailTaken)
+        );
+        assert_eq!(
+            user_service.set_email(bob_uuid, Some("bob".to_owned())),
+            Err(UsersError::InvalidEmail)
+        );
+    }
+
+    #[test]
+    fn should_fail_setting_email_of_unknown_user() {
+        let user_service = UsersImpl::with_hash_rounds(1_000);
+        assert_eq!(
+            user_service.set_email("unknown".to_owned(), Some("alice@example.com".to_owned())),
+            Err(UsersError::UserNotFound)
+        );
+    }
+
+    #[test]
+    fn should_find_user_by_email_after_rename() {
+        let user_service = user_service_with_email("alice", "alice@example.com");
+        let user = user_service.store.get_by_username(AccountKind::Local, "alice").unwrap();
+
+        user_service
+            .store
+            .update(User {
+                username: "alicia".to_owned(),
+                username_skeleton: skeleton("alicia"),
+                ..user
+            })
+            .unwrap();
+
+        assert_eq!(
+            user_service.find_user_by_email("alice@example.com").map(|user| user.username),
+            Some("alicia".to_owned())
+        );
+    }
+
+    // Fills every request with the next byte value, so tokens are predictable and differ between calls.
+    struct CountingRng(u8);
+
+    impl RngCore for CountingRng {
+        fn next_u32(&mut self) -> u32 {
+            rand_core::impls::next_u32_via_fill(self)
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            rand_core::impls::next_u64_via_fill(self)
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            self.0 += 1;
+            dest.fill(self.0);
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    struct VerificationFixture {
+        user_service: UsersImpl,
+        clock: Arc<ManualClock>,
+        events: Arc<RecordingEvents>,
+        user_uuid: String,
+    }
+
+    fn verification_fixture() -> VerificationFixture {
+        let clock = Arc::new(ManualClock::new());
+        let events = Arc::new(RecordingEvents::default());
+        let user_service = UsersImpl::with_hash_rounds(1_000)
+            .with_email_verification_ttl(Duration::from_secs(60))
+            .with_clock(clock.clone())
+            .with_rng(Box::new(CountingRng(0)))
+            .with_events(events.clone());
+        user_service
+            .create_user("alice".to_owned(), "password".into(), Some("alice@example.com".to_owned()))
+            .expect("should create user");
+        let user_uuid = user_service
+            .get_user_uuid("alice".to_owned(), "password".into())
+            .unwrap();
+
+        VerificationFixture {
+            user_service,
+            clock,
+            events,
+            user_uuid,
+        }
+    }
+
+    fn is_verified(fixture: &VerificationFixture) -> bool {
+        fixture.user_service.get_user(&fixture.user_uuid).unwrap().email_verified
+    }
 
-pub trait Users {
-    fn create_user(&mut self, username: String, password: String) -> Result<(), String>;
-    fn get_user_uuid(&self, username: String, password: String) -> Option<String>;
-    fn delete_user(&mut self, user_uuid: String);
-}
+    #[test]
+    fn should_verify_email_with_token_from_event() {
+        let fixture = verification_fixture();
+        let uuid = || fixture.user_uuid.clone();
 
-#[derive(Clone)]
-pub struct User {
-    user_uuid: String,
-    username: String,
-    password: String,
-}
+        let VerificationToken(token) = fixture.user_service.start_email_verification(uuid()).unwrap();
+        assert_eq!(token, URL_SAFE_NO_PAD.encode([1u8; 32]));
+        assert_eq!(
+            *fixture.events.0.lock().unwrap(),
+            vec![Event::EmailVerificationRequested {
+                user_uuid: uuid(),
+                email: "alice@example.com".to_owned(),
+                token: token.clone(),
+            }]
+        );
 
-#[derive(Default)]
-pub struct UsersImpl {
-    uuid_to_user: HashMap<String, User>,
-    username_to_user: HashMap<String, User>,
-}
+        // Only the hash is stored.
+        let pending = fixture.user_service.store.get_by_uuid(&uuid()).unwrap().email_verification.unwrap();
+        assert_ne!(pending.token_hash, token);
+        assert!(!is_verified(&fixture));
 
-impl Users for UsersImpl {
-    fn create_user(&mut self, username: String, password: String) -> Result<(), String> {
-        // TODO: Check if username already exist. If so return an error.
-        match self.username_to_user.get(&username) {
-            Some(name) => {
-                return Err("Username {name} already exists".to_owned());
-            }
-            None => {
-                let salt = SaltString::generate(&mut OsRng);
-
-                let hashed_password = Pbkdf2
-                    .hash_password(password.as_bytes(), &salt)
-                    .map_err(|e| format!("Failed to hash password.\n{e:?}"))?
-                    .to_string();
-        
-                let user: User = User{
-                    user_uuid: Uuid::new_v4().to_string(),
-                    username: username.clone(),
-                    password: hashed_password,
-                }; // Create new user with unique uuid and hashed password.
-        
-                self.username_to_user.insert(username, user.clone());
-                self.uuid_to_user.insert(user.user_uuid.clone(), user);
-        
-                return Ok(());
-            }
-        }
+        fixture.user_service.confirm_email(uuid(), &token).unwrap();
+        assert!(is_verified(&fixture));
+    }
+
+    #[test]
+    fn should_reject_reused_or_wrong_verification_token() {
+        let fixture = verification_fixture();
+        let uuid = || fixture.user_uuid.clone();
+
+        assert_eq!(fixture.user_service.confirm_email(uuid(), "anything"), Err(UsersError::VerificationFailed));
 
+        let VerificationToken(token) = fixture.user_service.start_email_verification(uuid()).unwrap();
+        assert_eq!(fixture.user_service.confirm_email(uuid(), "wrong"), Err(UsersError::VerificationFailed));
+        fixture.user_service.confirm_email(uuid(), &token).unwrap();
+        assert_eq!(fixture.user_service.confirm_email(uuid(), &token), Err(UsersError::VerificationFailed));
     }
 
-    fn get_user_uuid(&self, username: String, password: String) -> Option<String> {
-        
-        let user: Option<&User> = self.username_to_user.get(&username); // Retrieve `User` or return `None` is user can't be found.
-        if user.is_none() {
-            return None;
+    #[test]
+    fn should_reject_expired_verification_token() {
+        let fixture = verification_fixture();
+        let uuid = || fixture.user_uuid.clone();
+
+        let VerificationToken(token) = fixture.user_service.start_email_verification(uuid()).unwrap();
+        fixture.clock.advance(Duration::from_secs(60));
+
+        assert_eq!(fixture.user_service.confirm_email(uuid(), &token), Err(UsersError::VerificationExpired));
+        assert!(!is_verified(&fixture));
+    }
+
+    #[test]
+    fn should_invalidate_previous_token_on_re_request() {
+        let fixture = verification_fixture();
+        let uuid = || fixture.user_uuid.clone();
+
+        let VerificationToken(first) = fixture.user_service.start_email_verification(uuid()).unwrap();
+        let VerificationToken(second) = fixture.user_service.start_email_verification(uuid()).unwrap();
+        assert_ne!(first, second);
+
+        assert_eq!(fixture.user_service.confirm_email(uuid(), &first), Err(UsersError::VerificationFailed));
+        fixture.user_service.confirm_email(uuid(), &second).unwrap();
+    }
+
+    #[test]
+    fn should_reset_verification_when_email_changes() {
+        let fixture = verification_fixture();
+        let uuid = || fixture.user_uuid.clone();
+
+        let VerificationToken(token) = fixture.user_service.start_email_verification(uuid()).unwrap();
+        fixture.user_service.confirm_email(uuid(), &token).unwrap();
+
+        // Same address in another casing is no change.
+        fixture.user_service.set_email(uuid(), Some("Alice@Example.com".to_owned())).unwrap();
+        assert!(is_verified(&fixture));
+
+        let VerificationToken(token) = fixture.user_service.start_email_verification(uuid()).unwrap();
+        fixture.user_service.set_email(uuid(), Some("alice@new.example".to_owned())).unwrap();
+        assert!(!is_verified(&fixture));
+        assert_eq!(fixture.user_service.confirm_email(uuid(), &token), Err(UsersError::VerificationFailed));
+    }
+
+    #[test]
+    fn should_fail_starting_verification_without_email() {
+        let fixture = verification_fixture();
+        fixture.user_service.set_email(fixture.user_uuid.clone(), None).unwrap();
+
+        assert_eq!(
+            fixture.user_service.start_email_verification(fixture.user_uuid.clone()),
+            Err(UsersError::NoEmail)
+        );
+        assert!(fixture.events.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn should_reset_password_with_token_from_event() {
+        let fixture = verification_fixture();
+
+        let ResetToken(token) = fixture.user_service.start_password_reset("alice".to_owned()).unwrap();
+        assert_eq!(
+            *fixture.events.0.lock().unwrap(),
+            vec![Event::PasswordResetRequested {
+                user_uuid: fixture.user_uuid.clone(),
+                email: Some("alice@example.com".to_owned()),
+                token: token.clone(),
+            }]
+        );
+
+        assert_eq!(
+            fixture.user_service.complete_password_reset(token, "new password".into()),
+            Ok(fixture.user_uuid.clone())
+        );
+        assert_eq!(
+            fixture.user_service.get_user_uuid("alice".to_owned(), "new password".into()),
+            Some(fixture.user_uuid.clone())
+        );
+        assert_eq!(fixture.user_service.get_user_uuid("alice".to_owned(), "password".into()), None);
+    }
+
+    #[test]
+    fn should_start_password_reset_by_email_in_any_case() {
+        let fixture = verification_fixture();
+        assert!(fixture.user_service.start_password_reset("ALICE@example.com".to_owned()).is_some());
+    }
+
+    #[test]
+    fn should_find_account_however_username_is_typed() {
+        let user_service = UsersImpl::with_hash_rounds(1_000);
+        user_service.create_user("\u{00C9}lodie".to_owned(), "password".into(), None).unwrap();
+        let elodie = user_service.get_user_uuid("\u{00C9}lodie".to_owned(), "password".into()).unwrap();
+
+        for typed in [" \u{00C9}lodie", "\u{00E9}lodie", "E\u{0301}LODIE "] {
+            assert_eq!(user_service.get_user_uuid(typed.to_owned(), "password".into()).as_ref(), Some(&elodie), "{typed:?}");
+            assert!(user_service.start_password_reset(typed.to_owned()).is_some(), "{typed:?}");
         }
-        // Get user's password as `PasswordHash` instance. 
-        let hashed_password = user.unwrap().password.clone();
-        let parsed_hash = PasswordHash::new(&hashed_password).ok()?;
+        assert_eq!(user_service.change_password(" elodie".to_owned(), "password".into(), "new password".into()), Err(UsersError::WrongPassword));
+        assert_eq!(user_service.change_password(" \u{00E9}lodie".to_owned(), "password".into(), "new password".into()), Ok(elodie));
+    }
 
-        // Verify passed in password matches user's password.
-        let result = Pbkdf2.verify_password(password.as_bytes(), &parsed_hash);
+    #[test]
+    fn should_not_find_account_by_lookalike_username() {
+        let user_service = UsersImpl::with_hash_rounds(1_000);
+        user_service.create_user("alice".to_owned(), "password".into(), None).unwrap();
+
+        assert_eq!(user_service.get_user_uuid("\u{0430}lice".to_owned(), "password".into()), None); // Cyrillic 'а'
+        assert_eq!(user_service.start_password_reset("a\u{200B}lice".to_owned()), None);
+    }
+
+    #[test]
+    fn should_not_issue_reset_token_for_unknown_account() {
+        let fixture = verification_fixture();
+
+        assert_eq!(fixture.user_service.start_password_reset("bob".to_owned()), None);
+        assert_eq!(fixture.user_service.start_password_reset("bob@example.com".to_owned()), None);
+        assert!(fixture.events.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn should_reject_used_and_expired_reset_tokens_alike() {
+        let fixture = verification_fixture();
+
+        let ResetToken(used) = fixture.user_service.start_password_reset("alice".to_owned()).unwrap();
+        fixture.user_service.complete_password_reset(used.clone(), "first".into()).unwrap();
+        let used_error = fixture.user_service.complete_password_reset(used, "second".into());
+
+        let ResetToken(expired) = fixture.user_service.start_password_reset("alice".to_owned()).unwrap();
+        fixture.clock.advance(Duration::from_secs(30 * 60));
+        let expired_error = fixture.user_service.complete_password_reset(expired, "second".into());
+
+        assert_eq!(used_error, Err(UsersError::ResetTokenInvalid));
+        assert_eq!(expired_error, used_error);
+        assert_eq!(
+            fixture.user_service.get_user_uuid("alice".to_owned(), "first".into()),
+            Some(fixture.user_uuid.clone())
+        );
+    }
+
+    #[test]
+    fn should_reject_superseded_and_forged_reset_tokens() {
+        let fixture = verification_fixture();
 
-        // TODO: If the username and password passed in matches the user's username and password return the user's uuid.
+        let ResetToken(first) = fixture.user_service.start_password_reset("alice".to_owned()).unwrap();
+        let ResetToken(second) = fixture.user_service.start_password_reset("alice".to_owned()).unwrap();
 
-        match result {
-            Ok(_) => return Some(user.unwrap().user_uuid.clone()),
-            Err(_) => return None,
+        for token in [first, "garbage".to_owned(), format!("unknown-uuid.{}", second.split_once('.').unwrap().1)] {
+            assert_eq!(
+                fixture.user_service.complete_password_reset(token, "new password".into()),
+                Err(UsersError::ResetTokenInvalid)
+            );
         }
- 
+        fixture.user_service.complete_password_reset(second, "new password".into()).unwrap();
     }
 
-    fn delete_user(&mut self, user_uuid: String) {
-        let username = self.uuid_to_user.remove(&user_uuid).unwrap().username;
-        self.username_to_user.remove(&username).unwrap();
-    
+    #[test]
+    fn should_keep_reset_token_when_new_password_breaks_policy() {
+        let fixture = verification_fixture();
+        let user_service = fixture.user_service.with_min_password_length(12);
+
+        let ResetToken(token) = user_service.start_password_reset("alice".to_owned()).unwrap();
+        assert_eq!(
+            user_service.complete_password_reset(token.clone(), "short".into()),
+            Err(UsersError::PasswordTooShort { min_length: 12 })
+        );
+        user_service.complete_password_reset(token, "long enough password".into()).unwrap();
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn should_reset_password_only_once_under_concurrency() {
+        let fixture = verification_fixture();
+        let user_service = Arc::new(fixture.user_service);
+        let ResetToken(token) = user_service.start_password_reset("alice".to_owned()).unwrap();
+
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let user_service = user_service.clone();
+                let token = token.clone();
+                std::thread::spawn(move || user_service.complete_password_reset(token, format!("password {i}").into()))
+            })
+            .collect();
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+        assert!(results.iter().all(|r| r.is_ok() || *r == Err(UsersError::ResetTokenInvalid)));
+    }
 
     #[test]
-    fn should_create_user() {
-        let mut user_service = UsersImpl::default();
+    fn should_enforce_min_password_length_on_create() {
+        let user_service = UsersImpl::with_hash_rounds(1_000).with_min_password_length(8);
+
+        assert_eq!(
+            user_service.create_user("alice".to_owned(), "short".into(), None),
+            Err(UsersError::PasswordTooShort { min_length: 8 })
+        );
         user_service
-            .create_user("username".to_owned(), "password".to_owned())
+            .create_user("alice".to_owned(), "long enough".into(), None)
             .expect("should create user");
+    }
 
-        assert_eq!(user_service.uuid_to_user.len(), 1);
-        assert_eq!(user_service.username_to_user.len(), 1);
+    struct ReservationFixture {
+        user_service: UsersImpl,
+        clock: Arc<ManualClock>,
+        user_uuid: String,
     }
 
-    #[test]
-    fn should_fail_creating_user_with_existing_username() {
-        let mut user_service = UsersImpl::default();
+    // "admin" was created and deleted just now, with a 30 day reservation.
+    fn reservation_fixture() -> ReservationFixture {
+        let clock = Arc::new(ManualClock::new());
+        let user_service = UsersImpl::with_hash_rounds(1_000)
+            .with_username_reservation(Duration::from_secs(30 * 24 * 60 * 60))
+            .with_clock(clock.clone());
         user_service
-            .create_user("username".to_owned(), "password".to_owned())
+            .create_user("admin".to_owned(), "password".into(), None)
             .expect("should create user");
+        let user_uuid = user_service
+            .get_user_uuid("admin".to_owned(), "password".into())
+            .unwrap();
+        user_service.delete_user(user_uuid.clone());
 
-        let result = user_service.create_user("username".to_owned(), "password".to_owned());
+        ReservationFixture {
+            user_service,
+            clock,
+            user_uuid,
+        }
+    }
 
-        assert!(result.is_err());
+    fn create(user_service: &UsersImpl, username: &str) -> Result<String, UsersError> {
+        user_service.create_user(username.to_owned(), "password".into(), None)
     }
 
     #[test]
-    fn should_retrieve_user_uuid() {
-        let mut user_service = UsersImpl::default();
+    fn should_reserve_username_of_deleted_account_until_window_ends() {
+        let fixture = reservation_fixture();
+        let available_at = fixture.clock.now() + Duration::from_secs(30 * 24 * 60 * 60);
+
+        fixture.clock.advance(Duration::from_secs(30 * 24 * 60 * 60 - 1));
+        assert_eq!(create(&fixture.user_service, "admin"), Err(UsersError::UsernameReserved { available_at }));
+
+        fixture.clock.advance(Duration::from_secs(1));
+        create(&fixture.user_service, "admin").expect("should create user once the window ends");
+    }
+
+    #[test]
+    fn should_allow_reserved_username_after_window() {
+        let fixture = reservation_fixture();
+        fixture.clock.advance(Duration::from_secs(31 * 24 * 60 * 60));
+        create(&fixture.user_service, "admin").expect("should create user");
+    }
+
+    #[test]
+    fn should_reserve_lookalikes_of_deleted_username() {
+        let fixture = reservation_fixture();
+        assert!(matches!(
+            create(&fixture.user_service, "\u{0430}DMIN"),
+            Err(UsersError::UsernameReserved { .. })
+        ));
+    }
+
+    #[test]
+    fn should_let_original_owner_restore_reserved_username() {
+        let fixture = reservation_fixture();
+        let restore = |user_uuid: &str| {
+            fixture.user_service.restore_user(user_uuid.to_owned(), "admin".to_owned(), "password".into(), None)
+        };
+
+        let someone_else = "00000000-0000-0000-0000-000000000001";
+        assert!(matches!(restore(someone_else), Err(UsersError::UsernameReserved { .. })));
+        restore(&fixture.user_uuid).expect("should restore user");
+        assert_eq!(
+            fixture.user_service.get_user_uuid("admin".to_owned(), "password".into()),
+            Some(fixture.user_uuid.clone())
+        );
+        assert_eq!(restore(&fixture.user_uuid), Err(UsersError::UsernameTaken));
+    }
+
+    #[test]
+    fn should_reject_restoring_uuid_still_in_use() {
+        let fixture = reservation_fixture();
+        create(&fixture.user_service, "bob").unwrap();
+        let bob_uuid = fixture.user_service.get_user_uuid("bob".to_owned(), "password".into()).unwrap();
+
+        assert_eq!(
+            fixture.user_service.restore_user(bob_uuid, "carol".to_owned(), "password".into(), None),
+            Err(UsersError::UserAlreadyExists)
+        );
+    }
+
+    #[test]
+    fn should_reject_restoring_invalid_or_respelled_uuid() {
+        let fixture = reservation_fixture();
+        create(&fixture.user_service, "bob").unwrap();
+        let bob_uuid = fixture.user_service.get_user_uuid("bob".to_owned(), "password".into()).unwrap();
+        let restore = |user_uuid: String| {
+            fixture.user_service.restore_user(user_uuid, "carol".to_owned(), "password".into(), None)
+        };
+
+        assert_eq!(restore("not a uuid".to_owned()), Err(UsersError::InvalidUuid));
+        assert_eq!(restore(String::new()), Err(UsersError::InvalidUuid));
+        // Same uuid, other spellings.
+        assert_eq!(restore(bob_uuid.to_uppercase()), Err(UsersError::UserAlreadyExists));
+        assert_eq!(restore(format!("{{{bob_uuid}}}")), Err(UsersError::UserAlreadyExists));
+        assert_eq!(restore(bob_uuid.replace('-', "")), Err(UsersError::UserAlreadyExists));
+    }
+
+    #[test]
+    fn should_purge_only_expired_reservations() {
+        let fixture = reservation_fixture();
+        fixture.clock.advance(Duration::from_secs(10 * 24 * 60 * 60));
+        create(&fixture.user_service, "bob").unwrap();
+        let bob_uuid = fixture.user_service.get_user_uuid("bob".to_owned(), "password".into()).unwrap();
+        fixture.user_service.delete_user(bob_uuid);
+
+        assert_eq!(fixture.user_service.purge_reservations(&PurgeBudget::unlimited()).purged, 0);
+        fixture.clock.advance(Duration::from_secs(20 * 24 * 60 * 60));
+        assert_eq!(fixture.user_service.purge_reservations(&PurgeBudget::unlimited()).purged, 1);
+
+        create(&fixture.user_service, "admin").expect("should create user");
+        assert!(matches!(create(&fixture.user_service, "bob"), Err(UsersError::UsernameReserved { .. })));
+    }
+
+    // PBKDF2, but verification takes at least `delay`.
+    struct SlowScheme {
+        inner: Pbkdf2Scheme,
+        delay: Duration,
+    }
+
+    impl PasswordScheme for SlowScheme {
+        fn hash(&self, password: &Password) -> Result<String, String> {
+            self.inner.hash(password)
+        }
+
+        fn verify(&self, password: &Password, hash: &str) -> bool {
+            std::thread::sleep(self.delay);
+            self.inner.verify(password, hash)
+        }
+    }
+
+    fn slow_user_service(threshold: Duration) -> UsersImpl {
+        let user_service = UsersImpl::with_hash_rounds(1_000)
+            .with_scheme(Arc::new(SlowScheme {
+                inner: Pbkdf2Scheme::new(1_000),
+                delay: Duration::from_millis(50),
+            }))
+            .with_slow_verification_threshold(Some(threshold));
         user_service
-            .create_user("username".to_owned(), "password".to_owned())
+            .create_user("username".to_owned(), "password".into(), None)
             .expect("should create user");
+        user_service
+    }
 
-        assert!(user_service
-            .get_user_uuid("username".to_owned(), "password".to_owned())
-            .is_some());
+    #[test]
+    fn should_record_hash_and_verify_durations_by_algorithm() {
+        let user_service = slow_user_service(Duration::from_secs(60));
+        user_service.get_user_uuid("username".to_owned(), "password".into()).unwrap();
+        user_service.get_user_uuid("username".to_owned(), "wrong".into());
+
+        let durations = &user_service.hashing_metrics().durations;
+        assert_eq!(durations.with_labels(&["hash", "pbkdf2-sha256"]).count(), 1);
+        assert_eq!(durations.with_labels(&["verify", "pbkdf2-sha256"]).count(), 2);
+        assert!(durations.with_labels(&["verify", "pbkdf2-sha256"]).sum() >= Duration::from_millis(100));
+        assert_eq!(user_service.hashing_metrics().slow_verifications.get(), 0);
     }
 
     #[test]
-    fn should_fail_to_retrieve_user_uuid_with_incorrect_password() {
-        let mut user_service = UsersImpl::default();
+    fn should_time_store_lookup_apart_from_verification() {
+        let user_service = slow_user_service(Duration::from_secs(60));
+        let mut timings = PhaseTimings::default();
+
+        let user_uuid = user_service.get_user_uuid_timed("username".to_owned(), "password".into(), &mut timings);
+
+        assert!(user_uuid.is_some());
+        assert!(timings.get(SignInPhase::HashVerification) >= Duration::from_millis(50));
+        assert!(timings.get(SignInPhase::StoreLookup) < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn should_flag_verifications_slower_than_threshold() {
+        let user_service = slow_user_service(Duration::from_millis(10));
+
+        user_service.get_user_uuid("username".to_owned(), "password".into()).unwrap();
+
+        assert_eq!(user_service.hashing_metrics().slow_verifications.get(), 1);
+    }
+
+    #[test]
+    fn should_scan_hash_parameters_across_store() {
+        let user_service = UsersImpl::with_hash_rounds(1_000);
+        for username in ["alice", "bob", "carol", "dave"] {
+            user_service
+                .create_user(username.to_owned(), "password".into(), None)
+                .expect("should create user");
+        }
+        let rehash = |username: &str, password: String| {
+            let user = user_service.store.get_by_username(AccountKind::Local, username).unwrap();
+            user_service.store.update(User { password, ..user }).unwrap();
+        };
+        rehash("carol", Pbkdf2Scheme::new(2_000).hash(&Password::from("password")).unwrap());
+        rehash("dave", "not a hash".to_owned());
+
+        let scan = user_service.scan_hash_parameters();
+
+        assert_eq!(
+            scan,
+            HashParameterScan(BTreeMap::from([
+                (("pbkdf2-sha256".to_owned(), Some(1_000)), 2),
+                (("pbkdf2-sha256".to_owned(), Some(2_000)), 1),
+                (("unknown".to_owned(), None), 1),
+            ]))
+        );
+        assert_eq!(scan.to_string(), "pbkdf2-sha256 i=1000: 2, pbkdf2-sha256 i=2000: 1, unknown: 1");
+    }
+
+    #[test]
+    fn should_list_users_by_hash_algorithm() {
+        let user_service = UsersImpl::with_hash_rounds(1_000);
+        for username in ["carol", "alice", "bob", "dave"] {
+            user_service.create_user(username.to_owned(), "password".into(), None).unwrap();
+        }
+        user_service.create_guest();
+        let rehash = |username: &str, password: &str| {
+            let user = user_service.store.get_by_username(AccountKind::Local, username).unwrap();
+            user_service.store.update(User { password: password.to_owned(), ..user }).unwrap();
+        };
+        rehash("bob", "$argon2id$v=19$m=65536,t=3,p=4$c2FsdHNhbHQ$aGFzaGhhc2hoYXNoaGFzaA");
+        rehash("dave", "not a hash");
+
+        let usernames = |algorithm: &str| -> Vec<String> {
+            user_service.list_users_by_hash_algorithm(algorithm).into_iter().map(|user| user.username).collect()
+        };
+        assert_eq!(usernames("pbkdf2-sha256"), vec!["alice", "carol"]);
+        assert_eq!(usernames("argon2id"), vec!["bob"]);
+        assert_eq!(usernames("unknown"), vec!["dave"]);
+        assert!(usernames("bcrypt").is_empty());
+    }
+
+    #[test]
+    fn should_require_password_change_until_changed() {
+        let user_service = UsersImpl::with_hash_rounds(1_000);
+        user_service.create_user("alice".to_owned(), "old password".into(), None).unwrap();
+        let alice = user_service.get_user_uuid("alice".to_owned(), "old password".into()).unwrap();
+        let guest = user_service.create_guest();
+
+        let flagged = user_service.require_password_change_for(&[alice.clone(), guest.clone(), "unknown".to_owned()]);
+        assert_eq!(flagged, 1);
+        assert!(user_service.get_user(&alice).unwrap().password_change_required);
+        assert!(!user_service.get_user(&guest).unwrap().password_change_required);
+
+        assert_eq!(
+            user_service.change_password("alice".to_owned(), "wrong".into(), "new password".into()),
+            Err(UsersError::WrongPassword)
+        );
+        assert!(user_service.get_user(&alice).unwrap().password_change_required);
+
+        let changed = user_service.change_password("alice".to_owned(), "old password".into(), "new password".into());
+        assert_eq!(changed, Ok(alice.clone()));
+        assert!(!user_service.get_user(&alice).unwrap().password_change_required);
+        assert_eq!(user_service.get_user_uuid("alice".to_owned(), "new password".into()), Some(alice));
+        assert_eq!(user_service.get_user_uuid("alice".to_owned(), "old password".into()), None);
+    }
+
+    #[test]
+    fn should_clear_password_change_flag_on_reset() {
+        let user_service = UsersImpl::with_hash_rounds(1_000);
+        let alice = user_service.create_user("alice".to_owned(), "password".into(), None).unwrap();
+        user_service.require_password_change_for(std::slice::from_ref(&alice));
+
+        let ResetToken(token) = user_service.start_password_reset("alice".to_owned()).unwrap();
+        user_service.complete_password_reset(token, "new password".into()).unwrap();
+
+        assert!(!user_service.get_user(&alice).unwrap().password_change_required);
+    }
+
+    fn guest_user_service() -> (UsersImpl, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new());
+        (UsersImpl::with_hash_rounds(1_000).with_clock(clock.clone()), clock)
+    }
+
+    #[test]
+    fn should_keep_uuid_when_upgrading_guest() {
+        let (user_service, _) = guest_user_service();
+        let user_uuid = user_service.create_guest();
+        assert!(user_service.get_user(&user_uuid).unwrap().guest);
+
         user_service
-            .create_user("username".to_owned(), "password".to_owned())
-            .expect("should create user");
+            .upgrade_guest(user_uuid.clone(), "alice".to_owned(), "password".into())
+            .expect("should upgrade guest");
 
-        assert!(user_service
-            .get_user_uuid("username".to_owned(), "incorrect password".to_owned())
-            .is_none());
+        let user = user_service.get_user(&user_uuid).unwrap();
+        assert_eq!(user.username, "alice");
+        assert!(!user.guest);
     }
 
     #[test]
-    fn should_delete_user() {
-        let mut user_service = UsersImpl::default();
+    fn should_allow_password_login_only_after_upgrade() {
+        let (user_service, _) = guest_user_service();
+        let user_uuid = user_service.create_guest();
+        assert_eq!(user_service.get_user_uuid("".to_owned(), "".into()), None);
+
+        user_service
+            .upgrade_guest(user_uuid.clone(), "alice".to_owned(), "password".into())
+            .expect("should upgrade guest");
+
+        assert_eq!(user_service.get_user_uuid("alice".to_owned(), "password".into()), Some(user_uuid));
+    }
+
+    #[test]
+    fn should_not_count_guests_towards_username_uniqueness() {
+        let (user_service, _) = guest_user_service();
+        let first_guest = user_service.create_guest();
+        let second_guest = user_service.create_guest();
+
+        user_service
+            .upgrade_guest(first_guest, "alice".to_owned(), "password".into())
+            .expect("should upgrade guest");
+        assert_eq!(
+            user_service.upgrade_guest(second_guest, "alice".to_owned(), "password".into()),
+            Err(UsersError::UsernameTaken)
+        );
+    }
+
+    #[test]
+    fn should_validate_credentials_on_upgrade() {
+        let (user_service, _) = guest_user_service();
+        let user_service = user_service.with_min_password_length(8);
         user_service
-            .create_user("username".to_owned(), "password".to_owned())
+            .create_user("alice".to_owned(), "password".into(), None)
             .expect("should create user");
+        let user_uuid = user_service.create_guest();
 
-        let user_uuid = user_service
-            .get_user_uuid("username".to_owned(), "password".to_owned())
+        assert_eq!(
+            user_service.upgrade_guest(user_uuid.clone(), "Alice".to_owned(), "password".into()),
+            Err(UsersError::UsernameConfusable { conflicts_with: "alice".to_owned() })
+        );
+        assert_eq!(
+            user_service.upgrade_guest(user_uuid.clone(), "bob".to_owned(), "short".into()),
+            Err(UsersError::PasswordTooShort { min_length: 8 })
+        );
+        assert_eq!(
+            user_service.upgrade_guest(user_uuid.clone(), " \t ".to_owned(), "password".into()),
+            Err(UsersError::UsernameEmpty)
+        );
+        assert!(user_service.get_user(&user_uuid).unwrap().guest);
+    }
+
+    #[test]
+    fn should_only_upgrade_guests() {
+        let (user_service, _) = guest_user_service();
+        let alice_uuid = user_service.create_user("alice".to_owned(), "password".into(), None).unwrap();
+
+        assert_eq!(
+            user_service.upgrade_guest(alice_uuid, "bob".to_owned(), "password".into()),
+            Err(UsersError::NotAGuest)
+        );
+        assert_eq!(
+            user_service.upgrade_guest("unknown".to_owned(), "bob".to_owned(), "password".into()),
+            Err(UsersError::UserNotFound)
+        );
+    }
+
+    #[test]
+    fn should_purge_only_stale_guests() {
+        let (user_service, clock) = guest_user_service();
+        let stale_guest = user_service.create_guest();
+        let upgraded_guest = user_service.create_guest();
+        user_service
+            .upgrade_guest(upgraded_guest.clone(), "alice".to_owned(), "password".into())
             .unwrap();
+        clock.advance(Duration::from_secs(60));
+        let young_guest = user_service.create_guest();
 
-        user_service.delete_user(user_uuid);
+        assert_eq!(user_service.purge_guests(Duration::from_secs(60), false).guests, 1);
+
+        assert!(user_service.get_user(&stale_guest).is_none());
+        assert!(user_service.get_user(&upgraded_guest).is_some());
+        assert!(user_service.get_user(&young_guest).is_some());
+    }
+
+    #[test]
+    fn should_preview_guest_purge_without_deleting() {
+        let (user_service, clock) = guest_user_service();
+        let mut stale_guests = vec![user_service.create_guest(), user_service.create_guest()];
+        stale_guests.sort();
+        user_service.create_user("alice".to_owned(), "password".into(), None).unwrap();
+        clock.advance(Duration::from_secs(60));
+        user_service.create_guest();
+
+        let snapshot = || {
+            let mut users = user_service.store.users();
+            users.sort_by(|a, b| a.user_uuid.cmp(&b.user_uuid));
+            users
+        };
+        let before = snapshot();
+        let preview = user_service.purge_guests(Duration::from_secs(60), true);
+        assert_eq!(snapshot(), before);
+        assert_eq!(preview, GuestPurgeReport { guests: 2, user_uuids: stale_guests, dry_run: true });
+
+        let purged = user_service.purge_guests(Duration::from_secs(60), false);
+        assert_eq!(purged, GuestPurgeReport { dry_run: false, ..preview });
+        assert_eq!(snapshot().len(), 2);
+    }
+
+    #[test]
+    fn should_only_count_guests_in_large_purge_reports() {
+        let (user_service, clock) = guest_user_service();
+        for _ in 0..=MAX_REPORTED_UUIDS {
+            user_service.create_guest();
+        }
+        clock.advance(Duration::from_secs(60));
+
+        let report = user_service.purge_guests(Duration::from_secs(60), true);
+        assert_eq!(report.guests, MAX_REPORTED_UUIDS + 1);
+        assert!(report.user_uuids.is_empty());
+    }
+
+    #[test]
+    fn should_leave_guests_out_of_hash_parameter_scan() {
+        let (user_service, _) = guest_user_service();
+        user_service.create_guest();
+
+        assert_eq!(user_service.scan_hash_parameters(), HashParameterScan::default());
+    }
+
+    // Directory holding "alice" with password "secret", and a local account "bob" made before it was configured.
+    fn directory_user_service() -> (UsersImpl, Arc<FakeDirectory>) {
+        let directory = Arc::new(FakeDirectory {
+            entries: HashMap::from([
+                ("uid=alice,dc=example,dc=com".to_owned(), "secret".to_owned()),
+                ("uid=bob,dc=example,dc=com".to_owned(), "secret".to_owned()),
+            ]),
+            ..Default::default()
+        });
+        let user_service = UsersImpl::with_hash_rounds(1_000);
+        user_service.create_user("bob".to_owned(), "password".into(), None).unwrap();
+
+        let verifier = DirectoryVerifier::new(Box::new(directory.clone()), "dc=example,dc=com", "uid");
+        (user_service.with_verifier(Box::new(verifier)), directory)
+    }
+
+    #[test]
+    fn should_provision_directory_user_on_first_sign_in() {
+        let (user_service, _) = directory_user_service();
+
+        let user_uuid = user_service
+            .get_user_uuid("alice".to_owned(), "secret".into())
+            .expect("should sign in through the directory");
+
+        let user = user_service.store.get_by_uuid(&user_uuid).unwrap();
+        assert!(user.directory);
+        assert_eq!(user.username, "alice");
+        assert_eq!(user.password, "");
+        assert_eq!(user_service.get_user_uuid("alice".to_owned(), "secret".into()), Some(user_uuid));
+        assert_eq!(user_service.get_user_uuid("alice".to_owned(), "wrong".into()), None);
+    }
+
+    #[test]
+    fn should_not_sign_into_local_account_through_directory() {
+        let (user_service, directory) = directory_user_service();
+
+        assert_eq!(user_service.get_user_uuid("bob".to_owned(), "secret".into()), None);
+        assert_eq!(user_service.get_user_uuid("bob".to_owned(), "password".into()), None);
+        assert!(directory.binds.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn should_reject_local_password_operations_with_directory() {
+        let (user_service, _) = directory_user_service();
+        let alice_uuid = user_service.get_user_uuid("alice".to_owned(), "secret".into()).unwrap();
+
+        assert_eq!(
+            user_service.create_user("carol".to_owned(), "password".into(), None),
+            Err(UsersError::DirectoryManaged)
+        );
+        assert_eq!(user_service.start_password_reset("alice".to_owned()), None);
+        assert_eq!(
+            user_service.complete_password_reset(format!("{alice_uuid}.secret"), "new password".into()),
+            Err(UsersError::DirectoryManaged)
+        );
+
+        let guest_uuid = user_service.create_guest();
+        assert_eq!(
+            user_service.upgrade_guest(guest_uuid, "carol".to_owned(), "password".into()),
+            Err(UsersError::DirectoryManaged)
+        );
+    }
+
+    #[test]
+    fn should_reject_sign_in_when_directory_unavailable() {
+        let directory = FakeDirectory {
+            unavailable: true,
+            ..Default::default()
+        };
+        let verifier = DirectoryVerifier::new(Box::new(directory), "dc=example,dc=com", "uid");
+        let user_service = UsersImpl::with_hash_rounds(1_000).with_verifier(Box::new(verifier));
+
+        assert_eq!(user_service.get_user_uuid("alice".to_owned(), "secret".into()), None);
+        assert!(user_service.store.get_by_username(AccountKind::Local, "alice").is_none());
+    }
+
+    #[test]
+    fn should_label_directory_verifications() {
+        let (user_service, _) = directory_user_service();
+        user_service.get_user_uuid("alice".to_owned(), "secret".into()).unwrap();
+
+        assert_eq!(
+            user_service.hashing_metrics().durations.with_labels(&["verify", "directory"]).count(),
+            1
+        );
+    }
+
+    #[test]
+    fn should_refuse_banned_usernames_unless_created_as_reserved() {
+        let ban_list = UsernameBanList::parse("prefix staff").unwrap();
+        let user_service = UsersImpl::with_hash_rounds(1_000).with_ban_list(Arc::new(RwLock::new(ban_list)));
+
+        for username in ["admin", "\u{0410}dmin", "staff-alice", " admin "] {
+            assert_eq!(
+                user_service.create_user(username.to_owned(), "password".into(), None),
+                Err(UsersError::UsernameNotAllowed),
+                "{username:?}"
+            );
+        }
+        let guest_uuid = user_service.create_guest();
+        assert_eq!(
+            user_service.upgrade_guest(guest_uuid, "root".to_owned(), "password".into()),
+            Err(UsersError::UsernameNotAllowed)
+        );
+        assert_eq!(user_service.create_federated_user("StaffBob".to_owned(), None), Err(UsersError::UsernameNotAllowed));
+
+        user_service.create_reserved_user("admin".to_owned(), "password".into(), None).unwrap();
+        assert!(user_service.get_user_uuid("admin".to_owned(), "password".into()).is_some());
+        user_service.create_user("alice".to_owned(), "password".into(), None).unwrap();
+    }
+
+    fn scoped_user_service(scope: UsernameScope) -> UsersImpl {
+        UsersImpl::with_store(MemoryUserStore::with_username_scope(scope), 1_000)
+    }
+
+    #[test]
+    fn should_share_one_namespace_across_kinds_with_unified_scope() {
+        let user_service = scoped_user_service(UsernameScope::Unified);
+        user_service.create_federated_user("alice".to_owned(), None).unwrap();
+        user_service.create_user("bob".to_owned(), "password".into(), None).unwrap();
+
+        assert_eq!(
+            user_service.create_user("alice".to_owned(), "password".into(), None),
+            Err(UsersError::UsernameTaken)
+        );
+        assert_eq!(user_service.create_federated_user("bob".to_owned(), None), Err(UsersError::UsernameTaken));
+        assert_eq!(
+            user_service.create_federated_user("B0B".to_owned(), None),
+            Err(UsersError::UsernameConfusable { conflicts_with: "bob".to_owned() })
+        );
+        let guest_uuid = user_service.create_guest();
+        assert_eq!(
+            user_service.upgrade_guest(guest_uuid, "alice".to_owned(), "password".into()),
+            Err(UsersError::UsernameTaken)
+        );
+    }
+
+    #[test]
+    fn should_split_namespace_by_kind_with_per_kind_scope() {
+        let user_service = scoped_user_service(UsernameScope::PerKind);
+        user_service.create_user("alice".to_owned(), "password".into(), None).unwrap();
+        let federated_uuid = user_service.create_federated_user("alice".to_owned(), None).unwrap();
+
+        let local_uuid = user_service.get_user_uuid("alice".to_owned(), "password".into()).unwrap();
+        assert_ne!(local_uuid, federated_uuid);
+        assert_eq!(user_service.get_user(&federated_uuid).unwrap().account_kind, AccountKind::Federated);
+        assert_eq!(
+            user_service.store.get_by_username(AccountKind::Federated, "alice").unwrap().user_uuid,
+            federated_uuid
+        );
+
+        // Within a kind, duplicates and lookalikes are still rejected.
+        assert_eq!(user_service.create_federated_user("alice".to_owned(), None), Err(UsersError::UsernameTaken));
+        assert_eq!(
+            user_service.create_federated_user("ALICE".to_owned(), None),
+            Err(UsersError::UsernameConfusable { conflicts_with: "alice".to_owned() })
+        );
+
+        // Deleting a local account only reserves the local username.
+        let user_service = scoped_user_service(UsernameScope::PerKind).with_username_reservation(Duration::from_secs(60 * 60));
+        user_service.delete_user(user_service.create_user("alice".to_owned(), "password".into(), None).unwrap());
+        user_service.create_federated_user("alice".to_owned(), None).unwrap();
+        assert!(matches!(
+            user_service.create_user("alice".to_owned(), "password".into(), None),
+            Err(UsersError::UsernameReserved { .. })
+        ));
+    }
+
+    #[test]
+    fn should_never_sign_into_federated_account_with_password() {
+        for scope in [UsernameScope::Unified, UsernameScope::PerKind] {
+            let user_service = scoped_user_service(scope);
+            user_service.create_federated_user("alice".to_owned(), Some("alice@example.com".to_owned())).unwrap();
+
+            for password in ["", "password"] {
+                assert_eq!(user_service.get_user_uuid("alice".to_owned(), password.into()), None, "{scope:?}");
+            }
+            assert_eq!(
+                user_service.change_password("alice".to_owned(), "".into(), "new password".into()),
+                Err(UsersError::WrongPassword)
+            );
+            assert_eq!(user_service.start_password_reset("alice@example.com".to_owned()), None);
+            assert!(user_service.list_users_by_hash_algorithm("unknown").is_empty());
+        }
+    }
+
+    #[test]
+    fn should_verify_batch_in_input_order() {
+        let user_service = UsersImpl::with_hash_rounds(1_000);
+        let uuids: Vec<String> = (0..6)
+            .map(|i| user_service.create_user(format!("user{i}"), format!("password{i}").into(), None).unwrap())
+            .collect();
+        user_service.create_federated_user("federated".to_owned(), None).unwrap();
+
+        let mut pairs: Vec<(String, Password)> = (0..6).rev().map(|i| (format!("user{i}"), format!("password{i}").into())).collect();
+        pairs[1].1 = "wrong".into();
+        pairs.push(("nobody".to_owned(), "password".into()));
+        pairs.push(("federated".to_owned(), "".into()));
+        pairs.push((" USER0".to_owned(), "password0".into())); // Matched however typed, like on sign in.
+
+        let results = user_service.verify_credentials_batch(&pairs, &BatchThreads::new(3));
 
-        assert_eq!(user_service.uuid_to_user.len(), 0);
-        assert_eq!(user_service.username_to_user.len(), 0);
+        assert_eq!(
+            results,
+            vec![
+                Ok(uuids[5].clone()),
+                Err(CredentialFailure::WrongPassword),
+                Ok(uuids[3].clone()),
+                Ok(uuids[2].clone()),
+                Ok(uuids[1].clone()),
+                Ok(uuids[0].clone()),
+                Err(CredentialFailure::UnknownUser),
+                Err(CredentialFailure::UnknownUser),
+                Ok(uuids[0].clone()),
+            ]
+        );
+        assert_eq!(user_service.verify_credentials_batch(&[], &BatchThreads::new(3)), vec![]);
     }
 }
@@ -0,0 +1,153 @@
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+use rand_core::{OsRng, RngCore};
+use uuid::{Builder, Uuid};
+
+use crate::clock::{Clock, SystemClock};
+
+// Source of new ids for accounts, sessions and token nonces.
+pub trait UuidGenerator: Send + Sync {
+    fn generate(&self) -> Uuid;
+}
+
+// Random (version 4) ids.
+pub struct V4Generator;
+
+impl UuidGenerator for V4Generator {
+    fn generate(&self) -> Uuid {
+        Uuid::new_v4()
+    }
+}
+
+// Time-ordered (version 7) ids: a millisecond timestamp, then a 12-bit counter, then random bits. The counter makes
+// ids made within the same millisecond sort in creation order (RFC 9562, method 1). When it runs out, or the clock
+// goes backwards, the timestamp is advanced past the last id instead.
+pub struct V7Generator {
+    clock: Arc<dyn Clock>,
+    last: Mutex<(u64, u16)>, // (unix millis, counter) of the last id.
+}
+
+const V7_COUNTER_MAX: u16 = 0x0FFF;
+
+impl V7Generator {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            last: Mutex::new((0, 0)),
+        }
+    }
+}
+
+impl Default for V7Generator {
+    fn default() -> Self {
+        Self::new(Arc::new(SystemClock))
+    }
+}
+
+impl UuidGenerator for V7Generator {
+    fn generate(&self) -> Uuid {
+        let now = self.clock.now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64);
+
+        let (millis, counter) = {
+            let mut last = self.last.lock().unwrap();
+            *last = match *last {
+                (millis, counter) if now <= millis && counter < V7_COUNTER_MAX => (millis, counter + 1),
+                (millis, _) if now <= millis => (millis + 1, 0),
+                _ => (now, 0),
+            };
+            *last
+        };
+
+        let mut random_bytes = [0u8; 10];
+        OsRng.fill_bytes(&mut random_bytes[2..]);
+        random_bytes[..2].copy_from_slice(&counter.to_be_bytes()); // The builder keeps the low 12 bits.
+        Builder::from_unix_timestamp_millis(millis, &random_bytes).into_uuid()
+    }
+}
+
+// Builds the generator named by AUTH_UUID_VERSION.
+pub fn uuid_generator(version: &str) -> Result<Arc<dyn UuidGenerator>, String> {
+    match version {
+        "v4" => Ok(Arc::new(V4Generator)),
+        "v7" => Ok(Arc::new(V7Generator::default())),
+        other => Err(format!("Unsupported uuid version {other:?}, expected v4 or v7")),
+    }
+}
+
+// Deterministic ids for tests and fixtures: 00000000-0000-0000-0000-000000000001, then ...0002, and so on.
+#[cfg(test)]
+#[derive(Default)]
+pub struct SequentialGenerator(std::sync::atomic::AtomicU64);
+
+#[cfg(test)]
+impl UuidGenerator for SequentialGenerator {
+    fn generate(&self) -> Uuid {
+        let n = self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
+        Uuid::from_u128(n as u128)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use uuid::Version;
+
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn should_generate_random_v4_ids() {
+        let uuid = V4Generator.generate();
+        assert_eq!(uuid.get_version(), Some(Version::Random));
+        assert_ne!(uuid, V4Generator.generate());
+    }
+
+    #[test]
+    fn should_sort_v7_ids_by_creation_order() {
+        let clock = Arc::new(ManualClock::new());
+        let generator = V7Generator::new(clock.clone());
+
+        // Many ids per millisecond, enough to run the counter out, across several milliseconds.
+        let mut uuids = Vec::new();
+        for _ in 0..5 {
+            uuids.extend((0..5_000).map(|_| generator.generate()));
+            clock.advance(Duration::from_millis(1));
+        }
+
+        assert!(uuids.iter().all(|uuid| uuid.get_version() == Some(Version::SortRand)));
+        assert!(uuids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(uuids.iter().map(|uuid| uuid.to_string()).collect::<Vec<_>>().windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn should_keep_v7_ids_ordered_when_clock_goes_back() {
+        let ahead = Arc::new(ManualClock::new());
+        ahead.advance(Duration::from_secs(60 * 60));
+        let generator = V7Generator::new(ahead);
+        let first = generator.generate();
+
+        // Same state, but the clock is now an hour behind the last id.
+        let generator = V7Generator {
+            clock: Arc::new(ManualClock::new()),
+            last: Mutex::new(*generator.last.lock().unwrap()),
+        };
+
+        assert!(generator.generate() > first);
+    }
+
+    #[test]
+    fn should_generate_sequential_ids() {
+        let generator = SequentialGenerator::default();
+        assert_eq!(generator.generate().to_string(), "00000000-0000-0000-0000-000000000001");
+        assert_eq!(generator.generate().to_string(), "00000000-0000-0000-0000-000000000002");
+    }
+
+    #[test]
+    fn should_pick_generator_by_version() {
+        assert_eq!(uuid_generator("v4").unwrap().generate().get_version(), Some(Version::Random));
+        assert_eq!(uuid_generator("v7").unwrap().generate().get_version(), Some(Version::SortRand));
+        assert!(uuid_generator("v1").is_err());
+    }
+}
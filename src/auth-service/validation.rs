@@ -0,0 +1,204 @@
+use std::fmt;
+
+use crate::password::Password;
+
+// Byte limits, checked before anything is hashed or looked up. The password limit keeps hashing cost predictable.
+pub const MAX_USERNAME_BYTES: usize = 256;
+pub const MAX_PASSWORD_BYTES: usize = 1024;
+pub const MAX_EMAIL_BYTES: usize = 254;
+pub const MAX_INVITATION_CODE_BYTES: usize = 128;
+pub const MAX_CHALLENGE_RESPONSE_BYTES: usize = 4096;
+pub const MAX_IDEMPOTENCY_KEY_BYTES: usize = 128;
+pub const MAX_CREDENTIALS_PER_BATCH: usize = 1000;
+
+// The SignUp fields as they arrive, before any checks. Field names are the ones in the API.
+pub struct SignUpInput<'a> {
+    pub username: &'a str,
+    pub password: &'a str,
+    pub email: &'a str, // Empty means none.
+    pub invitation_code: &'a str,
+    pub challenge_response: &'a str,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct NormalizedSignUp {
+    pub username: String, // Surrounding whitespace removed.
+    pub password: Password, // Unchanged, so the user can sign in with what they typed.
+    pub email: Option<String>, // Trimmed, not yet normalized.
+    pub invitation_code: String,
+    pub challenge_response: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldViolation {
+    pub field: &'static str,
+    pub description: String,
+}
+
+impl fmt::Display for FieldViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.description)
+    }
+}
+
+// Checks every field and returns all the violations at once, so a client can fix them in one go. SignUp, CreateUser
+// and UpgradeGuest all run their input through here, so no way of creating an account skips the limits.
+pub fn validate_signup(input: &SignUpInput) -> Result<NormalizedSignUp, Vec<FieldViolation>> {
+    let mut violations = Vec::new();
+    let mut check = |field: &'static str, ok: bool, description: &dyn Fn() -> String| {
+        if !ok {
+            violations.push(FieldViolation { field, description: description() });
+        }
+        ok
+    };
+
+    let username = input.username.trim();
+    if check("username", input.username.len() <= MAX_USERNAME_BYTES, &|| too_long(MAX_USERNAME_BYTES))
+        && check("username", !username.is_empty(), &|| "must not be empty".to_owned())
+    {
+        check("username", username.chars().all(|c| !is_control(c) && !is_suspicious(c)), &|| {
+            "must not contain control, formatting or noncharacter code points".to_owned()
+        });
+    }
+
+    // Any printable text is a valid password, including surrounding spaces.
+    if check("password", input.password.len() <= MAX_PASSWORD_BYTES, &|| too_long(MAX_PASSWORD_BYTES)) {
+        check("password", input.password.chars().all(|c| !is_control(c)), &|| {
+            "must not contain control characters".to_owned()
+        });
+    }
+
+    // The address itself is checked when the account is created, which answers with a dedicated status code.
+    let email = input.email.trim();
+    if check("email", input.email.len() <= MAX_EMAIL_BYTES, &|| too_long(MAX_EMAIL_BYTES)) {
+        check("email", email.chars().all(|c| !is_control(c)), &|| {
+            "must not contain control characters".to_owned()
+        });
+    }
+
+    let invitation_code = input.invitation_code.trim();
+    if check("invitationCode", input.invitation_code.len() <= MAX_INVITATION_CODE_BYTES, &|| {
+        too_long(MAX_INVITATION_CODE_BYTES)
+    }) {
+        check("invitationCode", invitation_code.chars().all(|c| !is_control(c)), &|| {
+            "must not contain control characters".to_owned()
+        });
+    }
+
+    if check("challengeResponse", input.challenge_response.len() <= MAX_CHALLENGE_RESPONSE_BYTES, &|| {
+        too_long(MAX_CHALLENGE_RESPONSE_BYTES)
+    }) {
+        check("challengeResponse", input.challenge_response.chars().all(|c| !is_control(c)), &|| {
+            "must not contain control characters".to_owned()
+        });
+    }
+
+    if !violations.is_empty() {
+        return Err(violations);
+    }
+    Ok(NormalizedSignUp {
+        username: username.to_owned(),
+        password: input.password.into(),
+        email: Some(email.to_owned()).filter(|email| !email.is_empty()),
+        invitation_code: invitation_code.to_owned(),
+        challenge_response: input.challenge_response.to_owned(),
+    })
+}
+
+fn too_long(max_bytes: usize) -> String {
+    format!("must be at most {max_bytes} bytes")
+}
+
+fn is_control(c: char) -> bool {
+    c.is_control() || c == '\u{2028}' || c == '\u{2029}'
+}
+
+// Code points with no business in a name: bidi and other formatting controls, noncharacters, and the replacement
+// character, which means the text was mangled on the way in.
+fn is_suspicious(c: char) -> bool {
+    matches!(c,
+        '\u{200B}'..='\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2060}'..='\u{206F}' | '\u{FEFF}'
+        | '\u{FDD0}'..='\u{FDEF}' | '\u{FFFD}')
+        || (c as u32) & 0xFFFE == 0xFFFE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input<'a>(username: &'a str, password: &'a str) -> SignUpInput<'a> {
+        SignUpInput {
+            username,
+            password,
+            email: "",
+            invitation_code: "",
+            challenge_response: "",
+        }
+    }
+
+    fn fields(violations: Vec<FieldViolation>) -> Vec<&'static str> {
+        violations.into_iter().map(|violation| violation.field).collect()
+    }
+
+    #[test]
+    fn should_normalize_valid_input() {
+        let signup = validate_signup(&SignUpInput {
+            email: " Alice@Example.com ",
+            invitation_code: " code\n",
+            ..input("  alice ", " pass word ")
+        })
+        .unwrap();
+
+        assert_eq!(
+            signup,
+            NormalizedSignUp {
+                username: "alice".to_owned(),
+                password: " pass word ".into(),
+                email: Some("Alice@Example.com".to_owned()),
+                invitation_code: "code".to_owned(),
+                challenge_response: "".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn should_report_all_violations_at_once() {
+        let long = "x".repeat(MAX_CHALLENGE_RESPONSE_BYTES + 1);
+        let violations = validate_signup(&SignUpInput {
+            email: "alice@example.com\r\nBcc: eve@example.com",
+            invitation_code: "co\u{0}de",
+            challenge_response: &long,
+            ..input("ali\u{7}ce", "pass\u{0}word")
+        })
+        .unwrap_err();
+
+        assert_eq!(
+            fields(violations),
+            vec!["username", "password", "email", "invitationCode", "challengeResponse"]
+        );
+    }
+
+    #[test]
+    fn should_cap_field_lengths_in_bytes() {
+        // Fewer characters than the limit, but more bytes.
+        let username = "é".repeat(MAX_USERNAME_BYTES / 2 + 1);
+        let violations = validate_signup(&input(&username, "password")).unwrap_err();
+        assert_eq!(violations, vec![FieldViolation { field: "username", description: too_long(MAX_USERNAME_BYTES) }]);
+
+        let password = "p".repeat(MAX_PASSWORD_BYTES);
+        assert!(validate_signup(&input("alice", &password)).is_ok());
+        let password = "p".repeat(MAX_PASSWORD_BYTES + 1);
+        assert_eq!(fields(validate_signup(&input("alice", &password)).unwrap_err()), vec!["password"]);
+    }
+
+    #[test]
+    fn should_reject_invisible_and_mangled_usernames() {
+        for username in ["", "   ", "ali\u{202E}ce", "alice\u{FFFD}", "alice\u{FFFF}", "line\u{2028}break"] {
+            assert_eq!(
+                fields(validate_signup(&input(username, "password")).unwrap_err()),
+                vec!["username"],
+                "{username:?}"
+            );
+        }
+    }
+}
@@ -1,12 +1,18 @@
 use std::env;
+use std::fs;
 use clap::{Parser, Subcommand};
 
 use authentication::auth_client::AuthClient;
-use authentication::{SignInRequest, SignOutRequest, SignUpRequest};
+use authentication::{
+    ChangePasswordRequest, CompletePasswordResetRequest, Credential, ConfirmEmailRequest, CreateGuestRequest, CreateUserRequest, GetUserSecurityReportRequest, ListUsersByHashAlgorithmRequest, MintInvitationRequest, PurgeGuestsRequest, ReauthenticateRequest, ReloadConfigRequest,
+    RequirePasswordChangeRequest, SetMaintenanceModeRequest, SignInRequest,
+    SignOutRequest, SignUpRequest, StartEmailVerificationRequest, StartPasswordResetRequest, UpgradeGuestRequest,
+    VerifyCredentialsRequest, VerifyRequest, WatchUserEventsRequest,
+};
 use tonic::transport::Channel;
 use tonic::{Request, Response};
 
-use crate::authentication::{SignUpResponse, SignInResponse, SignOutResponse};
+use crate::authentication::{MintInvitationResponse, SignUpResponse, SignInResponse, SignOutResponse, StatusCode, VerifyResponse};
 
 pub mod authentication {
     tonic::include_proto!("authentication");
@@ -20,6 +26,7 @@ struct Cli {
 }
 
 #[derive(Subcommand)]
+#[allow(clippy::enum_variant_names)] // Variant names double as the subcommand names.
 enum Commands {
     SignIn {
         #[arg(short, long)]
@@ -32,11 +39,142 @@ enum Commands {
         username: String,
         #[arg(short, long)]
         password: String,
+        #[arg(short, long, default_value = "")]
+        invitation_code: String,
+        #[arg(short, long, default_value = "")]
+        challenge_response: String,
+        #[arg(short, long, default_value = "")]
+        email: String,
+        #[arg(long)]
+        idempotency_key: Option<String>, // Reuse it when retrying, to get the first answer back.
     },
     SignOut {
         #[arg(short, long)]
         session_token: String,
     },
+    Verify {
+        #[arg(short, long)]
+        session_token: String,
+    },
+    StartEmailVerification {
+        #[arg(short, long)]
+        session_token: String,
+    },
+    ConfirmEmail {
+        #[arg(short, long)]
+        session_token: String,
+        #[arg(short, long)]
+        verification_token: String,
+    },
+    StartPasswordReset {
+        #[arg(short, long)]
+        username_or_email: String,
+    },
+    CompletePasswordReset {
+        #[arg(short, long)]
+        reset_token: String,
+        #[arg(short, long)]
+        new_password: String,
+    },
+    ChangePassword {
+        #[arg(short, long)]
+        username: String,
+        #[arg(short, long)]
+        password: String,
+        #[arg(short, long)]
+        new_password: String,
+    },
+    CreateGuest,
+    UpgradeGuest {
+        #[arg(short, long)]
+        session_token: String,
+        #[arg(short, long)]
+        username: String,
+        #[arg(short, long)]
+        password: String,
+    },
+    Reauthenticate {
+        #[arg(short, long)]
+        session_token: String,
+        #[arg(short, long)]
+        password: String,
+    },
+    MintInvitation {
+        #[arg(short, long)]
+        admin_token: String,
+        #[arg(short, long, default_value_t = 1)]
+        max_uses: u32,
+        #[arg(short, long, default_value_t = 0)]
+        ttl_secs: u64,
+        #[arg(short, long, default_value = "")]
+        username: String,
+    },
+    WatchUserEvents {
+        #[arg(short, long)]
+        admin_token: String,
+        #[arg(short, long, default_value_t = 0)]
+        since_sequence: u64,
+    },
+    ReloadConfig {
+        #[arg(short, long)]
+        admin_token: String,
+    },
+    ListUsersByHashAlgorithm {
+        #[arg(short, long)]
+        admin_token: String,
+        #[arg(short = 'g', long)]
+        algorithm: String,
+    },
+    RequirePasswordChange {
+        #[arg(short, long)]
+        admin_token: String,
+        #[arg(short, long, num_args = 1.., required = true)]
+        user_uuids: Vec<String>,
+    },
+    CreateUser {
+        #[arg(short, long)]
+        admin_token: String,
+        #[arg(short, long)]
+        username: String,
+        #[arg(short, long)]
+        password: String,
+        #[arg(short, long, default_value = "")]
+        email: String,
+        #[arg(long)]
+        allow_banned_username: bool,
+    },
+    PurgeGuests {
+        #[arg(short, long)]
+        admin_token: String,
+        #[arg(short, long)]
+        older_than_secs: u64,
+        #[arg(long)]
+        dry_run: bool,
+    },
+    SetMaintenanceMode {
+        #[arg(short, long)]
+        admin_token: String,
+        #[arg(short, long)]
+        enabled: bool,
+        #[arg(short, long, default_value_t = 0)]
+        duration_secs: u64, // 0 for the service's maximum.
+    },
+    GetUserSecurityReport {
+        #[arg(short, long)]
+        admin_token: String,
+        #[arg(short, long)]
+        user_uuid: String,
+        #[arg(short, long, default_value_t = 0)]
+        max_events: u32, // 0 for the service's default.
+    },
+    VerifyBatch {
+        #[arg(short, long)]
+        admin_token: String,
+        #[arg(short, long)]
+        input: String, // CSV of username,password lines. A "username,password" header line is skipped.
+        #[arg(short, long, default_value_t = 500)]
+        batch_size: usize, // Pairs per call, at most 1000.
+    },
 }
 
 #[tokio::main]
@@ -52,18 +190,24 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             let request: Request<SignInRequest> = Request::new(SignInRequest{
                 username: username.clone(),
                 password: password.clone(),
-            }.into()); // Create a new `SignInRequest`.
+            }); // Create a new `SignInRequest`.
         
             // Make a sign in request. Propagate any errors. Convert Response<SignInResponse> into SignInResponse.
             let response: SignInResponse = client.sign_in(request).await?.into_inner();
         
             println!("{:?}", response);
         }
-        Some(Commands::SignUp { username, password }) => {
-            let request: Request<SignUpRequest> = Request::new(SignUpRequest{
+        Some(Commands::SignUp { username, password, invitation_code, challenge_response, email, idempotency_key }) => {
+            let mut request: Request<SignUpRequest> = Request::new(SignUpRequest{
                 username: username.clone(),
                 password: password.clone(),
-            }.into()); // Create a new `SignUpRequest`.
+                invitation_code: invitation_code.clone(),
+                challenge_response: challenge_response.clone(),
+                email: email.clone(),
+            }); // Create a new `SignUpRequest`.
+            if let Some(key) = idempotency_key {
+                request.metadata_mut().insert("idempotency-key", key.parse()?);
+            }
         
             let response: Response<SignUpResponse> = client.sign_up(request).await?; // Make a sign up request. Propagate any errors.
         
@@ -72,12 +216,208 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         Some(Commands::SignOut { session_token }) => {
             let request: Request<SignOutRequest> = Request::new(SignOutRequest{
                 session_token: session_token.clone(),
-            }.into()); // Create a new `SignOutRequest`.
+            }); // Create a new `SignOutRequest`.
         
             let response: Response<SignOutResponse> = client.sign_out(request).await?; // Make a sign out request. Propagate any errors.
         
             println!("{:?}", response.into_inner());
         }
+        Some(Commands::Verify { session_token }) => {
+            let request: Request<VerifyRequest> = Request::new(VerifyRequest{
+                session_token: session_token.clone(),
+            }); // Create a new `VerifyRequest`.
+        
+            let response: Response<VerifyResponse> = client.verify(request).await?; // Make a verify request. Propagate any errors.
+        
+            println!("{:?}", response.into_inner());
+        }
+        Some(Commands::StartEmailVerification { session_token }) => {
+            let request: Request<StartEmailVerificationRequest> = Request::new(StartEmailVerificationRequest{
+                session_token: session_token.clone(),
+            });
+
+            println!("{:?}", client.start_email_verification(request).await?.into_inner());
+        }
+        Some(Commands::ConfirmEmail { session_token, verification_token }) => {
+            let request: Request<ConfirmEmailRequest> = Request::new(ConfirmEmailRequest{
+                session_token: session_token.clone(),
+                verification_token: verification_token.clone(),
+            });
+
+            println!("{:?}", client.confirm_email(request).await?.into_inner());
+        }
+        Some(Commands::StartPasswordReset { username_or_email }) => {
+            let request: Request<StartPasswordResetRequest> = Request::new(StartPasswordResetRequest{
+                username_or_email: username_or_email.clone(),
+            });
+
+            println!("{:?}", client.start_password_reset(request).await?.into_inner());
+        }
+        Some(Commands::CompletePasswordReset { reset_token, new_password }) => {
+            let request: Request<CompletePasswordResetRequest> = Request::new(CompletePasswordResetRequest{
+                reset_token: reset_token.clone(),
+                new_password: new_password.clone(),
+            });
+
+            println!("{:?}", client.complete_password_reset(request).await?.into_inner());
+        }
+        Some(Commands::ChangePassword { username, password, new_password }) => {
+            let request: Request<ChangePasswordRequest> = Request::new(ChangePasswordRequest{
+                username: username.clone(),
+                password: password.clone(),
+                new_password: new_password.clone(),
+            });
+
+            println!("{:?}", client.change_password(request).await?.into_inner());
+        }
+        Some(Commands::CreateGuest) => {
+            let request: Request<CreateGuestRequest> = Request::new(CreateGuestRequest{});
+
+            println!("{:?}", client.create_guest(request).await?.into_inner());
+        }
+        Some(Commands::UpgradeGuest { session_token, username, password }) => {
+            let request: Request<UpgradeGuestRequest> = Request::new(UpgradeGuestRequest{
+                session_token: session_token.clone(),
+                username: username.clone(),
+                password: password.clone(),
+            });
+
+            println!("{:?}", client.upgrade_guest(request).await?.into_inner());
+        }
+        Some(Commands::Reauthenticate { session_token, password }) => {
+            let request: Request<ReauthenticateRequest> = Request::new(ReauthenticateRequest{
+                session_token: session_token.clone(),
+                password: password.clone(),
+            });
+
+            println!("{:?}", client.reauthenticate(request).await?.into_inner());
+        }
+        Some(Commands::MintInvitation { admin_token, max_uses, ttl_secs, username }) => {
+            let mut request: Request<MintInvitationRequest> = Request::new(MintInvitationRequest{
+                max_uses: *max_uses,
+                ttl_secs: *ttl_secs,
+                username: username.clone(),
+            }); // Create a new `MintInvitationRequest`.
+            request.metadata_mut().insert("authorization", format!("Bearer {}", admin_token).parse()?);
+        
+            let response: Response<MintInvitationResponse> = client.mint_invitation(request).await?; // Make a mint invitation request. Propagate any errors.
+        
+            println!("{:?}", response.into_inner());
+        }
+        Some(Commands::WatchUserEvents { admin_token, since_sequence }) => {
+            let mut request: Request<WatchUserEventsRequest> = Request::new(WatchUserEventsRequest{
+                since_sequence: *since_sequence,
+            });
+            request.metadata_mut().insert("authorization", format!("Bearer {}", admin_token).parse()?);
+
+            // Print events until the server ends the stream.
+            let mut stream = client.watch_user_events(request).await?.into_inner();
+            while let Some(event) = stream.message().await? {
+                println!("{:?}", event);
+            }
+        }
+        Some(Commands::ReloadConfig { admin_token }) => {
+            let mut request: Request<ReloadConfigRequest> = Request::new(ReloadConfigRequest{}); // Create a new `ReloadConfigRequest`.
+            request.metadata_mut().insert("authorization", format!("Bearer {}", admin_token).parse()?);
+
+            let response = client.reload_config(request).await?; // Make a reload config request. Propagate any errors.
+
+            println!("{:?}", response.into_inner());
+        }
+        Some(Commands::ListUsersByHashAlgorithm { admin_token, algorithm }) => {
+            let mut request: Request<ListUsersByHashAlgorithmRequest> = Request::new(ListUsersByHashAlgorithmRequest{
+                algorithm: algorithm.clone(),
+            });
+            request.metadata_mut().insert("authorization", format!("Bearer {}", admin_token).parse()?);
+
+            // One account per line, so the uuids can be fed back into require-password-change.
+            for user in client.list_users_by_hash_algorithm(request).await?.into_inner().users {
+                println!("{} {}{}", user.user_uuid, user.username, if user.password_change_required { " (change required)" } else { "" });
+            }
+        }
+        Some(Commands::RequirePasswordChange { admin_token, user_uuids }) => {
+            let mut request: Request<RequirePasswordChangeRequest> = Request::new(RequirePasswordChangeRequest{
+                user_uuids: user_uuids.clone(),
+            });
+            request.metadata_mut().insert("authorization", format!("Bearer {}", admin_token).parse()?);
+
+            println!("{:?}", client.require_password_change(request).await?.into_inner());
+        }
+        Some(Commands::CreateUser { admin_token, username, password, email, allow_banned_username }) => {
+            let mut request: Request<CreateUserRequest> = Request::new(CreateUserRequest{
+                username: username.clone(),
+                password: password.clone(),
+                email: email.clone(),
+                allow_banned_username: *allow_banned_username,
+            });
+            request.metadata_mut().insert("authorization", format!("Bearer {}", admin_token).parse()?);
+
+            println!("{:?}", client.create_user(request).await?.into_inner());
+        }
+        Some(Commands::PurgeGuests { admin_token, older_than_secs, dry_run }) => {
+            let mut request: Request<PurgeGuestsRequest> = Request::new(PurgeGuestsRequest{
+                older_than_secs: *older_than_secs,
+                dry_run: *dry_run,
+            });
+            request.metadata_mut().insert("authorization", format!("Bearer {}", admin_token).parse()?);
+
+            println!("{:?}", client.purge_guests(request).await?.into_inner());
+        }
+        Some(Commands::SetMaintenanceMode { admin_token, enabled, duration_secs }) => {
+            let mut request: Request<SetMaintenanceModeRequest> = Request::new(SetMaintenanceModeRequest{
+                enabled: *enabled,
+                duration_secs: *duration_secs,
+            });
+            request.metadata_mut().insert("authorization", format!("Bearer {}", admin_token).parse()?);
+
+            println!("{:?}", client.set_maintenance_mode(request).await?.into_inner());
+        }
+        Some(Commands::GetUserSecurityReport { admin_token, user_uuid, max_events }) => {
+            let mut request: Request<GetUserSecurityReportRequest> = Request::new(GetUserSecurityReportRequest{
+                user_uuid: user_uuid.to_owned(),
+                max_events: *max_events,
+            });
+            request.metadata_mut().insert("authorization", format!("Bearer {}", admin_token).parse()?);
+
+            println!("{:?}", client.get_user_security_report(request).await?.into_inner());
+        }
+        Some(Commands::VerifyBatch { admin_token, input, batch_size }) => {
+            // Line number and credential. Passwords may contain commas, usernames can't.
+            let contents = fs::read_to_string(input)?;
+            let mut credentials: Vec<(usize, Credential)> = Vec::new();
+            for (i, line) in contents.lines().enumerate() {
+                if line.trim().is_empty() || (i == 0 && line.trim() == "username,password") {
+                    continue;
+                }
+                let Some((username, password)) = line.split_once(',') else {
+                    return Err(format!("{}:{}: expected username,password", input, i + 1).into());
+                };
+                credentials.push((i + 1, Credential{ username: username.to_owned(), password: password.to_owned() }));
+            }
+
+            let (mut matched, mut unknown, mut wrong_password) = (0, 0, 0);
+            for batch in credentials.chunks((*batch_size).max(1)) {
+                let mut request: Request<VerifyCredentialsRequest> = Request::new(VerifyCredentialsRequest{
+                    credentials: batch.iter().map(|(_, credential)| credential.clone()).collect(),
+                });
+                request.metadata_mut().insert("authorization", format!("Bearer {}", admin_token).parse()?);
+
+                let response = client.verify_credentials(request).await?.into_inner();
+                for ((line, credential), result) in batch.iter().zip(response.results) {
+                    let status = StatusCode::from_i32(result.status_code).unwrap_or(StatusCode::Failure);
+                    match status {
+                        StatusCode::Success => {
+                            matched += 1;
+                            continue;
+                        }
+                        StatusCode::UserNotFound => unknown += 1,
+                        _ => wrong_password += 1,
+                    }
+                    println!("line {}: {}: {:?}", line, credential.username, status);
+                }
+            }
+            println!("{} checked: {} matched, {} unknown users, {} wrong passwords", credentials.len(), matched, unknown, wrong_password);
+        }
         None => {}
     }
 
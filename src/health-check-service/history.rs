@@ -0,0 +1,156 @@
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+
+// The calls a probe makes, in order.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(clippy::enum_variant_names)] // Named after the RPCs.
+pub enum Step {
+    SignUp,
+    SignIn,
+    SignOut,
+    Verify, // That the signed out session no longer verifies.
+}
+
+impl Step {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Step::SignUp => "sign_up",
+            Step::SignIn => "sign_in",
+            Step::SignOut => "sign_out",
+            Step::Verify => "verify",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    Success,
+    // The probe stops at the first step that fails, either in transport or with a non-success status.
+    Failure { step: Step, error: String },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProbeResult {
+    pub started_at: SystemTime,
+    pub latencies: Vec<(Step, Duration)>, // Only the steps that ran.
+    pub outcome: Outcome,
+}
+
+impl ProbeResult {
+    pub fn succeeded(&self) -> bool {
+        self.outcome == Outcome::Success
+    }
+}
+
+// The last `capacity` probe results, oldest first.
+pub struct ProbeHistory {
+    results: VecDeque<ProbeResult>,
+    capacity: usize,
+    window: usize, // How many of the latest results the success rate covers.
+}
+
+impl ProbeHistory {
+    pub fn new(capacity: usize, window: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            results: VecDeque::with_capacity(capacity),
+            capacity,
+            window: window.clamp(1, capacity),
+        }
+    }
+
+    pub fn record(&mut self, result: ProbeResult) {
+        if self.results.len() == self.capacity {
+            self.results.pop_front();
+        }
+        self.results.push_back(result);
+    }
+
+    pub fn results(&self) -> impl Iterator<Item = &ProbeResult> {
+        self.results.iter()
+    }
+
+    pub fn latest(&self) -> Option<&ProbeResult> {
+        self.results.back()
+    }
+
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    // Share of the latest `window` probes that succeeded, or None before the first probe.
+    pub fn success_rate(&self) -> Option<f64> {
+        let recent = self.results.iter().rev().take(self.window);
+        let (total, succeeded) = recent.fold((0, 0), |(total, succeeded), result| {
+            (total + 1, succeeded + result.succeeded() as usize)
+        });
+        (total > 0).then(|| succeeded as f64 / total as f64)
+    }
+}
+
+#[cfg(test)]
+pub fn probe_result(succeeded: bool) -> ProbeResult {
+    let latency = Duration::from_millis(5);
+    ProbeResult {
+        started_at: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        latencies: vec![(Step::SignUp, latency), (Step::SignIn, latency)],
+        outcome: if succeeded {
+            Outcome::Success
+        } else {
+            Outcome::Failure {
+                step: Step::SignIn,
+                error: "status FAILURE".to_owned(),
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_evict_oldest_results() {
+        let mut history = ProbeHistory::new(3, 3);
+        for i in 0..5 {
+            let mut result = probe_result(true);
+            result.started_at += Duration::from_secs(i);
+            history.record(result);
+        }
+
+        let started: Vec<u64> = history
+            .results()
+            .map(|result| result.started_at.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() - 1_700_000_000)
+            .collect();
+        assert_eq!(started, vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn should_compute_success_rate_over_latest_window() {
+        let mut history = ProbeHistory::new(10, 4);
+        assert_eq!(history.success_rate(), None);
+
+        history.record(probe_result(false));
+        assert_eq!(history.success_rate(), Some(0.0));
+
+        for succeeded in [true, true, false, true] {
+            history.record(probe_result(succeeded));
+        }
+        // The first failure has left the window of four.
+        assert_eq!(history.success_rate(), Some(0.75));
+
+        // The second failure leaves it after four more successes.
+        for _ in 0..2 {
+            history.record(probe_result(true));
+            assert_eq!(history.success_rate(), Some(0.75));
+        }
+        history.record(probe_result(true));
+        assert_eq!(history.success_rate(), Some(1.0));
+    }
+
+    #[test]
+    fn should_cap_window_at_capacity() {
+        let history = ProbeHistory::new(5, 50);
+        assert_eq!(history.window(), 5);
+    }
+}
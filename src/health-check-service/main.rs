@@ -1,13 +1,20 @@
 use std::env;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
 
 use authentication::auth_client::AuthClient;
-use authentication::{SignInRequest, SignOutRequest, SignUpRequest};
+use authentication::{SignInRequest, SignOutRequest, SignUpRequest, VerifyRequest};
 use tokio::time::{sleep, Duration};
+use tonic::transport::{Channel, Endpoint};
 use tonic::{Request, Response};
 use uuid::Uuid;
-use std::borrow::Borrow;
 
-use crate::authentication::{StatusCode, SignUpResponse, SignInResponse, SignOutResponse};
+use crate::authentication::{StatusCode, SignUpResponse, SignInResponse, SignOutResponse, VerifyResponse};
+use crate::history::{Outcome, ProbeHistory, ProbeResult, Step};
+
+mod history;
+mod status;
 
 pub mod authentication {
     tonic::include_proto!("authentication");
@@ -18,66 +25,128 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // AUTH_SERVICE_HOST_NAME will be set to 'auth' when running the health check service in Docker
     // ::0 is required for Docker to work: https://stackoverflow.com/questions/59179831/docker-app-server-ip-address-127-0-0-1-difference-of-0-0-0-0-ip
     let auth_hostname = env::var("AUTH_SERVICE_HOST_NAME").unwrap_or("[::0]".to_owned());
+    let auth_port: u16 = env_or("AUTH_SERVICE_PORT", 50051);
+    // Where GET /status and GET /healthz are served.
+    let status_addr = env::var("HEALTH_CHECK_STATUS_ADDR").unwrap_or("[::0]:8080".to_owned()).parse()?;
+    let history_size: usize = env_or("HEALTH_CHECK_HISTORY_SIZE", 100); // Probe results kept for /status.
+    let success_window: usize = env_or("HEALTH_CHECK_SUCCESS_WINDOW", 20); // Latest probes the success rate covers.
+    let interval = Duration::from_millis(env_or("HEALTH_CHECK_INTERVAL_MS", 3_000)); // Pause between probes.
+
+    // Connect lazily, so an unreachable auth service shows up as failed probes instead of stopping the service.
+    let mut client = AuthClient::new(Endpoint::from_shared(format!("http://{}:{}", auth_hostname, auth_port))?.connect_lazy());
 
-    // Establish connection when auth service
-    let mut client = AuthClient::connect(format!("http://{}:50051", auth_hostname)).await?;
+    let history = Arc::new(Mutex::new(ProbeHistory::new(history_size, success_window)));
+    tokio::spawn(status::serve(status_addr, history.clone()));
 
     loop {
-        let username: String = Uuid::new_v4().to_string(); // Create random username using new_v4()
-        let password: String = Uuid::new_v4().to_string(); // Create random password using new_v4()
+        let result = probe(&mut client).await;
+        if let Outcome::Failure { step, error } = &result.outcome {
+            println!("PROBE FAILED AT {}: {}", step.name(), error);
+        }
+        history.lock().unwrap().record(result);
 
-        let request: Request<SignUpRequest> = Request::new(SignUpRequest{
-            username: username.clone(),
-            password: password.clone(),
-        }.into()); // Create a new `SignUpRequest`.
+        println!("--------------------------------------",);
 
-        let response: Response<SignUpResponse> = client.sign_up(request).await?; // Make a sign up request. Propagate any errors.
+        sleep(interval).await;
+    }
+}
 
-        // Log the response
-        println!(
-            "SIGN UP RESPONSE STATUS: {:?}",
-            StatusCode::from_i32(response.into_inner().status_code)
-        );
+// Signs up a random user, signs it in and signs it out again, then checks the session is gone, timing each call.
+async fn probe(client: &mut AuthClient<Channel>) -> ProbeResult {
+    let mut result = ProbeResult {
+        started_at: SystemTime::now(),
+        latencies: Vec::new(),
+        outcome: Outcome::Success,
+    };
+    if let Err((step, error)) = run_steps(client, &mut result.latencies).await {
+        result.outcome = Outcome::Failure { step, error };
+    }
+    result
+}
 
-        // ---------------------------------------------
+async fn run_steps(client: &mut AuthClient<Channel>, latencies: &mut Vec<(Step, Duration)>) -> Result<(), (Step, String)> {
+    let username: String = Uuid::new_v4().to_string(); // Create random username using new_v4()
+    let password: String = Uuid::new_v4().to_string(); // Create random password using new_v4()
 
-        let request: Request<SignInRequest> = Request::new(SignInRequest{
-            username: username.clone(),
-            password: password.clone(),
-        }.into()); // Create a new `SignInRequest`.
+    let request: Request<SignUpRequest> = Request::new(SignUpRequest{
+        username: username.clone(),
+        password: password.clone(),
+        ..Default::default()
+    }); // Create a new `SignUpRequest`.
 
-        // Make a sign in request. Propagate any errors. Convert Response<SignInResponse> into SignInResponse.
-        let response: Response<SignInResponse> = client.sign_in(request).await?;
+    let started = Instant::now();
+    let response: Result<Response<SignUpResponse>, _> = client.sign_up(request).await; // Make a sign up request.
+    latencies.push((Step::SignUp, started.elapsed()));
+    let status_code = response.map_err(|e| (Step::SignUp, e.to_string()))?.into_inner().status_code;
 
-        println!(
-            "SIGN IN RESPONSE STATUS: {:?}",
-            StatusCode::from_i32(response.into_inner().status_code)
-        );
+    // Log the response
+    println!("SIGN UP RESPONSE STATUS: {:?}", StatusCode::from_i32(status_code));
+    check_status(Step::SignUp, status_code)?;
 
-        let request: Request<SignInRequest> = Request::new(SignInRequest{
-            username: username.clone(),
-            password: password.clone(),
-        }.into()); // Create a new `SignInRequest`.
+    // ---------------------------------------------
 
-        // Make a sign in request. Propagate any errors. Convert Response<SignInResponse> into SignInResponse.
-        let response: Response<SignInResponse> = client.sign_in(request).await?;
+    let request: Request<SignInRequest> = Request::new(SignInRequest{
+        username: username.clone(),
+        password: password.clone(),
+    }); // Create a new `SignInRequest`.
 
-        // ---------------------------------------------
-        let user_uuid: String = response.into_inner().user_uuid;
+    let started = Instant::now();
+    let response: Result<Response<SignInResponse>, _> = client.sign_in(request).await; // Make a sign in request.
+    latencies.push((Step::SignIn, started.elapsed()));
+    let response: SignInResponse = response.map_err(|e| (Step::SignIn, e.to_string()))?.into_inner();
 
-        let request: Request<SignOutRequest> = Request::new(SignOutRequest{
-            session_token: user_uuid,
-        }.into()); // Create a new `SignOutRequest`.
+    println!("SIGN IN RESPONSE STATUS: {:?}", StatusCode::from_i32(response.status_code));
+    check_status(Step::SignIn, response.status_code)?;
 
-        let response: Response<SignOutResponse> = client.sign_out(request).await?; // Make a sign out request. Propagate any errors.
+    // ---------------------------------------------
 
-        println!(
-            "SIGN OUT RESPONSE STATUS: {:?}",
-            StatusCode::from_i32(response.into_inner().status_code)
-        );
+    let session_token = response.session_token;
+    let request: Request<SignOutRequest> = Request::new(SignOutRequest{
+        session_token: session_token.clone(),
+    }); // Create a new `SignOutRequest`.
 
-        println!("--------------------------------------",);
+    let started = Instant::now();
+    let response: Result<Response<SignOutResponse>, _> = client.sign_out(request).await; // Make a sign out request.
+    latencies.push((Step::SignOut, started.elapsed()));
+    let status_code = response.map_err(|e| (Step::SignOut, e.to_string()))?.into_inner().status_code;
+
+    println!("SIGN OUT RESPONSE STATUS: {:?}", StatusCode::from_i32(status_code));
+    check_status(Step::SignOut, status_code)?;
+
+    // ---------------------------------------------
+
+    let request: Request<VerifyRequest> = Request::new(VerifyRequest{
+        session_token,
+    }); // Create a new `VerifyRequest`.
+
+    let started = Instant::now();
+    let response: Result<Response<VerifyResponse>, _> = client.verify(request).await; // Make a verify request.
+    latencies.push((Step::Verify, started.elapsed()));
+    let status_code = response.map_err(|e| (Step::Verify, e.to_string()))?.into_inner().status_code;
+
+    println!("VERIFY AFTER SIGN OUT RESPONSE STATUS: {:?}", StatusCode::from_i32(status_code));
+    // A session that still verifies after sign out is a failure, whatever else the service said.
+    if status_code == StatusCode::Success as i32 {
+        return Err((Step::Verify, "session still valid after sign out".to_owned()));
+    }
+    Ok(())
+}
+
+fn check_status(step: Step, status_code: i32) -> Result<(), (Step, String)> {
+    if status_code == StatusCode::Success as i32 {
+        Ok(())
+    } else {
+        Err((step, format!("status {:?}", StatusCode::from_i32(status_code))))
+    }
+}
 
-        sleep(Duration::from_secs(3)).await;
+// Reads and parses an environment variable, falling back to `default` if it is unset or invalid.
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            println!("Ignoring invalid value {:?} for {}", value, name);
+            default
+        }),
+        Err(_) => default,
     }
 }
@@ -0,0 +1,161 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+
+use crate::history::{Outcome, ProbeHistory};
+
+// Serves the probe history for dashboards:
+//
+//     GET /status   JSON with the recent probes and the rolling success rate.
+//     GET /healthz  200 when the latest probe succeeded, 503 otherwise (including before the first probe).
+pub async fn serve(addr: SocketAddr, history: Arc<Mutex<ProbeHistory>>) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(move |_| {
+        let history = history.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let response = respond(&request, &history.lock().unwrap());
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+
+    Server::bind(&addr).serve(make_service).await
+}
+
+fn respond(request: &Request<Body>, history: &ProbeHistory) -> Response<Body> {
+    let (status, content_type, body) = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/status") => (StatusCode::OK, "application/json", status_json(history)),
+        (&Method::GET, "/healthz") => match history.latest() {
+            Some(latest) if latest.succeeded() => (StatusCode::OK, "text/plain", "ok".to_owned()),
+            _ => (StatusCode::SERVICE_UNAVAILABLE, "text/plain", "failing".to_owned()),
+        },
+        _ => (StatusCode::NOT_FOUND, "text/plain", "not found".to_owned()),
+    };
+
+    Response::builder()
+        .status(status)
+        .header("content-type", content_type)
+        .body(Body::from(body))
+        .expect("static response parts are valid")
+}
+
+// {"healthy":true,"success_rate":0.5,"window":20,"probes":[{"started_at_ms":..,"latency_ms":{"sign_up":3,..},
+// "outcome":"failure","failed_step":"sign_in","error":".."},..]}, probes oldest first.
+fn status_json(history: &ProbeHistory) -> String {
+    let probes: Vec<String> = history
+        .results()
+        .map(|result| {
+            let started_at_ms = result.started_at.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis());
+            let latencies: Vec<String> = result
+                .latencies
+                .iter()
+                .map(|(step, latency)| format!(r#""{}":{}"#, step.name(), latency.as_millis()))
+                .collect();
+            let outcome = match &result.outcome {
+                Outcome::Success => r#""outcome":"success""#.to_owned(),
+                Outcome::Failure { step, error } => format!(
+                    r#""outcome":"failure","failed_step":"{}","error":{}"#,
+                    step.name(),
+                    json_string(error)
+                ),
+            };
+            format!(r#"{{"started_at_ms":{},"latency_ms":{{{}}},{}}}"#, started_at_ms, latencies.join(","), outcome)
+        })
+        .collect();
+
+    format!(
+        r#"{{"healthy":{},"success_rate":{},"window":{},"probes":[{}]}}"#,
+        history.latest().is_some_and(|latest| latest.succeeded()),
+        history.success_rate().map_or("null".to_owned(), |rate| rate.to_string()),
+        history.window(),
+        probes.join(",")
+    )
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::probe_result;
+
+    fn get(path: &str, history: &ProbeHistory) -> (StatusCode, String) {
+        let request = Request::get(path).body(Body::empty()).unwrap();
+        let response = respond(&request, history);
+        let status = response.status();
+        let body = body_string(response);
+        (status, body)
+    }
+
+    fn body_string(response: Response<Body>) -> String {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let bytes = runtime.block_on(hyper::body::to_bytes(response.into_body())).unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn should_report_probes_as_json() {
+        let mut history = ProbeHistory::new(10, 2);
+        history.record(probe_result(false));
+        history.record(probe_result(true));
+
+        let (status, body) = get("/status", &history);
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            concat!(
+                r#"{"healthy":true,"success_rate":0.5,"window":2,"probes":["#,
+                r#"{"started_at_ms":1700000000000,"latency_ms":{"sign_up":5,"sign_in":5},"#,
+                r#""outcome":"failure","failed_step":"sign_in","error":"status FAILURE"},"#,
+                r#"{"started_at_ms":1700000000000,"latency_ms":{"sign_up":5,"sign_in":5},"outcome":"success"}]}"#,
+            )
+        );
+    }
+
+    #[test]
+    fn should_report_empty_history() {
+        let (_, body) = get("/status", &ProbeHistory::new(10, 2));
+        assert_eq!(body, r#"{"healthy":false,"success_rate":null,"window":2,"probes":[]}"#);
+    }
+
+    #[test]
+    fn should_reflect_latest_probe_in_healthz() {
+        let mut history = ProbeHistory::new(10, 10);
+        assert_eq!(get("/healthz", &history).0, StatusCode::SERVICE_UNAVAILABLE);
+
+        history.record(probe_result(true));
+        assert_eq!(get("/healthz", &history), (StatusCode::OK, "ok".to_owned()));
+
+        history.record(probe_result(false));
+        assert_eq!(get("/healthz", &history).0, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn should_not_find_other_paths() {
+        assert_eq!(get("/", &ProbeHistory::new(1, 1)).0, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn should_escape_error_messages() {
+        assert_eq!(json_string("a \"quoted\"\nline"), r#""a \"quoted\"\nline""#);
+    }
+}
@@ -0,0 +1,113 @@
+// Runs the service binaries as child processes for tests that span services. Every process is killed when its
+// handle is dropped, so a failing test doesn't leave services running.
+#![allow(dead_code)] // Each test binary uses a different part of it.
+
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+// How long a service gets to start listening, and a condition to come true.
+pub const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct ServiceProcess {
+    child: Option<Child>,
+    pub addr: SocketAddr,
+    binary: &'static str,
+    env: Vec<(String, String)>,
+}
+
+impl ServiceProcess {
+    fn spawn(binary: &'static str, addr: SocketAddr, env: Vec<(String, String)>) -> Self {
+        let mut process = Self { child: None, addr, binary, env };
+        process.start();
+        process
+    }
+
+    fn start(&mut self) {
+        let child = Command::new(self.binary)
+            .envs(self.env.iter().map(|(name, value)| (name, value)))
+            .env_remove("AUTH_CONFIG_FILE")
+            .stdout(Stdio::null())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .unwrap_or_else(|e| panic!("failed to start {}: {e}", self.binary));
+        self.child = Some(child);
+        wait_for(&format!("{} to listen on {}", self.binary, self.addr), || TcpStream::connect(self.addr).is_ok());
+    }
+
+    // Kills the process straight away, like a crash would.
+    pub fn kill(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+
+    // Starts the process again on the same address, with the same settings.
+    pub fn restart(&mut self) {
+        self.kill();
+        self.start();
+    }
+}
+
+impl Drop for ServiceProcess {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+// Starts the auth service on a free local port. `config` is AUTH_* variables on top of settings that keep it fast
+// to test: cheap hashing and no metrics endpoint.
+pub fn spawn_auth(config: &[(&str, &str)]) -> ServiceProcess {
+    let addr = free_addr();
+    let mut env = vec![
+        ("AUTH_LISTEN_ADDR".to_owned(), addr.to_string()),
+        ("AUTH_HASH_ROUNDS".to_owned(), "10000".to_owned()),
+    ];
+    env.extend(config.iter().map(|(name, value)| (name.to_string(), value.to_string())));
+    ServiceProcess::spawn(env!("CARGO_BIN_EXE_auth"), addr, env)
+}
+
+// Starts the health-check service probing the auth service at `target`, probing every 200ms. Its HTTP status
+// endpoints are on the returned process's address.
+pub fn spawn_healthcheck(target: SocketAddr) -> ServiceProcess {
+    let addr = free_addr();
+    let env = vec![
+        ("AUTH_SERVICE_HOST_NAME".to_owned(), target.ip().to_string()),
+        ("AUTH_SERVICE_PORT".to_owned(), target.port().to_string()),
+        ("HEALTH_CHECK_STATUS_ADDR".to_owned(), addr.to_string()),
+        ("HEALTH_CHECK_INTERVAL_MS".to_owned(), "200".to_owned()),
+    ];
+    ServiceProcess::spawn(env!("CARGO_BIN_EXE_health-check"), addr, env)
+}
+
+// Status code and body of `GET path`, None when nothing answers.
+pub fn http_get(addr: SocketAddr, path: &str) -> Option<(u16, String)> {
+    let mut stream = TcpStream::connect(addr).ok()?;
+    stream.set_read_timeout(Some(Duration::from_secs(5))).ok()?;
+    write!(stream, "GET {path} HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n\r\n").ok()?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).ok()?;
+
+    let status = response.split(' ').nth(1)?.parse().ok()?;
+    let body = response.split_once("\r\n\r\n").map_or("", |(_, body)| body);
+    Some((status, body.to_owned()))
+}
+
+// Polls `condition` until it holds, panicking with `what` after `STARTUP_TIMEOUT`.
+pub fn wait_for(what: &str, mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out waiting for {what}");
+        sleep(Duration::from_millis(50));
+    }
+}
+
+// A port nothing listens on right now. Another process could take it before the service binds it, which is
+// unlikely enough for tests.
+fn free_addr() -> SocketAddr {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).expect("no free local port");
+    listener.local_addr().unwrap()
+}
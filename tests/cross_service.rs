@@ -0,0 +1,25 @@
+mod common;
+
+use common::{http_get, spawn_auth, spawn_healthcheck, wait_for};
+
+fn healthz_status(addr: std::net::SocketAddr) -> Option<u16> {
+    http_get(addr, "/healthz").map(|(status, _)| status)
+}
+
+#[test]
+fn health_check_should_follow_auth_service_going_down_and_coming_back() {
+    let mut auth = spawn_auth(&[]);
+    let health_check = spawn_healthcheck(auth.addr);
+
+    wait_for("probes to succeed", || healthz_status(health_check.addr) == Some(200));
+    let (_, status) = http_get(health_check.addr, "/status").unwrap();
+    assert!(status.contains(r#""outcome":"success""#), "{status}");
+
+    auth.kill();
+    wait_for("probes to fail", || healthz_status(health_check.addr) == Some(503));
+    let (_, status) = http_get(health_check.addr, "/status").unwrap();
+    assert!(status.contains(r#""failed_step":"sign_up""#), "{status}");
+
+    auth.restart();
+    wait_for("probes to succeed again", || healthz_status(health_check.addr) == Some(200));
+}